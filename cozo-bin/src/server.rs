@@ -15,6 +15,7 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 
 use axum::body::{boxed, Body, BoxBody};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::{Path, Query, State};
 use axum::http::{header, HeaderName, Method, Request, Response, StatusCode};
 use axum::response::sse::{Event, KeepAlive};
@@ -35,7 +36,8 @@ use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 
 use cozo::{
-    format_error_as_json, DataValue, DbInstance, MultiTransaction, NamedRows, SimpleFixedRule,
+    format_error_as_json, DataValue, DbInstance, MultiTransaction, NamedRows, QueryDelta, Session,
+    SimpleFixedRule,
 };
 
 #[derive(Args, Debug)]
@@ -66,6 +68,28 @@ pub(crate) struct ServerArgs {
     /// Port to use
     #[clap(short = 'P', long, default_value_t = 9070)]
     port: u16,
+
+    /// Default memory limit of each query in bytes, overridable with `:max_memory`
+    #[clap(long)]
+    max_memory: Option<usize>,
+
+    /// Number of tuples a temp store of a query may hold in memory before spilling to disk
+    #[clap(long)]
+    temp_spill_threshold: Option<usize>,
+
+    /// Default limit on the iterations of recursive rules of each query,
+    /// overridable with `:max_iterations`
+    #[clap(long)]
+    max_iterations: Option<usize>,
+
+    /// Number of results of read-only queries to cache, invalidated by writes to the relations read
+    #[clap(long)]
+    result_cache_capacity: Option<usize>,
+
+    /// Times to retry scripts failing on conflicts with concurrent transactions,
+    /// overridable with `:retry`
+    #[clap(long)]
+    conflict_retries: Option<usize>,
 }
 
 #[derive(Clone)]
@@ -75,6 +99,8 @@ struct DbState {
     rule_counter: Arc<AtomicU32>,
     tx_counter: Arc<AtomicU32>,
     txs: Arc<Mutex<BTreeMap<u32, Arc<MultiTransaction>>>>,
+    session_counter: Arc<AtomicU32>,
+    sessions: Arc<Mutex<BTreeMap<u32, Session>>>,
 }
 
 #[derive(Clone)]
@@ -141,6 +167,11 @@ fn x() {}
 
 pub(crate) async fn server_main(args: ServerArgs) {
     let db = DbInstance::new(&args.engine, &args.path, &args.config).unwrap();
+    db.set_max_memory(args.max_memory);
+    db.set_max_iterations(args.max_iterations);
+    db.set_temp_spill_threshold(args.temp_spill_threshold);
+    db.set_result_cache_capacity(args.result_cache_capacity);
+    db.set_conflict_retries(args.conflict_retries);
     if let Some(p) = &args.restore {
         if let Err(err) = db.restore_backup(p) {
             error!("{}", err);
@@ -179,6 +210,8 @@ pub(crate) async fn server_main(args: ServerArgs) {
         rule_counter: Default::default(),
         tx_counter: Default::default(),
         txs: Default::default(),
+        session_counter: Default::default(),
+        sessions: Default::default(),
     };
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
@@ -187,11 +220,14 @@ pub(crate) async fn server_main(args: ServerArgs) {
 
     let app = Router::new()
         .route("/text-query", post(text_query))
+        .route("/call/:name", post(call_procedure))
         .route("/export/:relations", get(export_relations))
         .route("/import", put(import_relations))
         .route("/backup", post(backup))
         .route("/import-from-backup", post(import_from_backup))
         .route("/changes/:relation", get(observe_changes))
+        .route("/subscribe", get(subscribe_query))
+        .route("/subscribe-ws", get(subscribe_query_ws))
         .route("/rules/:name", get(register_rule))
         .route(
             "/rule-result/:id",
@@ -199,6 +235,14 @@ pub(crate) async fn server_main(args: ServerArgs) {
         ) // +keep alive
         .route("/transact", post(start_transact))
         .route("/transact/:id", post(transact_query).put(finish_query))
+        .route(
+            "/transact/:id/savepoint/:name",
+            post(set_savepoint)
+                .put(rollback_to_savepoint)
+                .delete(release_savepoint),
+        )
+        .route("/session", post(start_session))
+        .route("/session/:id", post(session_query).delete(end_session))
         .with_state(state)
         .layer(AsyncRequireAuthorizationLayer::new(MyAuth {
             skip_auth,
@@ -304,6 +348,92 @@ async fn finish_query(
     }
 }
 
+async fn set_savepoint(
+    State(st): State<DbState>,
+    Path((id, name)): Path<(u32, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    run_savepoint_op(st, id, move |tx| tx.savepoint(&name)).await
+}
+
+async fn rollback_to_savepoint(
+    State(st): State<DbState>,
+    Path((id, name)): Path<(u32, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    run_savepoint_op(st, id, move |tx| tx.rollback_to(&name)).await
+}
+
+async fn release_savepoint(
+    State(st): State<DbState>,
+    Path((id, name)): Path<(u32, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    run_savepoint_op(st, id, move |tx| tx.release(&name)).await
+}
+
+async fn run_savepoint_op(
+    st: DbState,
+    id: u32,
+    op: impl FnOnce(&MultiTransaction) -> miette::Result<()> + Send + 'static,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let tx = match st.txs.lock().unwrap().get(&id) {
+        None => return (StatusCode::NOT_FOUND, json!({"ok": false}).into()),
+        Some(tx) => tx.clone(),
+    };
+    match spawn_blocking(move || op(&tx)).await {
+        Ok(Ok(())) => (StatusCode::OK, json!({"ok": true}).into()),
+        Ok(Err(err)) => (
+            StatusCode::BAD_REQUEST,
+            format_error_as_json(err, None).into(),
+        ),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn start_session(State(st): State<DbState>) -> (StatusCode, Json<serde_json::Value>) {
+    let id = st.session_counter.fetch_add(1, Ordering::SeqCst);
+    st.sessions.lock().unwrap().insert(id, Session::default());
+    (StatusCode::OK, json!({"ok": true, "id": id}).into())
+}
+
+async fn session_query(
+    State(st): State<DbState>,
+    Path(id): Path<u32>,
+    Json(payload): Json<QueryPayload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let session = match st.sessions.lock().unwrap().get(&id) {
+        None => return (StatusCode::NOT_FOUND, json!({"ok": false}).into()),
+        Some(session) => session.clone(),
+    };
+    let src = payload.script.clone();
+    let result = spawn_blocking(move || {
+        let params = payload
+            .params
+            .into_iter()
+            .map(|(k, v)| (k, DataValue::from(v)))
+            .collect();
+        st.db
+            .run_script_in_session(&session, &payload.script, params)
+    })
+    .await;
+    match result {
+        Ok(Ok(res)) => (StatusCode::OK, res.into_json().into()),
+        Ok(Err(err)) => (
+            StatusCode::BAD_REQUEST,
+            format_error_as_json(err, Some(&src)).into(),
+        ),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn end_session(
+    State(st): State<DbState>,
+    Path(id): Path<u32>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match st.sessions.lock().unwrap().remove(&id) {
+        None => (StatusCode::NOT_FOUND, json!({"ok": false}).into()),
+        Some(_) => (StatusCode::OK, json!({"ok": true}).into()),
+    }
+}
+
 #[derive(serde_derive::Deserialize)]
 struct QueryPayload {
     script: String,
@@ -326,6 +456,32 @@ async fn text_query(
     }
 }
 
+#[derive(serde_derive::Deserialize)]
+struct CallPayload {
+    args: Vec<serde_json::Value>,
+}
+
+async fn call_procedure(
+    State(st): State<DbState>,
+    Path(name): Path<String>,
+    Json(payload): Json<CallPayload>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let args = payload.args.into_iter().map(DataValue::from).collect_vec();
+    let result = spawn_blocking(move || match st.db.call_procedure(&name, args) {
+        Ok(res) => {
+            let mut j_val = res.into_json();
+            j_val["ok"] = json!(true);
+            j_val
+        }
+        Err(err) => format_error_as_json(err, None),
+    })
+    .await;
+    match result {
+        Ok(res) => wrap_json(res),
+        Err(err) => internal_error(err),
+    }
+}
+
 async fn export_relations(
     State(st): State<DbState>,
     Path(relations): Path<String>,
@@ -589,6 +745,133 @@ async fn observe_changes(
     Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
+#[derive(serde_derive::Deserialize)]
+struct SubscribeOptions {
+    script: String,
+    params: Option<String>,
+}
+
+impl SubscribeOptions {
+    fn subscribe(
+        &self,
+        db: &DbInstance,
+    ) -> miette::Result<(u32, NamedRows, crossbeam::channel::Receiver<QueryDelta>)> {
+        let params = match self.params.as_deref().map(serde_json::from_str) {
+            None => BTreeMap::new(),
+            Some(Ok(serde_json::Value::Object(map))) => map
+                .into_iter()
+                .map(|(k, v)| (k, DataValue::from(v)))
+                .collect(),
+            Some(Ok(_)) => return Err(miette!("params must be a JSON object")),
+            Some(Err(err)) => return Err(miette!(err)),
+        };
+        db.subscribe(&self.script, params, None)
+    }
+}
+
+struct SubscriptionGuard {
+    id: u32,
+    db: DbInstance,
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        info!("dropping subscription {}", self.id);
+        self.db.unsubscribe(self.id);
+    }
+}
+
+/// Forwards the deltas of a subscription to an async channel
+fn forward_deltas(
+    recv: crossbeam::channel::Receiver<QueryDelta>,
+) -> tokio::sync::mpsc::Receiver<QueryDelta> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(1);
+    spawn_blocking(move || {
+        for delta in recv {
+            if sender.blocking_send(delta).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn delta_json(delta: QueryDelta) -> serde_json::Value {
+    json!({"type": "delta", "added": delta.added.into_json(), "removed": delta.removed.into_json()})
+}
+
+async fn subscribe_query(
+    State(st): State<DbState>,
+    Query(opts): Query<SubscribeOptions>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let subscribed = opts.subscribe(&st.db);
+
+    let stream = async_stream::stream! {
+        match subscribed {
+            Err(err) => {
+                let item = json!({"type": "subscribe-error", "error": err.to_string()});
+                yield Ok(Event::default().json_data(item).unwrap());
+            }
+            Ok((id, initial, recv)) => {
+                info!("starting subscription SSE {}", id);
+                let _guard = SubscriptionGuard {id, db: st.db};
+                let mut receiver = forward_deltas(recv);
+                let item = json!({"type": "initial", "result": initial.into_json()});
+                yield Ok(Event::default().json_data(item).unwrap());
+                while let Some(delta) = receiver.recv().await {
+                    yield Ok(Event::default().json_data(delta_json(delta)).unwrap());
+                }
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn subscribe_query_ws(
+    State(st): State<DbState>,
+    Query(opts): Query<SubscribeOptions>,
+    ws: WebSocketUpgrade,
+) -> Response<BoxBody> {
+    ws.on_upgrade(move |socket| serve_subscription_ws(st.db, opts, socket))
+}
+
+async fn serve_subscription_ws(db: DbInstance, opts: SubscribeOptions, mut socket: WebSocket) {
+    let (id, initial, recv) = match opts.subscribe(&db) {
+        Ok(subscribed) => subscribed,
+        Err(err) => {
+            let item = json!({"type": "subscribe-error", "error": err.to_string()});
+            let _ = socket.send(Message::Text(item.to_string())).await;
+            return;
+        }
+    };
+    info!("starting subscription WebSocket {}", id);
+    let _guard = SubscriptionGuard { id, db };
+    let mut receiver = forward_deltas(recv);
+    let item = json!({"type": "initial", "result": initial.into_json()});
+    if socket.send(Message::Text(item.to_string())).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            delta = receiver.recv() => {
+                let delta = match delta {
+                    Some(delta) => delta,
+                    None => break,
+                };
+                if socket.send(Message::Text(delta_json(delta).to_string())).await.is_err() {
+                    break;
+                }
+            }
+            // the subscription ends when the client closes the socket
+            msg = socket.recv() => {
+                if matches!(msg, None | Some(Err(_)) | Some(Ok(Message::Close(_)))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
 async fn root() -> Html<&'static str> {
     Html(include_str!("./index.html"))
 }
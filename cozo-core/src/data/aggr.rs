@@ -9,10 +9,13 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
 
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::BigDecimal;
 use miette::{bail, ensure, miette, Result};
+use num_traits::Zero;
 use rand::prelude::*;
 
-use crate::data::value::DataValue;
+use crate::data::value::{decimal_to_f64, DataValue, Num};
 
 pub(crate) struct Aggregation {
     pub(crate) name: &'static str,
@@ -501,23 +504,21 @@ define_aggr!(AGGR_MEAN, false);
 #[derive(Default)]
 pub(crate) struct AggrMean {
     count: i64,
-    sum: f64,
+    sum: ExactSum,
 }
 
 impl NormalAggrObj for AggrMean {
     fn set(&mut self, value: &DataValue) -> Result<()> {
-        match value {
-            DataValue::Num(n) => {
-                self.sum += n.get_float();
-                self.count += 1;
-            }
-            v => bail!("cannot compute 'mean': encountered value {:?}", v),
-        }
+        self.sum.add(value, "mean")?;
+        self.count += 1;
         Ok(())
     }
 
     fn get(&self) -> Result<DataValue> {
-        Ok(DataValue::from(self.sum / (self.count as f64)))
+        Ok(match self.sum.exact() {
+            Some(d) if self.count > 0 => DataValue::Decimal(d / BigDecimal::from(self.count)),
+            _ => DataValue::from(self.sum.float / (self.count as f64)),
+        })
     }
 }
 
@@ -525,22 +526,56 @@ define_aggr!(AGGR_SUM, false);
 
 #[derive(Default)]
 pub(crate) struct AggrSum {
-    sum: f64,
+    sum: ExactSum,
 }
 
 impl NormalAggrObj for AggrSum {
     fn set(&mut self, value: &DataValue) -> Result<()> {
+        self.sum.add(value, "sum")
+    }
+
+    fn get(&self) -> Result<DataValue> {
+        Ok(match self.sum.exact() {
+            Some(d) => DataValue::Decimal(d),
+            None => DataValue::from(self.sum.float),
+        })
+    }
+}
+
+/// Running sum that stays exact as long as only decimals and integers are
+/// seen and at least one decimal is present. Otherwise it is a float sum.
+#[derive(Default)]
+struct ExactSum {
+    float: f64,
+    ints: i128,
+    decimal: Option<BigDecimal>,
+    has_float: bool,
+}
+
+impl ExactSum {
+    fn add(&mut self, value: &DataValue, name: &str) -> Result<()> {
         match value {
-            DataValue::Num(n) => {
-                self.sum += n.get_float();
+            DataValue::Num(Num::Int(i)) => {
+                self.float += *i as f64;
+                self.ints += *i as i128;
+            }
+            DataValue::Num(Num::Float(f)) => {
+                self.float += f;
+                self.has_float = true;
+            }
+            DataValue::Decimal(d) => {
+                self.float += decimal_to_f64(d);
+                *self.decimal.get_or_insert_with(BigDecimal::zero) += d;
             }
-            v => bail!("cannot compute 'sum': encountered value {:?}", v),
+            v => bail!("cannot compute '{}': encountered value {:?}", name, v),
         }
         Ok(())
     }
-
-    fn get(&self) -> Result<DataValue> {
-        Ok(DataValue::from(self.sum))
+    fn exact(&self) -> Option<BigDecimal> {
+        match &self.decimal {
+            Some(d) if !self.has_float => Some(d + BigDecimal::new(BigInt::from(self.ints), 0)),
+            _ => None,
+        }
     }
 }
 
@@ -572,6 +607,22 @@ impl NormalAggrObj for AggrProduct {
     }
 }
 
+/// Compares two numeric values for 'min' and 'max', exactly if a decimal is involved.
+fn num_gt(a: &DataValue, b: &DataValue, name: &str) -> Result<bool> {
+    if matches!(a, DataValue::Decimal(_)) || matches!(b, DataValue::Decimal(_)) {
+        if let (Some(x), Some(y)) = (a.get_decimal(), b.get_decimal()) {
+            return Ok(x > y);
+        }
+    }
+    let f1 = a
+        .get_float()
+        .ok_or_else(|| miette!("'{}' applied to non-numerical values", name))?;
+    let f2 = b
+        .get_float()
+        .ok_or_else(|| miette!("'{}' applied to non-numerical values", name))?;
+    Ok(f1 > f2)
+}
+
 define_aggr!(AGGR_MIN, true);
 
 pub(crate) struct AggrMin {
@@ -595,14 +646,7 @@ impl NormalAggrObj for AggrMin {
             self.found = value.clone();
             return Ok(());
         }
-        let f1 = self
-            .found
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        let f2 = value
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        if f1 > f2 {
+        if num_gt(&self.found, value, "min")? {
             self.found = value.clone();
         }
         Ok(())
@@ -628,14 +672,7 @@ impl MeetAggrObj for MeetAggrMin {
             *left = right.clone();
             return Ok(true);
         }
-        let f1 = left
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        let f2 = right
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-
-        Ok(if f1 > f2 {
+        Ok(if num_gt(left, right, "min")? {
             *left = right.clone();
             true
         } else {
@@ -667,14 +704,7 @@ impl NormalAggrObj for AggrMax {
             self.found = value.clone();
             return Ok(());
         }
-        let f1 = self
-            .found
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        let f2 = value
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        if f1 < f2 {
+        if num_gt(value, &self.found, "max")? {
             self.found = value.clone();
         }
         Ok(())
@@ -700,14 +730,7 @@ impl MeetAggrObj for MeetAggrMax {
             *left = right.clone();
             return Ok(true);
         }
-        let f1 = left
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-        let f2 = right
-            .get_float()
-            .ok_or_else(|| miette!("'min' applied to non-numerical values"))?;
-
-        Ok(if f1 < f2 {
+        Ok(if num_gt(right, left, "max")? {
             *left = right.clone();
             true
         } else {
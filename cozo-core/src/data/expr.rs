@@ -57,6 +57,15 @@ pub enum Bytecode {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// pop n, push 1
+    ApplyLambda {
+        op: &'static HigherOrderOp,
+        arity: usize,
+        lambda: Vec<Bytecode>,
+        lambda_base: usize,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
 }
 
 #[derive(Error, Diagnostic, Debug)]
@@ -149,11 +158,39 @@ pub fn eval_bytecode(
             Bytecode::Goto { jump_to, .. } => {
                 pointer = *jump_to;
             }
+            Bytecode::ApplyLambda {
+                op,
+                arity,
+                lambda,
+                lambda_base,
+                span,
+            } => {
+                let frame_start = stack.len() - *arity;
+                let mut lambda_stack = vec![];
+                let result = (op.inner)(&stack[frame_start..], &mut |params| {
+                    let env = lambda_env(bindings.as_ref(), *lambda_base, params);
+                    eval_bytecode(lambda, env, &mut lambda_stack)
+                })
+                .map_err(|err| EvalRaisedError(*span, err.to_string()))?;
+                stack.truncate(frame_start);
+                stack.push(result);
+                pointer += 1;
+            }
         }
     }
     Ok(stack.pop().unwrap())
 }
 
+/// The bindings seen by the body of a lambda: the enclosing tuple up to `base`,
+/// followed by the parameters of the lambda
+fn lambda_env(outer: &[DataValue], base: usize, params: Vec<DataValue>) -> Vec<DataValue> {
+    let mut env = Vec::with_capacity(base + params.len());
+    env.extend_from_slice(&outer[..min(base, outer.len())]);
+    env.resize(base, DataValue::Bot);
+    env.extend(params);
+    env
+}
+
 /// Expression can be evaluated to yield a DataValue
 #[derive(Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub enum Expr {
@@ -200,6 +237,99 @@ pub enum Expr {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// Application of a higher-order function taking a lambda as its last argument
+    HigherOrderApply {
+        /// Op representing the function to apply
+        op: &'static HigherOrderOp,
+        /// Arguments to the application, excluding the lambda
+        args: Box<[Expr]>,
+        /// The lambda
+        lambda: Box<Lambda>,
+        /// Source span
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+}
+
+/// Operators whose nested applications can be flattened
+const ASSOCIATIVE_OPS: &[&str] = &[
+    OP_ADD.name,
+    OP_MUL.name,
+    OP_AND.name,
+    OP_OR.name,
+    OP_MIN.name,
+    OP_MAX.name,
+];
+
+/// Operators whose arguments can be reordered
+const COMMUTATIVE_OPS: &[&str] = &[
+    OP_ADD.name,
+    OP_MUL.name,
+    OP_AND.name,
+    OP_OR.name,
+    OP_MIN.name,
+    OP_MAX.name,
+    OP_EQ.name,
+    OP_NEQ.name,
+];
+
+/// Structure of an expression without source spans. Nested applications of associative
+/// operators are flattened, the arguments of commutative operators sorted, and `>` and `>=`
+/// turned into `<` and `<=`, so that equivalent spellings of an expression are equal.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum NormalExpr {
+    Binding(SmartString<LazyCompact>),
+    Const(DataValue),
+    Apply(&'static str, Vec<NormalExpr>),
+    Other(String),
+}
+
+/// Anonymous function that can only appear as an argument to higher-order functions
+#[derive(Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Lambda {
+    /// Parameters of the lambda
+    pub(crate) params: Vec<Symbol>,
+    /// Body of the lambda, can refer to both the parameters and the enclosing bindings
+    pub(crate) body: Expr,
+    /// Position of the first parameter in the bindings seen by the body
+    pub(crate) base: usize,
+    /// Source span
+    #[serde(skip)]
+    pub(crate) span: SourceSpan,
+}
+
+impl Display for Lambda {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let params = self.params.iter().map(|p| &p.name).join(", ");
+        write!(f, "({params}) -> {}", self.body)
+    }
+}
+
+impl Debug for Lambda {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Lambda {
+    fn free_bindings(&self) -> Result<BTreeSet<Symbol>> {
+        let mut ret = self.body.bindings()?;
+        for param in &self.params {
+            ret.remove(param);
+        }
+        Ok(ret)
+    }
+    fn fill_binding_indices(&mut self, binding_map: &BTreeMap<Symbol, usize>) -> Result<()> {
+        self.base = binding_map.values().max().map_or(0, |i| *i + 1);
+        let mut inner_map = binding_map.clone();
+        for (i, param) in self.params.iter().enumerate() {
+            inner_map.insert(param.clone(), self.base + i);
+        }
+        self.body.fill_binding_indices(&inner_map)
+    }
+    fn eval(&self, bindings: &[DataValue], params: Vec<DataValue>) -> Result<DataValue> {
+        self.body.eval(lambda_env(bindings, self.base, params))
+    }
 }
 
 impl Debug for Expr {
@@ -240,6 +370,17 @@ impl Display for Expr {
                 }
                 writer.finish()
             }
+            Expr::HigherOrderApply {
+                op, args, lambda, ..
+            } => {
+                let mut writer =
+                    f.debug_tuple(op.name.strip_prefix("OP_").unwrap().to_lowercase().as_str());
+                for arg in args.iter() {
+                    writer.field(arg);
+                }
+                writer.field(lambda);
+                writer.finish()
+            }
         }
     }
 }
@@ -275,7 +416,7 @@ impl Expr {
         match self {
             Expr::Binding { var, .. } => var.span,
             Expr::Const { span, .. } | Expr::Apply { span, .. } | Expr::Cond { span, .. } => *span,
-            Expr::UnboundApply { span, .. } => *span,
+            Expr::UnboundApply { span, .. } | Expr::HigherOrderApply { span, .. } => *span,
         }
     }
     pub(crate) fn get_binding(&self) -> Option<&Symbol> {
@@ -326,6 +467,45 @@ impl Expr {
             v => vec![v.clone()],
         }
     }
+    /// Structure of the expression for comparing it with others, see [NormalExpr]
+    pub(crate) fn normalize(&self) -> NormalExpr {
+        match self {
+            Expr::Binding { var, .. } => NormalExpr::Binding(var.name.clone()),
+            Expr::Const { val, .. } => NormalExpr::Const(val.clone()),
+            Expr::Apply { op, args, .. } => {
+                let (name, args) = match op.name {
+                    // `a > b` is `b < a`
+                    n if n == OP_GT.name => (OP_LT.name, args.iter().rev().collect_vec()),
+                    n if n == OP_GE.name => (OP_LE.name, args.iter().rev().collect_vec()),
+                    name => (name, args.iter().collect_vec()),
+                };
+                let mut args = args.into_iter().map(|arg| arg.normalize()).collect_vec();
+                if ASSOCIATIVE_OPS.contains(&name) {
+                    args = args
+                        .into_iter()
+                        .flat_map(|arg| match arg {
+                            NormalExpr::Apply(n, inner) if n == name => inner,
+                            arg => vec![arg],
+                        })
+                        .collect();
+                }
+                if COMMUTATIVE_OPS.contains(&name) {
+                    args.sort();
+                }
+                NormalExpr::Apply(name, args)
+            }
+            Expr::Cond { clauses, .. } => NormalExpr::Apply(
+                "cond",
+                clauses
+                    .iter()
+                    .flat_map(|(cond, val)| [cond.normalize(), val.normalize()])
+                    .collect(),
+            ),
+            expr @ (Expr::UnboundApply { .. } | Expr::HigherOrderApply { .. }) => {
+                NormalExpr::Other(expr.to_string())
+            }
+        }
+    }
     pub(crate) fn fill_binding_indices(
         &mut self,
         binding_map: &BTreeMap<Symbol, usize>,
@@ -355,6 +535,12 @@ impl Expr {
                     val.fill_binding_indices(binding_map)?;
                 }
             }
+            Expr::HigherOrderApply { args, lambda, .. } => {
+                for arg in args.iter_mut() {
+                    arg.fill_binding_indices(binding_map)?;
+                }
+                lambda.fill_binding_indices(binding_map)?;
+            }
             Expr::UnboundApply { op, span, .. } => {
                 bail!(NoImplementationError(*span, op.to_string()));
             }
@@ -391,6 +577,14 @@ impl Expr {
             //         clause.do_binding_indices(coll)
             //     }
             // }
+            Expr::HigherOrderApply { args, lambda, .. } => {
+                for arg in args.iter() {
+                    arg.do_binding_indices(coll)?;
+                }
+                let mut inner = BTreeSet::new();
+                lambda.body.do_binding_indices(&mut inner)?;
+                coll.extend(inner.into_iter().filter(|i| *i < lambda.base));
+            }
             Expr::UnboundApply { op, span, .. } => {
                 bail!(NoImplementationError(*span, op.to_string()));
             }
@@ -410,6 +604,23 @@ impl Expr {
         }
     }
     pub(crate) fn partial_eval(&mut self) -> Result<()> {
+        if let Expr::HigherOrderApply {
+            args, lambda, span, ..
+        } = self
+        {
+            let span = *span;
+            let mut all_evaluated = true;
+            for arg in args.iter_mut() {
+                arg.partial_eval()?;
+                all_evaluated = all_evaluated && matches!(arg, Expr::Const { .. });
+            }
+            if all_evaluated && lambda.free_bindings()?.is_empty() {
+                lambda.fill_binding_indices(&Default::default())?;
+                let result = self.eval(vec![])?;
+                *self = Expr::Const { val: result, span };
+            }
+            return Ok(());
+        }
         if let Expr::Apply { args, span, .. } = self {
             let span = *span;
             let mut all_evaluated = true;
@@ -467,12 +678,51 @@ impl Expr {
                     val.collect_bindings(coll)?;
                 }
             }
+            Expr::HigherOrderApply { args, lambda, .. } => {
+                for arg in args.iter() {
+                    arg.collect_bindings(coll)?;
+                }
+                coll.extend(lambda.free_bindings()?);
+            }
             Expr::UnboundApply { op, span, .. } => {
                 bail!(NoImplementationError(*span, op.to_string()));
             }
         }
         Ok(())
     }
+    /// Renames the free bindings in the expression according to the mapping,
+    /// bindings not in the mapping are left alone.
+    pub(crate) fn rename_bindings(&mut self, mapping: &BTreeMap<Symbol, Symbol>) {
+        match self {
+            Expr::Binding { var, .. } => {
+                if let Some(new_var) = mapping.get(var) {
+                    *var = new_var.clone();
+                }
+            }
+            Expr::Const { .. } => {}
+            Expr::Apply { args, .. } | Expr::UnboundApply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.rename_bindings(mapping);
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.rename_bindings(mapping);
+                    val.rename_bindings(mapping);
+                }
+            }
+            Expr::HigherOrderApply { args, lambda, .. } => {
+                for arg in args.iter_mut() {
+                    arg.rename_bindings(mapping);
+                }
+                let mut inner_mapping = mapping.clone();
+                for param in &lambda.params {
+                    inner_mapping.remove(param);
+                }
+                lambda.body.rename_bindings(&inner_mapping);
+            }
+        }
+    }
     pub(crate) fn eval(&self, bindings: impl AsRef<[DataValue]>) -> Result<DataValue> {
         match self {
             Expr::Binding { var, tuple_pos, .. } => match tuple_pos {
@@ -514,6 +764,18 @@ impl Expr {
                 }
                 Ok(DataValue::Null)
             }
+            Expr::HigherOrderApply {
+                op, args, lambda, ..
+            } => {
+                let args: Box<[DataValue]> = args
+                    .iter()
+                    .map(|v| v.eval(bindings.as_ref()))
+                    .try_collect()?;
+                Ok(
+                    (op.inner)(&args, &mut |params| lambda.eval(bindings.as_ref(), params))
+                        .map_err(|err| EvalRaisedError(self.span(), err.to_string()))?,
+                )
+            }
             Expr::UnboundApply { op, span, .. } => {
                 bail!(NoImplementationError(*span, op.to_string()));
             }
@@ -521,16 +783,16 @@ impl Expr {
     }
     pub(crate) fn extract_bound(&self, target: &Symbol) -> Result<ValueRange> {
         Ok(match self {
-            Expr::Binding { .. } | Expr::Const { .. } | Expr::Cond { .. } => ValueRange::default(),
+            Expr::Binding { .. }
+            | Expr::Const { .. }
+            | Expr::Cond { .. }
+            | Expr::HigherOrderApply { .. } => ValueRange::default(),
             Expr::Apply { op, args, .. } => match op.name {
                 n if n == OP_GE.name || n == OP_GT.name => {
                     if let Some(symb) = args[0].get_binding() {
                         if let Some(val) = args[1].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_int() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
-                                };
+                                let tar_val = numeric_lower_bound(val);
                                 return Ok(ValueRange::lower_bound(tar_val));
                             }
                         }
@@ -564,10 +826,7 @@ impl Expr {
                     if let Some(symb) = args[1].get_binding() {
                         if let Some(val) = args[0].get_const() {
                             if target == symb {
-                                let tar_val = match val.get_int() {
-                                    Some(i) => DataValue::from(i),
-                                    None => val.clone(),
-                                };
+                                let tar_val = numeric_lower_bound(val);
 
                                 return Ok(ValueRange::lower_bound(tar_val));
                             }
@@ -648,6 +907,28 @@ pub(crate) fn compute_bounds(
     Ok((lowers, uppers))
 }
 
+/// Numbers and decimals are ordered by their float approximation first and
+/// their kind second, so a non-integral lower bound has to start just below
+/// its approximation in order to include every kind of number with that value.
+fn numeric_lower_bound(val: &DataValue) -> DataValue {
+    if !matches!(val, DataValue::Num(_) | DataValue::Decimal(_)) {
+        return val.clone();
+    }
+    if let Some(i) = val.get_int() {
+        return DataValue::from(i);
+    }
+    let f = val.get_float().unwrap();
+    DataValue::from(if f.is_nan() || f == f64::NEG_INFINITY {
+        f
+    } else if f == 0. {
+        -f64::from_bits(1)
+    } else if f > 0. {
+        f64::from_bits(f.to_bits() - 1)
+    } else {
+        f64::from_bits(f.to_bits() + 1)
+    })
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct ValueRange {
     pub(crate) lower: DataValue,
@@ -704,6 +985,79 @@ pub struct Op {
     pub(crate) inner: fn(&[DataValue]) -> Result<DataValue>,
 }
 
+/// Function taking a lambda as its last argument.
+/// `inner` receives the evaluated non-lambda arguments and a callback that applies the lambda.
+#[derive(Clone)]
+pub struct HigherOrderOp {
+    pub(crate) name: &'static str,
+    pub(crate) arity: usize,
+    pub(crate) lambda_arity: usize,
+    #[allow(clippy::type_complexity)]
+    pub(crate) inner:
+        fn(&[DataValue], &mut dyn FnMut(Vec<DataValue>) -> Result<DataValue>) -> Result<DataValue>,
+}
+
+impl serde::Serialize for &'_ HigherOrderOp {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.name)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for &'static HigherOrderOp {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(HigherOrderOpVisitor)
+    }
+}
+
+struct HigherOrderOpVisitor;
+
+impl<'de> Visitor<'de> for HigherOrderOpVisitor {
+    type Value = &'static HigherOrderOp;
+
+    fn expecting(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("name of the higher-order op")
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        let name = v.strip_prefix("OP_").unwrap().to_ascii_lowercase();
+        get_higher_order_op(&name)
+            .ok_or_else(|| E::custom(format!("op not found in serialized data: {v}")))
+    }
+}
+
+impl PartialEq for HigherOrderOp {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for HigherOrderOp {}
+
+impl Debug for HigherOrderOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub(crate) fn get_higher_order_op(name: &str) -> Option<&'static HigherOrderOp> {
+    Some(match name {
+        "map" => &OP_MAP,
+        "filter" => &OP_FILTER,
+        "reduce" => &OP_REDUCE,
+        "sort_by" => &OP_SORT_BY,
+        _ => return None,
+    })
+}
+
 /// Used as `Arc<dyn CustomOp>`
 pub trait CustomOp {
     fn name(&self) -> &'static str;
@@ -836,6 +1190,8 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "is_int" => &OP_IS_INT,
         "is_float" => &OP_IS_FLOAT,
         "is_num" => &OP_IS_NUM,
+        "is_decimal" => &OP_IS_DECIMAL,
+        "is_geometry" => &OP_IS_GEOMETRY,
         "is_string" => &OP_IS_STRING,
         "is_list" => &OP_IS_LIST,
         "is_bytes" => &OP_IS_BYTES,
@@ -898,6 +1254,25 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "now" => &OP_NOW,
         "format_timestamp" => &OP_FORMAT_TIMESTAMP,
         "parse_timestamp" => &OP_PARSE_TIMESTAMP,
+        "timestamp" => &OP_TIMESTAMP,
+        "date" => &OP_DATE,
+        "duration" => &OP_DURATION,
+        "decimal" => &OP_DECIMAL,
+        "geometry" => &OP_GEOMETRY,
+        "st_point" => &OP_ST_POINT,
+        "st_intersects" => &OP_ST_INTERSECTS,
+        "st_within" => &OP_ST_WITHIN,
+        "st_distance" => &OP_ST_DISTANCE,
+        "st_distance_sphere" => &OP_ST_DISTANCE_SPHERE,
+        "st_bbox" => &OP_ST_BBOX,
+        "current_timestamp" => &OP_CURRENT_TIMESTAMP,
+        "format_datetime" => &OP_FORMAT_DATETIME,
+        "parse_datetime" => &OP_PARSE_DATETIME,
+        "date_trunc" => &OP_DATE_TRUNC,
+        "date_part" => &OP_DATE_PART,
+        "day_of_week" => &OP_DAY_OF_WEEK,
+        "add_duration" => &OP_ADD_DURATION,
+        "date_diff" => &OP_DATE_DIFF,
         "vec" => &OP_VEC,
         "rand_vec" => &OP_RAND_VEC,
         _ => return None,
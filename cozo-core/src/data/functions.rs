@@ -6,7 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::cmp::Reverse;
+use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
 use std::ops::{Div, Rem};
 use std::str::FromStr;
@@ -14,23 +14,30 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use chrono::{DateTime, TimeZone, Utc};
+use bigdecimal::BigDecimal;
+use chrono::{
+    DateTime, Datelike, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Offset, SecondsFormat,
+    TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
 use itertools::Itertools;
 #[cfg(target_arch = "wasm32")]
 use js_sys::Date;
 use miette::{bail, ensure, miette, IntoDiagnostic, Result};
-use num_traits::FloatConst;
+use num_traits::{FloatConst, Signed, ToPrimitive, Zero};
 use rand::prelude::*;
 use serde_json::{json, Value};
 use smartstring::SmartString;
 use unicode_normalization::UnicodeNormalization;
 use uuid::v1::Timestamp;
 
-use crate::data::expr::Op;
+use crate::data::expr::{HigherOrderOp, Op};
+use crate::data::geo::Geometry;
 use crate::data::json::JsonValue;
 use crate::data::relation::VecElementType;
 use crate::data::value::{
-    DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
+    cmp_num_decimal, decimal_floor_f64, decimal_to_f64, DataValue, JsonData, Num, RegexWrapper,
+    UuidWrapper, Validity, ValidityTs, Vector,
 };
 
 macro_rules! define_op {
@@ -44,18 +51,43 @@ macro_rules! define_op {
     };
 }
 
+macro_rules! define_higher_order_op {
+    ($name:ident, $arity:expr, $lambda_arity:expr) => {
+        pub(crate) const $name: HigherOrderOp = HigherOrderOp {
+            name: stringify!($name),
+            arity: $arity,
+            lambda_arity: $lambda_arity,
+            inner: ::casey::lower!($name),
+        };
+    };
+}
+
+/// Compares an exact decimal with an ordinary number in the same order that
+/// sorting and index scans use, except that numerically equal values are equal.
+fn cmp_decimal_num(d: &BigDecimal, n: Num) -> Option<Ordering> {
+    match n {
+        Num::Float(f) if f.is_nan() => None,
+        Num::Float(f) if decimal_floor_f64(d) == (f, true) => Some(Ordering::Equal),
+        Num::Int(i) if *d == BigDecimal::from(i) => Some(Ordering::Equal),
+        n => Some(cmp_num_decimal(n, d).reverse()),
+    }
+}
+
 fn ensure_same_value_type(a: &DataValue, b: &DataValue) -> Result<()> {
     use DataValue::*;
     if !matches!(
         (a, b),
         (Null, Null)
             | (Bool(_), Bool(_))
-            | (Num(_), Num(_))
+            | (Num(_) | Decimal(_), Num(_) | Decimal(_))
             | (Str(_), Str(_))
             | (Bytes(_), Bytes(_))
             | (Regex(_), Regex(_))
             | (List(_), List(_))
             | (Set(_), Set(_))
+            | (Timestamp(_), Timestamp(_))
+            | (Date(_), Date(_))
+            | (Duration(_), Duration(_))
             | (Bot, Bot)
     ) {
         bail!(
@@ -258,6 +290,11 @@ fn to_json(d: &DataValue) -> JsonValue {
         DataValue::Validity(vld) => {
             json!([vld.timestamp.0, vld.is_assert.0])
         }
+        v @ (DataValue::Timestamp(_)
+        | DataValue::Date(_)
+        | DataValue::Duration(_)
+        | DataValue::Decimal(_)
+        | DataValue::Geometry(_)) => JsonValue::from(v.clone()),
         DataValue::Bot => {
             json!(null)
         }
@@ -298,6 +335,9 @@ pub(crate) fn op_eq(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 == *f,
+        (DataValue::Decimal(d), DataValue::Num(n)) | (DataValue::Num(n), DataValue::Decimal(d)) => {
+            cmp_decimal_num(d, *n) == Some(Ordering::Equal)
+        }
         (a, b) => a == b,
     }))
 }
@@ -334,6 +374,9 @@ pub(crate) fn op_neq(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 != *f,
+        (DataValue::Decimal(d), DataValue::Num(n)) | (DataValue::Num(n), DataValue::Decimal(d)) => {
+            cmp_decimal_num(d, *n) != Some(Ordering::Equal)
+        }
         (a, b) => a != b,
     }))
 }
@@ -344,6 +387,12 @@ pub(crate) fn op_gt(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l > *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 > *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => {
+            matches!(cmp_decimal_num(l, *r), Some(Ordering::Greater))
+        }
+        (DataValue::Num(l), DataValue::Decimal(r)) => {
+            matches!(cmp_decimal_num(r, *l), Some(Ordering::Less))
+        }
         (a, b) => a > b,
     }))
 }
@@ -354,6 +403,18 @@ pub(crate) fn op_ge(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l >= *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 >= *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => {
+            matches!(
+                cmp_decimal_num(l, *r),
+                Some(Ordering::Greater | Ordering::Equal)
+            )
+        }
+        (DataValue::Num(l), DataValue::Decimal(r)) => {
+            matches!(
+                cmp_decimal_num(r, *l),
+                Some(Ordering::Less | Ordering::Equal)
+            )
+        }
         (a, b) => a >= b,
     }))
 }
@@ -364,6 +425,12 @@ pub(crate) fn op_lt(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l < (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) < *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => {
+            matches!(cmp_decimal_num(l, *r), Some(Ordering::Less))
+        }
+        (DataValue::Num(l), DataValue::Decimal(r)) => {
+            matches!(cmp_decimal_num(r, *l), Some(Ordering::Greater))
+        }
         (a, b) => a < b,
     }))
 }
@@ -374,6 +441,18 @@ pub(crate) fn op_le(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l <= (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) <= *r,
+        (DataValue::Decimal(l), DataValue::Num(r)) => {
+            matches!(
+                cmp_decimal_num(l, *r),
+                Some(Ordering::Less | Ordering::Equal)
+            )
+        }
+        (DataValue::Num(l), DataValue::Decimal(r)) => {
+            matches!(
+                cmp_decimal_num(r, *l),
+                Some(Ordering::Greater | Ordering::Equal)
+            )
+        }
         (a, b) => a <= b,
     }))
 }
@@ -382,14 +461,24 @@ define_op!(OP_ADD, 0, true);
 pub(crate) fn op_add(args: &[DataValue]) -> Result<DataValue> {
     let mut i_accum = 0i64;
     let mut f_accum = 0.0f64;
+    let mut d_accum: Option<BigDecimal> = None;
     for arg in args {
         match arg {
             DataValue::Num(Num::Int(i)) => i_accum += i,
             DataValue::Num(Num::Float(f)) => f_accum += f,
+            DataValue::Decimal(d) => *d_accum.get_or_insert_with(BigDecimal::zero) += d,
             DataValue::Vec(_) => return add_vecs(args),
             _ => bail!("addition requires numbers"),
         }
     }
+    if let Some(d) = d_accum {
+        let d = d + BigDecimal::from(i_accum);
+        return Ok(if f_accum == 0.0f64 {
+            DataValue::Decimal(d)
+        } else {
+            DataValue::Num(Num::Float(decimal_to_f64(&d) + f_accum))
+        });
+    }
     if f_accum == 0.0f64 {
         Ok(DataValue::Num(Num::Int(i_accum)))
     } else {
@@ -456,6 +545,15 @@ pub(crate) fn op_max(args: &[DataValue]) -> Result<DataValue> {
         .try_fold(None, |accum, nxt| match (accum, nxt) {
             (None, d @ DataValue::Num(_)) => Ok(Some(d.clone())),
             (Some(DataValue::Num(a)), DataValue::Num(b)) => Ok(Some(DataValue::Num(a.max(*b)))),
+            (None, d @ DataValue::Decimal(_)) => Ok(Some(d.clone())),
+            (Some(a @ (DataValue::Num(_) | DataValue::Decimal(_))), b @ DataValue::Decimal(_))
+            | (Some(a @ DataValue::Decimal(_)), b @ DataValue::Num(_)) => Ok(Some(
+                if op_gt(&[a.clone(), b.clone()])? == DataValue::from(true) {
+                    a
+                } else {
+                    b.clone()
+                },
+            )),
             _ => bail!("'max can only be applied to numbers'"),
         })?;
     match res {
@@ -471,6 +569,15 @@ pub(crate) fn op_min(args: &[DataValue]) -> Result<DataValue> {
         .try_fold(None, |accum, nxt| match (accum, nxt) {
             (None, d @ DataValue::Num(_)) => Ok(Some(d.clone())),
             (Some(DataValue::Num(a)), DataValue::Num(b)) => Ok(Some(DataValue::Num(a.min(*b)))),
+            (None, d @ DataValue::Decimal(_)) => Ok(Some(d.clone())),
+            (Some(a @ (DataValue::Num(_) | DataValue::Decimal(_))), b @ DataValue::Decimal(_))
+            | (Some(a @ DataValue::Decimal(_)), b @ DataValue::Num(_)) => Ok(Some(
+                if op_gt(&[a.clone(), b.clone()])? == DataValue::from(false) {
+                    a
+                } else {
+                    b.clone()
+                },
+            )),
             _ => bail!("'min' can only be applied to numbers"),
         })?;
     match res {
@@ -494,6 +601,15 @@ pub(crate) fn op_sub(args: &[DataValue]) -> Result<DataValue> {
         (DataValue::Num(Num::Float(a)), DataValue::Num(Num::Int(b))) => {
             DataValue::Num(Num::Float(a - (*b as f64)))
         }
+        (DataValue::Decimal(_), DataValue::Decimal(_) | DataValue::Num(_))
+        | (DataValue::Num(_), DataValue::Decimal(_)) => {
+            match (args[0].get_decimal(), args[1].get_decimal()) {
+                (Some(a), Some(b)) => DataValue::Decimal(a - b),
+                _ => DataValue::Num(Num::Float(
+                    args[0].get_float().unwrap() - args[1].get_float().unwrap(),
+                )),
+            }
+        }
         (DataValue::Vec(a), DataValue::Vec(b)) => match (a, b) {
             (Vector::F32(a), Vector::F32(b)) => DataValue::Vec(Vector::F32(a - b)),
             (Vector::F64(a), Vector::F64(b)) => DataValue::Vec(Vector::F64(a - b)),
@@ -544,14 +660,29 @@ define_op!(OP_MUL, 0, true);
 pub(crate) fn op_mul(args: &[DataValue]) -> Result<DataValue> {
     let mut i_accum = 1i64;
     let mut f_accum = 1.0f64;
+    let mut d_accum: Option<BigDecimal> = None;
     for arg in args {
         match arg {
             DataValue::Num(Num::Int(i)) => i_accum *= i,
             DataValue::Num(Num::Float(f)) => f_accum *= f,
+            DataValue::Decimal(d) => {
+                d_accum = Some(match d_accum {
+                    None => d.clone(),
+                    Some(acc) => acc * d,
+                })
+            }
             DataValue::Vec(_) => return mul_vecs(args),
             _ => bail!("multiplication requires numbers"),
         }
     }
+    if let Some(d) = d_accum {
+        let d = d * BigDecimal::from(i_accum);
+        return Ok(if f_accum == 1.0f64 {
+            DataValue::Decimal(d)
+        } else {
+            DataValue::Num(Num::Float(decimal_to_f64(&d) * f_accum))
+        });
+    }
     if f_accum == 1.0f64 {
         Ok(DataValue::Num(Num::Int(i_accum)))
     } else {
@@ -626,6 +757,18 @@ pub(crate) fn op_div(args: &[DataValue]) -> Result<DataValue> {
         (DataValue::Num(Num::Float(a)), DataValue::Num(Num::Int(b))) => {
             DataValue::Num(Num::Float(a / (*b as f64)))
         }
+        (DataValue::Decimal(_), DataValue::Decimal(_) | DataValue::Num(_))
+        | (DataValue::Num(_), DataValue::Decimal(_)) => {
+            match (args[0].get_decimal(), args[1].get_decimal()) {
+                (Some(a), Some(b)) => {
+                    ensure!(!b.is_zero(), "decimal division by zero");
+                    DataValue::Decimal(a / b)
+                }
+                _ => DataValue::Num(Num::Float(
+                    args[0].get_float().unwrap() / args[1].get_float().unwrap(),
+                )),
+            }
+        }
         (DataValue::Vec(a), DataValue::Vec(b)) => match (a, b) {
             (Vector::F32(a), Vector::F32(b)) => DataValue::Vec(Vector::F32(a / b)),
             (Vector::F64(a), Vector::F64(b)) => DataValue::Vec(Vector::F64(a / b)),
@@ -671,6 +814,7 @@ pub(crate) fn op_minus(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(-(*i))),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(-(*f))),
+        DataValue::Decimal(d) => DataValue::Decimal(-d),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(0. - v)),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(0. - v)),
         _ => bail!("minus can only be applied to numbers"),
@@ -682,6 +826,7 @@ pub(crate) fn op_abs(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(i.abs())),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.abs())),
+        DataValue::Decimal(d) => DataValue::Decimal(d.abs()),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(v.mapv(|x| x.abs()))),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(v.mapv(|x| x.abs()))),
         _ => bail!("'abs' requires numbers"),
@@ -703,6 +848,13 @@ pub(crate) fn op_signum(args: &[DataValue]) -> Result<DataValue> {
                 DataValue::from(f64::NAN)
             }
         }
+        DataValue::Decimal(d) => DataValue::from(if d.is_zero() {
+            0
+        } else if d.is_negative() {
+            -1
+        } else {
+            1
+        }),
         _ => bail!("'signum' requires numbers"),
     })
 }
@@ -712,6 +864,10 @@ pub(crate) fn op_floor(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.floor())),
+        DataValue::Decimal(d) => {
+            let t = d.with_scale(0);
+            DataValue::Decimal(if t > *d { t - BigDecimal::from(1) } else { t })
+        }
         _ => bail!("'floor' requires numbers"),
     })
 }
@@ -721,6 +877,10 @@ pub(crate) fn op_ceil(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.ceil())),
+        DataValue::Decimal(d) => {
+            let t = d.with_scale(0);
+            DataValue::Decimal(if t < *d { t + BigDecimal::from(1) } else { t })
+        }
         _ => bail!("'ceil' requires numbers"),
     })
 }
@@ -730,6 +890,7 @@ pub(crate) fn op_round(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.round())),
+        DataValue::Decimal(d) => DataValue::Decimal(d.round(0)),
         _ => bail!("'round' requires numbers"),
     })
 }
@@ -739,6 +900,7 @@ pub(crate) fn op_exp(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.exp()))))
         }
@@ -755,6 +917,7 @@ pub(crate) fn op_exp2(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.exp2()))))
         }
@@ -771,6 +934,7 @@ pub(crate) fn op_ln(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.ln()))))
         }
@@ -787,6 +951,7 @@ pub(crate) fn op_log2(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.log2()))))
         }
@@ -803,6 +968,7 @@ pub(crate) fn op_log10(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.log10()))))
         }
@@ -819,6 +985,7 @@ pub(crate) fn op_sin(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.sin()))))
         }
@@ -835,6 +1002,7 @@ pub(crate) fn op_cos(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.cos()))))
         }
@@ -851,6 +1019,7 @@ pub(crate) fn op_tan(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.tan()))))
         }
@@ -867,6 +1036,7 @@ pub(crate) fn op_asin(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.asin()))))
         }
@@ -883,6 +1053,7 @@ pub(crate) fn op_acos(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.acos()))))
         }
@@ -899,6 +1070,7 @@ pub(crate) fn op_atan(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.atan()))))
         }
@@ -915,11 +1087,13 @@ pub(crate) fn op_atan2(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         _ => bail!("'atan2' requires numbers"),
     };
     let b = match &args[1] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         _ => bail!("'atan2' requires numbers"),
     };
 
@@ -931,6 +1105,7 @@ pub(crate) fn op_sinh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.sinh()))))
         }
@@ -947,6 +1122,7 @@ pub(crate) fn op_cosh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.cosh()))))
         }
@@ -963,6 +1139,7 @@ pub(crate) fn op_tanh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.tanh()))))
         }
@@ -979,6 +1156,7 @@ pub(crate) fn op_asinh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.asinh()))))
         }
@@ -995,6 +1173,7 @@ pub(crate) fn op_acosh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.acosh()))))
         }
@@ -1011,6 +1190,7 @@ pub(crate) fn op_atanh(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.atanh()))))
         }
@@ -1027,6 +1207,7 @@ pub(crate) fn op_sqrt(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             return Ok(DataValue::Vec(Vector::F32(v.mapv(|x| x.sqrt()))))
         }
@@ -1043,6 +1224,7 @@ pub(crate) fn op_pow(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         DataValue::Vec(Vector::F32(v)) => {
             let b = args[1]
                 .get_float()
@@ -1060,6 +1242,7 @@ pub(crate) fn op_pow(args: &[DataValue]) -> Result<DataValue> {
     let b = match &args[1] {
         DataValue::Num(Num::Int(i)) => *i as f64,
         DataValue::Num(Num::Float(f)) => *f,
+        DataValue::Decimal(d) => decimal_to_f64(d),
         _ => bail!("'pow' requires numbers"),
     };
     Ok(DataValue::Num(Num::Float(a.powf(b))))
@@ -1080,6 +1263,21 @@ pub(crate) fn op_mod(args: &[DataValue]) -> Result<DataValue> {
         (DataValue::Num(Num::Float(a)), DataValue::Num(Num::Int(b))) => {
             DataValue::Num(Num::Float(a.rem(*b as f64)))
         }
+        (DataValue::Decimal(_), DataValue::Decimal(_) | DataValue::Num(_))
+        | (DataValue::Num(_), DataValue::Decimal(_)) => {
+            match (args[0].get_decimal(), args[1].get_decimal()) {
+                (Some(a), Some(b)) => {
+                    ensure!(!b.is_zero(), "decimal division by zero");
+                    DataValue::Decimal(a.rem(b))
+                }
+                _ => DataValue::Num(Num::Float(
+                    args[0]
+                        .get_float()
+                        .unwrap()
+                        .rem(args[1].get_float().unwrap()),
+                )),
+            }
+        }
         _ => bail!("'mod' requires numbers"),
     })
 }
@@ -1478,14 +1676,19 @@ define_op!(OP_IS_NUM, 1, false);
 pub(crate) fn op_is_num(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(matches!(
         args[0],
-        DataValue::Num(Num::Int(_)) | DataValue::Num(Num::Float(_))
+        DataValue::Num(Num::Int(_)) | DataValue::Num(Num::Float(_)) | DataValue::Decimal(_)
     )))
 }
 
+define_op!(OP_IS_DECIMAL, 1, false);
+pub(crate) fn op_is_decimal(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Decimal(_))))
+}
+
 define_op!(OP_IS_FINITE, 1, false);
 pub(crate) fn op_is_finite(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match &args[0] {
-        DataValue::Num(Num::Int(_)) => true,
+        DataValue::Num(Num::Int(_)) | DataValue::Decimal(_) => true,
         DataValue::Num(Num::Float(f)) => f.is_finite(),
         _ => false,
     }))
@@ -1724,6 +1927,79 @@ pub(crate) fn op_windows(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::List(res))
 }
 
+fn elements_for_lambda(arg: &DataValue, fn_name: &str) -> Result<Vec<DataValue>> {
+    Ok(match arg {
+        DataValue::List(l) => l.clone(),
+        DataValue::Set(s) => s.iter().cloned().collect_vec(),
+        DataValue::Json(JsonData(JsonValue::Array(arr))) => {
+            arr.iter().map(DataValue::from).collect_vec()
+        }
+        DataValue::Vec(Vector::F32(v)) => {
+            v.iter().map(|f| DataValue::from(*f as f64)).collect_vec()
+        }
+        DataValue::Vec(Vector::F64(v)) => v.iter().map(|f| DataValue::from(*f)).collect_vec(),
+        _ => bail!(
+            "first argument of '{}' must be a list, a JSON array or a vector",
+            fn_name
+        ),
+    })
+}
+
+define_higher_order_op!(OP_MAP, 1, 1);
+pub(crate) fn op_map(
+    args: &[DataValue],
+    lambda: &mut dyn FnMut(Vec<DataValue>) -> Result<DataValue>,
+) -> Result<DataValue> {
+    let res: Vec<_> = elements_for_lambda(&args[0], "map")?
+        .into_iter()
+        .map(|el| lambda(vec![el]))
+        .try_collect()?;
+    Ok(DataValue::List(res))
+}
+
+define_higher_order_op!(OP_FILTER, 1, 1);
+pub(crate) fn op_filter(
+    args: &[DataValue],
+    lambda: &mut dyn FnMut(Vec<DataValue>) -> Result<DataValue>,
+) -> Result<DataValue> {
+    let mut res = vec![];
+    for el in elements_for_lambda(&args[0], "filter")? {
+        match lambda(vec![el.clone()])? {
+            DataValue::Bool(true) => res.push(el),
+            DataValue::Bool(false) => {}
+            v => bail!("the lambda of 'filter' must return booleans, got {:?}", v),
+        }
+    }
+    Ok(DataValue::List(res))
+}
+
+define_higher_order_op!(OP_REDUCE, 2, 2);
+pub(crate) fn op_reduce(
+    args: &[DataValue],
+    lambda: &mut dyn FnMut(Vec<DataValue>) -> Result<DataValue>,
+) -> Result<DataValue> {
+    let mut acc = args[1].clone();
+    for el in elements_for_lambda(&args[0], "reduce")? {
+        acc = lambda(vec![acc, el])?;
+    }
+    Ok(acc)
+}
+
+define_higher_order_op!(OP_SORT_BY, 1, 1);
+pub(crate) fn op_sort_by(
+    args: &[DataValue],
+    lambda: &mut dyn FnMut(Vec<DataValue>) -> Result<DataValue>,
+) -> Result<DataValue> {
+    let mut keyed: Vec<_> = elements_for_lambda(&args[0], "sort_by")?
+        .into_iter()
+        .map(|el| -> Result<(DataValue, DataValue)> { Ok((lambda(vec![el.clone()])?, el)) })
+        .try_collect()?;
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(DataValue::List(
+        keyed.into_iter().map(|(_, el)| el).collect_vec(),
+    ))
+}
+
 fn get_index(mut i: i64, total: usize) -> Result<usize> {
     if i < 0 {
         i += total as i64;
@@ -1777,9 +2053,7 @@ fn get_impl(args: &[DataValue]) -> Result<DataValue> {
                         .ok_or_else(|| miette!("index '{}' not found in json", i))?
                         .clone()
                 }
-                DataValue::List(l) => {
-                    get_json_path_immutable(json, l)?.clone()
-                }
+                DataValue::List(l) => get_json_path_immutable(json, l)?.clone(),
                 _ => bail!("second argument to 'get' mut be a string or integer"),
             };
             let res = json2val(res);
@@ -1913,6 +2187,9 @@ pub(crate) fn op_to_bool(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => !s.is_empty(),
         DataValue::Vec(_) => true,
         DataValue::Validity(vld) => vld.is_assert.0,
+        DataValue::Timestamp(_) | DataValue::Date(_) | DataValue::Geometry(_) => true,
+        DataValue::Duration(d) => *d != 0,
+        DataValue::Decimal(d) => !d.is_zero(),
         DataValue::Bot => false,
         DataValue::Json(json) => match &json.0 {
             Value::Null => false,
@@ -1939,6 +2216,9 @@ pub(crate) fn op_to_unity(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => i64::from(!s.is_empty()),
         DataValue::Vec(_) => 1,
         DataValue::Validity(vld) => i64::from(vld.is_assert.0),
+        DataValue::Timestamp(_) | DataValue::Date(_) | DataValue::Geometry(_) => 1,
+        DataValue::Duration(d) => i64::from(*d != 0),
+        DataValue::Decimal(d) => i64::from(!d.is_zero()),
         DataValue::Bot => 0,
         DataValue::Json(json) => match &json.0 {
             Value::Null => 0,
@@ -1961,6 +2241,11 @@ pub(crate) fn op_to_int(args: &[DataValue]) -> Result<DataValue> {
             }
             Some(i) => DataValue::Num(Num::Int(i)),
         },
+        DataValue::Decimal(d) => DataValue::from(
+            d.with_scale(0)
+                .to_i64()
+                .ok_or_else(|| miette!("decimal {} is out of range for int", d))?,
+        ),
         DataValue::Null => DataValue::from(0),
         DataValue::Bool(b) => DataValue::from(if *b { 1 } else { 0 }),
         DataValue::Str(t) => {
@@ -1978,6 +2263,7 @@ define_op!(OP_TO_FLOAT, 1, false);
 pub(crate) fn op_to_float(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(n) => n.get_float().into(),
+        DataValue::Decimal(d) => decimal_to_f64(d).into(),
         DataValue::Null => DataValue::from(0.0),
         DataValue::Bool(b) => DataValue::from(if *b { 1.0 } else { 0.0 }),
         DataValue::Str(t) => match t as &str {
@@ -2003,6 +2289,13 @@ fn val2str(arg: &DataValue) -> String {
     match arg {
         DataValue::Str(s) => s.to_string(),
         DataValue::Json(JsonData(JsonValue::String(s))) => s.clone(),
+        DataValue::Duration(d) => format_duration_micros(*d),
+        DataValue::Decimal(d) => d.to_string(),
+        DataValue::Geometry(g) => g.to_string(),
+        v @ (DataValue::Timestamp(_) | DataValue::Date(_)) => match JsonValue::from(v.clone()) {
+            JsonValue::String(s) => s,
+            jv => jv.to_string(),
+        },
         v => {
             let jv = to_json(v);
             jv.to_string()
@@ -2432,6 +2725,8 @@ pub(crate) fn op_format_timestamp(args: &[DataValue]) -> Result<DataValue> {
     let dt = {
         let millis = match &args[0] {
             DataValue::Validity(vld) => vld.timestamp.0 .0 / 1000,
+            DataValue::Timestamp(ts) => ts.div_euclid(1000),
+            DataValue::Date(d) => *d as i64 * (MICROS_PER_DAY / 1000),
             v => {
                 let f = v
                     .get_float()
@@ -2448,8 +2743,8 @@ pub(crate) fn op_format_timestamp(args: &[DataValue]) -> Result<DataValue> {
             let tz_s = tz_v.get_str().ok_or_else(|| {
                 miette!("'format_timestamp' timezone specification requires a string")
             })?;
-            let tz = chrono_tz::Tz::from_str(tz_s)
-                .map_err(|_| miette!("bad timezone specification: {}", tz_s))?;
+            let tz =
+                Tz::from_str(tz_s).map_err(|_| miette!("bad timezone specification: {}", tz_s))?;
             let dt_tz = dt.with_timezone(&tz);
             let s = SmartString::from(dt_tz.to_rfc3339());
             Ok(DataValue::Str(s))
@@ -2473,6 +2768,530 @@ pub(crate) fn op_parse_timestamp(args: &[DataValue]) -> Result<DataValue> {
     ))
 }
 
+const MICROS_PER_SEC: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SEC;
+
+fn micros_to_datetime(ts: i64) -> Option<DateTime<Utc>> {
+    let secs = ts.div_euclid(MICROS_PER_SEC);
+    let nanos = (ts.rem_euclid(MICROS_PER_SEC) * 1000) as u32;
+    NaiveDateTime::from_timestamp_opt(secs, nanos).map(|dt| DateTime::from_utc(dt, Utc))
+}
+
+fn datetime_to_micros<T: TimeZone>(dt: &DateTime<T>) -> Result<i64> {
+    dt.timestamp()
+        .checked_mul(MICROS_PER_SEC)
+        .and_then(|v| v.checked_add(dt.timestamp_subsec_micros() as i64))
+        .ok_or_else(|| miette!("timestamp out of range"))
+}
+
+fn epoch_date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+}
+
+fn days_to_date(days: i32) -> Option<NaiveDate> {
+    epoch_date().checked_add_signed(chrono::Duration::days(days as i64))
+}
+
+fn date_to_days(date: NaiveDate) -> Result<i32> {
+    i32::try_from(date.signed_duration_since(epoch_date()).num_days())
+        .map_err(|_| miette!("date out of range"))
+}
+
+/// Ambiguous local times take the earlier offset. Local times skipped when the clocks
+/// move forward are shifted forward by the length of the gap, e.g. 02:30 becomes 03:30
+/// when the clocks jump from 02:00 to 03:00.
+fn local_to_micros(dt: &NaiveDateTime, tz: &Tz) -> Result<i64> {
+    let dt = match tz.from_local_datetime(dt) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt,
+        LocalResult::None => {
+            // read the local time with the offset in effect before the gap
+            let out_of_range = || miette!("{} is out of range in timezone {}", dt, tz);
+            let before = dt
+                .checked_sub_signed(chrono::Duration::days(1))
+                .ok_or_else(out_of_range)?;
+            let offset = tz.offset_from_utc_datetime(&before).fix();
+            let utc = dt
+                .checked_sub_signed(chrono::Duration::seconds(offset.local_minus_utc() as i64))
+                .ok_or_else(out_of_range)?;
+            tz.from_utc_datetime(&utc)
+        }
+    };
+    datetime_to_micros(&dt)
+}
+
+pub(crate) fn format_timestamp_micros(ts: i64) -> Option<String> {
+    micros_to_datetime(ts).map(|dt| dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+pub(crate) fn format_date_days(days: i32) -> Option<String> {
+    days_to_date(days).map(|d| d.format("%Y-%m-%d").to_string())
+}
+
+/// Durations are written as a sequence of numbers with units, e.g. `1d2h30m15.5s`
+pub(crate) fn format_duration_micros(d: i64) -> String {
+    if d == 0 {
+        return "0s".to_string();
+    }
+    let mut ret = String::new();
+    if d < 0 {
+        ret.push('-');
+    }
+    let mut rem = d.unsigned_abs();
+    for (unit, size) in [
+        ("d", 86_400_000_000u64),
+        ("h", 3_600_000_000),
+        ("m", 60_000_000),
+    ] {
+        if rem >= size {
+            ret.push_str(&format!("{}{unit}", rem / size));
+            rem %= size;
+        }
+    }
+    if rem > 0 {
+        let secs = rem / 1_000_000;
+        let micros = rem % 1_000_000;
+        if micros == 0 {
+            ret.push_str(&format!("{secs}s"));
+        } else {
+            let frac = format!("{micros:06}");
+            ret.push_str(&format!("{secs}.{}s", frac.trim_end_matches('0')));
+        }
+    }
+    ret
+}
+
+pub(crate) fn parse_duration_str(s: &str) -> Result<i64> {
+    let err = || miette!("bad duration: {}", s);
+    let (neg, mut rest) = match s.trim().strip_prefix('-') {
+        Some(r) => (true, r),
+        None => (false, s.trim()),
+    };
+    ensure!(!rest.is_empty(), err());
+    let mut total: i64 = 0;
+    while !rest.is_empty() {
+        let num_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(err)?;
+        let (num, tail) = rest.split_at(num_end);
+        let unit_end = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        let size = match unit {
+            "w" => 7 * MICROS_PER_DAY,
+            "d" => MICROS_PER_DAY,
+            "h" => 3_600 * MICROS_PER_SEC,
+            "m" => 60 * MICROS_PER_SEC,
+            "s" => MICROS_PER_SEC,
+            "ms" => 1_000,
+            "us" => 1,
+            _ => bail!(err()),
+        };
+        let part = if num.contains('.') {
+            let f: f64 = num.parse().map_err(|_| err())?;
+            (f * size as f64).round() as i64
+        } else {
+            let n: i64 = num.parse().map_err(|_| err())?;
+            n.checked_mul(size).ok_or_else(err)?
+        };
+        total = total.checked_add(part).ok_or_else(err)?;
+        rest = tail;
+    }
+    Ok(if neg { -total } else { total })
+}
+
+fn parse_tz(v: Option<&DataValue>, fn_name: &str) -> Result<Tz> {
+    match v {
+        None => Ok(Tz::UTC),
+        Some(v) => {
+            let tz_s = v
+                .get_str()
+                .ok_or_else(|| miette!("'{}' timezone specification requires a string", fn_name))?;
+            Tz::from_str(tz_s).map_err(|_| miette!("bad timezone specification: {}", tz_s))
+        }
+    }
+}
+
+/// Parses RFC3339 timestamps, and timestamps and dates without offsets in the given timezone
+pub(crate) fn parse_timestamp_str(s: &str, tz: &Tz) -> Result<i64> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return datetime_to_micros(&dt);
+    }
+    for fmt in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, fmt) {
+            return local_to_micros(&dt, tz);
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return local_to_micros(&d.and_hms_opt(0, 0, 0).unwrap(), tz);
+    }
+    bail!("bad timestamp: {}", s)
+}
+
+pub(crate) fn parse_date_str(s: &str) -> Result<i32> {
+    let d = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| miette!("bad date: {}", s))?;
+    date_to_days(d)
+}
+
+fn timestamp_in_tz(ts: i64, tz: &Tz) -> Result<NaiveDateTime> {
+    Ok(micros_to_datetime(ts)
+        .ok_or_else(|| miette!("timestamp out of range"))?
+        .with_timezone(tz)
+        .naive_local())
+}
+
+/// Converts seconds to microseconds, failing instead of saturating
+fn secs_to_micros(n: &Num) -> Result<i64> {
+    match n {
+        Num::Int(i) => i
+            .checked_mul(MICROS_PER_SEC)
+            .ok_or_else(|| miette!("{} seconds is out of range", i)),
+        Num::Float(f) => {
+            let micros = (f * MICROS_PER_SEC as f64).round();
+            // i64::MAX rounds up to 2^63 as a float, which is itself out of range
+            ensure!(
+                micros.is_finite() && micros >= i64::MIN as f64 && micros < i64::MAX as f64,
+                "{} seconds is out of range",
+                f
+            );
+            Ok(micros as i64)
+        }
+    }
+}
+
+define_op!(OP_TIMESTAMP, 1, true);
+pub(crate) fn op_timestamp(args: &[DataValue]) -> Result<DataValue> {
+    let tz = parse_tz(args.get(1), "timestamp")?;
+    Ok(DataValue::Timestamp(match &args[0] {
+        DataValue::Timestamp(ts) => *ts,
+        DataValue::Validity(vld) => vld.timestamp.0 .0,
+        DataValue::Num(n) => secs_to_micros(n)?,
+        DataValue::Str(s) => parse_timestamp_str(s, &tz)?,
+        DataValue::Date(d) => {
+            let d = days_to_date(*d).ok_or_else(|| miette!("date out of range"))?;
+            local_to_micros(&d.and_hms_opt(0, 0, 0).unwrap(), &tz)?
+        }
+        v => bail!("cannot convert {:?} to timestamp", v),
+    }))
+}
+
+define_op!(OP_DATE, 1, true);
+pub(crate) fn op_date(args: &[DataValue]) -> Result<DataValue> {
+    let tz = parse_tz(args.get(1), "date")?;
+    Ok(DataValue::Date(match &args[0] {
+        DataValue::Date(d) => *d,
+        DataValue::Str(s) => match parse_date_str(s) {
+            Ok(d) => d,
+            Err(_) => date_to_days(timestamp_in_tz(parse_timestamp_str(s, &tz)?, &tz)?.date())?,
+        },
+        DataValue::Timestamp(ts) => date_to_days(timestamp_in_tz(*ts, &tz)?.date())?,
+        v => bail!("cannot convert {:?} to date", v),
+    }))
+}
+
+define_op!(OP_DURATION, 1, false);
+pub(crate) fn op_duration(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Duration(match &args[0] {
+        DataValue::Duration(d) => *d,
+        DataValue::Num(n) => secs_to_micros(n)?,
+        DataValue::Str(s) => parse_duration_str(s)?,
+        v => bail!("cannot convert {:?} to duration", v),
+    }))
+}
+
+define_op!(OP_DECIMAL, 1, true);
+pub(crate) fn op_decimal(args: &[DataValue]) -> Result<DataValue> {
+    let d = match &args[0] {
+        DataValue::Decimal(d) => d.clone(),
+        DataValue::Num(Num::Int(i)) => BigDecimal::from(*i),
+        DataValue::Num(Num::Float(f)) => {
+            ensure!(f.is_finite(), "cannot convert {} to decimal", f);
+            BigDecimal::from_str(&f.to_string()).into_diagnostic()?
+        }
+        DataValue::Str(s) => BigDecimal::from_str(s.trim())
+            .map_err(|_| miette!("cannot parse {:?} as decimal", s))?,
+        v => bail!("cannot convert {:?} to decimal", v),
+    };
+    Ok(DataValue::Decimal(match args.get(1) {
+        None => d,
+        Some(scale) => {
+            let scale = scale
+                .get_int()
+                .ok_or_else(|| miette!("'decimal' requires an integer scale"))?;
+            d.round(scale).with_scale(scale)
+        }
+    }))
+}
+
+define_op!(OP_GEOMETRY, 1, false);
+pub(crate) fn op_geometry(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Geometry(match &args[0] {
+        DataValue::Geometry(g) => g.clone(),
+        DataValue::Json(j) => Geometry::from_geojson(j)?,
+        DataValue::Str(s) => {
+            if s.trim_start().starts_with('{') {
+                let j: JsonValue = serde_json::from_str(s).into_diagnostic()?;
+                Geometry::from_geojson(&j)?
+            } else {
+                Geometry::from_wkt(s)?
+            }
+        }
+        v => bail!("cannot convert {:?} to geometry", v),
+    }))
+}
+
+fn get_geometry_arg(v: &DataValue, fn_name: &str) -> Result<Geometry> {
+    match v {
+        DataValue::Geometry(g) => Ok(g.clone()),
+        DataValue::Str(_) | DataValue::Json(_) => match op_geometry(std::slice::from_ref(v))? {
+            DataValue::Geometry(g) => Ok(g),
+            _ => unreachable!(),
+        },
+        _ => bail!(
+            "'{}' requires geometries as arguments, got {:?}",
+            fn_name,
+            v
+        ),
+    }
+}
+
+define_op!(OP_IS_GEOMETRY, 1, false);
+pub(crate) fn op_is_geometry(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Geometry(_))))
+}
+
+define_op!(OP_ST_POINT, 2, false);
+pub(crate) fn op_st_point(args: &[DataValue]) -> Result<DataValue> {
+    let x = args[0]
+        .get_float()
+        .ok_or_else(|| miette!("'st_point' requires numbers"))?;
+    let y = args[1]
+        .get_float()
+        .ok_or_else(|| miette!("'st_point' requires numbers"))?;
+    ensure!(
+        x.is_finite() && y.is_finite(),
+        "geometry coordinates must be finite"
+    );
+    Ok(DataValue::Geometry(Geometry::Point([x, y])))
+}
+
+define_op!(OP_ST_INTERSECTS, 2, false);
+pub(crate) fn op_st_intersects(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry_arg(&args[0], "st_intersects")?;
+    let b = get_geometry_arg(&args[1], "st_intersects")?;
+    Ok(DataValue::from(a.intersects(&b)))
+}
+
+define_op!(OP_ST_WITHIN, 2, false);
+pub(crate) fn op_st_within(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry_arg(&args[0], "st_within")?;
+    let b = get_geometry_arg(&args[1], "st_within")?;
+    Ok(DataValue::from(a.within(&b)))
+}
+
+define_op!(OP_ST_DISTANCE, 2, false);
+pub(crate) fn op_st_distance(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry_arg(&args[0], "st_distance")?;
+    let b = get_geometry_arg(&args[1], "st_distance")?;
+    Ok(DataValue::from(a.distance(&b)))
+}
+
+define_op!(OP_ST_DISTANCE_SPHERE, 2, false);
+pub(crate) fn op_st_distance_sphere(args: &[DataValue]) -> Result<DataValue> {
+    let a = get_geometry_arg(&args[0], "st_distance_sphere")?;
+    let b = get_geometry_arg(&args[1], "st_distance_sphere")?;
+    Ok(DataValue::from(match (a, b) {
+        (Geometry::Point(p), g) | (g, Geometry::Point(p)) => g.sphere_distance(&p),
+        _ => bail!("'st_distance_sphere' requires at least one point"),
+    }))
+}
+
+define_op!(OP_ST_BBOX, 1, false);
+pub(crate) fn op_st_bbox(args: &[DataValue]) -> Result<DataValue> {
+    let bbox = get_geometry_arg(&args[0], "st_bbox")?.bbox();
+    Ok(DataValue::List(vec![
+        DataValue::from(bbox.min_x),
+        DataValue::from(bbox.min_y),
+        DataValue::from(bbox.max_x),
+        DataValue::from(bbox.max_y),
+    ]))
+}
+
+define_op!(OP_CURRENT_TIMESTAMP, 0, false);
+pub(crate) fn op_current_timestamp(_args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Timestamp(current_validity().0 .0))
+}
+
+define_op!(OP_FORMAT_DATETIME, 2, true);
+pub(crate) fn op_format_datetime(args: &[DataValue]) -> Result<DataValue> {
+    let fmt = args[1]
+        .get_str()
+        .ok_or_else(|| miette!("'format_datetime' requires a format string"))?;
+    let tz = parse_tz(args.get(2), "format_datetime")?;
+    let s = match &args[0] {
+        DataValue::Timestamp(ts) => micros_to_datetime(*ts)
+            .ok_or_else(|| miette!("timestamp out of range"))?
+            .with_timezone(&tz)
+            .format(fmt)
+            .to_string(),
+        DataValue::Date(d) => days_to_date(*d)
+            .ok_or_else(|| miette!("date out of range"))?
+            .format(fmt)
+            .to_string(),
+        _ => bail!("'format_datetime' requires a timestamp or a date"),
+    };
+    Ok(DataValue::from(s))
+}
+
+define_op!(OP_PARSE_DATETIME, 2, true);
+pub(crate) fn op_parse_datetime(args: &[DataValue]) -> Result<DataValue> {
+    let s = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'parse_datetime' expects a string"))?;
+    let fmt = args[1]
+        .get_str()
+        .ok_or_else(|| miette!("'parse_datetime' requires a format string"))?;
+    let tz = parse_tz(args.get(2), "parse_datetime")?;
+    if let Ok(dt) = DateTime::parse_from_str(s, fmt) {
+        return Ok(DataValue::Timestamp(datetime_to_micros(&dt)?));
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(s, fmt) {
+        return Ok(DataValue::Timestamp(local_to_micros(&dt, &tz)?));
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, fmt) {
+        return Ok(DataValue::Date(date_to_days(d)?));
+    }
+    bail!("cannot parse {} with format {}", s, fmt)
+}
+
+fn truncate_date(d: NaiveDate, unit: &str) -> Result<NaiveDate> {
+    Ok(match unit {
+        "year" => NaiveDate::from_ymd_opt(d.year(), 1, 1).unwrap(),
+        "quarter" => NaiveDate::from_ymd_opt(d.year(), (d.month0() / 3) * 3 + 1, 1).unwrap(),
+        "month" => NaiveDate::from_ymd_opt(d.year(), d.month(), 1).unwrap(),
+        "week" => d - chrono::Duration::days(d.weekday().num_days_from_monday() as i64),
+        "day" | "hour" | "minute" | "second" => d,
+        u => bail!("unknown unit for truncation: {}", u),
+    })
+}
+
+define_op!(OP_DATE_TRUNC, 2, true);
+pub(crate) fn op_date_trunc(args: &[DataValue]) -> Result<DataValue> {
+    let unit = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'date_trunc' requires a unit as the first argument"))?;
+    let tz = parse_tz(args.get(2), "date_trunc")?;
+    match &args[1] {
+        DataValue::Date(d) => {
+            let d = days_to_date(*d).ok_or_else(|| miette!("date out of range"))?;
+            Ok(DataValue::Date(date_to_days(truncate_date(d, unit)?)?))
+        }
+        DataValue::Timestamp(ts) => {
+            let dt = timestamp_in_tz(*ts, &tz)?;
+            let date = truncate_date(dt.date(), unit)?;
+            let time = match unit {
+                "hour" => NaiveTime::from_hms_opt(dt.hour(), 0, 0),
+                "minute" => NaiveTime::from_hms_opt(dt.hour(), dt.minute(), 0),
+                "second" => NaiveTime::from_hms_opt(dt.hour(), dt.minute(), dt.second()),
+                _ => NaiveTime::from_hms_opt(0, 0, 0),
+            }
+            .unwrap();
+            Ok(DataValue::Timestamp(local_to_micros(
+                &date.and_time(time),
+                &tz,
+            )?))
+        }
+        _ => bail!("'date_trunc' requires a timestamp or a date"),
+    }
+}
+
+define_op!(OP_DATE_PART, 2, true);
+pub(crate) fn op_date_part(args: &[DataValue]) -> Result<DataValue> {
+    let unit = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'date_part' requires a unit as the first argument"))?;
+    let tz = parse_tz(args.get(2), "date_part")?;
+    let dt = match &args[1] {
+        DataValue::Duration(d) => {
+            return match unit {
+                "epoch" => Ok(DataValue::from(*d as f64 / MICROS_PER_SEC as f64)),
+                u => bail!("unit {} cannot be extracted from a duration", u),
+            }
+        }
+        DataValue::Timestamp(ts) => {
+            if unit == "epoch" {
+                return Ok(DataValue::from(*ts as f64 / MICROS_PER_SEC as f64));
+            }
+            timestamp_in_tz(*ts, &tz)?
+        }
+        DataValue::Date(d) => days_to_date(*d)
+            .ok_or_else(|| miette!("date out of range"))?
+            .and_hms_opt(0, 0, 0)
+            .unwrap(),
+        _ => bail!("'date_part' requires a timestamp, a date or a duration"),
+    };
+    let v = match unit {
+        "year" => dt.year() as i64,
+        "quarter" => (dt.month0() / 3 + 1) as i64,
+        "month" => dt.month() as i64,
+        "week" => dt.iso_week().week() as i64,
+        "day" => dt.day() as i64,
+        "dow" => dt.weekday().number_from_monday() as i64,
+        "doy" => dt.ordinal() as i64,
+        "hour" => dt.hour() as i64,
+        "minute" => dt.minute() as i64,
+        "second" => dt.second() as i64,
+        "microsecond" => (dt.nanosecond() / 1000) as i64,
+        "epoch" => dt.timestamp(),
+        u => bail!("unknown unit for extraction: {}", u),
+    };
+    Ok(DataValue::from(v))
+}
+
+define_op!(OP_DAY_OF_WEEK, 1, true);
+pub(crate) fn op_day_of_week(args: &[DataValue]) -> Result<DataValue> {
+    let mut part_args = vec![DataValue::from("dow"), args[0].clone()];
+    part_args.extend(args.get(1).cloned());
+    op_date_part(&part_args)
+}
+
+define_op!(OP_ADD_DURATION, 2, false);
+pub(crate) fn op_add_duration(args: &[DataValue]) -> Result<DataValue> {
+    let d = args[1]
+        .get_duration()
+        .ok_or_else(|| miette!("second argument of 'add_duration' must be a duration"))?;
+    let overflow = || miette!("overflow in 'add_duration'");
+    Ok(match &args[0] {
+        DataValue::Timestamp(ts) => DataValue::Timestamp(ts.checked_add(d).ok_or_else(overflow)?),
+        DataValue::Duration(d0) => DataValue::Duration(d0.checked_add(d).ok_or_else(overflow)?),
+        DataValue::Date(days) => {
+            ensure!(
+                d % MICROS_PER_DAY == 0,
+                "only durations of whole days can be added to dates"
+            );
+            let n = i32::try_from(d / MICROS_PER_DAY).map_err(|_| overflow())?;
+            DataValue::Date(days.checked_add(n).ok_or_else(overflow)?)
+        }
+        _ => bail!("first argument of 'add_duration' must be a timestamp, a date or a duration"),
+    })
+}
+
+define_op!(OP_DATE_DIFF, 2, false);
+pub(crate) fn op_date_diff(args: &[DataValue]) -> Result<DataValue> {
+    let overflow = || miette!("overflow in 'date_diff'");
+    Ok(DataValue::Duration(match (&args[0], &args[1]) {
+        (DataValue::Timestamp(a), DataValue::Timestamp(b)) => {
+            a.checked_sub(*b).ok_or_else(overflow)?
+        }
+        (DataValue::Date(a), DataValue::Date(b)) => (*a as i64 - *b as i64) * MICROS_PER_DAY,
+        _ => bail!("'date_diff' requires two timestamps or two dates"),
+    }))
+}
+
 pub(crate) fn str2vld(s: &str) -> Result<ValidityTs> {
     let dt = DateTime::parse_from_rfc3339(s).map_err(|_| miette!("bad datetime: {}", s))?;
     let st: SystemTime = dt.into();
@@ -0,0 +1,620 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Planar geometries: points, line strings and polygons.
+//!
+//! All computations are done in the plane, in the units of the coordinates, except for
+//! the distances on the sphere, which read coordinates as longitudes and latitudes in
+//! degrees and are in meters.
+
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+use miette::{bail, ensure, miette, Result};
+use serde_json::json;
+
+use crate::data::json::JsonValue;
+
+/// A coordinate pair, `[x, y]`, or `[longitude, latitude]` for geographic data
+pub type Coord = [f64; 2];
+
+/// Mean radius of the Earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Geometry value in the database
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub enum Geometry {
+    /// a single point
+    Point(Coord),
+    /// a line string of at least two points
+    LineString(Vec<Coord>),
+    /// a polygon: the first ring is the exterior, the rest are holes.
+    /// All rings are closed.
+    Polygon(Vec<Vec<Coord>>),
+}
+
+/// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BBox {
+    pub(crate) min_x: f64,
+    pub(crate) min_y: f64,
+    pub(crate) max_x: f64,
+    pub(crate) max_y: f64,
+}
+
+impl BBox {
+    pub(crate) fn union(&self, other: &BBox) -> BBox {
+        BBox {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y),
+        }
+    }
+    pub(crate) fn area(&self) -> f64 {
+        (self.max_x - self.min_x) * (self.max_y - self.min_y)
+    }
+    /// Half perimeter, used to break ties between degenerate boxes with no area
+    pub(crate) fn margin(&self) -> f64 {
+        (self.max_x - self.min_x) + (self.max_y - self.min_y)
+    }
+    pub(crate) fn enlargement(&self, other: &BBox) -> f64 {
+        self.union(other).area() - self.area()
+    }
+    pub(crate) fn intersects(&self, other: &BBox) -> bool {
+        self.min_x <= other.max_x
+            && other.min_x <= self.max_x
+            && self.min_y <= other.max_y
+            && other.min_y <= self.max_y
+    }
+    pub(crate) fn contains(&self, other: &BBox) -> bool {
+        self.min_x <= other.min_x
+            && self.min_y <= other.min_y
+            && self.max_x >= other.max_x
+            && self.max_y >= other.max_y
+    }
+    /// Smallest distance between any two points of the boxes, a lower bound
+    /// for the distance between anything contained in them.
+    pub(crate) fn distance(&self, other: &BBox) -> f64 {
+        let dx = (self.min_x - other.max_x)
+            .max(other.min_x - self.max_x)
+            .max(0.);
+        let dy = (self.min_y - other.max_y)
+            .max(other.min_y - self.max_y)
+            .max(0.);
+        dx.hypot(dy)
+    }
+    /// Smallest distance on the sphere between the point and any point of the box,
+    /// a lower bound for the distance on the sphere to anything contained in it.
+    pub(crate) fn sphere_distance(&self, p: &Coord) -> f64 {
+        let [lon, lat] = *p;
+        if self.min_x <= lon && lon <= self.max_x {
+            // along the meridian of the point
+            let d_lat = (self.min_y - lat).max(lat - self.max_y).max(0.);
+            return d_lat.to_radians() * EARTH_RADIUS;
+        }
+        // the nearest point lies on the meridian edge nearer in longitude
+        let edge = if lon_diff(lon, self.min_x) <= lon_diff(lon, self.max_x) {
+            self.min_x
+        } else {
+            self.max_x
+        };
+        let cos_d_lon = lon_diff(lon, edge).to_radians().cos();
+        // the latitude at which the great circle through the meridian is nearest
+        let nearest_lat = if cos_d_lon > 0. {
+            (lat.to_radians().tan() / cos_d_lon).atan().to_degrees()
+        } else {
+            90f64.copysign(lat)
+        };
+        [
+            nearest_lat.clamp(self.min_y, self.max_y),
+            self.min_y,
+            self.max_y,
+        ]
+        .into_iter()
+        .map(|y| haversine(p, &[edge, y]))
+        .fold(f64::INFINITY, f64::min)
+    }
+}
+
+impl Geometry {
+    /// Parses the well-known text representation, e.g. `POINT (1 2)`
+    pub fn from_wkt(s: &str) -> Result<Self> {
+        let mut parser = WktParser { src: s, pos: 0 };
+        let kind = parser.word().to_ascii_uppercase();
+        let ret = match kind.as_str() {
+            "POINT" => Geometry::Point(parser.point()?),
+            "LINESTRING" => Geometry::LineString(parser.coords()?),
+            "POLYGON" => {
+                parser.expect('(')?;
+                let mut rings = vec![parser.coords()?];
+                while parser.eat(',') {
+                    rings.push(parser.coords()?);
+                }
+                parser.expect(')')?;
+                Geometry::Polygon(rings)
+            }
+            _ => bail!("unsupported WKT geometry: {:?}", s),
+        };
+        parser.skip_ws();
+        ensure!(parser.pos == s.len(), "trailing characters in WKT: {:?}", s);
+        ret.validated()
+    }
+    /// Parses a GeoJSON geometry object. Features are accepted and their geometry is used.
+    pub fn from_geojson(j: &JsonValue) -> Result<Self> {
+        let obj = j
+            .as_object()
+            .ok_or_else(|| miette!("GeoJSON geometry must be an object"))?;
+        let typ = obj
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| miette!("GeoJSON object must have a 'type'"))?;
+        if typ == "Feature" {
+            let geom = obj
+                .get("geometry")
+                .ok_or_else(|| miette!("GeoJSON feature must have a 'geometry'"))?;
+            return Self::from_geojson(geom);
+        }
+        let coords = obj
+            .get("coordinates")
+            .ok_or_else(|| miette!("GeoJSON geometry must have 'coordinates'"))?;
+        let ret = match typ {
+            "Point" => Geometry::Point(json_coord(coords)?),
+            "LineString" => Geometry::LineString(json_coords(coords)?),
+            "Polygon" => Geometry::Polygon(
+                coords
+                    .as_array()
+                    .ok_or_else(|| miette!("polygon coordinates must be an array of rings"))?
+                    .iter()
+                    .map(json_coords)
+                    .collect::<Result<_>>()?,
+            ),
+            t => bail!("unsupported GeoJSON geometry type: {}", t),
+        };
+        ret.validated()
+    }
+    /// Converts to a GeoJSON geometry object
+    pub fn to_geojson(&self) -> JsonValue {
+        match self {
+            Geometry::Point(p) => json!({"type": "Point", "coordinates": p}),
+            Geometry::LineString(l) => json!({"type": "LineString", "coordinates": l}),
+            Geometry::Polygon(rings) => json!({"type": "Polygon", "coordinates": rings}),
+        }
+    }
+    fn validated(mut self) -> Result<Self> {
+        match &mut self {
+            Geometry::Point(_) => {}
+            Geometry::LineString(l) => {
+                ensure!(l.len() >= 2, "a line string needs at least two points");
+            }
+            Geometry::Polygon(rings) => {
+                ensure!(!rings.is_empty(), "a polygon needs at least one ring");
+                for ring in rings.iter_mut() {
+                    if ring.first() != ring.last() {
+                        ring.push(ring[0]);
+                    }
+                    ensure!(
+                        ring.len() >= 4,
+                        "a polygon ring needs at least three points"
+                    );
+                }
+            }
+        }
+        ensure!(
+            self.vertices().all(|p| p.iter().all(|c| c.is_finite())),
+            "geometry coordinates must be finite"
+        );
+        Ok(self)
+    }
+    fn vertices(&self) -> Box<dyn Iterator<Item = &Coord> + '_> {
+        match self {
+            Geometry::Point(p) => Box::new(std::iter::once(p)),
+            Geometry::LineString(l) => Box::new(l.iter()),
+            Geometry::Polygon(rings) => Box::new(rings.iter().flatten()),
+        }
+    }
+    fn segments(&self) -> Box<dyn Iterator<Item = (&Coord, &Coord)> + '_> {
+        match self {
+            Geometry::Point(_) => Box::new(std::iter::empty()),
+            Geometry::LineString(l) => Box::new(l.windows(2).map(|w| (&w[0], &w[1]))),
+            Geometry::Polygon(rings) => Box::new(
+                rings
+                    .iter()
+                    .flat_map(|r| r.windows(2).map(|w| (&w[0], &w[1]))),
+            ),
+        }
+    }
+    pub(crate) fn bbox(&self) -> BBox {
+        let mut ret = BBox {
+            min_x: f64::INFINITY,
+            min_y: f64::INFINITY,
+            max_x: f64::NEG_INFINITY,
+            max_y: f64::NEG_INFINITY,
+        };
+        for [x, y] in self.vertices() {
+            ret.min_x = ret.min_x.min(*x);
+            ret.min_y = ret.min_y.min(*y);
+            ret.max_x = ret.max_x.max(*x);
+            ret.max_y = ret.max_y.max(*y);
+        }
+        ret
+    }
+    /// Whether the point lies on or inside the geometry
+    fn covers_point(&self, p: &Coord) -> bool {
+        match self {
+            Geometry::Point(q) => p == q,
+            Geometry::LineString(_) => self.segments().any(|(a, b)| on_segment(p, a, b)),
+            Geometry::Polygon(rings) => {
+                if self.segments().any(|(a, b)| on_segment(p, a, b)) {
+                    return true;
+                }
+                in_ring(p, &rings[0]) && !rings[1..].iter().any(|hole| in_ring(p, hole))
+            }
+        }
+    }
+    /// Whether the two geometries share at least one point
+    pub fn intersects(&self, other: &Geometry) -> bool {
+        if !self.bbox().intersects(&other.bbox()) {
+            return false;
+        }
+        for (a, b) in self.segments() {
+            for (c, d) in other.segments() {
+                if segments_intersect(a, b, c, d) {
+                    return true;
+                }
+            }
+        }
+        self.vertices().any(|p| other.covers_point(p))
+            || other.vertices().any(|p| self.covers_point(p))
+    }
+    /// Whether every point of `self` lies on or inside `other`
+    pub fn within(&self, other: &Geometry) -> bool {
+        if !other.bbox().contains(&self.bbox()) {
+            return false;
+        }
+        if !self.vertices().all(|p| other.covers_point(p)) {
+            return false;
+        }
+        match other {
+            Geometry::Polygon(_) => {
+                // an edge leaving the polygon must properly cross its boundary
+                !self.segments().any(|(a, b)| {
+                    other
+                        .segments()
+                        .any(|(c, d)| segments_cross_properly(a, b, c, d))
+                        || !other.covers_point(&midpoint(a, b))
+                })
+            }
+            _ => self
+                .segments()
+                .all(|(a, b)| other.covers_point(&midpoint(a, b))),
+        }
+    }
+    /// Smallest Euclidean distance between the two geometries, zero if they intersect
+    pub fn distance(&self, other: &Geometry) -> f64 {
+        if self.intersects(other) {
+            return 0.;
+        }
+        let one_way = |l: &Geometry, r: &Geometry| -> f64 {
+            let mut ret = f64::INFINITY;
+            for p in l.vertices() {
+                match r {
+                    Geometry::Point(q) => ret = ret.min(point_distance(p, q)),
+                    _ => {
+                        for (a, b) in r.segments() {
+                            ret = ret.min(point_segment_distance(p, a, b))
+                        }
+                    }
+                }
+            }
+            ret
+        };
+        one_way(self, other).min(one_way(other, self))
+    }
+    /// Smallest distance on the sphere, in meters, between the point and the geometry,
+    /// zero if the point lies on or inside it. Edges are the straight lines between
+    /// their ends in longitude and latitude, not great circles.
+    pub fn sphere_distance(&self, p: &Coord) -> f64 {
+        if self.covers_point(p) {
+            return 0.;
+        }
+        match self {
+            Geometry::Point(q) => haversine(p, q),
+            _ => self
+                .segments()
+                .map(|(a, b)| point_segment_sphere_distance(p, a, b))
+                .fold(f64::INFINITY, f64::min),
+        }
+    }
+    fn kind_rank(&self) -> u8 {
+        match self {
+            Geometry::Point(_) => 1,
+            Geometry::LineString(_) => 2,
+            Geometry::Polygon(_) => 3,
+        }
+    }
+}
+
+fn json_coord(j: &JsonValue) -> Result<Coord> {
+    match j.as_array().map(|a| a.as_slice()) {
+        Some([x, y, ..]) => Ok([
+            x.as_f64()
+                .ok_or_else(|| miette!("bad GeoJSON coordinate: {}", j))?,
+            y.as_f64()
+                .ok_or_else(|| miette!("bad GeoJSON coordinate: {}", j))?,
+        ]),
+        _ => bail!("bad GeoJSON coordinate: {}", j),
+    }
+}
+
+fn json_coords(j: &JsonValue) -> Result<Vec<Coord>> {
+    j.as_array()
+        .ok_or_else(|| miette!("expected an array of GeoJSON coordinates, got {}", j))?
+        .iter()
+        .map(json_coord)
+        .collect()
+}
+
+struct WktParser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> WktParser<'a> {
+    fn skip_ws(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn word(&mut self) -> &'a str {
+        self.skip_ws();
+        let rest = &self.src[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.src[self.pos..].starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, c: char) -> Result<()> {
+        ensure!(
+            self.eat(c),
+            "expected '{}' at position {} of WKT {:?}",
+            c,
+            self.pos,
+            self.src
+        );
+        Ok(())
+    }
+    fn number(&mut self) -> Result<f64> {
+        self.skip_ws();
+        let rest = &self.src[self.pos..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(rest.len());
+        let n = rest[..len]
+            .parse::<f64>()
+            .map_err(|_| miette!("bad number at position {} of WKT {:?}", self.pos, self.src))?;
+        self.pos += len;
+        Ok(n)
+    }
+    fn coord(&mut self) -> Result<Coord> {
+        Ok([self.number()?, self.number()?])
+    }
+    fn point(&mut self) -> Result<Coord> {
+        self.expect('(')?;
+        let p = self.coord()?;
+        self.expect(')')?;
+        Ok(p)
+    }
+    fn coords(&mut self) -> Result<Vec<Coord>> {
+        self.expect('(')?;
+        let mut ret = vec![self.coord()?];
+        while self.eat(',') {
+            ret.push(self.coord()?);
+        }
+        self.expect(')')?;
+        Ok(ret)
+    }
+}
+
+fn orientation(a: &Coord, b: &Coord, c: &Coord) -> f64 {
+    (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+}
+
+fn on_segment(p: &Coord, a: &Coord, b: &Coord) -> bool {
+    orientation(a, b, p) == 0.
+        && p[0] >= a[0].min(b[0])
+        && p[0] <= a[0].max(b[0])
+        && p[1] >= a[1].min(b[1])
+        && p[1] <= a[1].max(b[1])
+}
+
+fn segments_intersect(a: &Coord, b: &Coord, c: &Coord, d: &Coord) -> bool {
+    let o1 = orientation(a, b, c);
+    let o2 = orientation(a, b, d);
+    let o3 = orientation(c, d, a);
+    let o4 = orientation(c, d, b);
+    if ((o1 > 0. && o2 < 0.) || (o1 < 0. && o2 > 0.))
+        && ((o3 > 0. && o4 < 0.) || (o3 < 0. && o4 > 0.))
+    {
+        return true;
+    }
+    on_segment(c, a, b) || on_segment(d, a, b) || on_segment(a, c, d) || on_segment(b, c, d)
+}
+
+fn segments_cross_properly(a: &Coord, b: &Coord, c: &Coord, d: &Coord) -> bool {
+    let o1 = orientation(a, b, c);
+    let o2 = orientation(a, b, d);
+    let o3 = orientation(c, d, a);
+    let o4 = orientation(c, d, b);
+    o1 * o2 < 0. && o3 * o4 < 0.
+}
+
+fn midpoint(a: &Coord, b: &Coord) -> Coord {
+    [(a[0] + b[0]) / 2., (a[1] + b[1]) / 2.]
+}
+
+/// Ray casting; points on the boundary may go either way.
+fn in_ring(p: &Coord, ring: &[Coord]) -> bool {
+    let mut inside = false;
+    for w in ring.windows(2) {
+        let (a, b) = (&w[0], &w[1]);
+        if (a[1] > p[1]) != (b[1] > p[1]) {
+            let x = a[0] + (p[1] - a[1]) * (b[0] - a[0]) / (b[1] - a[1]);
+            if p[0] < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+fn point_distance(a: &Coord, b: &Coord) -> f64 {
+    (a[0] - b[0]).hypot(a[1] - b[1])
+}
+
+fn point_segment_distance(p: &Coord, a: &Coord, b: &Coord) -> f64 {
+    let dx = b[0] - a[0];
+    let dy = b[1] - a[1];
+    let len_sq = dx * dx + dy * dy;
+    if len_sq == 0. {
+        return point_distance(p, a);
+    }
+    let t = (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / len_sq).clamp(0., 1.);
+    point_distance(p, &[a[0] + t * dx, a[1] + t * dy])
+}
+
+/// Great-circle distance in meters between two `[longitude, latitude]` pairs
+fn haversine(a: &Coord, b: &Coord) -> f64 {
+    let (lat_a, lat_b) = (a[1].to_radians(), b[1].to_radians());
+    let half_d_lat = (lat_b - lat_a) / 2.;
+    let half_d_lon = (b[0] - a[0]).to_radians() / 2.;
+    let h = half_d_lat.sin().powi(2) + lat_a.cos() * lat_b.cos() * half_d_lon.sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().min(1.).asin()
+}
+
+/// Difference of two longitudes in degrees, between 0 and 180
+fn lon_diff(a: f64, b: f64) -> f64 {
+    let d = (a - b).rem_euclid(360.);
+    d.min(360. - d)
+}
+
+/// Golden-section search for the point of the segment nearest on the sphere
+fn point_segment_sphere_distance(p: &Coord, a: &Coord, b: &Coord) -> f64 {
+    let at = |t: f64| haversine(p, &[a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])]);
+    let ratio = (5f64.sqrt() - 1.) / 2.;
+    let (mut lo, mut hi) = (0., 1.);
+    for _ in 0..64 {
+        let l = hi - ratio * (hi - lo);
+        let r = lo + ratio * (hi - lo);
+        if at(l) <= at(r) {
+            hi = r;
+        } else {
+            lo = l;
+        }
+    }
+    at((lo + hi) / 2.).min(at(0.)).min(at(1.))
+}
+
+fn write_coords(f: &mut Formatter<'_>, coords: &[Coord]) -> std::fmt::Result {
+    f.write_str("(")?;
+    for (i, [x, y]) in coords.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{x} {y}")?;
+    }
+    f.write_str(")")
+}
+
+/// Formats as well-known text
+impl Display for Geometry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Geometry::Point([x, y]) => write!(f, "POINT ({x} {y})"),
+            Geometry::LineString(l) => {
+                f.write_str("LINESTRING ")?;
+                write_coords(f, l)
+            }
+            Geometry::Polygon(rings) => {
+                f.write_str("POLYGON (")?;
+                for (i, ring) in rings.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_coords(f, ring)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+fn cmp_coords(l: &[Coord], r: &[Coord]) -> Ordering {
+    for (a, b) in l.iter().zip(r) {
+        match a[0].total_cmp(&b[0]).then_with(|| a[1].total_cmp(&b[1])) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    l.len().cmp(&r.len())
+}
+
+impl PartialEq for Geometry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Geometry {}
+
+impl PartialOrd for Geometry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Sorts by kind first, then lexicographically by coordinates,
+/// the same as the memcmp encoding.
+impl Ord for Geometry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Geometry::Point(l), Geometry::Point(r)) => cmp_coords(&[*l], &[*r]),
+            (Geometry::LineString(l), Geometry::LineString(r)) => cmp_coords(l, r),
+            (Geometry::Polygon(l), Geometry::Polygon(r)) => {
+                for (a, b) in l.iter().zip(r) {
+                    match cmp_coords(a, b) {
+                        Ordering::Equal => continue,
+                        o => return o,
+                    }
+                }
+                l.len().cmp(&r.len())
+            }
+            (l, r) => l.kind_rank().cmp(&r.kind_rank()),
+        }
+    }
+}
+
+impl Hash for Geometry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind_rank().hash(state);
+        for [x, y] in self.vertices() {
+            x.to_bits().hash(state);
+            y.to_bits().hash(state);
+        }
+    }
+}
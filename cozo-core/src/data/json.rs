@@ -11,6 +11,7 @@ use base64::Engine;
 use serde_json::json;
 pub(crate) use serde_json::Value as JsonValue;
 
+use crate::data::functions::{format_date_days, format_duration_micros, format_timestamp_micros};
 use crate::data::value::{DataValue, Num, Vector};
 use crate::JsonData;
 
@@ -102,6 +103,17 @@ impl From<DataValue> for JsonValue {
                 json!([v.timestamp.0, v.is_assert])
             }
             DataValue::Json(j) => j.0,
+            DataValue::Timestamp(ts) => match format_timestamp_micros(ts) {
+                Some(s) => json!(s),
+                None => json!(ts),
+            },
+            DataValue::Date(d) => match format_date_days(d) {
+                Some(s) => json!(s),
+                None => json!(d),
+            },
+            DataValue::Duration(d) => json!(format_duration_micros(d)),
+            DataValue::Decimal(d) => json!(d.to_string()),
+            DataValue::Geometry(g) => g.to_geojson(),
         }
     }
 }
@@ -11,11 +11,15 @@ use std::collections::BTreeSet;
 use std::io::Write;
 use std::str::FromStr;
 
+use bigdecimal::num_bigint::{BigInt, Sign};
+use bigdecimal::BigDecimal;
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use regex::Regex;
 
+use crate::data::geo::{Coord, Geometry};
 use crate::data::value::{
-    DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
+    decimal_floor_f64, DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs,
+    Vector,
 };
 
 const INIT_TAG: u8 = 0x00;
@@ -32,13 +36,27 @@ const LIST_TAG: u8 = 0x0A;
 const SET_TAG: u8 = 0x0B;
 const VLD_TAG: u8 = 0x0C;
 const JSON_TAG: u8 = 0x0D;
+const TIMESTAMP_TAG: u8 = 0x0E;
+const DATE_TAG: u8 = 0x0F;
+const DURATION_TAG: u8 = 0x10;
+const GEOMETRY_TAG: u8 = 0x11;
 const BOT_TAG: u8 = 0xFF;
 
 const VEC_F32: u8 = 0x01;
 const VEC_F64: u8 = 0x02;
 
+const GEOM_POINT: u8 = 0x01;
+const GEOM_LINESTRING: u8 = 0x02;
+const GEOM_POLYGON: u8 = 0x03;
+
+const DECIMAL_NEG: u8 = 0x00;
+const DECIMAL_ZERO: u8 = 0x01;
+const DECIMAL_POS: u8 = 0x02;
+
 const IS_FLOAT: u8 = 0b00010000;
 const IS_APPROX_INT: u8 = 0b00000100;
+const IS_DECIMAL: u8 = 0b00001000;
+const IS_INEXACT_DECIMAL: u8 = 0b00011000;
 const IS_EXACT_INT: u8 = 0b00000000;
 const EXACT_INT_BOUND: i64 = 0x20_0000_0000_0000;
 
@@ -121,9 +139,97 @@ pub(crate) trait MemCmpEncoder: Write {
                 self.write_u64::<BigEndian>(ts_flipped).unwrap();
                 self.write_u8(!vld.is_assert.0 as u8).unwrap();
             }
+            DataValue::Timestamp(ts) => {
+                self.write_u8(TIMESTAMP_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*ts)).unwrap();
+            }
+            DataValue::Date(d) => {
+                self.write_u8(DATE_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*d as i64))
+                    .unwrap();
+            }
+            DataValue::Duration(d) => {
+                self.write_u8(DURATION_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*d)).unwrap();
+            }
+            DataValue::Decimal(d) => {
+                self.write_u8(NUM_TAG).unwrap();
+                self.encode_decimal(d);
+            }
+            DataValue::Geometry(g) => {
+                self.write_u8(GEOMETRY_TAG).unwrap();
+                self.encode_geometry(g);
+            }
             DataValue::Bot => self.write_u8(BOT_TAG).unwrap(),
         }
     }
+    /// Geometries are sorted by kind, then by their coordinates. Each
+    /// coordinate and each ring is preceded by a continuation byte and the
+    /// sequence is terminated by zero, so that prefixes sort first.
+    fn encode_geometry(&mut self, g: &Geometry) {
+        fn write_coords<W: Write + ?Sized>(w: &mut W, coords: &[Coord]) {
+            for [x, y] in coords {
+                w.write_u8(1).unwrap();
+                w.write_u64::<BigEndian>(order_encode_f64(*x)).unwrap();
+                w.write_u64::<BigEndian>(order_encode_f64(*y)).unwrap();
+            }
+            w.write_u8(INIT_TAG).unwrap();
+        }
+        match g {
+            Geometry::Point([x, y]) => {
+                self.write_u8(GEOM_POINT).unwrap();
+                self.write_u64::<BigEndian>(order_encode_f64(*x)).unwrap();
+                self.write_u64::<BigEndian>(order_encode_f64(*y)).unwrap();
+            }
+            Geometry::LineString(l) => {
+                self.write_u8(GEOM_LINESTRING).unwrap();
+                write_coords(self, l);
+            }
+            Geometry::Polygon(rings) => {
+                self.write_u8(GEOM_POLYGON).unwrap();
+                for ring in rings {
+                    self.write_u8(1).unwrap();
+                    write_coords(self, ring);
+                }
+                self.write_u8(INIT_TAG).unwrap();
+            }
+        }
+    }
+    /// Decimals are sorted among numbers by the largest float not above them,
+    /// tagged to sort before floats when equal to it and after them otherwise,
+    /// followed by the exact value written as `0.d1d2d3... * 10^exp` with
+    /// normalized digits: a sign byte, the order-encoded exponent, then one
+    /// byte per digit terminated by zero. Negative numbers have everything
+    /// after the sign byte inverted so that larger magnitudes sort first.
+    fn encode_decimal(&mut self, d: &BigDecimal) {
+        let (floor, exact) = decimal_floor_f64(d);
+        let u = order_encode_f64(floor);
+        self.write_u64::<BigEndian>(u).unwrap();
+        let tag = if exact { IS_DECIMAL } else { IS_INEXACT_DECIMAL };
+        self.write_u8(tag).unwrap();
+        let (int_val, scale) = d.normalized().into_bigint_and_exponent();
+        let (sign, digits) = int_val.to_radix_be(10);
+        let sign_byte = match sign {
+            Sign::Minus => DECIMAL_NEG,
+            Sign::NoSign => DECIMAL_ZERO,
+            Sign::Plus => DECIMAL_POS,
+        };
+        self.write_u8(sign_byte).unwrap();
+        if sign == Sign::NoSign {
+            return;
+        }
+        let exp = digits.len() as i64 - scale;
+        let mut buf = Vec::with_capacity(digits.len() + 9);
+        buf.write_u64::<BigEndian>(order_encode_i64(exp)).unwrap();
+        buf.extend(digits.iter().map(|d| d + 1));
+        buf.push(0);
+        if sign == Sign::Minus {
+            for b in buf.iter_mut() {
+                *b = !*b;
+            }
+        }
+        self.write_all(&buf).unwrap();
+    }
     fn encode_num(&mut self, v: Num) {
         let f = v.get_float();
         let u = order_encode_f64(f);
@@ -191,6 +297,82 @@ pub fn decode_bytes(data: &[u8]) -> (Vec<u8>, &[u8]) {
     }
 }
 
+fn decode_coord(data: &[u8]) -> (Coord, &[u8]) {
+    let (x, rest) = data.split_at(8);
+    let (y, rest) = rest.split_at(8);
+    (
+        [
+            order_decode_f64(BigEndian::read_u64(x)),
+            order_decode_f64(BigEndian::read_u64(y)),
+        ],
+        rest,
+    )
+}
+
+fn decode_coords(mut data: &[u8]) -> (Vec<Coord>, &[u8]) {
+    let mut ret = vec![];
+    while data[0] != INIT_TAG {
+        let (c, rest) = decode_coord(&data[1..]);
+        ret.push(c);
+        data = rest;
+    }
+    (ret, &data[1..])
+}
+
+fn decode_geometry(data: &[u8]) -> (Geometry, &[u8]) {
+    let (kind, rest) = data.split_first().unwrap();
+    match *kind {
+        GEOM_POINT => {
+            let (c, rest) = decode_coord(rest);
+            (Geometry::Point(c), rest)
+        }
+        GEOM_LINESTRING => {
+            let (l, rest) = decode_coords(rest);
+            (Geometry::LineString(l), rest)
+        }
+        GEOM_POLYGON => {
+            let mut rings = vec![];
+            let mut rest = rest;
+            while rest[0] != INIT_TAG {
+                let (ring, nxt) = decode_coords(&rest[1..]);
+                rings.push(ring);
+                rest = nxt;
+            }
+            (Geometry::Polygon(rings), &rest[1..])
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn decode_decimal(data: &[u8]) -> (BigDecimal, &[u8]) {
+    let (sign_byte, rest) = data.split_first().unwrap();
+    let (sign, flip) = match *sign_byte {
+        DECIMAL_NEG => (Sign::Minus, 0xFF),
+        DECIMAL_ZERO => return (BigDecimal::from(0), rest),
+        DECIMAL_POS => (Sign::Plus, 0x00),
+        _ => unreachable!(),
+    };
+    let (exp_bytes, mut rest) = rest.split_at(8);
+    let mut exp_buf = [0u8; 8];
+    for (t, b) in exp_buf.iter_mut().zip(exp_bytes) {
+        *t = b ^ flip;
+    }
+    let exp = order_decode_i64(BigEndian::read_u64(&exp_buf));
+    let mut digits = vec![];
+    loop {
+        let (b, next) = rest.split_first().unwrap();
+        rest = next;
+        let b = b ^ flip;
+        if b == 0 {
+            break;
+        }
+        digits.push(b - 1);
+    }
+    let scale = digits.len() as i64 - exp;
+    let int_val = BigInt::from_radix_be(sign, &digits, 10).unwrap();
+    (BigDecimal::new(int_val, scale), rest)
+}
+
 const SIGN_MARK: u64 = 0x8000000000000000;
 
 fn order_encode_i64(v: i64) -> u64 {
@@ -261,6 +443,10 @@ impl DataValue {
             NULL_TAG => (DataValue::Null, remaining),
             FALSE_TAG => (DataValue::from(false), remaining),
             TRUE_TAG => (DataValue::from(true), remaining),
+            NUM_TAG if matches!(remaining[8], IS_DECIMAL | IS_INEXACT_DECIMAL) => {
+                let (d, rest) = decode_decimal(&remaining[9..]);
+                (DataValue::Decimal(d), rest)
+            }
             NUM_TAG => {
                 let (n, remaining) = Num::decode_from_key(remaining);
                 (DataValue::Num(n), remaining)
@@ -334,6 +520,25 @@ impl DataValue {
                     rest,
                 )
             }
+            TIMESTAMP_TAG => {
+                let (ts_bytes, rest) = remaining.split_at(8);
+                let ts = order_decode_i64(BigEndian::read_u64(ts_bytes));
+                (DataValue::Timestamp(ts), rest)
+            }
+            DATE_TAG => {
+                let (d_bytes, rest) = remaining.split_at(8);
+                let d = order_decode_i64(BigEndian::read_u64(d_bytes));
+                (DataValue::Date(d as i32), rest)
+            }
+            DURATION_TAG => {
+                let (d_bytes, rest) = remaining.split_at(8);
+                let d = order_decode_i64(BigEndian::read_u64(d_bytes));
+                (DataValue::Duration(d), rest)
+            }
+            GEOMETRY_TAG => {
+                let (g, rest) = decode_geometry(remaining);
+                (DataValue::Geometry(g), rest)
+            }
             BOT_TAG => (DataValue::Bot, remaining),
             VEC_TAG => {
                 let (t_tag, remaining) = remaining.split_first().unwrap();
@@ -9,6 +9,7 @@
 pub(crate) mod aggr;
 pub(crate) mod expr;
 pub(crate) mod functions;
+pub(crate) mod geo;
 pub(crate) mod json;
 pub(crate) mod memcmp;
 pub(crate) mod program;
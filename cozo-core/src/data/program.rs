@@ -23,13 +23,14 @@ use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fixed_rule::{FixedRule, FixedRuleHandle};
 use crate::fts::FtsIndexManifest;
-use crate::parse::SourceSpan;
+use crate::parse::{parse_stored_rules, SourceSpan};
 use crate::query::logical::{Disjunction, NamedFieldNotFound};
 use crate::runtime::hnsw::HnswIndexManifest;
 use crate::runtime::minhash_lsh::{LshSearch, MinHashLshIndexManifest};
 use crate::runtime::relation::{
     AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
 };
+use crate::runtime::rtree::{RTreeIndexManifest, RTreeSearch, RTreeSearchKind};
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
@@ -44,6 +45,14 @@ pub(crate) struct QueryOutOptions {
     pub(crate) limit: Option<usize>,
     pub(crate) offset: Option<usize>,
     pub(crate) timeout: Option<f64>,
+    /// in bytes, zero for no limit, `None` for the default of the database
+    pub(crate) max_memory: Option<usize>,
+    /// of recursive rules, zero for no limit, `None` for the default of the database
+    pub(crate) max_iterations: Option<usize>,
+    /// evaluate under the well-founded semantics
+    pub(crate) well_founded: bool,
+    /// times to retry on transaction conflicts, `None` for the default of the database
+    pub(crate) retry: Option<usize>,
     pub(crate) sleep: Option<f64>,
     pub(crate) sorters: Vec<(Symbol, SortDir)>,
     pub(crate) store_relation: Option<(InputRelationHandle, RelationOp)>,
@@ -67,6 +76,18 @@ impl Display for QueryOutOptions {
         if let Some(l) = self.timeout {
             writeln!(f, ":timeout {l};")?;
         }
+        if let Some(l) = self.max_memory {
+            writeln!(f, ":max_memory {l};")?;
+        }
+        if let Some(l) = self.max_iterations {
+            writeln!(f, ":max_iterations {l};")?;
+        }
+        if self.well_founded {
+            writeln!(f, ":well_founded;")?;
+        }
+        if let Some(n) = self.retry {
+            writeln!(f, ":retry {n};")?;
+        }
         for (symb, dir) in &self.sorters {
             write!(f, ":order ")?;
             if *dir == SortDir::Dsc {
@@ -80,6 +101,7 @@ impl Display for QueryOutOptions {
                 metadata: StoredRelationMetadata { keys, non_keys },
                 key_bindings,
                 dep_bindings,
+                constraints,
                 ..
             },
             op,
@@ -117,6 +139,16 @@ impl Display for QueryOutOptions {
                     write!(f, ", ")?;
                 }
                 write!(f, "{}: {}", col.name, col.typing)?;
+                if constraints.unique.contains(&col.name) {
+                    write!(f, " unique")?;
+                }
+                for (_, target) in constraints
+                    .references
+                    .iter()
+                    .filter(|(c, _)| *c == col.name)
+                {
+                    write!(f, " references {target}")?;
+                }
                 if let Some(gen) = &col.default_gen {
                     write!(f, " default {gen}")?;
                 } else {
@@ -132,12 +164,30 @@ impl Display for QueryOutOptions {
                     write!(f, ", ")?;
                 }
                 write!(f, "{}: {}", col.name, col.typing)?;
+                if constraints.unique.contains(&col.name) {
+                    write!(f, " unique")?;
+                }
+                for (_, target) in constraints
+                    .references
+                    .iter()
+                    .filter(|(c, _)| *c == col.name)
+                {
+                    write!(f, " references {target}")?;
+                }
                 if let Some(gen) = &col.default_gen {
                     write!(f, " default {gen}")?;
                 } else {
                     write!(f, " = {bind}")?;
                 }
             }
+            for check in &constraints.checks {
+                if is_first {
+                    is_first = false
+                } else {
+                    write!(f, ", ")?;
+                }
+                write!(f, "check {check}")?;
+            }
             writeln!(f, "}};")?;
         }
 
@@ -535,6 +585,95 @@ struct EntryHeadNotExplicitlyDefinedError(#[label] SourceSpan);
 pub(crate) struct NoEntryError;
 
 impl InputProgram {
+    /// Names of the stored relations read by the program
+    pub(crate) fn stored_relations_read(&self) -> BTreeSet<SmartString<LazyCompact>> {
+        let mut collector = BTreeSet::new();
+        for rules_or_fixed in self.prog.values() {
+            match rules_or_fixed {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for rule in rules {
+                        for atom in &rule.body {
+                            atom.collect_stored_relations(&mut collector);
+                        }
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for arg in &fixed.rule_args {
+                        match arg {
+                            FixedRuleArg::InMem { .. } => {}
+                            FixedRuleArg::Stored { name, .. }
+                            | FixedRuleArg::NamedStored { name, .. } => {
+                                collector.insert(name.name.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        collector
+    }
+
+    /// Adds the definitions of the stored rules applied but not defined by the program,
+    /// including the stored rules these in turn depend on.
+    /// Stored rules are added under private names, and the rules they apply are always
+    /// resolved against the stored rules, so that rules of the program cannot replace them.
+    pub(crate) fn inline_stored_rules(
+        &mut self,
+        tx: &SessionTx<'_>,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        let private_name = |name: &Symbol| Symbol::new(format!("{}*stored", name.name), name.span);
+        let mut pending = vec![];
+        let defined: BTreeSet<_> = self.prog.keys().cloned().collect();
+        for rules_or_fixed in self.prog.values_mut() {
+            let mut resolve = |name: &mut Symbol| -> Result<()> {
+                if !defined.contains(&*name) && tx.get_stored_rule(&name.name)?.is_some() {
+                    pending.push(name.clone());
+                    *name = private_name(name);
+                }
+                Ok(())
+            };
+            match rules_or_fixed {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for atom in rules.iter_mut().flat_map(|rule| rule.body.iter_mut()) {
+                        atom.for_each_rule_application_mut(&mut resolve)?;
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for arg in &mut fixed.rule_args {
+                        if let FixedRuleArg::InMem { name, .. } = arg {
+                            resolve(name)?;
+                        }
+                    }
+                }
+            }
+        }
+        while let Some(name) = pending.pop() {
+            let private = private_name(&name);
+            if self.prog.contains_key(&private) {
+                continue;
+            }
+            let stored = match tx.get_stored_rule(&name.name)? {
+                Some(stored) => stored,
+                None => continue,
+            };
+            let (_, mut rules) = parse_stored_rules(&stored.src, cur_vld)?;
+            let mut resolve = |name: &mut Symbol| -> Result<()> {
+                if tx.get_stored_rule(&name.name)?.is_some() {
+                    pending.push(name.clone());
+                    *name = private_name(name);
+                }
+                Ok(())
+            };
+            for atom in rules.iter_mut().flat_map(|rule| rule.body.iter_mut()) {
+                atom.for_each_rule_application_mut(&mut resolve)?;
+            }
+            self.prog
+                .insert(private, InputInlineRulesOrFixed::Rules { rules });
+        }
+        Ok(())
+    }
+
     pub(crate) fn needs_write_lock(&self) -> Option<SmartString<LazyCompact>> {
         if let Some((h, _)) = &self.out_opts.store_relation {
             if !h.name.name.starts_with('_') {
@@ -1134,6 +1273,217 @@ impl SearchInput {
 
         Ok(Disjunction::conj(conj))
     }
+    fn normalize_rtree(
+        mut self,
+        base_handle: RelationHandle,
+        idx_handle: RelationHandle,
+        manifest: RTreeIndexManifest,
+        gen: &mut TempSymbGen,
+    ) -> Result<Disjunction> {
+        let mut conj = Vec::with_capacity(self.bindings.len() + 8);
+        let mut bindings = Vec::with_capacity(self.bindings.len());
+        let mut seen_variables = BTreeSet::new();
+
+        for col in base_handle
+            .metadata
+            .keys
+            .iter()
+            .chain(base_handle.metadata.non_keys.iter())
+        {
+            if let Some(arg) = self.bindings.remove(&col.name) {
+                match arg {
+                    Expr::Binding { var, .. } => {
+                        if var.is_ignored_symbol() {
+                            bindings.push(gen.next_ignored(var.span));
+                        } else if seen_variables.insert(var.clone()) {
+                            bindings.push(var);
+                        } else {
+                            let span = var.span;
+                            let dup = gen.next(span);
+                            let unif = NormalFormAtom::Unification(Unification {
+                                binding: dup.clone(),
+                                expr: Expr::Binding {
+                                    var,
+                                    tuple_pos: None,
+                                },
+                                one_many_unif: false,
+                                span,
+                            });
+                            conj.push(unif);
+                            bindings.push(dup);
+                        }
+                    }
+                    expr => {
+                        let span = expr.span();
+                        let kw = gen.next(span);
+                        bindings.push(kw.clone());
+                        let unif = NormalFormAtom::Unification(Unification {
+                            binding: kw,
+                            expr,
+                            one_many_unif: false,
+                            span,
+                        });
+                        conj.push(unif)
+                    }
+                }
+            } else {
+                bindings.push(gen.next_ignored(self.span));
+            }
+        }
+
+        if let Some((name, _)) = self.bindings.pop_first() {
+            bail!(NamedFieldNotFound(
+                self.relation.name.to_string(),
+                name.to_string(),
+                self.span
+            ));
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Exactly one of `intersects` and `query` is required for R-tree search")]
+        #[diagnostic(code(parser::rtree_query_required))]
+        struct RTreeQueryRequired(#[label] SourceSpan);
+
+        let (kind, query_expr) = match (
+            self.parameters.remove("intersects"),
+            self.parameters.remove("query"),
+        ) {
+            (Some(expr), None) => (RTreeSearchKind::Intersects, expr),
+            (None, Some(expr)) => (RTreeSearchKind::Nearest, expr),
+            _ => bail!(RTreeQueryRequired(self.span)),
+        };
+        let query = match query_expr {
+            Expr::Binding { var, .. } => var,
+            expr => {
+                let span = expr.span();
+                let kw = gen.next(span);
+                let unif = NormalFormAtom::Unification(Unification {
+                    binding: kw.clone(),
+                    expr,
+                    one_many_unif: false,
+                    span,
+                });
+                conj.push(unif);
+                kw
+            }
+        };
+
+        let k = match self.parameters.remove("k") {
+            None => None,
+            Some(k_expr) => {
+                let k = k_expr.eval_to_const()?;
+                let k = k.get_int().ok_or(ExpectedPosIntForRTreeK(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected positive integer for `k`")]
+                #[diagnostic(code(parser::expected_int_for_rtree_k))]
+                struct ExpectedPosIntForRTreeK(#[label] SourceSpan);
+
+                ensure!(k > 0, ExpectedPosIntForRTreeK(self.span));
+                Some(k as usize)
+            }
+        };
+
+        let within_distance = match self.parameters.remove("within_distance") {
+            None => None,
+            Some(expr) => {
+                let d = expr.eval_to_const()?;
+                let d = d
+                    .get_float()
+                    .ok_or(ExpectedFloatForWithinDistance(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected non-negative number for `within_distance`")]
+                #[diagnostic(code(parser::expected_float_for_rtree_within_distance))]
+                struct ExpectedFloatForWithinDistance(#[label] SourceSpan);
+
+                ensure!(d >= 0.0, ExpectedFloatForWithinDistance(self.span));
+                Some(d)
+            }
+        };
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("`within_distance` can only be used together with `query`")]
+        #[diagnostic(code(parser::rtree_within_distance_without_query))]
+        struct WithinDistanceWithoutQuery(#[label] SourceSpan);
+
+        ensure!(
+            kind == RTreeSearchKind::Nearest || within_distance.is_none(),
+            WithinDistanceWithoutQuery(self.span)
+        );
+
+        let geodesic = match self.parameters.remove("geodesic") {
+            None => false,
+            Some(expr) => {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected boolean for `geodesic`")]
+                #[diagnostic(code(parser::expected_bool_for_rtree_geodesic))]
+                struct ExpectedBoolForGeodesic(#[label] SourceSpan);
+
+                expr.eval_to_const()?
+                    .get_bool()
+                    .ok_or(ExpectedBoolForGeodesic(self.span))?
+            }
+        };
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("`geodesic` can only be used together with `query`")]
+        #[diagnostic(code(parser::rtree_geodesic_without_query))]
+        struct GeodesicWithoutQuery(#[label] SourceSpan);
+
+        ensure!(
+            kind == RTreeSearchKind::Nearest || !geodesic,
+            GeodesicWithoutQuery(self.span)
+        );
+
+        let filter = self.parameters.remove("filter");
+
+        let bind_distance = match self.parameters.remove("bind_distance") {
+            None => None,
+            Some(Expr::Binding { var, .. }) => Some(var),
+            Some(expr) => {
+                let span = expr.span();
+                let kw = gen.next(span);
+                let unif = NormalFormAtom::Unification(Unification {
+                    binding: kw.clone(),
+                    expr,
+                    one_many_unif: false,
+                    span,
+                });
+                conj.push(unif);
+                Some(kw)
+            }
+        };
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Extra parameters for R-tree search: {0:?}")]
+        #[diagnostic(code(parser::extra_parameters_for_rtree_search))]
+        struct ExtraParametersForRTreeSearch(Vec<String>, #[label] SourceSpan);
+
+        if !self.parameters.is_empty() {
+            bail!(ExtraParametersForRTreeSearch(
+                self.parameters.keys().map(|s| s.to_string()).collect(),
+                self.span
+            ));
+        }
+
+        conj.push(NormalFormAtom::RTreeSearch(RTreeSearch {
+            base_handle,
+            idx_handle,
+            manifest,
+            bindings,
+            kind,
+            query,
+            within_distance,
+            geodesic,
+            k,
+            bind_distance,
+            filter,
+            span: self.span,
+        }));
+
+        Ok(Disjunction::conj(conj))
+    }
     fn normalize_fts(
         mut self,
         base_handle: RelationHandle,
@@ -1553,6 +1903,11 @@ impl SearchInput {
         {
             return self.normalize_lsh(base_handle, idx_handle, manifest, gen);
         }
+        if let Some((idx_handle, manifest)) =
+            base_handle.rtree_indices.get(&self.index.name).cloned()
+        {
+            return self.normalize_rtree(base_handle, idx_handle, manifest, gen);
+        }
         #[derive(Debug, Error, Diagnostic)]
         #[error("Index {name} not found on relation {relation}")]
         #[diagnostic(code(eval::hnsw_index_not_found))]
@@ -1667,6 +2022,48 @@ impl InputAtom {
     //         _ => false,
     //     }
     // }
+    fn for_each_rule_application_mut(
+        &mut self,
+        f: &mut impl FnMut(&mut Symbol) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            InputAtom::Rule { inner } => f(&mut inner.name),
+            InputAtom::Negation { inner, .. } => inner.for_each_rule_application_mut(f),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    atom.for_each_rule_application_mut(f)?;
+                }
+                Ok(())
+            }
+            InputAtom::Relation { .. }
+            | InputAtom::NamedFieldRelation { .. }
+            | InputAtom::Search { .. }
+            | InputAtom::Predicate { .. }
+            | InputAtom::Unification { .. } => Ok(()),
+        }
+    }
+    fn collect_stored_relations(&self, collector: &mut BTreeSet<SmartString<LazyCompact>>) {
+        match self {
+            InputAtom::Relation { inner } => {
+                collector.insert(inner.name.name.clone());
+            }
+            InputAtom::NamedFieldRelation { inner } => {
+                collector.insert(inner.name.name.clone());
+            }
+            InputAtom::Search { inner } => {
+                collector.insert(inner.relation.name.clone());
+            }
+            InputAtom::Negation { inner, .. } => inner.collect_stored_relations(collector),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    atom.collect_stored_relations(collector);
+                }
+            }
+            InputAtom::Rule { .. }
+            | InputAtom::Predicate { .. }
+            | InputAtom::Unification { .. } => {}
+        }
+    }
     pub(crate) fn span(&self) -> SourceSpan {
         match self {
             InputAtom::Negation { span, .. }
@@ -1693,6 +2090,7 @@ pub(crate) enum NormalFormAtom {
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    RTreeSearch(RTreeSearch),
 }
 
 #[derive(Debug, Clone)]
@@ -1706,6 +2104,7 @@ pub(crate) enum MagicAtom {
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    RTreeSearch(RTreeSearch),
 }
 
 #[derive(Clone, Debug)]
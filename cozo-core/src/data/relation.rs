@@ -8,6 +8,7 @@
 
 use std::cmp::Reverse;
 use std::fmt::{Display, Formatter};
+use std::slice;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
@@ -20,6 +21,8 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::expr::Expr;
+use crate::data::functions::{op_date, op_decimal, op_duration, op_geometry, op_timestamp};
+use crate::data::json::JsonValue;
 use crate::data::value::{DataValue, JsonData, UuidWrapper, Validity, ValidityTs, Vector};
 use crate::Num;
 
@@ -71,6 +74,11 @@ impl Display for NullableColType {
             ColType::Json => {
                 f.write_str("Json")?;
             }
+            ColType::Timestamp => f.write_str("Timestamp")?,
+            ColType::Date => f.write_str("Date")?,
+            ColType::Duration => f.write_str("Duration")?,
+            ColType::Decimal => f.write_str("Decimal")?,
+            ColType::Geometry => f.write_str("Geometry")?,
         }
         if self.nullable {
             f.write_str("?")?;
@@ -99,6 +107,11 @@ pub enum ColType {
     Tuple(Vec<NullableColType>),
     Validity,
     Json,
+    Timestamp,
+    Date,
+    Duration,
+    Decimal,
+    Geometry,
 }
 
 #[derive(
@@ -357,6 +370,44 @@ impl NullableColType {
                     v => bail!(InvalidValidity(v)),
                 }
             }
+            ColType::Timestamp => match &data {
+                DataValue::Timestamp(_) => data,
+                DataValue::Str(_)
+                | DataValue::Num(_)
+                | DataValue::Date(_)
+                | DataValue::Validity(_) => {
+                    op_timestamp(slice::from_ref(&data)).map_err(|_| make_err())?
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Date => match &data {
+                DataValue::Date(_) => data,
+                DataValue::Str(_) | DataValue::Timestamp(_) => {
+                    op_date(slice::from_ref(&data)).map_err(|_| make_err())?
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Duration => match &data {
+                DataValue::Duration(_) => data,
+                DataValue::Str(_) | DataValue::Num(_) => {
+                    op_duration(slice::from_ref(&data)).map_err(|_| make_err())?
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Decimal => match &data {
+                DataValue::Decimal(_) => data,
+                DataValue::Str(_) | DataValue::Num(_) => {
+                    op_decimal(slice::from_ref(&data)).map_err(|_| make_err())?
+                }
+                _ => bail!(make_err()),
+            },
+            ColType::Geometry => match &data {
+                DataValue::Geometry(_) => data,
+                DataValue::Str(_) | DataValue::Json(_) => {
+                    op_geometry(slice::from_ref(&data)).map_err(|_| make_err())?
+                }
+                _ => bail!(make_err()),
+            },
             ColType::Json => DataValue::Json(JsonData(match data {
                 DataValue::Null => {
                     json!(null)
@@ -418,6 +469,11 @@ impl NullableColType {
                 DataValue::Validity(vld) => {
                     json!([vld.timestamp.0, vld.is_assert.0])
                 }
+                v @ (DataValue::Timestamp(_)
+                | DataValue::Date(_)
+                | DataValue::Duration(_)
+                | DataValue::Decimal(_)
+                | DataValue::Geometry(_)) => JsonValue::from(v),
                 DataValue::Bot => {
                     json!(null)
                 }
@@ -299,6 +299,55 @@ fn test_sum() {
     assert_eq!(sum_aggr.get().unwrap(), DataValue::from(15.));
 }
 
+#[test]
+fn test_decimal_aggrs() {
+    let dec = |s: &str| DataValue::Decimal(s.parse().unwrap());
+
+    let mut aggr = parse_aggr("sum").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut sum_aggr = aggr.normal_op.unwrap();
+    sum_aggr.set(&dec("0.1")).unwrap();
+    sum_aggr.set(&dec("0.2")).unwrap();
+    sum_aggr.set(&DataValue::from(1)).unwrap();
+    assert_eq!(sum_aggr.get().unwrap(), dec("1.3"));
+    sum_aggr.set(&DataValue::from(0.5)).unwrap();
+    assert_eq!(sum_aggr.get().unwrap(), DataValue::from(1.8));
+
+    let mut aggr = parse_aggr("mean").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut mean_aggr = aggr.normal_op.unwrap();
+    mean_aggr.set(&dec("1.5")).unwrap();
+    mean_aggr.set(&dec("2.5")).unwrap();
+    mean_aggr.set(&DataValue::from(5)).unwrap();
+    assert_eq!(mean_aggr.get().unwrap(), dec("3"));
+
+    let mut aggr = parse_aggr("min").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    aggr.meet_init(&[]).unwrap();
+    let mut min_aggr = aggr.normal_op.unwrap();
+    min_aggr.set(&dec("0.30000000000000000001")).unwrap();
+    min_aggr.set(&dec("0.3")).unwrap();
+    min_aggr.set(&DataValue::from(1)).unwrap();
+    assert_eq!(min_aggr.get().unwrap(), dec("0.3"));
+    let m_min_aggr = aggr.meet_op.unwrap();
+    let mut v = dec("0.30000000000000000001");
+    assert!(m_min_aggr.update(&mut v, &dec("0.3")).unwrap());
+    assert_eq!(v, dec("0.3"));
+
+    let mut aggr = parse_aggr("max").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    aggr.meet_init(&[]).unwrap();
+    let mut max_aggr = aggr.normal_op.unwrap();
+    max_aggr.set(&dec("0.3")).unwrap();
+    max_aggr.set(&dec("0.30000000000000000001")).unwrap();
+    max_aggr.set(&DataValue::from(0)).unwrap();
+    assert_eq!(max_aggr.get().unwrap(), dec("0.30000000000000000001"));
+    let m_max_aggr = aggr.meet_op.unwrap();
+    let mut v = DataValue::from(0);
+    assert!(m_max_aggr.update(&mut v, &dec("0.5")).unwrap());
+    assert_eq!(v, dec("0.5"));
+}
+
 #[test]
 fn test_product() {
     let mut aggr = parse_aggr("product").unwrap().clone();
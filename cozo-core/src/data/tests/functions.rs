@@ -12,7 +12,7 @@ use regex::Regex;
 use serde_json::json;
 
 use crate::data::functions::*;
-use crate::data::value::{DataValue, RegexWrapper};
+use crate::data::value::{DataValue, JsonData, RegexWrapper};
 use crate::new_cozo_mem;
 
 #[test]
@@ -1476,4 +1476,316 @@ fn test_range() {
         .unwrap()
         .into_json();
     assert_eq!(res["rows"][0][0], json!([15, 13, 11, 9, 7, 5]));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_higher_order() {
+    let db = new_cozo_mem().unwrap();
+    let res = db
+        .run_script("?[a] := a = map([1, 2, 3], x -> x * 2)", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!([2, 4, 6]));
+    let res = db
+        .run_script(
+            "?[a] := n = 2, a = filter([1, 2, 3, 4], x -> x % n == 0)",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!([2, 4]));
+    let res = db
+        .run_script(
+            "?[a] := a = reduce([1, 2, 3, 4], 10, (acc, x) -> acc + x)",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!(20));
+    let res = db
+        .run_script(
+            "?[a] := a = sort_by(['ccc', 'a', 'bb'], s -> length(s))",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!(["a", "bb", "ccc"]));
+    let res = db
+        .run_script(
+            "?[a] := a = map(parse_json('[1, 2]'), x -> map([x, x], y -> y + x))",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!([[2, 2], [4, 4]]));
+    let res = db
+        .run_script("?[a] <- [[map([1, 2], x -> -x)]]", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!([-1, -2]));
+    assert!(db
+        .run_script("?[a] := a = length(x -> x)", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("?[a] := a = reduce([1], 0, x -> x)", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("?[a] := a = map([1], x -> y)", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_temporal() {
+    let ts = op_timestamp(&[DataValue::from("2023-03-15T10:20:30.5Z")]).unwrap();
+    assert_eq!(ts, DataValue::Timestamp(1678875630500000));
+    assert_eq!(
+        op_timestamp(&[
+            DataValue::from("2023-03-15 11:20:30.5"),
+            DataValue::from("Europe/Paris")
+        ])
+        .unwrap(),
+        ts
+    );
+    assert_eq!(
+        op_date(&[ts.clone(), DataValue::from("Asia/Tokyo")]).unwrap(),
+        op_date(&[DataValue::from("2023-03-15")]).unwrap()
+    );
+    assert_eq!(
+        op_date(&[DataValue::Timestamp(0), DataValue::from("America/New_York")]).unwrap(),
+        DataValue::Date(-1)
+    );
+    // skipped by the switch to daylight saving time, so shifted forward by an hour
+    assert_eq!(
+        op_timestamp(&[
+            DataValue::from("2023-03-12 02:30:00"),
+            DataValue::from("America/New_York")
+        ])
+        .unwrap(),
+        op_timestamp(&[DataValue::from("2023-03-12T07:30:00Z")]).unwrap()
+    );
+    assert_eq!(
+        op_duration(&[DataValue::from("1d2h30m0.25s")]).unwrap(),
+        DataValue::Duration(95_400_250_000)
+    );
+    assert_eq!(format_duration_micros(95_400_250_000), "1d2h30m0.25s");
+    assert_eq!(format_duration_micros(-90_000_000), "-1m30s");
+    assert!(op_duration(&[DataValue::from("3 parsecs")]).is_err());
+    assert_eq!(
+        op_duration(&[DataValue::from(1.5)]).unwrap(),
+        DataValue::Duration(1_500_000)
+    );
+    assert!(op_duration(&[DataValue::from(f64::NAN)]).is_err());
+    assert!(op_duration(&[DataValue::from(f64::INFINITY)]).is_err());
+    assert!(op_duration(&[DataValue::from(1e20)]).is_err());
+    assert!(op_timestamp(&[DataValue::from(i64::MAX)]).is_err());
+    assert!(op_timestamp(&[DataValue::from(-1e300)]).is_err());
+
+    assert_eq!(
+        op_date_trunc(&[DataValue::from("month"), ts.clone()]).unwrap(),
+        op_timestamp(&[DataValue::from("2023-03-01T00:00:00Z")]).unwrap()
+    );
+    assert_eq!(
+        op_date_trunc(&[
+            DataValue::from("day"),
+            ts.clone(),
+            DataValue::from("Asia/Tokyo")
+        ])
+        .unwrap(),
+        op_timestamp(&[DataValue::from("2023-03-15T00:00:00+09:00")]).unwrap()
+    );
+    assert_eq!(
+        op_date_trunc(&[DataValue::from("week"), DataValue::Date(19431)]).unwrap(),
+        DataValue::Date(19429)
+    );
+    assert_eq!(
+        op_day_of_week(std::slice::from_ref(&ts)).unwrap(),
+        DataValue::from(3)
+    );
+    assert_eq!(
+        op_date_part(&[
+            DataValue::from("hour"),
+            ts.clone(),
+            DataValue::from("Asia/Kolkata")
+        ])
+        .unwrap(),
+        DataValue::from(15)
+    );
+
+    let later = op_add_duration(&[ts.clone(), DataValue::Duration(3_600_000_000)]).unwrap();
+    assert_eq!(
+        op_date_diff(&[later, ts.clone()]).unwrap(),
+        DataValue::Duration(3_600_000_000)
+    );
+    assert_eq!(
+        op_add_duration(&[DataValue::Date(0), DataValue::Duration(2 * 86_400_000_000)]).unwrap(),
+        DataValue::Date(2)
+    );
+    assert!(op_add_duration(&[DataValue::Date(0), DataValue::Duration(1)]).is_err());
+    assert_eq!(
+        op_format_datetime(&[
+            ts,
+            DataValue::from("%Y/%m/%d %H:%M"),
+            DataValue::from("UTC")
+        ])
+        .unwrap(),
+        DataValue::from("2023/03/15 10:20")
+    );
+}
+
+#[test]
+fn test_decimal() {
+    let dec = |s: &str| op_decimal(&[DataValue::from(s)]).unwrap();
+    assert_eq!(op_add(&[dec("0.1"), dec("0.2")]).unwrap(), dec("0.3"));
+    assert_eq!(
+        op_add(&[dec("0.1"), DataValue::from(1)]).unwrap(),
+        dec("1.1")
+    );
+    assert_eq!(
+        op_add(&[dec("0.5"), DataValue::from(0.25)]).unwrap(),
+        DataValue::from(0.75)
+    );
+    assert_eq!(op_sub(&[dec("1"), dec("0.9")]).unwrap(), dec("0.1"));
+    assert_eq!(
+        op_mul(&[dec("1.5"), DataValue::from(3)]).unwrap(),
+        dec("4.5")
+    );
+    assert_eq!(op_div(&[dec("1"), dec("8")]).unwrap(), dec("0.125"));
+    assert!(op_div(&[dec("1"), DataValue::from(0)]).is_err());
+    assert_eq!(
+        op_mod(&[dec("7.5"), DataValue::from(2)]).unwrap(),
+        dec("1.5")
+    );
+    assert_eq!(op_minus(&[dec("1.5")]).unwrap(), dec("-1.5"));
+    assert_eq!(op_abs(&[dec("-1.5")]).unwrap(), dec("1.5"));
+    assert_eq!(op_signum(&[dec("-1.5")]).unwrap(), DataValue::from(-1));
+    assert_eq!(op_floor(&[dec("-1.5")]).unwrap(), dec("-2"));
+    assert_eq!(op_ceil(&[dec("1.2")]).unwrap(), dec("2"));
+    assert_eq!(op_round(&[dec("2.5")]).unwrap(), dec("3"));
+    assert_eq!(
+        op_decimal(&[DataValue::from("2.345"), DataValue::from(2)])
+            .unwrap()
+            .to_string(),
+        "decimal(\"2.35\")"
+    );
+    assert_eq!(op_decimal(&[DataValue::from(0.1)]).unwrap(), dec("0.1"));
+    assert!(op_decimal(&[DataValue::from("abc")]).is_err());
+
+    assert_eq!(
+        op_eq(&[dec("2.0"), DataValue::from(2)]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_gt(&[dec("2.01"), DataValue::from(2)]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_le(&[DataValue::from(2), dec("1.99")]).unwrap(),
+        DataValue::from(false)
+    );
+    assert_eq!(
+        op_lt(&[dec("0.1"), dec("0.11")]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_max(&[DataValue::from(1), dec("1.5")]).unwrap(),
+        dec("1.5")
+    );
+    assert_eq!(
+        op_min(&[dec("1.5"), DataValue::from(1)]).unwrap(),
+        DataValue::from(1)
+    );
+
+    assert_eq!(op_to_float(&[dec("0.5")]).unwrap(), DataValue::from(0.5));
+    assert_eq!(op_to_int(&[dec("-2.7")]).unwrap(), DataValue::from(-2));
+    assert_eq!(
+        op_to_string(&[dec("12.50")]).unwrap(),
+        DataValue::from("12.50")
+    );
+    assert_eq!(op_is_decimal(&[dec("1")]).unwrap(), DataValue::from(true));
+    assert_eq!(op_is_num(&[dec("1")]).unwrap(), DataValue::from(true));
+    assert_eq!(op_sqrt(&[dec("4")]).unwrap(), DataValue::from(2.0));
+}
+
+#[test]
+fn test_geometry() {
+    let geom = |s: &str| op_geometry(&[DataValue::from(s)]).unwrap();
+    let square = geom("POLYGON ((0 0, 4 0, 4 4, 0 4, 0 0))");
+    assert_eq!(
+        op_st_point(&[DataValue::from(1), DataValue::from(2.5)]).unwrap(),
+        geom("POINT (1 2.5)")
+    );
+    assert_eq!(
+        geom(r#"{"type": "Point", "coordinates": [1, 2.5]}"#),
+        geom("POINT (1 2.5)")
+    );
+    assert_eq!(
+        op_geometry(&[DataValue::Json(JsonData(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": [[0, 0], [1, 1]]},
+            "properties": {}
+        })))])
+        .unwrap(),
+        geom("LINESTRING (0 0, 1 1)")
+    );
+    // rings are closed automatically
+    assert_eq!(geom("POLYGON ((0 0, 4 0, 4 4, 0 4))"), square);
+    assert_eq!(geom("POINT(1 2)").to_string(), "geometry(\"POINT (1 2)\")");
+    assert!(op_geometry(&[DataValue::from("POINT (1)")]).is_err());
+    assert!(op_geometry(&[DataValue::from("LINESTRING (0 0)")]).is_err());
+    assert!(op_geometry(&[DataValue::from("CIRCLE (0 0, 1)")]).is_err());
+
+    assert_eq!(
+        op_st_intersects(&[square.clone(), geom("LINESTRING (-1 2, 5 2)")]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_intersects(&[square.clone(), geom("POINT (4 4)")]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_intersects(&[square.clone(), geom("POINT (5 5)")]).unwrap(),
+        DataValue::from(false)
+    );
+    assert_eq!(
+        op_st_within(&[geom("POINT (1 1)"), square.clone()]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_within(&[geom("LINESTRING (1 1, 5 5)"), square.clone()]).unwrap(),
+        DataValue::from(false)
+    );
+    assert_eq!(
+        op_st_distance(&[geom("POINT (7 8)"), square.clone()]).unwrap(),
+        DataValue::from(5.0)
+    );
+    assert_eq!(
+        op_st_distance(&[geom("POINT (2 2)"), square.clone()]).unwrap(),
+        DataValue::from(0.0)
+    );
+    // one degree of longitude at the equator, and half of that at 60 degrees north
+    let d = op_st_distance_sphere(&[geom("POINT (0 0)"), geom("POINT (1 0)")])
+        .unwrap()
+        .get_float()
+        .unwrap();
+    assert!((d - 111_195.).abs() < 1.);
+    let d = op_st_distance_sphere(&[geom("POINT (1 60)"), geom("LINESTRING (0 0, 0 80)")])
+        .unwrap()
+        .get_float()
+        .unwrap();
+    assert!((d - 55_596.).abs() < 100.);
+    assert!(op_st_distance_sphere(&[square.clone(), square.clone()]).is_err());
+    assert_eq!(
+        op_st_bbox(&[geom("LINESTRING (3 -1, 0 2)")]).unwrap(),
+        DataValue::List(vec![
+            DataValue::from(0.0),
+            DataValue::from(-1.0),
+            DataValue::from(3.0),
+            DataValue::from(2.0)
+        ])
+    );
+    assert_eq!(op_is_geometry(&[square]).unwrap(), DataValue::from(true));
+    assert_eq!(
+        op_is_geometry(&[DataValue::from("POINT (1 2)")]).unwrap(),
+        DataValue::from(false)
+    );
+}
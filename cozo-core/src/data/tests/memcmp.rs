@@ -7,8 +7,13 @@
  *
  */
 
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
 use uuid::Uuid;
 
+use crate::data::functions::{op_eq, op_lt};
+use crate::data::geo::Geometry;
 use crate::data::memcmp::{decode_bytes, MemCmpEncoder};
 use crate::data::value::{DataValue, Num, UuidWrapper};
 
@@ -135,3 +140,207 @@ fn encode_decode_datavalues() {
     assert!(remaining.is_empty());
     assert_eq!(decoded, v);
 }
+
+#[test]
+fn encode_decode_temporal() {
+    let mut values = vec![];
+    for v in [
+        i64::MIN,
+        -86_400_000_000,
+        -1,
+        0,
+        1,
+        1_700_000_000_000_000,
+        i64::MAX,
+    ] {
+        values.push(DataValue::Timestamp(v));
+        values.push(DataValue::Duration(v));
+    }
+    for d in [i32::MIN, -1, 0, 1, 19_000, i32::MAX] {
+        values.push(DataValue::Date(d));
+    }
+    let mut encoded = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        encoded.push((encoder, v.clone()));
+    }
+    let mut by_bytes = encoded.clone();
+    by_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut by_value = encoded;
+    by_value.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(by_bytes, by_value);
+}
+
+#[test]
+fn encode_decode_decimal() {
+    let mut values = vec![];
+    for s in [
+        "-12345678901234567890.5",
+        "-100",
+        "-99.99",
+        "-1.5",
+        "-1",
+        "-0.001",
+        "0",
+        "0.0000001",
+        "0.1",
+        "0.11",
+        "1",
+        "1.0000000000000000000001",
+        "9.9",
+        "10",
+        "100.25",
+        "12345678901234567890.5",
+    ] {
+        values.push(DataValue::Decimal(BigDecimal::from_str(s).unwrap()));
+    }
+    let mut encoded = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        encoded.push((encoder, v.clone()));
+    }
+    let mut by_bytes = encoded.clone();
+    by_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(by_bytes, encoded);
+
+    for v in [
+        DataValue::from(-100),
+        DataValue::from(-1.5),
+        DataValue::from(0),
+        DataValue::from(0.1),
+        DataValue::from(1),
+        DataValue::from(1.1),
+        DataValue::from(10),
+        DataValue::from(i64::MAX),
+    ] {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(&v);
+        encoded.push((encoder, v));
+    }
+    let mut by_bytes = encoded.clone();
+    by_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut by_value = encoded;
+    by_value.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(by_bytes, by_value);
+
+    let mut with_trailing = vec![];
+    with_trailing.encode_datavalue(&DataValue::Decimal(BigDecimal::from_str("1.500").unwrap()));
+    let mut normalized = vec![];
+    normalized.encode_datavalue(&DataValue::Decimal(BigDecimal::from_str("1.5").unwrap()));
+    assert_eq!(with_trailing, normalized);
+}
+
+#[test]
+fn mixed_decimal_order_matches_predicates() {
+    let dec = |s: &str| DataValue::Decimal(BigDecimal::from_str(s).unwrap());
+    let values = vec![
+        DataValue::from(0.1),
+        dec("0.1"),
+        DataValue::from(1),
+        dec("0.99999999999999999999"),
+        dec("1"),
+        dec("1.00000000000000000001"),
+        DataValue::from(1.0),
+        DataValue::from(9007199254740991i64),
+        dec("9007199254740990.5"),
+        dec("9007199254740991"),
+        dec("9007199254740991.5"),
+        DataValue::from(9007199254740992i64),
+        DataValue::from(9007199254740993i64),
+        dec("9007199254740992.5"),
+        dec("9007199254740993"),
+        DataValue::from(9007199254740994.0),
+        DataValue::from(9007199254740995i64),
+        dec("9007199254740994.5"),
+        dec("-9007199254740992.5"),
+        DataValue::from(-9007199254740993i64),
+        dec("1e400"),
+        DataValue::from(f64::INFINITY),
+        dec("-1e400"),
+        DataValue::from(f64::NEG_INFINITY),
+    ];
+    let mut encoded = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        encoded.push((encoder, v.clone()));
+    }
+    let mut by_bytes = encoded.clone();
+    by_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut by_value = encoded;
+    by_value.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(by_bytes, by_value);
+
+    // apart from ties between equal numbers, predicates agree with the sort order
+    for (i, a) in values.iter().enumerate() {
+        for b in &values[i + 1..] {
+            let args = [a.clone(), b.clone()];
+            if op_eq(&args).unwrap() == DataValue::from(true) {
+                continue;
+            }
+            assert_eq!(
+                op_lt(&args).unwrap(),
+                DataValue::from(a < b),
+                "{a:?} < {b:?}"
+            );
+        }
+    }
+
+    // exact below 2^53
+    assert!(DataValue::from(1) > dec("0.99999999999999999999"));
+    assert!(DataValue::from(9007199254740991i64) < dec("9007199254740991.5"));
+    assert!(dec("0.1") < DataValue::from(0.1));
+    assert_eq!(
+        op_eq(&[
+            dec("9007199254740993"),
+            DataValue::from(9007199254740993i64)
+        ])
+        .unwrap(),
+        DataValue::from(true)
+    );
+}
+
+#[test]
+fn encode_decode_geometry() {
+    let mut values = vec![];
+    for s in [
+        "POINT (-1 5)",
+        "POINT (0 0)",
+        "POINT (0 0.5)",
+        "POINT (3 -2)",
+        "LINESTRING (0 0, 1 1)",
+        "LINESTRING (0 0, 1 1, 2 0)",
+        "LINESTRING (0 1, 0 0)",
+        "POLYGON ((0 0, 1 0, 1 1, 0 0))",
+        "POLYGON ((0 0, 4 0, 4 4, 0 4, 0 0), (1 1, 2 1, 2 2, 1 1))",
+        "POLYGON ((0 0, 4 0, 4 4, 0 4, 0 0), (1 1, 3 1, 3 3, 1 1))",
+    ] {
+        values.push(DataValue::Geometry(Geometry::from_wkt(s).unwrap()));
+    }
+    let mut encoded = vec![];
+    for v in &values {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert_eq!(&decoded, v);
+        assert!(remaining.is_empty());
+        encoded.push((encoder, v.clone()));
+    }
+    let mut by_bytes = encoded.clone();
+    by_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(by_bytes, encoded);
+    let mut by_value = encoded;
+    by_value.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(by_bytes, by_value);
+}
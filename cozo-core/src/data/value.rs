@@ -8,13 +8,18 @@
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::BigDecimal;
 use ndarray::Array1;
 use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::str::FromStr;
 
+use crate::data::functions::{format_date_days, format_duration_micros, format_timestamp_micros};
+use crate::data::geo::Geometry;
 use crate::data::json::JsonValue;
 use crate::data::relation::VecElementType;
 use ordered_float::OrderedFloat;
@@ -131,9 +136,7 @@ pub struct Validity {
 }
 
 /// A Value in the database
-#[derive(
-    Clone, PartialEq, Eq, PartialOrd, Ord, serde_derive::Deserialize, serde_derive::Serialize, Hash,
-)]
+#[derive(Clone, PartialEq, Eq, serde_derive::Deserialize, serde_derive::Serialize, Hash)]
 pub enum DataValue {
     /// null
     Null,
@@ -160,6 +163,16 @@ pub enum DataValue {
     Json(JsonData),
     /// validity,
     Validity(Validity),
+    /// timestamp, in microseconds since the UNIX epoch
+    Timestamp(i64),
+    /// calendar date, in days since 1970-01-01
+    Date(i32),
+    /// duration, in microseconds
+    Duration(i64),
+    /// exact decimal number with arbitrary precision, sorted together with `Num`
+    Decimal(BigDecimal),
+    /// geometry: point, line string or polygon
+    Geometry(Geometry),
     /// bottom type, used internally only
     Bot,
 }
@@ -567,6 +580,131 @@ impl Ord for Num {
     }
 }
 
+impl DataValue {
+    /// Position of the variant in the sort order. Decimals share the rank of numbers.
+    fn sort_rank(&self) -> u8 {
+        match self {
+            DataValue::Null => 0,
+            DataValue::Bool(_) => 1,
+            DataValue::Num(_) | DataValue::Decimal(_) => 2,
+            DataValue::Str(_) => 3,
+            DataValue::Bytes(_) => 4,
+            DataValue::Uuid(_) => 5,
+            DataValue::Regex(_) => 6,
+            DataValue::List(_) => 7,
+            DataValue::Set(_) => 8,
+            DataValue::Vec(_) => 9,
+            DataValue::Json(_) => 10,
+            DataValue::Validity(_) => 11,
+            DataValue::Timestamp(_) => 12,
+            DataValue::Date(_) => 13,
+            DataValue::Duration(_) => 14,
+            DataValue::Geometry(_) => 15,
+            DataValue::Bot => 16,
+        }
+    }
+}
+
+impl PartialOrd for DataValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DataValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (DataValue::Null, DataValue::Null) | (DataValue::Bot, DataValue::Bot) => {
+                Ordering::Equal
+            }
+            (DataValue::Bool(l), DataValue::Bool(r)) => l.cmp(r),
+            (DataValue::Num(l), DataValue::Num(r)) => l.cmp(r),
+            (DataValue::Decimal(l), DataValue::Decimal(r)) => l.cmp(r),
+            (DataValue::Num(l), DataValue::Decimal(r)) => cmp_num_decimal(*l, r),
+            (DataValue::Decimal(l), DataValue::Num(r)) => cmp_num_decimal(*r, l).reverse(),
+            (DataValue::Str(l), DataValue::Str(r)) => l.cmp(r),
+            (DataValue::Bytes(l), DataValue::Bytes(r)) => l.cmp(r),
+            (DataValue::Uuid(l), DataValue::Uuid(r)) => l.cmp(r),
+            (DataValue::Regex(l), DataValue::Regex(r)) => l.cmp(r),
+            (DataValue::List(l), DataValue::List(r)) => l.cmp(r),
+            (DataValue::Set(l), DataValue::Set(r)) => l.cmp(r),
+            (DataValue::Vec(l), DataValue::Vec(r)) => l.cmp(r),
+            (DataValue::Json(l), DataValue::Json(r)) => l.cmp(r),
+            (DataValue::Validity(l), DataValue::Validity(r)) => l.cmp(r),
+            (DataValue::Timestamp(l), DataValue::Timestamp(r)) => l.cmp(r),
+            (DataValue::Date(l), DataValue::Date(r)) => l.cmp(r),
+            (DataValue::Duration(l), DataValue::Duration(r)) => l.cmp(r),
+            (DataValue::Geometry(l), DataValue::Geometry(r)) => l.cmp(r),
+            (l, r) => l.sort_rank().cmp(&r.sort_rank()),
+        }
+    }
+}
+
+/// Converts a decimal to the nearest float. Unlike `ToPrimitive::to_f64`, this
+/// rounds correctly, so it never reverses the order of two decimals.
+pub(crate) fn decimal_to_f64(d: &BigDecimal) -> f64 {
+    f64::from_str(&d.to_string()).unwrap_or(f64::NAN)
+}
+
+/// Converts a finite float to the decimal it represents exactly.
+fn f64_to_decimal_exact(f: f64) -> BigDecimal {
+    let bits = f.to_bits();
+    let biased_exp = ((bits >> 52) & 0x7ff) as i64;
+    let frac = bits & 0xf_ffff_ffff_ffff;
+    let (mantissa, exp) = if biased_exp == 0 {
+        (frac, -1074)
+    } else {
+        (frac | (1 << 52), biased_exp - 1075)
+    };
+    let mut mantissa = BigInt::from(mantissa);
+    if f.is_sign_negative() {
+        mantissa = -mantissa;
+    }
+    if exp >= 0 {
+        BigDecimal::new(mantissa << exp as usize, 0)
+    } else {
+        BigDecimal::new(mantissa * BigInt::from(5).pow(-exp as u32), -exp)
+    }
+}
+
+/// Returns the largest float not greater than the decimal, and whether it is
+/// equal to the decimal. Decimals are sorted among numbers by this float.
+pub(crate) fn decimal_floor_f64(d: &BigDecimal) -> (f64, bool) {
+    let approx = decimal_to_f64(d);
+    if approx == f64::INFINITY {
+        return (f64::MAX, false);
+    }
+    if approx == f64::NEG_INFINITY {
+        return (f64::NEG_INFINITY, false);
+    }
+    match d.cmp(&f64_to_decimal_exact(approx)) {
+        Ordering::Equal => (approx, true),
+        Ordering::Greater => (approx, false),
+        Ordering::Less => (approx.next_down(), false),
+    }
+}
+
+/// Orders a number against a decimal the same way the memcmp encoding does:
+/// by the float each is sorted by first, and on ties integers come first, then
+/// decimals equal to that float, then floats, then decimals strictly above it.
+/// This is exact against floats and against integers below 2^53 in magnitude;
+/// larger integers are sorted by their float approximation, as they are
+/// against floats.
+pub(crate) fn cmp_num_decimal(n: Num, d: &BigDecimal) -> Ordering {
+    let (floor, exact) = decimal_floor_f64(d);
+    match n {
+        Num::Int(i) => match (i as f64).total_cmp(&floor) {
+            Ordering::Equal => Ordering::Less,
+            o => o,
+        },
+        Num::Float(f) => match f.total_cmp(&floor) {
+            Ordering::Equal if exact => Ordering::Greater,
+            Ordering::Equal => Ordering::Less,
+            o => o,
+        },
+    }
+}
+
 impl Debug for DataValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{self}")
@@ -614,6 +752,17 @@ impl Display for DataValue {
                     write!(f, "json({})", j.0)
                 }
             }
+            DataValue::Timestamp(ts) => match format_timestamp_micros(*ts) {
+                Some(s) => write!(f, "timestamp({s:?})"),
+                None => write!(f, "timestamp({ts})"),
+            },
+            DataValue::Date(d) => match format_date_days(*d) {
+                Some(s) => write!(f, "date({s:?})"),
+                None => write!(f, "date({d})"),
+            },
+            DataValue::Duration(d) => write!(f, "duration({:?})", format_duration_micros(*d)),
+            DataValue::Decimal(d) => write!(f, "decimal(\"{d}\")"),
+            DataValue::Geometry(g) => write!(f, "geometry({:?})", g.to_string()),
         }
     }
 }
@@ -659,6 +808,7 @@ impl DataValue {
     pub fn get_float(&self) -> Option<f64> {
         match self {
             DataValue::Num(n) => Some(n.get_float()),
+            DataValue::Decimal(d) => Some(decimal_to_f64(d)),
             _ => None,
         }
     }
@@ -672,6 +822,35 @@ impl DataValue {
     pub(crate) fn uuid(uuid: Uuid) -> Self {
         Self::Uuid(UuidWrapper(uuid))
     }
+    /// Returns the timestamp in microseconds if this one is a Timestamp
+    pub fn get_timestamp(&self) -> Option<i64> {
+        match self {
+            DataValue::Timestamp(ts) => Some(*ts),
+            _ => None,
+        }
+    }
+    /// Returns the duration in microseconds if this one is a Duration
+    pub fn get_duration(&self) -> Option<i64> {
+        match self {
+            DataValue::Duration(d) => Some(*d),
+            _ => None,
+        }
+    }
+    /// Returns an exact decimal if this one is a Decimal or an Int
+    pub fn get_decimal(&self) -> Option<BigDecimal> {
+        match self {
+            DataValue::Decimal(d) => Some(d.clone()),
+            DataValue::Num(Num::Int(i)) => Some(BigDecimal::from(*i)),
+            _ => None,
+        }
+    }
+    /// Returns the geometry if this one is a Geometry
+    pub fn get_geometry(&self) -> Option<&Geometry> {
+        match self {
+            DataValue::Geometry(g) => Some(g),
+            _ => None,
+        }
+    }
     pub(crate) fn get_uuid(&self) -> Option<Uuid> {
         match self {
             DataValue::Uuid(UuidWrapper(uuid)) => Some(*uuid),
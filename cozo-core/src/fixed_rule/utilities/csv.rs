@@ -13,7 +13,10 @@ use miette::{bail, ensure, IntoDiagnostic, Result};
 use smartstring::{LazyCompact, SmartString};
 
 use crate::data::expr::Expr;
-use crate::data::functions::{op_to_float, op_to_uuid, TERMINAL_VALIDITY};
+use crate::data::functions::{
+    op_date, op_decimal, op_duration, op_geometry, op_timestamp, op_to_float, op_to_uuid,
+    TERMINAL_VALIDITY,
+};
 use crate::data::program::{FixedRuleOptionNotFoundError, WrongFixedRuleOptionError};
 use crate::data::relation::{ColType, NullableColType};
 use crate::data::symb::Symbol;
@@ -138,6 +141,29 @@ impl FixedRule for CsvReader {
                                     Some(i) => out_tuple.push(DataValue::from(i)),
                                 };
                             }
+                            ColType::Timestamp
+                            | ColType::Date
+                            | ColType::Duration
+                            | ColType::Decimal
+                            | ColType::Geometry => {
+                                let converted = match &typ.coltype {
+                                    ColType::Timestamp => op_timestamp(&[dv]),
+                                    ColType::Date => op_date(&[dv]),
+                                    ColType::Decimal => op_decimal(&[dv]),
+                                    ColType::Geometry => op_geometry(&[dv]),
+                                    _ => op_duration(&[dv]),
+                                };
+                                out_tuple.push(match converted {
+                                    Ok(data) => data,
+                                    Err(err) => {
+                                        if typ.nullable {
+                                            DataValue::Null
+                                        } else {
+                                            bail!(err)
+                                        }
+                                    }
+                                })
+                            }
                             _ => bail!("cannot convert {} to type {}", s, typ),
                         }
                     }
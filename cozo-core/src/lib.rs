@@ -30,6 +30,10 @@
 #![warn(missing_docs)]
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_arguments)]
+// Tuples used as map keys may hold a regex, whose match cache is interior
+// mutable; `RegexWrapper` hashes and compares by the pattern alone, so keys
+// never change under a map.
+#![allow(clippy::mutable_key_type)]
 
 use std::collections::BTreeMap;
 use std::path::Path;
@@ -53,6 +57,7 @@ pub use fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRulePayload};
 pub use runtime::db::Db;
 pub use runtime::db::NamedRows;
 pub use runtime::relation::decode_tuple_from_kv;
+pub use runtime::session::Session;
 pub use runtime::temp_store::RegularTempStore;
 pub use storage::mem::{new_cozo_mem, MemStorage};
 #[cfg(feature = "storage-rocksdb")]
@@ -66,12 +71,15 @@ pub use storage::tikv::{new_cozo_tikv, TiKvStorage};
 pub use storage::{Storage, StoreTx};
 
 pub use crate::data::expr::Expr;
+pub use crate::data::geo::Geometry;
 use crate::data::json::JsonValue;
 pub use crate::data::symb::Symbol;
 pub use crate::data::value::{Vector, JsonData};
 pub use crate::fixed_rule::SimpleFixedRule;
 pub use crate::parse::SourceSpan;
 pub use crate::runtime::callback::CallbackOp;
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::runtime::subscription::QueryDelta;
 pub use crate::runtime::db::Poison;
 pub use crate::runtime::db::TransactionPayload;
 
@@ -179,6 +187,39 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.run_script(payload, params),
         }
     }
+    /// Dispatcher method. See [crate::Db::run_script_in_session].
+    pub fn run_script_in_session(
+        &self,
+        session: &Session,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_in_session(session, payload, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_in_session(session, payload, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_in_session(session, payload, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_in_session(session, payload, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_in_session(session, payload, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::call_procedure].
+    pub fn call_procedure(&self, name: &str, args: Vec<DataValue>) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.call_procedure(name, args),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.call_procedure(name, args),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.call_procedure(name, args),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.call_procedure(name, args),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.call_procedure(name, args),
+        }
+    }
     /// Run the CozoScript passed in. The `params` argument is a map of parameters.
     /// Fold any error into the return JSON itself.
     /// See [crate::Db::run_script].
@@ -425,6 +466,113 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.unregister_callback(id),
         }
     }
+
+    /// Dispatcher method. See [crate::Db::subscribe].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subscribe(
+        &self,
+        query: &str,
+        params: BTreeMap<String, DataValue>,
+        capacity: Option<usize>,
+    ) -> Result<(u32, NamedRows, Receiver<QueryDelta>)> {
+        match self {
+            DbInstance::Mem(db) => db.subscribe(query, params, capacity),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.subscribe(query, params, capacity),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.subscribe(query, params, capacity),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.subscribe(query, params, capacity),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.subscribe(query, params, capacity),
+        }
+    }
+
+    /// Dispatcher method. See [crate::Db::unsubscribe].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        match self {
+            DbInstance::Mem(db) => db.unsubscribe(id),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unsubscribe(id),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unsubscribe(id),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unsubscribe(id),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unsubscribe(id),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_max_memory].
+    pub fn set_max_memory(&self, bytes: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_max_memory(bytes),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_max_memory(bytes),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_max_memory(bytes),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_max_memory(bytes),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_max_memory(bytes),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_temp_spill_threshold].
+    pub fn set_temp_spill_threshold(&self, tuples: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_temp_spill_threshold(tuples),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_temp_spill_threshold(tuples),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_temp_spill_threshold(tuples),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_temp_spill_threshold(tuples),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_temp_spill_threshold(tuples),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_max_iterations].
+    pub fn set_max_iterations(&self, iterations: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_max_iterations(iterations),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_max_iterations(iterations),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_max_iterations(iterations),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_max_iterations(iterations),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_max_iterations(iterations),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_result_cache_capacity].
+    pub fn set_result_cache_capacity(&self, results: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_result_cache_capacity(results),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_result_cache_capacity(results),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_result_cache_capacity(results),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_result_cache_capacity(results),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_result_cache_capacity(results),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_conflict_retries].
+    pub fn set_conflict_retries(&self, retries: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_conflict_retries(retries),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_conflict_retries(retries),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_conflict_retries(retries),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_conflict_retries(retries),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_conflict_retries(retries),
+        }
+    }
     /// Dispatcher method. See [crate::Db::register_fixed_rule].
     pub fn register_fixed_rule<R>(&self, name: String, rule_impl: R) -> Result<()>
     where
@@ -537,6 +685,29 @@ impl MultiTransaction {
             Err(err) => bail!(err),
         }
     }
+    /// Marks a savepoint of the given name in the multi-transaction.
+    /// A savepoint with the same name as an earlier one shadows it.
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        self.run_savepoint_op(TransactionPayload::Savepoint(name.to_string()))
+    }
+    /// Discards the changes made since the savepoint of the given name,
+    /// and removes the savepoints after it. The savepoint itself is kept.
+    pub fn rollback_to(&self, name: &str) -> Result<()> {
+        self.run_savepoint_op(TransactionPayload::RollbackTo(name.to_string()))
+    }
+    /// Removes the savepoint of the given name and those after it, keeping the changes.
+    pub fn release(&self, name: &str) -> Result<()> {
+        self.run_savepoint_op(TransactionPayload::Release(name.to_string()))
+    }
+    fn run_savepoint_op(&self, payload: TransactionPayload) -> Result<()> {
+        if let Err(err) = self.sender.send(payload) {
+            bail!(err);
+        }
+        match self.receiver.recv() {
+            Ok(r) => r.map(|_| ()),
+            Err(err) => bail!(err),
+        }
+    }
 }
 
 /// Convert error raised by the database into friendly JSON format
@@ -15,7 +15,9 @@ use pest::pratt_parser::{Op, PrattParser};
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::{get_op, Bytecode, Expr, NoImplementationError};
+use crate::data::expr::{
+    get_higher_order_op, get_op, Bytecode, Expr, Lambda, NoImplementationError,
+};
 use crate::data::functions::{
     OP_ADD, OP_AND, OP_COALESCE, OP_CONCAT, OP_DIV, OP_EQ, OP_GE, OP_GT, OP_JSON_OBJECT, OP_LE,
     OP_LIST, OP_LT, OP_MINUS, OP_MOD, OP_MUL, OP_NEGATE, OP_NEQ, OP_OR, OP_POW, OP_SUB,
@@ -105,6 +107,24 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) -> Resul
                 }
             }
         }
+        Expr::HigherOrderApply {
+            op,
+            args,
+            lambda,
+            span,
+        } => {
+            let arity = args.len();
+            for arg in args.iter() {
+                expr2bytecode(arg, collector)?;
+            }
+            collector.push(Bytecode::ApplyLambda {
+                op,
+                arity,
+                lambda: lambda.body.compile()?,
+                lambda_base: lambda.base,
+                span: *span,
+            })
+        }
         Expr::UnboundApply { op, span, .. } => {
             bail!(NoImplementationError(*span, op.to_string()));
         }
@@ -295,17 +315,62 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
             let mut p = pair.into_inner();
             let ident_p = p.next().unwrap();
             let ident = ident_p.as_str();
-            let mut args: Vec<_> = p
-                .next()
-                .unwrap()
-                .into_inner()
-                .map(|v| build_expr(v, param_pool))
-                .try_collect()?;
+            #[derive(Error, Diagnostic, Debug)]
+            #[error("A lambda can only appear as the last argument of a higher-order function")]
+            #[diagnostic(code(parser::misplaced_lambda))]
+            #[diagnostic(help(
+                "The higher-order functions are 'map', 'filter', 'reduce' and 'sort_by'"
+            ))]
+            struct MisplacedLambdaError(#[label] SourceSpan);
+
+            let mut args = vec![];
+            let mut lambda: Option<Lambda> = None;
+            for arg_p in p.next().unwrap().into_inner() {
+                if let Some(l) = &lambda {
+                    bail!(MisplacedLambdaError(l.span))
+                }
+                match arg_p.as_rule() {
+                    Rule::lambda => lambda = Some(build_lambda(arg_p, param_pool)?),
+                    _ => args.push(build_expr(arg_p, param_pool)?),
+                }
+            }
             #[derive(Error, Diagnostic, Debug)]
             #[error("Named function '{0}' not found")]
             #[diagnostic(code(parser::func_not_function))]
             struct FuncNotFoundError(String, #[label] SourceSpan);
 
+            if let Some(op) = get_higher_order_op(ident) {
+                #[derive(Error, Diagnostic, Debug)]
+                #[error("Wrong arguments for higher-order function '{0}'")]
+                #[diagnostic(code(parser::bad_higher_order_args))]
+                struct BadHigherOrderArgsError(String, #[label] SourceSpan, #[help] String);
+
+                let expected = || {
+                    BadHigherOrderArgsError(
+                        ident.to_string(),
+                        span,
+                        format!(
+                            "Need {} argument(s) followed by a lambda taking {} parameter(s)",
+                            op.arity, op.lambda_arity
+                        ),
+                    )
+                };
+                let lambda = lambda.ok_or_else(expected)?;
+                ensure!(
+                    args.len() == op.arity && lambda.params.len() == op.lambda_arity,
+                    expected()
+                );
+                return Ok(Expr::HigherOrderApply {
+                    op,
+                    args: args.into(),
+                    lambda: Box::new(lambda),
+                    span,
+                });
+            }
+            if let Some(lambda) = lambda {
+                bail!(MisplacedLambdaError(lambda.span))
+            }
+
             match ident {
                 "cond" => {
                     if args.is_empty() {
@@ -421,6 +486,36 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
     })
 }
 
+fn build_lambda(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Lambda> {
+    let span = pair.extract_span();
+    let mut src = pair.into_inner();
+    let params = src
+        .next()
+        .unwrap()
+        .into_inner()
+        .map(|p| Symbol::new(p.as_str(), p.extract_span()))
+        .collect_vec();
+
+    #[derive(Error, Diagnostic, Debug)]
+    #[error("Duplicate parameter '{0}' in lambda")]
+    #[diagnostic(code(parser::dup_lambda_param))]
+    struct DuplicateLambdaParamError(String, #[label] SourceSpan);
+
+    for (i, param) in params.iter().enumerate() {
+        ensure!(
+            !params[..i].contains(param),
+            DuplicateLambdaParamError(param.name.to_string(), param.span)
+        );
+    }
+    let body = build_expr(src.next().unwrap(), param_pool)?;
+    Ok(Lambda {
+        params,
+        body,
+        base: 0,
+        span,
+    })
+}
+
 pub(crate) fn parse_int(s: &str, radix: u32) -> i64 {
     i64::from_str_radix(&s[2..].replace('_', ""), radix).unwrap()
 }
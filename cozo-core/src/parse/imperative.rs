@@ -7,17 +7,20 @@
  *
  */
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
 use either::{Left, Right};
 use itertools::Itertools;
 use miette::{Diagnostic, Result};
-use smartstring::SmartString;
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::parse::expr::build_expr;
 use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, ImperativeProgram, ImperativeStmt, Pair, Rule, SourceSpan};
+use crate::parse::{
+    DeferredBlock, ExtractSpan, ImperativeProgram, ImperativeStmt, Pair, Rule, SourceSpan,
+};
 use crate::{DataValue, FixedRule, ValidityTs};
 
 pub(crate) fn parse_imperative_block(
@@ -25,11 +28,30 @@ pub(crate) fn parse_imperative_block(
     param_pool: &BTreeMap<String, DataValue>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
+) -> Result<ImperativeProgram> {
+    parse_imperative_stmts(src.into_inner(), param_pool, fixed_rules, cur_vld)
+}
+
+fn parse_imperative_stmts<'a>(
+    src: impl Iterator<Item = Pair<'a>>,
+    param_pool: &BTreeMap<String, DataValue>,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    cur_vld: ValidityTs,
 ) -> Result<ImperativeProgram> {
     let mut collected = vec![];
 
-    for pair in src.into_inner() {
-        if pair.as_rule() == Rule::EOI {
+    let mut pairs = src.take_while(|pair| pair.as_rule() != Rule::EOI);
+    while let Some(pair) = pairs.next() {
+        if pair.as_rule() == Rule::let_stmt {
+            // everything after the binding may refer to it
+            let rest = pairs.collect_vec();
+            collected.push(parse_let_stmt(
+                pair,
+                rest,
+                param_pool,
+                fixed_rules,
+                cur_vld,
+            )?);
             break;
         }
         collected.push(parse_imperative_stmt(
@@ -43,6 +65,75 @@ pub(crate) fn parse_imperative_block(
     Ok(collected)
 }
 
+fn parse_let_stmt(
+    pair: Pair<'_>,
+    rest: Vec<Pair<'_>>,
+    param_pool: &BTreeMap<String, DataValue>,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    cur_vld: ValidityTs,
+) -> Result<ImperativeStmt> {
+    let span = pair.extract_span();
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().strip_prefix('$').unwrap();
+    let prog = parse_query(
+        inner.next().unwrap().into_inner(),
+        param_pool,
+        fixed_rules,
+        cur_vld,
+    )?;
+    let mut write_locks = BTreeSet::new();
+    let mut retry = None;
+    for p in &rest {
+        collect_write_locks(p.clone(), &mut write_locks);
+        collect_retry(p.clone(), param_pool, &mut retry);
+    }
+    Ok(ImperativeStmt::Let {
+        name: name.to_string(),
+        prog,
+        rest: DeferredBlock {
+            src: rest.iter().map(|p| p.as_str()).join("\n"),
+            param_pool: param_pool.clone(),
+            write_locks,
+            retry,
+        },
+        span,
+    })
+}
+
+fn collect_write_locks(pair: Pair<'_>, collector: &mut BTreeSet<SmartString<LazyCompact>>) {
+    if pair.as_rule() == Rule::relation_option {
+        let name = pair.into_inner().nth(1).unwrap().as_str();
+        if !name.starts_with('_') {
+            collector.insert(SmartString::from(name));
+        }
+    } else {
+        for p in pair.into_inner() {
+            collect_write_locks(p, collector);
+        }
+    }
+}
+
+/// Options depending on parameters only bound during execution are skipped
+fn collect_retry(
+    pair: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    retry: &mut Option<usize>,
+) {
+    if pair.as_rule() == Rule::retry_option {
+        let found = build_expr(pair.into_inner().next().unwrap(), param_pool)
+            .and_then(|expr| expr.eval_to_const())
+            .ok()
+            .and_then(|val| val.get_non_neg_int());
+        if let Some(n) = found {
+            *retry = (*retry).max(Some(n as usize));
+        }
+    } else {
+        for p in pair.into_inner() {
+            collect_retry(p, param_pool, retry);
+        }
+    }
+}
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("cannot manipulate permanent relation in imperative script")]
 #[diagnostic(code(parser::manipulate_perm_rel_in_script))]
@@ -108,18 +199,11 @@ fn parse_imperative_stmt(
                 )?),
                 _ => unreachable!(),
             };
-            let body = inner
-                .next()
-                .unwrap()
-                .into_inner()
-                .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
-                .try_collect()?;
+            let body =
+                parse_imperative_block(inner.next().unwrap(), param_pool, fixed_rules, cur_vld)?;
             let else_body = match inner.next() {
                 None => vec![],
-                Some(rest) => rest
-                    .into_inner()
-                    .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
-                    .try_collect()?,
+                Some(rest) => parse_imperative_block(rest, param_pool, fixed_rules, cur_vld)?,
             };
             ImperativeStmt::If {
                 condition: cond,
@@ -161,6 +245,27 @@ fn parse_imperative_stmt(
                 temp: SmartString::from(name),
             }
         }
+        Rule::for_block => {
+            let mut inner = pair.into_inner();
+            let row = SmartString::from(inner.next().unwrap().as_str());
+            let relation = SmartString::from(inner.next().unwrap().as_str());
+            let body = inner.next().unwrap();
+            let src = body.as_str().to_string();
+            let mut write_locks = BTreeSet::new();
+            collect_write_locks(body.clone(), &mut write_locks);
+            let mut retry = None;
+            collect_retry(body, param_pool, &mut retry);
+            ImperativeStmt::For {
+                row,
+                relation,
+                body: DeferredBlock {
+                    src,
+                    param_pool: param_pool.clone(),
+                    write_locks,
+                    retry,
+                },
+            }
+        }
         Rule::query_script_inner => {
             let prog = parse_query(pair.into_inner(), param_pool, fixed_rules, cur_vld)?;
             ImperativeStmt::Program { prog }
@@ -11,18 +11,19 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
-use either::{Either, Left};
+use either::{Either, Left, Right};
 use miette::{bail, Diagnostic, IntoDiagnostic, Result};
 use pest::error::InputLocation;
 use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::program::InputProgram;
+use crate::data::program::{InputInlineRule, InputProgram};
 use crate::data::relation::NullableColType;
+use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::imperative::parse_imperative_block;
-use crate::parse::query::parse_query;
+use crate::parse::query::{parse_query, parse_rule_definitions};
 use crate::parse::schema::parse_nullable_type;
 use crate::parse::sys::{parse_sys, SysOp};
 use crate::FixedRule;
@@ -41,6 +42,7 @@ pub(crate) struct CozoScriptParser;
 pub(crate) type Pair<'a> = pest::iterators::Pair<'a, Rule>;
 pub(crate) type Pairs<'a> = pest::iterators::Pairs<'a, Rule>;
 
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum CozoScript {
     Single(InputProgram),
     Imperative(ImperativeProgram),
@@ -84,6 +86,31 @@ pub(crate) enum ImperativeStmt {
     TempDebug {
         temp: SmartString<LazyCompact>,
     },
+    /// The body is kept as source, since the fields of each row become parameters
+    /// of the body, and parameters are substituted during parsing.
+    For {
+        row: SmartString<LazyCompact>,
+        relation: SmartString<LazyCompact>,
+        body: DeferredBlock,
+    },
+    /// The statements following the binding are kept as source, for the same reason.
+    Let {
+        name: String,
+        prog: InputProgram,
+        rest: DeferredBlock,
+        span: SourceSpan,
+    },
+}
+
+/// Statements parsed only when executed, once the parameters they refer to are known.
+#[derive(Debug)]
+pub(crate) struct DeferredBlock {
+    pub(crate) src: String,
+    pub(crate) param_pool: BTreeMap<String, DataValue>,
+    /// stored relations written by the statements, found from the syntax alone
+    pub(crate) write_locks: BTreeSet<SmartString<LazyCompact>>,
+    /// the largest `:retry` option of the statements known before execution
+    pub(crate) retry: Option<usize>,
 }
 
 pub(crate) type ImperativeCondition = Either<SmartString<LazyCompact>, InputProgram>;
@@ -91,6 +118,77 @@ pub(crate) type ImperativeCondition = Either<SmartString<LazyCompact>, InputProg
 pub(crate) type ImperativeProgram = Vec<ImperativeStmt>;
 
 impl ImperativeStmt {
+    pub(crate) fn for_each_program_mut(&mut self, f: &mut impl FnMut(&mut InputProgram)) {
+        match self {
+            ImperativeStmt::Program { prog, .. }
+            | ImperativeStmt::IgnoreErrorProgram { prog, .. } => f(prog),
+            ImperativeStmt::Return { returns, .. } => {
+                for ret in returns {
+                    if let Left(prog) = ret {
+                        f(prog)
+                    }
+                }
+            }
+            ImperativeStmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                if let Right(prog) = condition {
+                    f(prog)
+                }
+                for stmt in then_branch.iter_mut().chain(else_branch.iter_mut()) {
+                    stmt.for_each_program_mut(f)
+                }
+            }
+            ImperativeStmt::Loop { body, .. } => {
+                for stmt in body {
+                    stmt.for_each_program_mut(f)
+                }
+            }
+            ImperativeStmt::Let { prog, .. } => f(prog),
+            ImperativeStmt::TempDebug { .. }
+            | ImperativeStmt::Break { .. }
+            | ImperativeStmt::Continue { .. }
+            | ImperativeStmt::TempSwap { .. }
+            | ImperativeStmt::For { .. } => {}
+        }
+    }
+    /// The largest `:retry` option of the queries of the statement, including nested ones
+    pub(crate) fn max_retry(&self) -> Option<usize> {
+        match self {
+            ImperativeStmt::Program { prog, .. }
+            | ImperativeStmt::IgnoreErrorProgram { prog, .. } => prog.out_opts.retry,
+            ImperativeStmt::Return { returns, .. } => returns
+                .iter()
+                .filter_map(|ret| ret.as_ref().left().and_then(|p| p.out_opts.retry))
+                .max(),
+            ImperativeStmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let cond_retry = match condition {
+                    ImperativeCondition::Right(prog) => prog.out_opts.retry,
+                    ImperativeCondition::Left(_) => None,
+                };
+                then_branch
+                    .iter()
+                    .chain(else_branch.iter())
+                    .map(|stmt| stmt.max_retry())
+                    .fold(cond_retry, Ord::max)
+            }
+            ImperativeStmt::Loop { body, .. } => body.iter().filter_map(|s| s.max_retry()).max(),
+            ImperativeStmt::For { body, .. } => body.retry,
+            ImperativeStmt::Let { prog, rest, .. } => prog.out_opts.retry.max(rest.retry),
+            ImperativeStmt::TempDebug { .. }
+            | ImperativeStmt::Break { .. }
+            | ImperativeStmt::Continue { .. }
+            | ImperativeStmt::TempSwap { .. } => None,
+        }
+    }
     pub(crate) fn needs_write_locks(&self, collector: &mut BTreeSet<SmartString<LazyCompact>>) {
         match self {
             ImperativeStmt::Program { prog, .. }
@@ -128,6 +226,15 @@ impl ImperativeStmt {
                     prog.needs_write_locks(collector);
                 }
             }
+            ImperativeStmt::For { body, .. } => {
+                collector.extend(body.write_locks.iter().cloned());
+            }
+            ImperativeStmt::Let { prog, rest, .. } => {
+                if let Some(name) = prog.needs_write_lock() {
+                    collector.insert(name);
+                }
+                collector.extend(rest.write_locks.iter().cloned());
+            }
             ImperativeStmt::TempDebug { .. }
             | ImperativeStmt::Break { .. }
             | ImperativeStmt::Continue { .. }
@@ -203,6 +310,18 @@ pub(crate) fn parse_type(src: &str) -> Result<NullableColType> {
     parse_nullable_type(parsed.into_inner().next().unwrap())
 }
 
+/// Parses the source of a stored rule, as saved by `::rules define`.
+pub(crate) fn parse_stored_rules(
+    src: &str,
+    cur_vld: ValidityTs,
+) -> Result<(Symbol, Vec<InputInlineRule>)> {
+    let parsed = CozoScriptParser::parse(Rule::stored_rules_script, src)
+        .into_diagnostic()?
+        .next()
+        .unwrap();
+    parse_rule_definitions(parsed.into_inner(), cur_vld)
+}
+
 pub(crate) fn parse_script(
     src: &str,
     param_pool: &BTreeMap<String, DataValue>,
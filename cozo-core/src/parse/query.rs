@@ -117,16 +117,6 @@ pub(crate) fn parse_query(
                         let key = e.key().to_string();
                         match e.get_mut() {
                             InputInlineRulesOrFixed::Rules { rules: rs } => {
-                                #[derive(Debug, Error, Diagnostic)]
-                                #[error("Rule {0} has multiple definitions with conflicting heads")]
-                                #[diagnostic(code(parser::head_aggr_mismatch))]
-                                #[diagnostic(help("The arity of each rule head must match. In addition, any aggregation \
-                            applied must be the same."))]
-                                struct RuleHeadMismatch(
-                                    String,
-                                    #[label] SourceSpan,
-                                    #[label] SourceSpan,
-                                );
                                 let prev = rs.first().unwrap();
                                 ensure!(prev.aggr == rule.aggr, {
                                     RuleHeadMismatch(
@@ -271,6 +261,36 @@ pub(crate) fn parse_query(
                     .ok_or(OptionNotNonNegIntError("limit", span))?;
                 out_opts.limit = Some(limit as usize);
             }
+            Rule::max_memory_option => {
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let max_memory = build_expr(pair, param_pool)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("max_memory", span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError("max_memory", span))?;
+                out_opts.max_memory = Some(max_memory as usize);
+            }
+            Rule::max_iterations_option => {
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let max_iterations = build_expr(pair, param_pool)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("max_iterations", span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError("max_iterations", span))?;
+                out_opts.max_iterations = Some(max_iterations as usize);
+            }
+            Rule::retry_option => {
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let retry = build_expr(pair, param_pool)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("retry", span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError("retry", span))?;
+                out_opts.retry = Some(retry as usize);
+            }
             Rule::offset_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
@@ -319,7 +339,8 @@ pub(crate) fn parse_query(
                 match args.next() {
                     None => stored_relation = Some(Left((name, span, op))),
                     Some(schema_p) => {
-                        let (metadata, key_bindings, dep_bindings) = parse_schema(schema_p)?;
+                        let (metadata, key_bindings, dep_bindings, constraints) =
+                            parse_schema(schema_p)?;
                         stored_relation = Some(Right((
                             InputRelationHandle {
                                 name,
@@ -327,12 +348,14 @@ pub(crate) fn parse_query(
                                 key_bindings,
                                 dep_bindings,
                                 span,
+                                constraints,
                             },
                             op,
                         )))
                     }
                 }
             }
+            Rule::well_founded_option => out_opts.well_founded = true,
             Rule::assert_none_option => {
                 ensure!(
                     out_opts.assertion.is_none(),
@@ -404,6 +427,7 @@ pub(crate) fn parse_query(
                 key_bindings: head,
                 dep_bindings: vec![],
                 span,
+                constraints: Default::default(),
             };
             prog.out_opts.store_relation = Some((handle, op))
         }
@@ -477,6 +501,64 @@ fn parse_rule(
     ))
 }
 
+/// Parses the clauses of a stored rule, which must all define the same rule.
+/// Stored rules are kept as source, so they cannot refer to parameters.
+pub(crate) fn parse_rule_definitions(
+    src: Pairs<'_>,
+    cur_vld: ValidityTs,
+) -> Result<(Symbol, Vec<InputInlineRule>)> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("All clauses of a stored rule must define the same rule, found {0} and {1}")]
+    #[diagnostic(code(parser::stored_rule_name_mismatch))]
+    struct StoredRuleNameMismatch(String, String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("The entry rule cannot be stored")]
+    #[diagnostic(code(parser::stored_entry_rule))]
+    struct StoredEntryRule(#[label] SourceSpan);
+
+    let mut name: Option<Symbol> = None;
+    let mut rules: Vec<InputInlineRule> = vec![];
+    for pair in src {
+        if pair.as_rule() != Rule::rule {
+            continue;
+        }
+        let (rule_name, rule) = parse_rule(pair, &Default::default(), cur_vld)?;
+        ensure!(!rule_name.is_prog_entry(), StoredEntryRule(rule_name.span));
+        match &name {
+            None => name = Some(rule_name),
+            Some(prev) => {
+                ensure!(
+                    prev.name == rule_name.name,
+                    StoredRuleNameMismatch(
+                        prev.name.to_string(),
+                        rule_name.name.to_string(),
+                        rule_name.span
+                    )
+                );
+                let first = rules.first().unwrap();
+                ensure!(
+                    first.head.len() == rule.head.len() && first.aggr == rule.aggr,
+                    RuleHeadMismatch(
+                        rule_name.name.to_string(),
+                        merge_spans(&first.head),
+                        merge_spans(&rule.head)
+                    )
+                );
+            }
+        }
+        rules.push(rule);
+    }
+    Ok((name.unwrap(), rules))
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Rule {0} has multiple definitions with conflicting heads")]
+#[diagnostic(code(parser::head_aggr_mismatch))]
+#[diagnostic(help("The arity of each rule head must match. In addition, any aggregation \
+applied must be the same."))]
+struct RuleHeadMismatch(String, #[label] SourceSpan, #[label] SourceSpan);
+
 fn parse_disjunction(
     pair: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
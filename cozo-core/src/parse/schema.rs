@@ -18,10 +18,11 @@ use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::expr::{build_expr};
 use crate::parse::{ExtractSpan, Pair, Rule, SourceSpan};
+use crate::runtime::relation::RelationConstraints;
 
 pub(crate) fn parse_schema(
     pair: Pair<'_>,
-) -> Result<(StoredRelationMetadata, Vec<Symbol>, Vec<Symbol>)> {
+) -> Result<(StoredRelationMetadata, Vec<Symbol>, Vec<Symbol>, RelationConstraints)> {
     // assert_eq!(pair.as_rule(), Rule::table_schema);
     let span = pair.extract_span();
 
@@ -31,14 +32,19 @@ pub(crate) fn parse_schema(
     let mut key_bindings = vec![];
     let mut dep_bindings = vec![];
     let mut seen_names = BTreeSet::new();
+    let mut constraints = RelationConstraints::default();
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("Column {0} is defined multiple times")]
     #[diagnostic(code(parser::dup_name_in_cols))]
     struct DuplicateNameInCols(String, #[label] SourceSpan);
     for p in src.next().unwrap().into_inner() {
+        if p.as_rule() == Rule::table_check {
+            parse_check(p, &mut constraints)?;
+            continue;
+        }
         let span = p.extract_span();
-        let (col, ident) = parse_col(p)?;
+        let (col, ident) = parse_col(p, &mut constraints)?;
         if !seen_names.insert(col.name.clone()) {
             bail!(DuplicateNameInCols(col.name.to_string(), span));
         }
@@ -47,8 +53,12 @@ pub(crate) fn parse_schema(
     }
     if let Some(ps) = src.next() {
         for p in ps.into_inner() {
+            if p.as_rule() == Rule::table_check {
+                parse_check(p, &mut constraints)?;
+                continue;
+            }
             let span = p.extract_span();
-            let (col, ident) = parse_col(p)?;
+            let (col, ident) = parse_col(p, &mut constraints)?;
             if !seen_names.insert(col.name.clone()) {
                 bail!(DuplicateNameInCols(col.name.to_string(), span));
             }
@@ -72,10 +82,11 @@ pub(crate) fn parse_schema(
         },
         key_bindings,
         dep_bindings,
+        constraints,
     ))
 }
 
-fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
+pub(crate) fn parse_col(pair: Pair<'_>, constraints: &mut RelationConstraints) -> Result<(ColumnDef, Symbol)> {
     let mut src = pair.into_inner();
     let name_p = src.next().unwrap();
     let name = SmartString::from(name_p.as_str());
@@ -92,6 +103,18 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
             Rule::out_arg => {
                 binding_candidate = Some(Symbol::new(nxt.as_str(), nxt.extract_span()))
             }
+            Rule::unique_constraint => {
+                if !constraints.unique.contains(&name) {
+                    constraints.unique.push(name.clone())
+                }
+            }
+            Rule::references_constraint => {
+                let target = nxt.into_inner().next().unwrap();
+                constraints
+                    .references
+                    .push((name.clone(), SmartString::from(target.as_str())))
+            }
+            Rule::check_constraint => parse_check(nxt, constraints)?,
             r => unreachable!("{:?}", r),
         }
     }
@@ -107,6 +130,14 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
     ))
 }
 
+fn parse_check(pair: Pair<'_>, constraints: &mut RelationConstraints) -> Result<()> {
+    let expr_p = pair.into_inner().nth(1).unwrap();
+    // only for validation, the source is kept
+    build_expr(expr_p.clone(), &Default::default())?;
+    constraints.checks.push(expr_p.as_str().to_string());
+    Ok(())
+}
+
 pub(crate) fn parse_nullable_type(pair: Pair<'_>) -> Result<NullableColType> {
     let nullable = pair.as_str().ends_with('?');
     let coltype = parse_type_inner(pair.into_inner().next().unwrap())?;
@@ -124,6 +155,11 @@ fn parse_type_inner(pair: Pair<'_>) -> Result<ColType> {
         Rule::uuid_type => ColType::Uuid,
         Rule::json_type => ColType::Json,
         Rule::validity_type => ColType::Validity,
+        Rule::timestamp_type => ColType::Timestamp,
+        Rule::date_type => ColType::Date,
+        Rule::duration_type => ColType::Duration,
+        Rule::decimal_type => ColType::Decimal,
+        Rule::geometry_type => ColType::Geometry,
         Rule::list_type => {
             let mut inner = pair.into_inner();
             let eltype = parse_nullable_type(inner.next().unwrap())?;
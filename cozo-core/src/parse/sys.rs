@@ -16,14 +16,16 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::program::InputProgram;
-use crate::data::relation::VecElementType;
+use crate::data::relation::{ColumnDef, NullableColType, VecElementType};
 use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fts::TokenizerConfig;
 use crate::parse::expr::{build_expr, parse_string};
-use crate::parse::query::parse_query;
+use crate::parse::imperative::parse_imperative_block;
+use crate::parse::query::{parse_query, parse_rule_definitions};
+use crate::parse::schema::{parse_col, parse_nullable_type};
 use crate::parse::{ExtractSpan, Pairs, Rule, SourceSpan};
-use crate::runtime::relation::AccessLevel;
+use crate::runtime::relation::{AccessLevel, RelationConstraints};
 use crate::{Expr, FixedRule};
 
 pub(crate) enum SysOp {
@@ -35,6 +37,7 @@ pub(crate) enum SysOp {
     ListFixedRules,
     KillRunning(u64),
     Explain(Box<InputProgram>),
+    Why(Box<InputProgram>, Vec<DataValue>),
     RemoveRelation(Vec<Symbol>),
     RenameRelation(Vec<(Symbol, Symbol)>),
     ShowTrigger(Symbol),
@@ -44,8 +47,31 @@ pub(crate) enum SysOp {
     CreateVectorIndex(HnswIndexConfig),
     CreateFtsIndex(FtsIndexConfig),
     CreateMinHashLshIndex(MinHashLshConfig),
+    CreateRTreeIndex(RTreeIndexConfig),
+    CreateExprIndex(ExprIndexConfig),
     RemoveIndex(Symbol, Symbol),
-    DescribeRelation(Symbol, SmartString<LazyCompact>)
+    DescribeRelation(Symbol, SmartString<LazyCompact>),
+    AlterRelation(Symbol, AlterRelationOp),
+    CreateView(Symbol, String),
+    DropView(Symbol),
+    DefineRule(Symbol, usize, String),
+    DropRule(Symbol),
+    DescribeRule(Symbol, SmartString<LazyCompact>),
+    ListRules,
+    CreateProcedure(Symbol, Vec<String>, String),
+    DropProcedure(Symbol),
+    ListProcedures,
+    CallProcedure(Symbol, Vec<DataValue>),
+}
+
+/// Schema changes of `::alter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AlterRelationOp {
+    /// a new non-key column, together with check constraints declared on it
+    AddColumn(ColumnDef, Vec<String>),
+    DropColumn(Symbol),
+    RenameColumn(Symbol, Symbol),
+    ChangeType(Symbol, NullableColType),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -71,6 +97,22 @@ pub(crate) struct MinHashLshConfig {
     pub(crate) target_threshold: OrderedFloat<f64>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ExprIndexConfig {
+    pub(crate) base_relation: Symbol,
+    pub(crate) index_name: Symbol,
+    pub(crate) elements: Vec<String>,
+    pub(crate) filter: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RTreeIndexConfig {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    pub(crate) extractor: String,
+    pub(crate) max_entries: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct HnswIndexConfig {
     pub(crate) base_relation: SmartString<LazyCompact>,
@@ -128,6 +170,29 @@ pub(crate) fn parse_sys(
             )?;
             SysOp::Explain(Box::new(prog))
         }
+        Rule::why_op => {
+            let mut src = inner.into_inner();
+            let prog = parse_query(
+                src.next().unwrap().into_inner(),
+                param_pool,
+                algorithms,
+                cur_vld,
+            )?;
+            let tuple_p = src.next().unwrap();
+            let span = tuple_p.extract_span();
+            let tuple = match build_expr(tuple_p, param_pool)?.eval_to_const()? {
+                DataValue::List(l) => l,
+                _ => {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("The tuple to explain must be given as a list")]
+                    #[diagnostic(code(parser::why_tuple_not_list))]
+                    struct WhyTupleNotList(#[label] SourceSpan);
+
+                    bail!(WhyTupleNotList(span))
+                }
+            };
+            SysOp::Why(Box::new(prog), tuple)
+        }
         Rule::describe_relation_op => {
             let mut inner = inner.into_inner();
             let rels_p = inner.next().unwrap();
@@ -204,12 +269,19 @@ pub(crate) fn parse_sys(
                 let op = clause_inner.next().unwrap();
                 let script = clause_inner.next().unwrap();
                 let script_str = script.as_str();
-                parse_query(
-                    script.into_inner(),
-                    &Default::default(),
-                    algorithms,
-                    cur_vld,
-                )?;
+                if script.as_rule() == Rule::trigger_call {
+                    // the trigger calls a stored procedure, which need not exist yet
+                    for arg in script.into_inner().next().unwrap().into_inner().skip(1) {
+                        build_expr(arg, &Default::default())?.eval_to_const()?;
+                    }
+                } else {
+                    parse_query(
+                        script.into_inner(),
+                        &Default::default(),
+                        algorithms,
+                        cur_vld,
+                    )?;
+                }
                 match op.as_rule() {
                     Rule::trigger_put => puts.push(script_str.to_string()),
                     Rule::trigger_rm => rms.push(script_str.to_string()),
@@ -510,6 +582,71 @@ pub(crate) fn parse_sys(
                 r => unreachable!("{:?}", r),
             }
         }
+        Rule::rtree_idx_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::index_create_adv => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    let mut extractor = "".to_string();
+                    let mut extract_filter = "".to_string();
+                    let mut max_entries = 16;
+                    for opt_pair in inner {
+                        let mut opt_inner = opt_pair.into_inner();
+                        let opt_name = opt_inner.next().unwrap();
+                        let opt_val = opt_inner.next().unwrap();
+                        let opt_val_str = opt_val.as_str();
+                        match opt_name.as_str() {
+                            "extractor" => {
+                                let mut ex = build_expr(opt_val, param_pool)?;
+                                ex.partial_eval()?;
+                                extractor = ex.to_string();
+                            }
+                            "extract_filter" => {
+                                let mut ex = build_expr(opt_val, param_pool)?;
+                                ex.partial_eval()?;
+                                extract_filter = ex.to_string();
+                            }
+                            "max_entries" => {
+                                let v = build_expr(opt_val, param_pool)?
+                                    .eval_to_const()?
+                                    .get_int()
+                                    .ok_or_else(|| {
+                                        miette!("Invalid max_entries: {}", opt_val_str)
+                                    })?;
+                                ensure!(v >= 4, "max_entries must be at least 4, got {}", v);
+                                max_entries = v as usize;
+                            }
+                            _ => bail!("Unknown option {} for R-tree index", opt_name.as_str()),
+                        }
+                    }
+                    ensure!(
+                        !extractor.is_empty(),
+                        "extractor must be set for R-tree index"
+                    );
+                    if !extract_filter.is_empty() {
+                        extractor = format!("if({}, {})", extract_filter, extractor);
+                    }
+                    SysOp::CreateRTreeIndex(RTreeIndexConfig {
+                        base_relation: SmartString::from(rel.as_str()),
+                        index_name: SmartString::from(name.as_str()),
+                        extractor,
+                        max_entries,
+                    })
+                }
+                Rule::index_drop => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    SysOp::RemoveIndex(
+                        Symbol::new(rel.as_str(), rel.extract_span()),
+                        Symbol::new(name.as_str(), name.extract_span()),
+                    )
+                }
+                r => unreachable!("{:?}", r),
+            }
+        }
         Rule::vec_idx_op => {
             let inner = inner.into_inner().next().unwrap();
             match inner.as_rule() {
@@ -639,22 +776,51 @@ pub(crate) fn parse_sys(
                     let span = inner.extract_span();
                     let mut inner = inner.into_inner();
                     let rel = inner.next().unwrap();
+                    let rel = Symbol::new(rel.as_str(), rel.extract_span());
                     let name = inner.next().unwrap();
-                    let cols = inner
-                        .map(|p| Symbol::new(p.as_str(), p.extract_span()))
-                        .collect_vec();
+                    let name = Symbol::new(name.as_str(), name.extract_span());
+                    let mut elements = vec![];
+                    let mut filter = None;
+                    for p in inner {
+                        // index expressions are stored as source, parameters cannot be used
+                        match p.as_rule() {
+                            Rule::index_filter => {
+                                let p = p.into_inner().next().unwrap();
+                                let src = p.as_str().to_string();
+                                build_expr(p, &Default::default())?;
+                                filter = Some(src);
+                            }
+                            _ => {
+                                let src = p.as_str().to_string();
+                                elements.push((build_expr(p, &Default::default())?, src));
+                            }
+                        }
+                    }
 
                     #[derive(Debug, Diagnostic, Error)]
                     #[error("index must have at least one column specified")]
                     #[diagnostic(code(parser::empty_index))]
                     struct EmptyIndex(#[label] SourceSpan);
 
-                    ensure!(!cols.is_empty(), EmptyIndex(span));
-                    SysOp::CreateIndex(
-                        Symbol::new(rel.as_str(), rel.extract_span()),
-                        Symbol::new(name.as_str(), name.extract_span()),
-                        cols,
-                    )
+                    ensure!(!elements.is_empty(), EmptyIndex(span));
+                    if filter.is_none()
+                        && elements
+                            .iter()
+                            .all(|(expr, _)| matches!(expr, Expr::Binding { .. }))
+                    {
+                        let cols = elements
+                            .into_iter()
+                            .map(|(expr, _)| expr.get_binding().unwrap().clone())
+                            .collect_vec();
+                        SysOp::CreateIndex(rel, name, cols)
+                    } else {
+                        SysOp::CreateExprIndex(ExprIndexConfig {
+                            base_relation: rel,
+                            index_name: name,
+                            elements: elements.into_iter().map(|(_, src)| src).collect_vec(),
+                            filter,
+                        })
+                    }
                 }
                 Rule::index_drop => {
                     let mut inner = inner.into_inner();
@@ -669,6 +835,169 @@ pub(crate) fn parse_sys(
             }
         }
         Rule::list_fixed_rules => SysOp::ListFixedRules,
+        Rule::alter_relation_op => {
+            let mut inner = inner.into_inner();
+            let rel_p = inner.next().unwrap();
+            let rel = Symbol::new(rel_p.as_str(), rel_p.extract_span());
+            let op_p = inner.next().unwrap();
+            let op = match op_p.as_rule() {
+                Rule::alter_add => {
+                    let col_p = op_p.into_inner().next().unwrap();
+                    let span = col_p.extract_span();
+                    let mut constraints = RelationConstraints::default();
+                    let (col, _) = parse_col(col_p, &mut constraints)?;
+                    if !constraints.unique.is_empty() || !constraints.references.is_empty() {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("unique and foreign key constraints cannot be added to existing relations")]
+                        #[diagnostic(code(parser::constraint_in_alter))]
+                        struct ConstraintInAlter(#[label] SourceSpan);
+                        bail!(ConstraintInAlter(span))
+                    }
+                    AlterRelationOp::AddColumn(col, constraints.checks)
+                }
+                Rule::alter_drop => {
+                    let col_p = op_p.into_inner().next().unwrap();
+                    AlterRelationOp::DropColumn(Symbol::new(col_p.as_str(), col_p.extract_span()))
+                }
+                Rule::alter_rename => {
+                    let mut src = op_p.into_inner();
+                    let old_p = src.next().unwrap();
+                    let new_p = src.next().unwrap();
+                    AlterRelationOp::RenameColumn(
+                        Symbol::new(old_p.as_str(), old_p.extract_span()),
+                        Symbol::new(new_p.as_str(), new_p.extract_span()),
+                    )
+                }
+                Rule::alter_change => {
+                    let mut src = op_p.into_inner();
+                    let col_p = src.next().unwrap();
+                    let typing = parse_nullable_type(src.next().unwrap())?;
+                    AlterRelationOp::ChangeType(
+                        Symbol::new(col_p.as_str(), col_p.extract_span()),
+                        typing,
+                    )
+                }
+                r => unreachable!("{:?}", r),
+            };
+            SysOp::AlterRelation(rel, op)
+        }
+        Rule::view_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::view_create => {
+                    let mut src = inner.into_inner();
+                    let name_p = src.next().unwrap();
+                    let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                    let script = src.next().unwrap();
+                    let script_str = script.as_str();
+                    // the query is stored as source and re-run on changes, so no parameters
+                    let prog = parse_query(
+                        script.into_inner(),
+                        &Default::default(),
+                        algorithms,
+                        cur_vld,
+                    )?;
+                    if prog.out_opts.store_relation.is_some() {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("the query defining view {0} cannot write to stored relations")]
+                        #[diagnostic(code(parser::view_with_mutation))]
+                        struct ViewWithMutation(String, #[label] SourceSpan);
+                        bail!(ViewWithMutation(name.name.to_string(), name.span))
+                    }
+                    SysOp::CreateView(name, script_str.to_string())
+                }
+                Rule::view_drop => {
+                    let name_p = inner.into_inner().next().unwrap();
+                    SysOp::DropView(Symbol::new(name_p.as_str(), name_p.extract_span()))
+                }
+                r => unreachable!("{:?}", r),
+            }
+        }
+        Rule::rules_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::rules_define => {
+                    let clauses = inner.into_inner();
+                    let src = clauses.clone().map(|p| p.as_str()).join("\n");
+                    let (name, rules) = parse_rule_definitions(clauses, cur_vld)?;
+                    SysOp::DefineRule(name, rules[0].head.len(), src)
+                }
+                Rule::rules_drop => {
+                    let name_p = inner.into_inner().next().unwrap();
+                    SysOp::DropRule(Symbol::new(name_p.as_str(), name_p.extract_span()))
+                }
+                Rule::rules_describe => {
+                    let mut inner = inner.into_inner();
+                    let name_p = inner.next().unwrap();
+                    let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                    let description = match inner.next() {
+                        None => Default::default(),
+                        Some(desc_p) => parse_string(desc_p)?,
+                    };
+                    SysOp::DescribeRule(name, description)
+                }
+                Rule::rules_list => SysOp::ListRules,
+                r => unreachable!("{:?}", r),
+            }
+        }
+        Rule::procedure_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::procedure_create => {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("Procedure parameter {0} is declared more than once")]
+                    #[diagnostic(code(parser::dup_procedure_param))]
+                    struct DuplicateProcedureParam(String, #[label] SourceSpan);
+
+                    let mut src = inner.into_inner();
+                    let name_p = src.next().unwrap();
+                    let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                    let mut params: Vec<String> = vec![];
+                    let mut body = None;
+                    for p in src {
+                        if p.as_rule() == Rule::param {
+                            let param = p.as_str().strip_prefix('$').unwrap().to_string();
+                            ensure!(
+                                !params.contains(&param),
+                                DuplicateProcedureParam(param, p.extract_span())
+                            );
+                            params.push(param);
+                        } else {
+                            body = Some(p);
+                        }
+                    }
+                    let body = body.unwrap();
+                    let body_str = body.as_str().to_string();
+                    // the body is stored as source, here it is only checked to be valid,
+                    // including that it uses no parameters other than the declared ones
+                    let declared = params
+                        .iter()
+                        .map(|p| (p.clone(), DataValue::Null))
+                        .collect();
+                    if body.as_rule() == Rule::imperative_block {
+                        parse_imperative_block(body, &declared, algorithms, cur_vld)?;
+                    } else {
+                        parse_query(body.into_inner(), &declared, algorithms, cur_vld)?;
+                    }
+                    SysOp::CreateProcedure(name, params, body_str)
+                }
+                Rule::procedure_drop => {
+                    let name_p = inner.into_inner().next().unwrap();
+                    SysOp::DropProcedure(Symbol::new(name_p.as_str(), name_p.extract_span()))
+                }
+                Rule::procedure_list => SysOp::ListProcedures,
+                r => unreachable!("{:?}", r),
+            }
+        }
+        Rule::call_op => {
+            let mut src = inner.into_inner();
+            let name_p = src.next().unwrap();
+            let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+            let args = src
+                .map(|p| build_expr(p, param_pool)?.eval_to_const())
+                .try_collect()?;
+            SysOp::CallProcedure(name, args)
+        }
         r => unreachable!("{:?}", r),
     })
 }
@@ -14,15 +14,18 @@ use thiserror::Error;
 
 use crate::data::aggr::Aggregation;
 use crate::data::expr::Expr;
+use crate::data::functions::OP_EQ;
 use crate::data::program::{
-    MagicAtom, MagicFixedRuleApply, MagicInlineRule, MagicRulesOrFixed, MagicSymbol,
-    StratifiedMagicProgram,
+    MagicAtom, MagicFixedRuleApply, MagicInlineRule, MagicRelationApplyAtom, MagicRulesOrFixed,
+    MagicSymbol, StratifiedMagicProgram,
 };
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::SourceSpan;
-use crate::query::ra::RelAlgebra;
-use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::query::ra::{join_is_prefix, RelAlgebra};
+use crate::runtime::relation::{
+    AccessLevel, ExprIndexManifest, InsufficientAccessLevel, RelationHandle,
+};
 use crate::runtime::transact::SessionTx;
 
 pub(crate) type CompiledProgram = BTreeMap<MagicSymbol, CompiledRuleSet>;
@@ -95,6 +98,134 @@ struct RuleNotFound(String, #[label] SourceSpan);
 #[diagnostic(help("Required arity: {1}, number of arguments given: {2}"))]
 struct ArityMismatch(String, usize, usize, #[label] SourceSpan);
 
+/// An expression index chosen for a stored relation, together with
+/// the expressions to compute its prefix from the variables already bound
+struct ExprIndexUse {
+    idx_handle: RelationHandle,
+    manifest: ExprIndexManifest,
+    prefix: Vec<Expr>,
+}
+
+/// An expression index can be used if the rule body contains equality predicates
+/// comparing its leading expressions to values computable before the relation is reached.
+/// For partial indices, each conjunct of the filter must also appear as a predicate in the body.
+/// Expressions are compared by their normalized structure, see [crate::data::expr::NormalExpr].
+/// No expression index is used if the bound prefix of the primary key is at least as long.
+fn choose_expr_index(
+    store: &RelationHandle,
+    rel_app: &MagicRelationApplyAtom,
+    body: &[MagicAtom],
+    seen_variables: &BTreeSet<Symbol>,
+) -> Result<Option<ExprIndexUse>> {
+    if store.expr_indices.is_empty() || rel_app.valid_at.is_some() {
+        return Ok(None);
+    }
+    let mapping: BTreeMap<_, _> = store
+        .metadata
+        .keys
+        .iter()
+        .chain(store.metadata.non_keys.iter())
+        .zip(rel_app.args.iter())
+        .map(|(col, arg)| (Symbol::new(col.name.clone(), rel_app.span), arg.clone()))
+        .collect();
+    let predicates = body
+        .iter()
+        .filter_map(|atom| match atom {
+            MagicAtom::Predicate(p) => Some(p.to_conjunction()),
+            _ => None,
+        })
+        .flatten()
+        .collect_vec();
+    let normal_predicates: BTreeSet<_> = predicates.iter().map(|p| p.normalize()).collect();
+    let bound_key_prefix = rel_app.args[..store.metadata.keys.len()]
+        .iter()
+        .take_while(|arg| seen_variables.contains(*arg))
+        .count();
+    if bound_key_prefix == store.metadata.keys.len() {
+        // a point lookup
+        return Ok(None);
+    }
+
+    let mut chosen: Option<ExprIndexUse> = None;
+    for (idx_handle, manifest) in store.expr_indices.values() {
+        let (elements, filter) = manifest.parse_exprs()?;
+        if let Some(mut filter) = filter {
+            filter.rename_bindings(&mapping);
+            if !filter
+                .to_conjunction()
+                .iter()
+                .all(|f| normal_predicates.contains(&f.normalize()))
+            {
+                continue;
+            }
+        }
+        let mut prefix = vec![];
+        for mut element in elements {
+            element.rename_bindings(&mapping);
+            if let Some(var) = element.get_binding() {
+                if seen_variables.contains(var) {
+                    prefix.push(element);
+                    continue;
+                }
+            }
+            let normal_element = element.normalize();
+            let found = predicates.iter().find_map(|p| match p {
+                Expr::Apply { op, args, .. } if **op == OP_EQ && args.len() == 2 => {
+                    [(&args[0], &args[1]), (&args[1], &args[0])]
+                        .into_iter()
+                        .find(|(this, other)| {
+                            this.normalize() == normal_element
+                                && other
+                                    .bindings()
+                                    .map(|b| b.is_subset(seen_variables))
+                                    .unwrap_or(false)
+                        })
+                        .map(|(_, other)| other.clone())
+                }
+                _ => None,
+            });
+            match found {
+                Some(expr) => prefix.push(expr),
+                None => break,
+            }
+        }
+        if prefix.len() > bound_key_prefix
+            && chosen
+                .as_ref()
+                .map(|c| c.prefix.len() < prefix.len())
+                .unwrap_or(true)
+        {
+            chosen = Some(ExprIndexUse {
+                idx_handle: idx_handle.clone(),
+                manifest: manifest.clone(),
+                prefix,
+            });
+        }
+    }
+    Ok(chosen)
+}
+
+/// Joins by key prefix lookups into the right relation if the right keys form a prefix of its
+/// bindings, otherwise by a hash join, as the lookups would each scan the whole relation
+fn join_on_keys(
+    left: RelAlgebra,
+    right: RelAlgebra,
+    left_keys: Vec<Symbol>,
+    right_keys: Vec<Symbol>,
+    span: SourceSpan,
+) -> RelAlgebra {
+    let right_bindings = right.bindings_after_eliminate();
+    let right_positions = right_keys
+        .iter()
+        .map(|k| right_bindings.iter().position(|b| b == k).unwrap())
+        .collect_vec();
+    if right_keys.is_empty() || join_is_prefix(&right_positions) {
+        left.join(right, left_keys, right_keys, span)
+    } else {
+        left.hash_join(right, left_keys, right_keys, span)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(crate) enum IndexPositionUse {
     Join,
@@ -170,7 +301,37 @@ impl<'a> SessionTx<'a> {
             serial_id += 1;
             ret
         };
-        for atom in &rule.body {
+        // the cyclic part of the body, if any, is joined by a single multi-way join
+        // in place of its first atom
+        let (wco_positions, mut wco_join) = match self.plan_wco_join(&rule.body)? {
+            Some((positions, wco)) => (positions, Some(wco)),
+            None => (BTreeSet::new(), None),
+        };
+        for (atom_idx, atom) in rule.body.iter().enumerate() {
+            if wco_positions.contains(&atom_idx) {
+                if let Some(mut wco) = wco_join.take() {
+                    let mut prev_joiner_vars = vec![];
+                    let mut right_joiner_vars = vec![];
+                    for var in wco.bindings.iter_mut() {
+                        if seen_variables.contains(var) {
+                            prev_joiner_vars.push(var.clone());
+                            let rk = gen_symb(var.span);
+                            right_joiner_vars.push(rk.clone());
+                            *var = rk;
+                        } else {
+                            seen_variables.insert(var.clone());
+                        }
+                    }
+                    let span = wco.span;
+                    let right = RelAlgebra::WcoJoin(wco);
+                    ret = if ret.is_unit() {
+                        right
+                    } else {
+                        ret.join(right, prev_joiner_vars, right_joiner_vars, span)
+                    };
+                }
+                continue;
+            }
             match atom {
                 MagicAtom::Rule(rule_app) => {
                     let store_arity = store_arities.get(&rule_app.name).ok_or_else(|| {
@@ -208,7 +369,13 @@ impl<'a> SessionTx<'a> {
                     let right =
                         RelAlgebra::derived(right_vars, rule_app.name.clone(), rule_app.span);
                     debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
-                    ret = ret.join(right, prev_joiner_vars, right_joiner_vars, rule_app.span);
+                    ret = join_on_keys(
+                        ret,
+                        right,
+                        prev_joiner_vars,
+                        right_joiner_vars,
+                        rule_app.span,
+                    );
                 }
                 MagicAtom::Relation(rel_app) => {
                     let store = self.get_relation(&rel_app.name, false)?;
@@ -228,6 +395,8 @@ impl<'a> SessionTx<'a> {
                             rel_app.span
                         )
                     );
+                    let expr_index_use =
+                        choose_expr_index(&store, rel_app, &rule.body, &seen_variables)?;
                     // already existing vars
                     let mut prev_joiner_vars = vec![];
                     // vars introduced by right and joined
@@ -258,6 +427,63 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
+                    if let Some(ExprIndexUse {
+                        idx_handle,
+                        manifest,
+                        prefix,
+                    }) = expr_index_use
+                    {
+                        // look up the expression index by the computed prefix,
+                        // then fetch the rows by their keys
+                        let mut prefix_vars = vec![];
+                        for expr in prefix {
+                            let v = gen_symb(rel_app.span);
+                            ret = ret.unify(v.clone(), expr, false, rel_app.span);
+                            prefix_vars.push(v);
+                        }
+                        let idx_vars = (0..idx_handle.arity())
+                            .map(|_| gen_symb(rel_app.span))
+                            .collect_vec();
+                        let idx_joiner_vars = idx_vars[..prefix_vars.len()].to_vec();
+                        let key_vars = manifest
+                            .key_positions
+                            .iter()
+                            .map(|i| idx_vars[*i].clone())
+                            .collect_vec();
+                        let middle =
+                            RelAlgebra::relation(idx_vars, idx_handle, rel_app.span, None)?;
+                        ret = ret.join(middle, prefix_vars, idx_joiner_vars, rel_app.span);
+
+                        let right_key_vars = right_vars[..store.metadata.keys.len()].to_vec();
+                        let right = RelAlgebra::relation(right_vars, store, rel_app.span, None)?;
+                        ret = ret.join(right, key_vars, right_key_vars, rel_app.span);
+                        // joining on the full key is a point lookup, other joins become filters
+                        if !prev_joiner_vars.is_empty() {
+                            let post_filters = prev_joiner_vars
+                                .into_iter()
+                                .zip(right_joiner_vars)
+                                .map(|(l, r)| {
+                                    let span = l.span;
+                                    Expr::build_equate(
+                                        vec![
+                                            Expr::Binding {
+                                                var: l,
+                                                tuple_pos: None,
+                                            },
+                                            Expr::Binding {
+                                                var: r,
+                                                tuple_pos: None,
+                                            },
+                                        ],
+                                        span,
+                                    )
+                                })
+                                .collect_vec();
+                            ret = ret.filter(Expr::build_and(post_filters, rel_app.span))?;
+                        }
+                        continue;
+                    }
+
                     let chosen_index =
                         store.choose_index(&join_indices, rel_app.valid_at.is_some());
 
@@ -271,8 +497,13 @@ impl<'a> SessionTx<'a> {
                                 rel_app.valid_at,
                             )?;
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
-                            ret =
-                                ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            ret = join_on_keys(
+                                ret,
+                                right,
+                                prev_joiner_vars,
+                                right_joiner_vars,
+                                rel_app.span,
+                            );
                         }
                         Some((chosen_index, mapper, false)) => {
                             // index-only
@@ -287,8 +518,13 @@ impl<'a> SessionTx<'a> {
                                 rel_app.valid_at,
                             )?;
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
-                            ret =
-                                ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            ret = join_on_keys(
+                                ret,
+                                right,
+                                prev_joiner_vars,
+                                right_joiner_vars,
+                                rel_app.span,
+                            );
                         }
                         Some((chosen_index, mapper, true)) => {
                             // index-with-join
@@ -573,6 +809,40 @@ impl<'a> SessionTx<'a> {
                         ret = ret.filter(Expr::build_and(post_filters, s.span))?;
                     }
                 }
+                MagicAtom::RTreeSearch(s) => {
+                    debug_assert!(
+                        seen_variables.contains(&s.query),
+                        "R-tree search query must be bound"
+                    );
+                    let mut own_bindings = vec![];
+                    let mut post_filters = vec![];
+                    for var in s.all_bindings() {
+                        if seen_variables.contains(var) {
+                            let rk = gen_symb(var.span);
+                            post_filters.push(Expr::build_equate(
+                                vec![
+                                    Expr::Binding {
+                                        var: var.clone(),
+                                        tuple_pos: None,
+                                    },
+                                    Expr::Binding {
+                                        var: rk.clone(),
+                                        tuple_pos: None,
+                                    },
+                                ],
+                                var.span,
+                            ));
+                            own_bindings.push(rk);
+                        } else {
+                            seen_variables.insert(var.clone());
+                            own_bindings.push(var.clone());
+                        }
+                    }
+                    ret = ret.rtree_search(s.clone(), own_bindings)?;
+                    if !post_filters.is_empty() {
+                        ret = ret.filter(Expr::build_and(post_filters, s.span))?;
+                    }
+                }
                 MagicAtom::Unification(u) => {
                     if seen_variables.contains(&u.binding) {
                         let expr = if u.one_many_unif {
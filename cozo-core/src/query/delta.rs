@@ -0,0 +1,828 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Delta programs for the incremental maintenance of materialized views.
+// The rules of the query of a view that are not recursive are inlined into
+// the rules applying them, leaving the rules of the entry and the recursive
+// rules, whose bodies apply stored relations, recursive rules, predicates and
+// unifications only.
+//
+// Without recursive rules, the view keeps the number of derivations of each of
+// its rows (the counting algorithm). A derivation is a binding of all variables
+// of a rule body, and the derivations gained or lost by a write are those using
+// at least one of the written rows. To count each of them exactly once, the
+// occurrence of the written relation taking a written row is the first such
+// occurrence: the occurrences before it take the rows that were not written.
+//
+// With recursive rules, the tuples of each recursive rule are kept as well, and
+// the DRed algorithm is used: the tuples that were derivable using removed rows
+// are deleted, those of them still derivable otherwise are rederived, and the
+// consequences of the added rows are inserted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use itertools::Itertools;
+use miette::Result;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::Expr;
+use crate::data::program::{
+    FixedRuleApply, InputProgram, MagicSymbol, NormalFormAtom, NormalFormInlineRule,
+    NormalFormProgram, NormalFormRelationApplyAtom, NormalFormRuleApplyAtom,
+    NormalFormRulesOrFixed, Unification,
+};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::fixed_rule::utilities::Constant;
+use crate::fixed_rule::FixedRuleHandle;
+use crate::parse::SourceSpan;
+use crate::query::graph::{strongly_connected_components, Graph};
+use crate::runtime::db::Poison;
+use crate::runtime::transact::SessionTx;
+
+/// Inlining multiplies rules, past this many rules of one name the view is evaluated in full
+const MAX_FLAT_RULES: usize = 128;
+
+/// A rule whose body applies no rules that are not recursive
+#[derive(Clone, Debug)]
+pub(crate) struct FlatRule {
+    pub(crate) head: Vec<Symbol>,
+    pub(crate) body: Vec<NormalFormAtom>,
+}
+
+/// How a view can be maintained incrementally
+pub(crate) enum ViewPlan {
+    /// by counting the derivations of the rows, from the rules of the entry
+    Counting(Vec<FlatRule>),
+    /// by DRed, keeping the tuples of the recursive rules
+    Rederive {
+        recursive: BTreeMap<Symbol, Vec<FlatRule>>,
+        entry: Vec<FlatRule>,
+    },
+}
+
+/// Generates variables that cannot clash with those of the user
+#[derive(Default)]
+struct VarGen {
+    last_id: u32,
+}
+
+impl VarGen {
+    fn next(&mut self, span: SourceSpan) -> Symbol {
+        self.last_id += 1;
+        Symbol::new(&format!("%{}", self.last_id) as &str, span)
+    }
+}
+
+/// Returns how the view with the given query can be maintained incrementally,
+/// or `None` if the query must be evaluated again in full: this is the case for
+/// queries with aggregations, negations, fixed rules, searches or time travel.
+pub(crate) fn plan_view(tx: &SessionTx<'_>, program: InputProgram) -> Result<Option<ViewPlan>> {
+    if program.out_opts.limit.is_some() || program.out_opts.offset.is_some() {
+        return Ok(None);
+    }
+    let (normalized, _) = program.into_normalized_program(tx)?;
+    let mut rules = BTreeMap::new();
+    for (name, ruleset) in normalized.prog {
+        let ruleset = match ruleset {
+            NormalFormRulesOrFixed::Rules { rules } => rules,
+            NormalFormRulesOrFixed::Fixed { .. } => return Ok(None),
+        };
+        let mut collected = Vec::with_capacity(ruleset.len());
+        for rule in ruleset {
+            if rule.aggr.iter().any(|aggr| aggr.is_some()) {
+                return Ok(None);
+            }
+            for atom in &rule.body {
+                match atom {
+                    NormalFormAtom::Rule(_)
+                    | NormalFormAtom::Predicate(_)
+                    | NormalFormAtom::Unification(_) => {}
+                    NormalFormAtom::Relation(r) => {
+                        if r.valid_at.is_some() {
+                            return Ok(None);
+                        }
+                    }
+                    _ => return Ok(None),
+                }
+            }
+            collected.push(FlatRule {
+                head: rule.head,
+                body: rule.body,
+            });
+        }
+        rules.insert(name, collected);
+    }
+
+    let graph: Graph<Symbol> = rules
+        .iter()
+        .map(|(name, ruleset)| {
+            let applied = ruleset
+                .iter()
+                .flat_map(|rule| rule.body.iter())
+                .filter_map(|atom| match atom {
+                    NormalFormAtom::Rule(r) => Some(r.name.clone()),
+                    _ => None,
+                })
+                .collect_vec();
+            (name.clone(), applied)
+        })
+        .collect();
+    let mut recursive = BTreeSet::new();
+    for scc in strongly_connected_components(&graph)? {
+        if scc.len() > 1 || graph[scc[0]].contains(scc[0]) {
+            recursive.extend(scc.into_iter().cloned());
+        }
+    }
+    let entry = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+    if recursive.contains(&entry) {
+        return Ok(None);
+    }
+
+    let mut flattener = Flattener {
+        rules,
+        recursive,
+        flattened: Default::default(),
+        gen: Default::default(),
+    };
+    let entry_rules = match flattener.flatten(&entry)? {
+        None => return Ok(None),
+        Some(rules) => rules,
+    };
+    if flattener.recursive.is_empty() {
+        return Ok(Some(ViewPlan::Counting(entry_rules)));
+    }
+    let mut recursive_rules = BTreeMap::new();
+    for name in flattener.recursive.clone() {
+        match flattener.flatten(&name)? {
+            None => return Ok(None),
+            Some(rules) => {
+                recursive_rules.insert(name, rules);
+            }
+        }
+    }
+    Ok(Some(ViewPlan::Rederive {
+        recursive: recursive_rules,
+        entry: entry_rules,
+    }))
+}
+
+struct Flattener {
+    rules: BTreeMap<Symbol, Vec<FlatRule>>,
+    recursive: BTreeSet<Symbol>,
+    /// flattened rules that are not recursive
+    flattened: BTreeMap<Symbol, Vec<FlatRule>>,
+    gen: VarGen,
+}
+
+impl Flattener {
+    /// Returns the rules of the given name with the rules that are not recursive inlined,
+    /// or `None` if there are too many of them
+    fn flatten(&mut self, name: &Symbol) -> Result<Option<Vec<FlatRule>>> {
+        if let Some(rules) = self.flattened.get(name) {
+            return Ok(Some(rules.clone()));
+        }
+        let mut ret = vec![];
+        for rule in self.rules[name].clone() {
+            let mut bodies: Vec<Vec<NormalFormAtom>> = vec![vec![]];
+            for atom in rule.body {
+                let applied = match &atom {
+                    NormalFormAtom::Rule(r) if !self.recursive.contains(&r.name) => r,
+                    _ => {
+                        for body in bodies.iter_mut() {
+                            body.push(atom.clone());
+                        }
+                        continue;
+                    }
+                };
+                let callee_rules = match self.flatten(&applied.name)? {
+                    None => return Ok(None),
+                    Some(rules) => rules,
+                };
+                let mut expanded = Vec::with_capacity(bodies.len() * callee_rules.len());
+                for body in &bodies {
+                    for callee in &callee_rules {
+                        let mut body = body.clone();
+                        body.extend(self.inline(callee, &applied.args)?);
+                        expanded.push(body);
+                    }
+                }
+                bodies = expanded;
+                if bodies.len() > MAX_FLAT_RULES {
+                    return Ok(None);
+                }
+            }
+            for body in bodies {
+                ret.push(self.bind_ignored(FlatRule {
+                    head: rule.head.clone(),
+                    body,
+                }));
+            }
+            if ret.len() > MAX_FLAT_RULES {
+                return Ok(None);
+            }
+        }
+        if !self.recursive.contains(name) {
+            self.flattened.insert(name.clone(), ret.clone());
+        }
+        Ok(Some(ret))
+    }
+    /// Returns the body of the rule with fresh variables, bound to the arguments of the application
+    fn inline(&mut self, callee: &FlatRule, args: &[Symbol]) -> Result<Vec<NormalFormAtom>> {
+        let mut vars = callee.head.iter().cloned().collect::<BTreeSet<_>>();
+        for atom in &callee.body {
+            collect_atom_vars(atom, &mut vars)?;
+        }
+        let mut mapping: BTreeMap<_, _> = vars
+            .into_iter()
+            .map(|var| {
+                let fresh = self.gen.next(var.span);
+                (var, fresh)
+            })
+            .collect();
+        let mut unifications = vec![];
+        let mut bound = BTreeSet::new();
+        for (head, arg) in callee.head.iter().zip(args) {
+            if arg.is_generated_ignored_symbol() {
+                continue;
+            }
+            if bound.insert(head) {
+                mapping.insert(head.clone(), arg.clone());
+            } else {
+                unifications.push(NormalFormAtom::Unification(Unification {
+                    binding: arg.clone(),
+                    expr: Expr::Binding {
+                        var: mapping[head].clone(),
+                        tuple_pos: None,
+                    },
+                    one_many_unif: false,
+                    span: arg.span,
+                }));
+            }
+        }
+        let mut body = callee.body.clone();
+        for atom in body.iter_mut() {
+            rename_atom_vars(atom, &mapping);
+        }
+        body.extend(unifications);
+        Ok(body)
+    }
+    /// Binds the ignored arguments to variables, as derivations and written rows are told apart by them
+    fn bind_ignored(&mut self, mut rule: FlatRule) -> FlatRule {
+        let mut mapping = BTreeMap::new();
+        for atom in &rule.body {
+            let args = match atom {
+                NormalFormAtom::Rule(r) => &r.args,
+                NormalFormAtom::Relation(r) => &r.args,
+                _ => continue,
+            };
+            for arg in args {
+                if arg.is_generated_ignored_symbol() {
+                    mapping.insert(arg.clone(), self.gen.next(arg.span));
+                }
+            }
+        }
+        for atom in rule.body.iter_mut() {
+            rename_atom_vars(atom, &mapping);
+        }
+        rule
+    }
+}
+
+fn collect_atom_vars(atom: &NormalFormAtom, coll: &mut BTreeSet<Symbol>) -> Result<()> {
+    match atom {
+        NormalFormAtom::Rule(r) | NormalFormAtom::NegatedRule(r) => {
+            coll.extend(r.args.iter().cloned());
+        }
+        NormalFormAtom::Relation(r) | NormalFormAtom::NegatedRelation(r) => {
+            coll.extend(r.args.iter().cloned());
+        }
+        NormalFormAtom::Predicate(p) => p.collect_bindings(coll)?,
+        NormalFormAtom::Unification(u) => {
+            coll.insert(u.binding.clone());
+            u.expr.collect_bindings(coll)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn rename_atom_vars(atom: &mut NormalFormAtom, mapping: &BTreeMap<Symbol, Symbol>) {
+    let rename = |var: &mut Symbol| {
+        if let Some(new_var) = mapping.get(var) {
+            *var = new_var.clone();
+        }
+    };
+    match atom {
+        NormalFormAtom::Rule(r) | NormalFormAtom::NegatedRule(r) => {
+            r.args.iter_mut().for_each(rename);
+        }
+        NormalFormAtom::Relation(r) | NormalFormAtom::NegatedRelation(r) => {
+            r.args.iter_mut().for_each(rename);
+        }
+        NormalFormAtom::Predicate(p) => p.rename_bindings(mapping),
+        NormalFormAtom::Unification(u) => {
+            rename(&mut u.binding);
+            u.expr.rename_bindings(mapping);
+        }
+        _ => {}
+    }
+}
+
+impl FlatRule {
+    /// The variables telling derivations apart, starting with those of the head
+    fn derivation_vars(&self) -> Result<Vec<Symbol>> {
+        let mut body_vars = BTreeSet::new();
+        for atom in &self.body {
+            collect_atom_vars(atom, &mut body_vars)?;
+        }
+        let mut ret = self.head.iter().unique().cloned().collect_vec();
+        ret.extend(body_vars.into_iter().filter(|var| !self.head.contains(var)));
+        Ok(ret)
+    }
+    /// Positions of the head in the derivation variables
+    fn head_positions(&self, vars: &[Symbol]) -> Vec<usize> {
+        self.head
+            .iter()
+            .map(|h| vars.iter().position(|v| v == h).unwrap())
+            .collect_vec()
+    }
+}
+
+fn rule_atom(name: &Symbol, args: Vec<Symbol>) -> NormalFormAtom {
+    NormalFormAtom::Rule(NormalFormRuleApplyAtom {
+        name: name.clone(),
+        args,
+        span: name.span,
+    })
+}
+
+fn negated_rule_atom(name: &Symbol, args: Vec<Symbol>) -> NormalFormAtom {
+    NormalFormAtom::NegatedRule(NormalFormRuleApplyAtom {
+        name: name.clone(),
+        args,
+        span: name.span,
+    })
+}
+
+fn relation_atom(name: &str, args: Vec<Symbol>, span: SourceSpan) -> NormalFormAtom {
+    NormalFormAtom::Relation(NormalFormRelationApplyAtom {
+        name: Symbol::new(name, span),
+        args,
+        valid_at: None,
+        span,
+    })
+}
+
+fn constant_rule(rows: &[Tuple], arity: usize) -> NormalFormRulesOrFixed {
+    let span = SourceSpan(0, 0);
+    let data = rows
+        .iter()
+        .map(|row| DataValue::List(row.clone()))
+        .collect_vec();
+    let options = BTreeMap::from([(
+        SmartString::from("data"),
+        Expr::Const {
+            val: DataValue::List(data),
+            span,
+        },
+    )]);
+    NormalFormRulesOrFixed::Fixed {
+        fixed: FixedRuleApply {
+            fixed_handle: FixedRuleHandle {
+                name: Symbol::new("Constant", span),
+            },
+            rule_args: vec![],
+            options: Arc::new(options),
+            head: (0..arity)
+                .map(|i| Symbol::new(format!("_{i}"), span))
+                .collect(),
+            arity,
+            span,
+            fixed_impl: Arc::new(Box::new(Constant)),
+        },
+    }
+}
+
+/// Every combination of one alternative for each atom, in order
+fn expand_alternatives(alternatives: Vec<Vec<Vec<NormalFormAtom>>>) -> Vec<Vec<NormalFormAtom>> {
+    let mut bodies = vec![vec![]];
+    for choices in alternatives {
+        bodies = bodies
+            .into_iter()
+            .flat_map(|body: Vec<NormalFormAtom>| {
+                choices.iter().map(move |choice| {
+                    let mut body = body.clone();
+                    body.extend(choice.iter().cloned());
+                    body
+                })
+            })
+            .collect();
+    }
+    bodies
+}
+
+fn add_rules(
+    prog: &mut NormalFormProgram,
+    name: Symbol,
+    head: &[Symbol],
+    bodies: Vec<Vec<NormalFormAtom>>,
+) {
+    if bodies.is_empty() {
+        return;
+    }
+    let rules = bodies.into_iter().map(|body| NormalFormInlineRule {
+        head: head.to_vec(),
+        aggr: vec![None; head.len()],
+        body,
+    });
+    match prog
+        .prog
+        .entry(name)
+        .or_insert_with(|| NormalFormRulesOrFixed::Rules { rules: vec![] })
+    {
+        NormalFormRulesOrFixed::Rules { rules: existing } => existing.extend(rules),
+        NormalFormRulesOrFixed::Fixed { .. } => unreachable!(),
+    }
+}
+
+/// The rows of the written relation, before and after the write
+struct WrittenRows<'a> {
+    relation: &'a str,
+    removed: &'a [Tuple],
+    added: &'a [Tuple],
+    removed_rule: Symbol,
+    added_rule: Symbol,
+}
+
+impl<'a> WrittenRows<'a> {
+    fn new(relation: &'a str, removed: &'a [Tuple], added: &'a [Tuple]) -> Self {
+        let span = SourceSpan(0, 0);
+        Self {
+            relation,
+            removed,
+            added,
+            removed_rule: Symbol::new("%removed", span),
+            added_rule: Symbol::new("%added", span),
+        }
+    }
+    fn is_written(&self, atom: &NormalFormAtom) -> bool {
+        matches!(atom, NormalFormAtom::Relation(r) if r.name.name == self.relation)
+    }
+    /// The rows not written, which were there both before and after the write
+    fn unchanged(&self, args: &[Symbol], span: SourceSpan) -> Vec<NormalFormAtom> {
+        vec![
+            relation_atom(self.relation, args.to_vec(), span),
+            negated_rule_atom(&self.added_rule, args.to_vec()),
+        ]
+    }
+    /// The rows before the write, as alternatives
+    fn before(&self, args: &[Symbol], span: SourceSpan) -> Vec<Vec<NormalFormAtom>> {
+        vec![
+            self.unchanged(args, span),
+            vec![rule_atom(&self.removed_rule, args.to_vec())],
+        ]
+    }
+    fn add_constant_rules(&self, prog: &mut NormalFormProgram, arity: usize) {
+        prog.prog.insert(
+            self.removed_rule.clone(),
+            constant_rule(self.removed, arity),
+        );
+        prog.prog
+            .insert(self.added_rule.clone(), constant_rule(self.added, arity));
+    }
+}
+
+fn atom_args(atom: &NormalFormAtom) -> (&[Symbol], SourceSpan) {
+    match atom {
+        NormalFormAtom::Rule(r) | NormalFormAtom::NegatedRule(r) => (&r.args, r.span),
+        NormalFormAtom::Relation(r) | NormalFormAtom::NegatedRelation(r) => (&r.args, r.span),
+        _ => unreachable!(),
+    }
+}
+
+/// The rules of a counting program, each deriving rows with the given sign,
+/// together with the positions of the row in the derivations
+pub(crate) type CountingOutputs = Vec<(Symbol, i64, Vec<usize>)>;
+
+/// Derivations of every row of the view
+pub(crate) fn counting_program(rules: &[FlatRule]) -> Result<(NormalFormProgram, CountingOutputs)> {
+    let mut prog = NormalFormProgram::default();
+    let mut outputs = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        let vars = rule.derivation_vars()?;
+        let name = Symbol::new(format!("%+{i}"), SourceSpan(0, 0));
+        add_rules(&mut prog, name.clone(), &vars, vec![rule.body.clone()]);
+        outputs.push((name, 1, rule.head_positions(&vars)));
+    }
+    Ok((prog, outputs))
+}
+
+/// Derivations gained and lost by a write of `arity` columns
+pub(crate) fn counting_delta_program(
+    rules: &[FlatRule],
+    relation: &str,
+    arity: usize,
+    removed: &[Tuple],
+    added: &[Tuple],
+) -> Result<(NormalFormProgram, CountingOutputs)> {
+    let written = WrittenRows::new(relation, removed, added);
+    let mut prog = NormalFormProgram::default();
+    written.add_constant_rules(&mut prog, arity);
+    let mut outputs = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        let occurrences = rule
+            .body
+            .iter()
+            .positions(|atom| written.is_written(atom))
+            .collect_vec();
+        if occurrences.is_empty() {
+            continue;
+        }
+        let vars = rule.derivation_vars()?;
+        let positions = rule.head_positions(&vars);
+        let gained = Symbol::new(format!("%+{i}"), SourceSpan(0, 0));
+        let lost = Symbol::new(format!("%-{i}"), SourceSpan(0, 0));
+        for &taking in &occurrences {
+            let (args, _) = atom_args(&rule.body[taking]);
+            let mut gained_alts = vec![vec![vec![rule_atom(&written.added_rule, args.to_vec())]]];
+            let mut lost_alts = vec![vec![vec![rule_atom(&written.removed_rule, args.to_vec())]]];
+            for (pos, atom) in rule.body.iter().enumerate() {
+                if pos == taking {
+                    continue;
+                }
+                if written.is_written(atom) {
+                    let (args, span) = atom_args(atom);
+                    if pos < taking {
+                        gained_alts.push(vec![written.unchanged(args, span)]);
+                        lost_alts.push(vec![written.unchanged(args, span)]);
+                    } else {
+                        gained_alts.push(vec![vec![atom.clone()]]);
+                        lost_alts.push(written.before(args, span));
+                    }
+                } else {
+                    gained_alts.push(vec![vec![atom.clone()]]);
+                    lost_alts.push(vec![vec![atom.clone()]]);
+                }
+            }
+            if !added.is_empty() {
+                add_rules(
+                    &mut prog,
+                    gained.clone(),
+                    &vars,
+                    expand_alternatives(gained_alts),
+                );
+            }
+            if !removed.is_empty() {
+                add_rules(
+                    &mut prog,
+                    lost.clone(),
+                    &vars,
+                    expand_alternatives(lost_alts),
+                );
+            }
+        }
+        if prog.prog.contains_key(&gained) {
+            outputs.push((gained, 1, positions.clone()));
+        }
+        if prog.prog.contains_key(&lost) {
+            outputs.push((lost, -1, positions));
+        }
+    }
+    Ok((prog, outputs))
+}
+
+/// Tuples of each recursive rule, and of the entry
+pub(crate) fn rederive_program(
+    recursive: &BTreeMap<Symbol, Vec<FlatRule>>,
+    entry: &[FlatRule],
+) -> NormalFormProgram {
+    let mut prog = NormalFormProgram::default();
+    let entry_name = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+    let entry = entry.to_vec();
+    for (name, rules) in recursive.iter().chain([(&entry_name, &entry)]) {
+        for rule in rules {
+            add_rules(&mut prog, name.clone(), &rule.head, vec![rule.body.clone()]);
+        }
+    }
+    prog
+}
+
+/// Names of the rules computing the deleted, rederived and inserted tuples of a rule
+pub(crate) fn rederive_rule_names(name: &Symbol) -> [Symbol; 3] {
+    ["%deleted", "%rederived", "%inserted"]
+        .map(|kind| Symbol::new(format!("{kind}:{}", name.name), SourceSpan(0, 0)))
+}
+
+/// Deletions, rederivations and insertions caused by a write of `arity` columns.
+/// `kept` gives the stored relation keeping the tuples of each recursive rule.
+/// Returns the rules whose tuples change.
+pub(crate) fn rederive_delta_program(
+    recursive: &BTreeMap<Symbol, Vec<FlatRule>>,
+    entry: &[FlatRule],
+    kept: &BTreeMap<Symbol, SmartString<LazyCompact>>,
+    relation: &str,
+    arity: usize,
+    removed: &[Tuple],
+    added: &[Tuple],
+) -> (NormalFormProgram, BTreeSet<Symbol>) {
+    let written = WrittenRows::new(relation, removed, added);
+    let entry_name = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+    let entry = entry.to_vec();
+    let all_rules = recursive
+        .iter()
+        .chain([(&entry_name, &entry)])
+        .collect_vec();
+
+    let mut affected = BTreeSet::new();
+    loop {
+        let mut changed = false;
+        for (name, rules) in &all_rules {
+            if affected.contains(*name) {
+                continue;
+            }
+            let reads_changed = rules.iter().flat_map(|rule| rule.body.iter()).any(|atom| {
+                written.is_written(atom)
+                    || matches!(atom, NormalFormAtom::Rule(r) if affected.contains(&r.name))
+            });
+            if reads_changed {
+                affected.insert((*name).clone());
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut prog = NormalFormProgram::default();
+    written.add_constant_rules(&mut prog, arity);
+    let kept_tuples =
+        |r: &NormalFormRuleApplyAtom| relation_atom(&kept[&r.name], r.args.clone(), r.span);
+    for (name, rules) in &all_rules {
+        if !affected.contains(*name) {
+            continue;
+        }
+        let [deleted, rederived, inserted] = rederive_rule_names(name);
+        for rule in rules.iter() {
+            let changing = |atom: &NormalFormAtom| {
+                written.is_written(atom)
+                    || matches!(atom, NormalFormAtom::Rule(r) if affected.contains(&r.name))
+            };
+
+            // deleted: derivable before the write using a deleted tuple
+            for (taking, taken) in rule.body.iter().enumerate() {
+                if !changing(taken) {
+                    continue;
+                }
+                let mut alts = vec![vec![vec![match taken {
+                    NormalFormAtom::Rule(r) => {
+                        rule_atom(&rederive_rule_names(&r.name)[0], r.args.clone())
+                    }
+                    atom => rule_atom(&written.removed_rule, atom_args(atom).0.to_vec()),
+                }]]];
+                for (pos, atom) in rule.body.iter().enumerate() {
+                    if pos == taking {
+                        continue;
+                    }
+                    alts.push(match atom {
+                        NormalFormAtom::Rule(r) => vec![vec![kept_tuples(r)]],
+                        atom if written.is_written(atom) => {
+                            let (args, span) = atom_args(atom);
+                            written.before(args, span)
+                        }
+                        atom => vec![vec![atom.clone()]],
+                    });
+                }
+                add_rules(
+                    &mut prog,
+                    deleted.clone(),
+                    &rule.head,
+                    expand_alternatives(alts),
+                );
+            }
+
+            // rederived: deleted, but still derivable after the write
+            let mut seen = BTreeSet::new();
+            let mut head_args = vec![];
+            let mut head_unifications = vec![];
+            for var in &rule.head {
+                if seen.insert(var) {
+                    head_args.push(var.clone());
+                } else {
+                    let dup = Symbol::new(format!("{}%dup{}", var.name, head_args.len()), var.span);
+                    head_unifications.push(NormalFormAtom::Unification(Unification {
+                        binding: dup.clone(),
+                        expr: Expr::Binding {
+                            var: var.clone(),
+                            tuple_pos: None,
+                        },
+                        one_many_unif: false,
+                        span: var.span,
+                    }));
+                    head_args.push(dup);
+                }
+            }
+            let mut first = vec![rule_atom(&deleted, head_args)];
+            first.extend(head_unifications);
+            let mut alts = vec![vec![first]];
+            for atom in &rule.body {
+                alts.push(match atom {
+                    NormalFormAtom::Rule(r) if affected.contains(&r.name) => {
+                        let [r_deleted, r_rederived, _] = rederive_rule_names(&r.name);
+                        vec![
+                            vec![
+                                kept_tuples(r),
+                                negated_rule_atom(&r_deleted, r.args.clone()),
+                            ],
+                            vec![rule_atom(&r_rederived, r.args.clone())],
+                        ]
+                    }
+                    NormalFormAtom::Rule(r) => vec![vec![kept_tuples(r)]],
+                    atom => vec![vec![atom.clone()]],
+                });
+            }
+            add_rules(
+                &mut prog,
+                rederived.clone(),
+                &rule.head,
+                expand_alternatives(alts),
+            );
+
+            // inserted: derivable after the write using an inserted tuple
+            for (taking, taken) in rule.body.iter().enumerate() {
+                if !changing(taken) {
+                    continue;
+                }
+                let mut alts = vec![vec![vec![match taken {
+                    NormalFormAtom::Rule(r) => {
+                        rule_atom(&rederive_rule_names(&r.name)[2], r.args.clone())
+                    }
+                    atom => rule_atom(&written.added_rule, atom_args(atom).0.to_vec()),
+                }]]];
+                for (pos, atom) in rule.body.iter().enumerate() {
+                    if pos == taking {
+                        continue;
+                    }
+                    alts.push(match atom {
+                        NormalFormAtom::Rule(r) if affected.contains(&r.name) => {
+                            let [r_deleted, r_rederived, r_inserted] = rederive_rule_names(&r.name);
+                            vec![
+                                vec![
+                                    kept_tuples(r),
+                                    negated_rule_atom(&r_deleted, r.args.clone()),
+                                ],
+                                vec![rule_atom(&r_rederived, r.args.clone())],
+                                vec![rule_atom(&r_inserted, r.args.clone())],
+                            ]
+                        }
+                        NormalFormAtom::Rule(r) => vec![vec![kept_tuples(r)]],
+                        atom => vec![vec![atom.clone()]],
+                    });
+                }
+                add_rules(
+                    &mut prog,
+                    inserted.clone(),
+                    &rule.head,
+                    expand_alternatives(alts),
+                );
+            }
+        }
+    }
+    (prog, affected)
+}
+
+impl<'a> SessionTx<'a> {
+    /// Evaluates every rule of the program in full, returning the tuples of each
+    pub(crate) fn evaluate_view_program(
+        &mut self,
+        prog: NormalFormProgram,
+        poison: Poison,
+    ) -> Result<BTreeMap<Symbol, Vec<Tuple>>> {
+        let roots = prog.prog.keys().cloned().collect();
+        let (stratified, _) = prog.into_stratified_program_with_roots(&roots)?;
+        let compiled = self.stratified_magic_compile(stratified.plain_rewrite(self)?)?;
+        let store_lifetimes = compiled
+            .iter()
+            .flat_map(|stratum| stratum.keys())
+            .map(|name| (name.clone(), usize::MAX))
+            .collect();
+        let (stores, _) =
+            self.evaluate_strata(&compiled, store_lifetimes, None, None, None, poison, None)?;
+        let mut ret = BTreeMap::new();
+        for (name, store) in stores {
+            if let MagicSymbol::Muggle { inner } = name {
+                let tuples = store.all_iter().map_ok(|t| t.into_tuple()).try_collect()?;
+                ret.insert(inner, tuples);
+            }
+        }
+        Ok(ret)
+    }
+}
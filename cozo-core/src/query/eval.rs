@@ -7,12 +7,15 @@
  */
 
 use std::collections::btree_map::Entry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 use itertools::Itertools;
 use log::{debug, trace};
-use miette::Result;
+use miette::{bail, Diagnostic, LabeledSpan, Result};
 #[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
 
@@ -24,6 +27,8 @@ use crate::data::value::DataValue;
 use crate::fixed_rule::FixedRulePayload;
 use crate::parse::SourceSpan;
 use crate::query::compile::{AggrKind, CompiledProgram, CompiledRule, CompiledRuleSet};
+use crate::query::provenance::DerivationRecorder;
+use crate::query::sort::TopK;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::{EpochStore, MeetAggrStore, RegularTempStore};
 use crate::runtime::transact::SessionTx;
@@ -32,6 +37,8 @@ pub(crate) struct QueryLimiter {
     total: Option<usize>,
     skip: Option<usize>,
     counter: AtomicUsize,
+    /// for sorted queries with a limit, the best tuples of the entry rule so far
+    top_k: Option<Mutex<TopK>>,
 }
 
 impl QueryLimiter {
@@ -57,6 +64,51 @@ impl QueryLimiter {
             Some(i) => i > self.counter.load(Ordering::Relaxed),
         }
     }
+    /// Tuples of a sorted entry rule that cannot make it into the limited result
+    /// need not be stored. As the entry rule cannot be applied in rule bodies,
+    /// no other tuple depends on them.
+    fn should_prune(&self, rule_symb: &MagicSymbol) -> bool {
+        self.top_k.is_some() && rule_symb.is_prog_entry()
+    }
+    fn admit(&self, tuple: &Tuple) -> bool {
+        match &self.top_k {
+            None => true,
+            Some(top_k) => top_k.lock().unwrap().offer(tuple),
+        }
+    }
+}
+
+/// Raised when recursive rules are still deriving new tuples after the maximum number of iterations
+#[derive(Debug)]
+struct IterationLimitExceeded(usize, BTreeSet<Symbol>);
+
+impl Error for IterationLimitExceeded {}
+
+impl Display for IterationLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Running query exceeded its limit of {} iterations, with the rules {} still deriving new tuples",
+            self.0,
+            self.1.iter().map(|s| &s.name).join(", ")
+        )
+    }
+}
+
+impl Diagnostic for IterationLimitExceeded {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("eval::iteration_limit_exceeded"))
+    }
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(
+            "Raise the limit with the `:max_iterations` option, or check for unbounded recursion",
+        ))
+    }
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(self.1.iter().map(|s| {
+            LabeledSpan::new_with_span(Some("still deriving new tuples".to_string()), s.span)
+        })))
+    }
 }
 
 impl<'a> SessionTx<'a> {
@@ -66,8 +118,36 @@ impl<'a> SessionTx<'a> {
         store_lifetimes: BTreeMap<MagicSymbol, usize>,
         total_num_to_take: Option<usize>,
         num_to_skip: Option<usize>,
+        top_k: Option<TopK>,
         poison: Poison,
     ) -> Result<(EpochStore, bool)> {
+        let (mut stores, early_return) = self.evaluate_strata(
+            strata,
+            store_lifetimes,
+            total_num_to_take,
+            num_to_skip,
+            top_k,
+            poison,
+            None,
+        )?;
+        let entry_symbol = MagicSymbol::Muggle {
+            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+        };
+        let ret_area = stores.remove(&entry_symbol).ok_or(NoEntryError)?;
+        Ok((ret_area, early_return))
+    }
+    /// Evaluates the strata in order, returning the stores that are still alive at the end.
+    /// If a recorder is given, the step at which each tuple is first derived is recorded.
+    pub(crate) fn evaluate_strata(
+        &self,
+        strata: &[CompiledProgram],
+        store_lifetimes: BTreeMap<MagicSymbol, usize>,
+        total_num_to_take: Option<usize>,
+        num_to_skip: Option<usize>,
+        mut top_k: Option<TopK>,
+        poison: Poison,
+        mut recorder: Option<&mut DerivationRecorder>,
+    ) -> Result<(BTreeMap<MagicSymbol, EpochStore>, bool)> {
         let mut stores: BTreeMap<MagicSymbol, EpochStore> = BTreeMap::new();
         let mut early_return = false;
         for (stratum, cur_prog) in strata.iter().enumerate() {
@@ -81,7 +161,9 @@ impl<'a> SessionTx<'a> {
             }
             for (rule_name, rule_set) in cur_prog {
                 let store = match rule_set.aggr_kind() {
-                    AggrKind::None | AggrKind::Normal => EpochStore::new_normal(rule_set.arity()),
+                    AggrKind::None | AggrKind::Normal => {
+                        EpochStore::new_normal(rule_set.arity(), self.temp_spill_threshold)
+                    }
                     AggrKind::Meet => {
                         let rs = match rule_set {
                             CompiledRuleSet::Rules(rs) => rs,
@@ -93,19 +175,22 @@ impl<'a> SessionTx<'a> {
                 stores.insert(rule_name.clone(), store);
             }
             debug!("stratum {}", stratum);
+            let stratum_top_k = if cur_prog.keys().any(|symb| symb.is_prog_entry()) {
+                top_k.take()
+            } else {
+                None
+            };
             early_return = self.semi_naive_magic_evaluate(
                 cur_prog,
                 &mut stores,
                 total_num_to_take,
                 num_to_skip,
+                stratum_top_k,
                 poison.clone(),
+                recorder.as_deref_mut(),
             )?;
         }
-        let entry_symbol = MagicSymbol::Muggle {
-            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
-        };
-        let ret_area = stores.remove(&entry_symbol).ok_or(NoEntryError)?;
-        Ok((ret_area, early_return))
+        Ok((stores, early_return))
     }
     /// returns true if early return is activated
     fn semi_naive_magic_evaluate(
@@ -114,12 +199,15 @@ impl<'a> SessionTx<'a> {
         stores: &mut BTreeMap<MagicSymbol, EpochStore>,
         total_num_to_take: Option<usize>,
         num_to_skip: Option<usize>,
+        top_k: Option<TopK>,
         poison: Poison,
+        mut recorder: Option<&mut DerivationRecorder>,
     ) -> Result<bool> {
         let limiter = QueryLimiter {
             total: total_num_to_take,
             skip: num_to_skip,
             counter: 0.into(),
+            top_k: top_k.map(Mutex::new),
         };
 
         let used_limiter: AtomicBool = false.into();
@@ -167,7 +255,8 @@ impl<'a> SessionTx<'a> {
                         },
                         CompiledRuleSet::Fixed(fixed) => {
                             let fixed_impl = fixed.fixed_impl.as_ref();
-                            let mut out = RegularTempStore::default();
+                            let mut out =
+                                RegularTempStore::with_spill_threshold(self.temp_spill_threshold);
                             let payload = FixedRulePayload {
                                 manifest: &fixed,
                                 stores: borrowed_stores,
@@ -289,12 +378,25 @@ impl<'a> SessionTx<'a> {
             for (k, new_store) in to_merge {
                 let old_store = stores.get_mut(k).unwrap();
                 old_store.merge_in(new_store)?;
+                poison.charge_memory(|| old_store.delta_size())?;
                 trace!("delta for {}: {}", k, old_store.has_delta());
                 changed |= old_store.has_delta();
             }
+            if let Some(recorder) = recorder.as_deref_mut() {
+                recorder.record(prog.keys().map(|k| (k, &stores[k])))?;
+            }
             if !changed {
                 break;
             }
+            let max_iterations = poison.max_iterations();
+            if max_iterations != 0 && epoch as usize >= max_iterations {
+                let rules = prog
+                    .keys()
+                    .filter(|k| stores[*k].has_delta())
+                    .map(|k| k.as_plain_symbol().clone())
+                    .collect();
+                bail!(IterationLimitExceeded(max_iterations, rules))
+            }
         }
         Ok(used_limiter.load(Ordering::Acquire))
     }
@@ -307,8 +409,9 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_spill_threshold(self.temp_spill_threshold);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
+        let should_prune = limiter.should_prune(rule_symb);
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
             debug!("initial calculation for rule {:?}.{}", rule_symb, rule_n);
@@ -316,7 +419,7 @@ impl<'a> SessionTx<'a> {
                 let item = item_res?;
                 trace!("item for {:?}.{}: {:?} at {}", rule_symb, rule_n, item, 0);
                 if should_check_limit {
-                    if !out_store.exists(&item) {
+                    if !out_store.exists(&item)? {
                         if limiter.should_skip_next() {
                             out_store.put_with_skip(item);
                         } else {
@@ -327,7 +430,7 @@ impl<'a> SessionTx<'a> {
                             return Ok((true, out_store));
                         }
                     }
-                } else {
+                } else if !should_prune || limiter.admit(&item) {
                     out_store.put(item);
                 }
             }
@@ -383,7 +486,7 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_spill_threshold(self.temp_spill_threshold);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         let mut aggr_work: BTreeMap<Vec<DataValue>, Vec<Aggregation>> = BTreeMap::new();
 
@@ -484,7 +587,7 @@ impl<'a> SessionTx<'a> {
                 .try_collect()?;
             let tuple = tuple_data;
             if should_check_limit {
-                if !out_store.exists(&tuple) {
+                if !out_store.exists(&tuple)? {
                     if limiter.should_skip_next() {
                         out_store.put_with_skip(tuple);
                     } else {
@@ -511,8 +614,9 @@ impl<'a> SessionTx<'a> {
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
         let prev_store = stores.get(rule_symb).unwrap();
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_spill_threshold(self.temp_spill_threshold);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
+        let should_prune = limiter.should_prune(rule_symb);
         for (rule_n, rule) in ruleset.iter().enumerate() {
             let dependencies_changed = rule
                 .contained_rules
@@ -534,7 +638,7 @@ impl<'a> SessionTx<'a> {
                 for item_res in rule.relation.iter(self, Some(delta_key), stores)? {
                     let item = item_res?;
                     // improvement: the clauses can actually be evaluated in parallel
-                    if prev_store.exists(&item) {
+                    if prev_store.exists(&item)? {
                         trace!(
                             "item for {:?}.{}: {:?} at {}, rederived",
                             rule_symb,
@@ -542,6 +646,14 @@ impl<'a> SessionTx<'a> {
                             item,
                             epoch
                         );
+                    } else if should_prune && !limiter.admit(&item) {
+                        trace!(
+                            "item for {:?}.{}: {:?} at {}, outside of the limit",
+                            rule_symb,
+                            rule_n,
+                            item,
+                            epoch
+                        );
                     } else {
                         trace!(
                             "item for {:?}.{}: {:?} at {}",
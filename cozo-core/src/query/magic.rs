@@ -60,6 +60,23 @@ impl StratifiedNormalFormProgram {
         }
         Ok(StratifiedMagicProgram(collected))
     }
+    /// Turns the program into a magic program without adorning any rule,
+    /// so that every rule is computed in full.
+    pub(crate) fn plain_rewrite(self, tx: &SessionTx<'_>) -> Result<StratifiedMagicProgram> {
+        let all_rules: BTreeSet<_> = self
+            .0
+            .iter()
+            .flat_map(|prog| prog.prog.keys().cloned())
+            .collect();
+        let collected = self
+            .0
+            .into_iter()
+            .map(|prog| -> Result<MagicProgram> {
+                Ok(prog.adorn(&all_rules, tx)?.magic_rewrite())
+            })
+            .try_collect()?;
+        Ok(StratifiedMagicProgram(collected))
+    }
 }
 
 impl MagicProgram {
@@ -184,6 +201,10 @@ fn magic_rewrite_ruleset(
                     seen_bindings.extend(s.all_bindings().cloned());
                     collected_atoms.push(MagicAtom::LshSearch(s));
                 }
+                MagicAtom::RTreeSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::RTreeSearch(s));
+                }
                 MagicAtom::Rule(r_app) => {
                     if r_app.name.has_bound_adornment() {
                         // we are guaranteed to have a magic rule application
@@ -551,6 +572,14 @@ impl NormalFormAtom {
                 }
                 MagicAtom::LshSearch(s.clone())
             }
+            NormalFormAtom::RTreeSearch(s) => {
+                for arg in s.all_bindings() {
+                    if !seen_bindings.contains(arg) {
+                        seen_bindings.insert(arg.clone());
+                    }
+                }
+                MagicAtom::RTreeSearch(s.clone())
+            }
 
             NormalFormAtom::Predicate(p) => {
                 // predicate cannot introduce new bindings
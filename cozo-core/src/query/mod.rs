@@ -7,12 +7,16 @@
  */
 
 pub(crate) mod compile;
+pub(crate) mod delta;
 pub(crate) mod eval;
 pub(crate) mod graph;
 pub(crate) mod logical;
 pub(crate) mod magic;
+pub(crate) mod provenance;
 pub(crate) mod ra;
 pub(crate) mod reorder;
 pub(crate) mod sort;
 pub(crate) mod stored;
 pub(crate) mod stratify;
+pub(crate) mod wco;
+pub(crate) mod well_founded;
@@ -0,0 +1,363 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Why-provenance of the tuples returned by a query.
+// The query is evaluated without the magic set rewrite, so that every rule
+// is computed in full, and the evaluation step at which each tuple is first
+// derived is recorded. A derivation of a tuple is then found by evaluating the
+// bodies of its rule again with the head bound to the tuple, accepting only
+// bindings whose rule inputs were all derived at earlier steps. This ensures
+// that the derivation tree is finite even for recursive rules.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::{
+    InputProgram, MagicAtom, MagicInlineRule, MagicRelationApplyAtom, MagicRuleApplyAtom,
+    MagicRulesOrFixed, MagicSymbol, Unification,
+};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::db::{NamedRows, Poison};
+use crate::runtime::temp_store::EpochStore;
+use crate::runtime::transact::SessionTx;
+
+/// Derivation trees larger than this are not explained
+const MAX_DERIVATION_NODES: usize = 10000;
+
+/// Records the evaluation step at which each tuple of each rule is first derived.
+#[derive(Default)]
+pub(crate) struct DerivationRecorder {
+    step: usize,
+    ranks: BTreeMap<MagicSymbol, BTreeMap<Tuple, usize>>,
+}
+
+impl DerivationRecorder {
+    /// Records the tuples added to the stores by the epoch just finished
+    pub(crate) fn record<'a>(
+        &mut self,
+        stores: impl Iterator<Item = (&'a MagicSymbol, &'a EpochStore)>,
+    ) -> Result<()> {
+        for (name, store) in stores {
+            let ranks = self.ranks.entry(name.clone()).or_default();
+            for tuple in store.delta_all_iter() {
+                ranks.entry(tuple?.into_tuple()).or_insert(self.step);
+            }
+        }
+        self.step += 1;
+        Ok(())
+    }
+    fn rank(&self, name: &MagicSymbol, tuple: &Tuple) -> Option<usize> {
+        self.ranks.get(name)?.get(tuple).copied()
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The tuple {0:?} is not returned by the query")]
+#[diagnostic(code(eval::why_tuple_not_found))]
+struct WhyTupleNotFound(Tuple);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The derivation of the tuple has more than {0} steps")]
+#[diagnostic(code(eval::why_derivation_too_large))]
+struct WhyDerivationTooLarge(usize);
+
+enum DerivationInput {
+    Rule(MagicSymbol, Tuple),
+    Relation(Symbol, Tuple),
+}
+
+struct DerivationNode {
+    kind: &'static str,
+    name: String,
+    clause: Option<usize>,
+    tuple: Tuple,
+    children: Vec<DerivationNode>,
+}
+
+impl DerivationNode {
+    fn leaf(kind: &'static str, name: String, tuple: Tuple) -> Self {
+        Self {
+            kind,
+            name,
+            clause: None,
+            tuple,
+            children: vec![],
+        }
+    }
+    fn flatten(self, parent: Option<usize>, rows: &mut Vec<Tuple>) {
+        let id = rows.len();
+        rows.push(vec![
+            DataValue::from(id as i64),
+            match parent {
+                None => DataValue::Null,
+                Some(p) => DataValue::from(p as i64),
+            },
+            DataValue::from(self.kind),
+            DataValue::from(self.name),
+            match self.clause {
+                None => DataValue::Null,
+                Some(c) => DataValue::from(c as i64),
+            },
+            DataValue::List(self.tuple),
+        ]);
+        for child in self.children {
+            child.flatten(Some(id), rows);
+        }
+    }
+}
+
+struct WhyExplainer<'a, 'b> {
+    tx: &'a mut SessionTx<'b>,
+    rules: BTreeMap<&'a MagicSymbol, &'a MagicRulesOrFixed>,
+    store_arities: BTreeMap<MagicSymbol, usize>,
+    stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    recorder: &'a DerivationRecorder,
+    // the justification found for each tuple, shared by all its occurrences in the tree
+    justifications: BTreeMap<(MagicSymbol, Tuple), (usize, Vec<DerivationInput>)>,
+    n_nodes: usize,
+}
+
+impl<'a, 'b> WhyExplainer<'a, 'b> {
+    fn explain(&mut self, name: &MagicSymbol, tuple: Tuple) -> Result<DerivationNode> {
+        self.n_nodes += 1;
+        if self.n_nodes > MAX_DERIVATION_NODES {
+            bail!(WhyDerivationTooLarge(MAX_DERIVATION_NODES))
+        }
+        let rules = match self.rules.get(name).copied() {
+            Some(MagicRulesOrFixed::Rules { rules }) => rules,
+            _ => return Ok(DerivationNode::leaf("fixed_rule", name.to_string(), tuple)),
+        };
+        if rules.iter().any(|r| r.aggr.iter().any(|a| a.is_some())) {
+            return Ok(DerivationNode::leaf("aggregate", name.to_string(), tuple));
+        }
+        let key = (name.clone(), tuple);
+        if !self.justifications.contains_key(&key) {
+            let justification = self.justify(name, rules, &key.1)?;
+            self.justifications.insert(key.clone(), justification);
+        }
+        let (clause, inputs) = &self.justifications[&key];
+        let clause = *clause;
+        let inputs = inputs
+            .iter()
+            .map(|input| match input {
+                DerivationInput::Rule(n, t) => (Some(n.clone()), n.to_string(), t.clone()),
+                DerivationInput::Relation(n, t) => (None, n.to_string(), t.clone()),
+            })
+            .collect_vec();
+        let mut children = Vec::with_capacity(inputs.len());
+        for (rule, rel_name, t) in inputs {
+            children.push(match rule {
+                Some(rule) => self.explain(&rule, t)?,
+                None => {
+                    self.n_nodes += 1;
+                    DerivationNode::leaf("relation", rel_name, t)
+                }
+            });
+        }
+        Ok(DerivationNode {
+            kind: "rule",
+            name: name.to_string(),
+            clause: Some(clause),
+            tuple: key.1,
+            children,
+        })
+    }
+    /// Finds a clause of the rule and its inputs that derive the tuple
+    fn justify(
+        &mut self,
+        name: &MagicSymbol,
+        rules: &[MagicInlineRule],
+        tuple: &Tuple,
+    ) -> Result<(usize, Vec<DerivationInput>)> {
+        let rank = self
+            .recorder
+            .rank(name, tuple)
+            .ok_or_else(|| WhyTupleNotFound(tuple.clone()))?;
+        for (idx, rule) in rules.iter().enumerate() {
+            if let Some(inputs) = self.justify_by_clause(name, rule, tuple, rank)? {
+                return Ok((idx, inputs));
+            }
+        }
+        bail!(WhyTupleNotFound(tuple.clone()))
+    }
+    fn justify_by_clause(
+        &mut self,
+        name: &MagicSymbol,
+        rule: &MagicInlineRule,
+        tuple: &Tuple,
+        rank: usize,
+    ) -> Result<Option<Vec<DerivationInput>>> {
+        let span = name.symbol().span;
+        let mut body = vec![];
+        let mut vars = vec![];
+        let mut seen = BTreeSet::new();
+        for (var, val) in rule.head.iter().zip(tuple.iter()) {
+            if seen.insert(var.clone()) {
+                vars.push(var.clone());
+                body.push(MagicAtom::Unification(Unification {
+                    binding: var.clone(),
+                    expr: Expr::Const {
+                        val: val.clone(),
+                        span,
+                    },
+                    one_many_unif: false,
+                    span,
+                }));
+            }
+        }
+        // ignored positions of the inputs are bound as well, to report the inputs in full
+        let mut n_ignored = 0;
+        for atom in rule.body.iter() {
+            let mut atom = atom.clone();
+            if let MagicAtom::Rule(MagicRuleApplyAtom { args, .. })
+            | MagicAtom::Relation(MagicRelationApplyAtom { args, .. }) = &mut atom
+            {
+                for arg in args.iter_mut() {
+                    if arg.is_generated_ignored_symbol() {
+                        *arg = Symbol::new(&format!("*why{n_ignored}") as &str, arg.span);
+                        n_ignored += 1;
+                    }
+                    if seen.insert(arg.clone()) {
+                        vars.push(arg.clone());
+                    }
+                }
+            }
+            body.push(atom);
+        }
+        let clause = MagicInlineRule {
+            head: rule.head.clone(),
+            aggr: rule.aggr.clone(),
+            body,
+        };
+        let mut relation =
+            self.tx
+                .compile_magic_rule_body(&clause, name, &self.store_arities, &vars)?;
+        relation.fill_binding_indices_and_compile()?;
+
+        for row in relation.iter(self.tx, None, self.stores)? {
+            let row = row?;
+            let bindings: BTreeMap<_, _> = vars.iter().zip(row.iter()).collect();
+            let bind_args = |args: &[Symbol]| -> Tuple {
+                args.iter().map(|arg| bindings[arg].clone()).collect_vec()
+            };
+            if bind_args(&clause.head) != *tuple {
+                continue;
+            }
+            let mut inputs = vec![];
+            let mut justified = true;
+            for atom in clause.body.iter() {
+                match atom {
+                    MagicAtom::Rule(r) => {
+                        let input = bind_args(&r.args);
+                        // inputs derived later cannot justify the tuple, as the
+                        // derivation could then be circular
+                        match self.recorder.rank(&r.name, &input) {
+                            Some(r_rank) if r_rank < rank => {}
+                            _ => {
+                                justified = false;
+                                break;
+                            }
+                        }
+                        inputs.push(DerivationInput::Rule(r.name.clone(), input));
+                    }
+                    MagicAtom::Relation(r) => {
+                        inputs.push(DerivationInput::Relation(
+                            r.name.clone(),
+                            bind_args(&r.args),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            if justified {
+                return Ok(Some(inputs));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Explains how the tuple is derived by the query, as the rows of a derivation tree.
+    pub(crate) fn why_derived(
+        &mut self,
+        prog: InputProgram,
+        tuple: Tuple,
+        poison: Poison,
+    ) -> Result<NamedRows> {
+        // the program is needed both for evaluation and for finding derivations
+        let plain_program = |tx: &SessionTx<'_>, prog: InputProgram| {
+            let (normalized_program, _) = prog.into_normalized_program(tx)?;
+            let (stratified_program, _) = normalized_program.into_stratified_program()?;
+            stratified_program.plain_rewrite(tx)
+        };
+        let program = plain_program(self, prog.clone())?;
+        let compiled_program = plain_program(self, prog)?;
+        let compiled = self.stratified_magic_compile(compiled_program)?;
+        let store_lifetimes = compiled
+            .iter()
+            .flat_map(|stratum| stratum.keys())
+            .map(|name| (name.clone(), usize::MAX))
+            .collect();
+        let mut recorder = DerivationRecorder::default();
+        let (stores, _) = self.evaluate_strata(
+            &compiled,
+            store_lifetimes,
+            None,
+            None,
+            None,
+            poison,
+            Some(&mut recorder),
+        )?;
+
+        let store_arities = program
+            .0
+            .iter()
+            .flat_map(|stratum| stratum.prog.iter())
+            .map(|(name, ruleset)| -> Result<(MagicSymbol, usize)> {
+                Ok((name.clone(), ruleset.arity()?))
+            })
+            .try_collect()?;
+        let entry_symbol = MagicSymbol::Muggle {
+            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+        };
+        if recorder.rank(&entry_symbol, &tuple).is_none() {
+            bail!(WhyTupleNotFound(tuple))
+        }
+        let mut explainer = WhyExplainer {
+            tx: self,
+            rules: program
+                .0
+                .iter()
+                .flat_map(|stratum| stratum.prog.iter())
+                .collect(),
+            store_arities,
+            stores: &stores,
+            recorder: &recorder,
+            justifications: Default::default(),
+            n_nodes: 0,
+        };
+        let tree = explainer.explain(&entry_symbol, tuple)?;
+        let mut rows = vec![];
+        tree.flatten(None, &mut rows);
+        Ok(NamedRows::new(
+            ["id", "parent", "kind", "name", "clause", "tuple"]
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect(),
+            rows,
+        ))
+    }
+}
@@ -6,14 +6,17 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::iter;
 
 use either::{Left, Right};
 use itertools::Itertools;
 use log::{debug, error};
 use miette::{bail, Diagnostic, Result};
+use rustc_hash::FxHashMap;
 use thiserror::Error;
 
 use crate::data::expr::{compute_bounds, eval_bytecode, eval_bytecode_pred, Bytecode, Expr};
@@ -23,8 +26,11 @@ use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleIter};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::SourceSpan;
+use crate::query::wco::WcoJoinRA;
 use crate::runtime::minhash_lsh::LshSearch;
 use crate::runtime::relation::RelationHandle;
+use crate::runtime::rtree::{to_geometry, RTreeSearch};
+use crate::runtime::spill::SpillFile;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 use crate::utils::swap_option_result;
@@ -35,6 +41,7 @@ pub(crate) enum RelAlgebra {
     Stored(StoredRA),
     StoredWithValidity(StoredWithValidityRA),
     Join(Box<InnerJoin>),
+    HashJoin(Box<InnerJoin>),
     NegJoin(Box<NegJoin>),
     Reorder(ReorderRA),
     Filter(FilteredRA),
@@ -42,6 +49,8 @@ pub(crate) enum RelAlgebra {
     HnswSearch(HnswSearchRA),
     FtsSearch(FtsSearchRA),
     LshSearch(LshSearchRA),
+    RTreeSearch(RTreeSearchRA),
+    WcoJoin(WcoJoinRA),
 }
 
 impl RelAlgebra {
@@ -50,7 +59,7 @@ impl RelAlgebra {
             RelAlgebra::Fixed(i) => i.span,
             RelAlgebra::TempStore(i) => i.span,
             RelAlgebra::Stored(i) => i.span,
-            RelAlgebra::Join(i) => i.span,
+            RelAlgebra::Join(i) | RelAlgebra::HashJoin(i) => i.span,
             RelAlgebra::NegJoin(i) => i.span,
             RelAlgebra::Reorder(i) => i.relation.span(),
             RelAlgebra::Filter(i) => i.span,
@@ -59,6 +68,8 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(i) => i.hnsw_search.span,
             RelAlgebra::FtsSearch(i) => i.fts_search.span,
             RelAlgebra::LshSearch(i) => i.lsh_search.span,
+            RelAlgebra::RTreeSearch(i) => i.rtree_search.span,
+            RelAlgebra::WcoJoin(i) => i.span,
         }
     }
 }
@@ -78,7 +89,7 @@ pub(crate) struct UnificationRA {
 #[diagnostic(code(eval::iter_bad_entity_id))]
 struct EntityIdExpected(DataValue, #[label] SourceSpan);
 
-fn eliminate_from_tuple(mut ret: Tuple, eliminate_indices: &BTreeSet<usize>) -> Tuple {
+pub(crate) fn eliminate_from_tuple(mut ret: Tuple, eliminate_indices: &BTreeSet<usize>) -> Tuple {
     if !eliminate_indices.is_empty() {
         ret = ret
             .into_iter()
@@ -298,6 +309,11 @@ impl Debug for RelAlgebra {
                 .field(&bindings)
                 .field(&s.lsh_search.idx_handle.name)
                 .finish(),
+            RelAlgebra::RTreeSearch(s) => f
+                .debug_tuple("RTreeSearch")
+                .field(&bindings)
+                .field(&s.rtree_search.idx_handle.name)
+                .finish(),
             RelAlgebra::StoredWithValidity(r) => f
                 .debug_tuple("StoredWithValidity")
                 .field(&bindings)
@@ -305,6 +321,11 @@ impl Debug for RelAlgebra {
                 .field(&r.filters)
                 .field(&r.valid_at)
                 .finish(),
+            RelAlgebra::WcoJoin(r) => f
+                .debug_tuple("WcoJoin")
+                .field(&bindings)
+                .field(&r.atoms.iter().map(|a| &a.storage.name).collect_vec())
+                .finish(),
             RelAlgebra::Join(r) => {
                 if r.left.is_unit() {
                     r.right.fmt(f)
@@ -317,6 +338,13 @@ impl Debug for RelAlgebra {
                         .finish()
                 }
             }
+            RelAlgebra::HashJoin(r) => f
+                .debug_tuple("HashJoin")
+                .field(&bindings)
+                .field(&r.joiner)
+                .field(&r.left)
+                .field(&r.right)
+                .finish(),
             RelAlgebra::NegJoin(r) => f
                 .debug_tuple("NegJoin")
                 .field(&bindings)
@@ -357,7 +385,7 @@ pub(crate) struct InvalidTimeTravelScanning(pub(crate) String, #[label] pub(crat
 impl RelAlgebra {
     pub(crate) fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
         match self {
-            RelAlgebra::Fixed(_) => {}
+            RelAlgebra::Fixed(_) | RelAlgebra::WcoJoin(_) => {}
             RelAlgebra::TempStore(d) => {
                 d.fill_binding_indices_and_compile()?;
             }
@@ -373,6 +401,9 @@ impl RelAlgebra {
             RelAlgebra::LshSearch(s) => {
                 s.fill_binding_indices_and_compile()?;
             }
+            RelAlgebra::RTreeSearch(s) => {
+                s.fill_binding_indices_and_compile()?;
+            }
             RelAlgebra::StoredWithValidity(v) => {
                 v.fill_binding_indices_and_compile()?;
             }
@@ -390,7 +421,7 @@ impl RelAlgebra {
                 u.parent.fill_binding_indices_and_compile()?;
                 u.fill_binding_indices_and_compile()?
             }
-            RelAlgebra::Join(r) => {
+            RelAlgebra::Join(r) | RelAlgebra::HashJoin(r) => {
                 r.left.fill_binding_indices_and_compile()?;
                 r.right.fill_binding_indices_and_compile()?;
             }
@@ -471,7 +502,9 @@ impl RelAlgebra {
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_)) => {
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::RTreeSearch(_)
+            | RelAlgebra::WcoJoin(_)) => {
                 let span = filter.span();
                 RelAlgebra::Filter(FilteredRA {
                     parent: Box::new(s),
@@ -547,7 +580,12 @@ impl RelAlgebra {
                     filters_bytecodes: filter_bytecodes,
                 })
             }
-            RelAlgebra::Join(inner) => {
+            join @ (RelAlgebra::Join(_) | RelAlgebra::HashJoin(_)) => {
+                let (inner, is_hash_join) = match join {
+                    RelAlgebra::Join(inner) => (inner, false),
+                    RelAlgebra::HashJoin(inner) => (inner, true),
+                    _ => unreachable!(),
+                };
                 let filters = filter.to_conjunction();
                 let left_bindings: BTreeSet<Symbol> =
                     inner.left.bindings_before_eliminate().into_iter().collect();
@@ -575,13 +613,18 @@ impl RelAlgebra {
                         remaining.push(filter);
                     }
                 }
-                let mut joined = RelAlgebra::Join(Box::new(InnerJoin {
+                let inner = Box::new(InnerJoin {
                     left,
                     right,
                     joiner,
                     to_eliminate,
                     span,
-                }));
+                });
+                let mut joined = if is_hash_join {
+                    RelAlgebra::HashJoin(inner)
+                } else {
+                    RelAlgebra::Join(inner)
+                };
                 if !remaining.is_empty() {
                     joined = RelAlgebra::Filter(FilteredRA {
                         parent: Box::new(joined),
@@ -648,6 +691,18 @@ impl RelAlgebra {
             own_bindings,
         }))
     }
+    pub(crate) fn rtree_search(
+        self,
+        rtree_search: RTreeSearch,
+        own_bindings: Vec<Symbol>,
+    ) -> Result<Self> {
+        Ok(Self::RTreeSearch(RTreeSearchRA {
+            parent: Box::new(self),
+            rtree_search,
+            filter_bytecode: None,
+            own_bindings,
+        }))
+    }
     pub(crate) fn join(
         self,
         right: RelAlgebra,
@@ -666,6 +721,24 @@ impl RelAlgebra {
             span,
         }))
     }
+    pub(crate) fn hash_join(
+        self,
+        right: RelAlgebra,
+        left_keys: Vec<Symbol>,
+        right_keys: Vec<Symbol>,
+        span: SourceSpan,
+    ) -> Self {
+        RelAlgebra::HashJoin(Box::new(InnerJoin {
+            left: self,
+            right,
+            joiner: Joiner {
+                left_keys,
+                right_keys,
+            },
+            to_eliminate: Default::default(),
+            span,
+        }))
+    }
     pub(crate) fn neg_join(
         self,
         right: RelAlgebra,
@@ -972,6 +1045,73 @@ impl LshSearchRA {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct RTreeSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) rtree_search: RTreeSearch,
+    pub(crate) filter_bytecode: Option<(Vec<Bytecode>, SourceSpan)>,
+    pub(crate) own_bindings: Vec<Symbol>,
+}
+
+impl RTreeSearchRA {
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        self.parent.fill_binding_indices_and_compile()?;
+        if let Some(filter) = self.rtree_search.filter.as_mut() {
+            let bindings: BTreeMap<_, _> = self
+                .own_bindings
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(a, b)| (b, a))
+                .collect();
+            filter.fill_binding_indices(&bindings)?;
+            self.filter_bytecode = Some((filter.compile()?, filter.span()));
+        }
+        Ok(())
+    }
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let bindings = self.parent.bindings_after_eliminate();
+        let mut bind_idx = usize::MAX;
+        for (i, b) in bindings.iter().enumerate() {
+            if *b == self.rtree_search.query {
+                bind_idx = i;
+                break;
+            }
+        }
+        let config = self.rtree_search.clone();
+        let filter_code = self.filter_bytecode.clone();
+        let extractor = config.manifest.compile_extractor(&config.base_handle)?;
+        let mut stack = vec![];
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Expected a geometry for R-tree search, got {0:?}")]
+        #[diagnostic(code(eval::rtree_query_not_geometry))]
+        struct RTreeQueryNotGeometry(DataValue, #[label] SourceSpan);
+
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<_> {
+                let q = to_geometry(tuple[bind_idx].clone())?
+                    .ok_or_else(|| RTreeQueryNotGeometry(tuple[bind_idx].clone(), config.span))?;
+                let res = tx.rtree_search(&q, &config, &extractor, &mut stack, &filter_code)?;
+                Ok(res.into_iter().map(move |t| {
+                    let mut r = tuple.clone();
+                    r.extend(t);
+                    r
+                }))
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(Box::new(it))
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct FtsSearchRA {
     pub(crate) parent: Box<RelAlgebra>,
@@ -1253,7 +1393,12 @@ impl StoredRA {
         let all_right_val_indices: BTreeSet<usize> =
             (0..val_len).map(|i| left_tuple_len + key_len + i).collect();
         let mut stack = vec![];
-        if self.filters.is_empty() && eliminate_indices.is_superset(&all_right_val_indices) {
+        // bound non-key columns must be compared with the stored values
+        let joins_non_keys = left_to_prefix_indices.len() > key_len;
+        if self.filters.is_empty()
+            && !joins_non_keys
+            && eliminate_indices.is_superset(&all_right_val_indices)
+        {
             let it = left_iter
                 .map_ok(move |tuple| -> Result<Option<Tuple>> {
                     let prefix = left_to_prefix_indices
@@ -1295,6 +1440,9 @@ impl StoredRA {
                     match self.storage.get(tx, key)? {
                         None => Ok(None),
                         Some(found) => {
+                            if joins_non_keys && found[key_len..prefix.len()] != prefix[key_len..] {
+                                return Ok(None);
+                            }
                             for (p, span) in self.filters_bytecodes.iter() {
                                 if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                     return Ok(None);
@@ -1519,7 +1667,7 @@ impl StoredRA {
     }
 }
 
-fn join_is_prefix(right_join_indices: &[usize]) -> bool {
+pub(crate) fn join_is_prefix(right_join_indices: &[usize]) -> bool {
     let mut indices = right_join_indices.to_vec();
     indices.sort();
     let l = indices.len();
@@ -1563,9 +1711,9 @@ impl TempStoreRA {
             Some(name) => *name == self.storage_key,
         };
         let it = if scan_epoch {
-            Left(storage.delta_all_iter().map(|t| Ok(t.into_tuple())))
+            Left(storage.delta_all_iter().map_ok(|t| t.into_tuple()))
         } else {
-            Right(storage.all_iter().map(|t| Ok(t.into_tuple())))
+            Right(storage.all_iter().map_ok(|t| t.into_tuple()))
         };
         Ok(if self.filters.is_empty() {
             Box::new(it)
@@ -1601,6 +1749,7 @@ impl TempStoreRA {
                             .collect_vec();
 
                         'outer: for found in storage.prefix_iter(&prefix) {
+                            let found = found?;
                             for (left_idx, right_idx) in
                                 left_join_indices.iter().zip(right_join_indices.iter())
                             {
@@ -1633,6 +1782,7 @@ impl TempStoreRA {
         } else {
             let mut right_join_vals = BTreeSet::new();
             for tuple in storage.all_iter() {
+                let tuple = tuple?;
                 let to_join: Box<[DataValue]> = right_join_indices
                     .iter()
                     .map(|i| tuple.get(*i).clone())
@@ -1722,10 +1872,10 @@ impl TempStoreRA {
                             it.map(move |res_found| -> Result<Option<Tuple>> {
                                 if self.filters.is_empty() {
                                     let mut ret = tuple.clone();
-                                    ret.extend(res_found.into_iter().cloned());
+                                    ret.extend(res_found?.into_tuple());
                                     Ok(Some(ret))
                                 } else {
-                                    let found = res_found.into_tuple();
+                                    let found = res_found?.into_tuple();
                                     for (p, span) in self.filters_bytecodes.iter() {
                                         if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                             return Ok(None);
@@ -1752,10 +1902,10 @@ impl TempStoreRA {
                     it.map(move |res_found| -> Result<Option<Tuple>> {
                         if self.filters.is_empty() {
                             let mut ret = tuple.clone();
-                            ret.extend(res_found.into_iter().cloned());
+                            ret.extend(res_found?.into_tuple());
                             Ok(Some(ret))
                         } else {
-                            let found = res_found.into_tuple();
+                            let found = res_found?.into_tuple();
                             for (p, span) in self.filters_bytecodes.iter() {
                                 if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                     return Ok(None);
@@ -1835,7 +1985,7 @@ impl RelAlgebra {
             RelAlgebra::TempStore(_r) => Ok(()),
             RelAlgebra::Stored(_v) => Ok(()),
             RelAlgebra::StoredWithValidity(_v) => Ok(()),
-            RelAlgebra::Join(r) => r.do_eliminate_temp_vars(used),
+            RelAlgebra::Join(r) | RelAlgebra::HashJoin(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::Reorder(r) => r.relation.eliminate_temp_vars(used),
             RelAlgebra::Filter(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::NegJoin(r) => r.do_eliminate_temp_vars(used),
@@ -1843,6 +1993,8 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(_) => Ok(()),
             RelAlgebra::FtsSearch(_) => Ok(()),
             RelAlgebra::LshSearch(_) => Ok(()),
+            RelAlgebra::RTreeSearch(_) => Ok(()),
+            RelAlgebra::WcoJoin(_) => Ok(()),
         }
     }
 
@@ -1852,7 +2004,7 @@ impl RelAlgebra {
             RelAlgebra::TempStore(_) => None,
             RelAlgebra::Stored(_) => None,
             RelAlgebra::StoredWithValidity(_) => None,
-            RelAlgebra::Join(r) => Some(&r.to_eliminate),
+            RelAlgebra::Join(r) | RelAlgebra::HashJoin(r) => Some(&r.to_eliminate),
             RelAlgebra::Reorder(_) => None,
             RelAlgebra::Filter(r) => Some(&r.to_eliminate),
             RelAlgebra::NegJoin(r) => Some(&r.to_eliminate),
@@ -1860,6 +2012,8 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(_) => None,
             RelAlgebra::FtsSearch(_) => None,
             RelAlgebra::LshSearch(_) => None,
+            RelAlgebra::RTreeSearch(_) => None,
+            RelAlgebra::WcoJoin(_) => None,
         }
     }
 
@@ -1880,7 +2034,7 @@ impl RelAlgebra {
             RelAlgebra::TempStore(d) => d.bindings.clone(),
             RelAlgebra::Stored(v) => v.bindings.clone(),
             RelAlgebra::StoredWithValidity(v) => v.bindings.clone(),
-            RelAlgebra::Join(j) => j.bindings(),
+            RelAlgebra::Join(j) | RelAlgebra::HashJoin(j) => j.bindings(),
             RelAlgebra::Reorder(r) => r.bindings(),
             RelAlgebra::Filter(r) => r.parent.bindings_after_eliminate(),
             RelAlgebra::NegJoin(j) => j.left.bindings_after_eliminate(),
@@ -1904,6 +2058,12 @@ impl RelAlgebra {
                 bindings.extend_from_slice(&s.own_bindings);
                 bindings
             }
+            RelAlgebra::RTreeSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend_from_slice(&s.own_bindings);
+                bindings
+            }
+            RelAlgebra::WcoJoin(w) => w.bindings.clone(),
         }
     }
     pub(crate) fn iter<'a>(
@@ -1918,6 +2078,7 @@ impl RelAlgebra {
             RelAlgebra::Stored(v) => v.iter(tx),
             RelAlgebra::StoredWithValidity(v) => v.iter(tx),
             RelAlgebra::Join(j) => j.iter(tx, delta_rule, stores),
+            RelAlgebra::HashJoin(j) => j.hash_join(tx, delta_rule, stores),
             RelAlgebra::Reorder(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::Filter(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::NegJoin(r) => r.iter(tx, delta_rule, stores),
@@ -1925,6 +2086,8 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::FtsSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::LshSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::RTreeSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::WcoJoin(w) => w.iter(tx),
         }
     }
 }
@@ -2107,6 +2270,7 @@ impl InnerJoin {
             RelAlgebra::HnswSearch(_) => "hnsw_search_join",
             RelAlgebra::FtsSearch(_) => "fts_search_join",
             RelAlgebra::LshSearch(_) => "lsh_search_join",
+            RelAlgebra::RTreeSearch(_) => "rtree_search_join",
             RelAlgebra::StoredWithValidity(_) => {
                 let join_indices = self
                     .joiner
@@ -2121,9 +2285,24 @@ impl InnerJoin {
                     "stored_mat_join"
                 }
             }
-            RelAlgebra::Join(_) | RelAlgebra::Filter(_) | RelAlgebra::Unification(_) => {
-                "generic_mat_join"
+            RelAlgebra::WcoJoin(_) => {
+                let join_indices = self
+                    .joiner
+                    .join_indices(
+                        &self.left.bindings_after_eliminate(),
+                        &self.right.bindings_after_eliminate(),
+                    )
+                    .unwrap();
+                if join_is_prefix(&join_indices.1) {
+                    "wco_prefix_join"
+                } else {
+                    "generic_mat_join"
+                }
             }
+            RelAlgebra::Join(_)
+            | RelAlgebra::HashJoin(_)
+            | RelAlgebra::Filter(_)
+            | RelAlgebra::Unification(_) => "generic_mat_join",
             RelAlgebra::Reorder(_) => {
                 panic!("joining on reordered")
             }
@@ -2215,12 +2394,33 @@ impl InnerJoin {
                     self.materialized_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
+            RelAlgebra::WcoJoin(r) => {
+                let join_indices = self
+                    .joiner
+                    .join_indices(
+                        &self.left.bindings_after_eliminate(),
+                        &self.right.bindings_after_eliminate(),
+                    )
+                    .unwrap();
+                if join_is_prefix(&join_indices.1) {
+                    r.prefix_join(
+                        tx,
+                        self.left.iter(tx, delta_rule, stores)?,
+                        join_indices,
+                        eliminate_indices,
+                    )
+                } else {
+                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                }
+            }
             RelAlgebra::Join(_)
+            | RelAlgebra::HashJoin(_)
             | RelAlgebra::Filter(_)
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_) => {
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::RTreeSearch(_) => {
                 self.materialized_join(tx, eliminate_indices, delta_rule, stores)
             }
             RelAlgebra::Reorder(_) => {
@@ -2298,6 +2498,139 @@ impl InnerJoin {
         };
         Ok(Box::new(it))
     }
+    /// Builds a hash table of the right side on the join columns and probes it with
+    /// the left tuples. If the right side grows past the spill threshold of temp stores,
+    /// both sides are partitioned by the hash of the join columns into files instead,
+    /// and the partitions are joined one pair at a time.
+    pub(crate) fn hash_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        debug!("using hash join");
+        let eliminate_indices = get_eliminate_indices(&self.bindings(), &self.to_eliminate);
+        let (left_join_indices, right_join_indices) = self
+            .joiner
+            .join_indices(
+                &self.left.bindings_after_eliminate(),
+                &self.right.bindings_after_eliminate(),
+            )
+            .unwrap();
+
+        let mut table: FxHashMap<Tuple, Vec<Tuple>> = FxHashMap::default();
+        let mut n_built = 0;
+        let mut right_partitions: Option<Vec<SpillFile>> = None;
+        for tuple in self.right.iter(tx, delta_rule, stores)? {
+            let tuple = tuple?;
+            let key = right_join_indices
+                .iter()
+                .map(|i| tuple[*i].clone())
+                .collect_vec();
+            if let Some(partitions) = &mut right_partitions {
+                partitions[hash_partition(&key)].push(&tuple)?;
+                continue;
+            }
+            table.entry(key).or_default().push(tuple);
+            n_built += 1;
+            if tx.temp_spill_threshold != 0 && n_built > tx.temp_spill_threshold {
+                let mut partitions: Vec<_> = (0..HASH_JOIN_PARTITIONS)
+                    .map(|_| SpillFile::new())
+                    .try_collect()?;
+                for (key, tuples) in table.drain() {
+                    let partition = &mut partitions[hash_partition(&key)];
+                    for tuple in tuples.iter() {
+                        partition.push(tuple)?;
+                    }
+                }
+                right_partitions = Some(partitions);
+            }
+        }
+
+        let left_iter = self.left.iter(tx, delta_rule, stores)?;
+        let right_partitions = match right_partitions {
+            None => {
+                let it = left_iter
+                    .map_ok(move |tuple| {
+                        probe_hash_table(&table, tuple, &left_join_indices, &eliminate_indices)
+                    })
+                    .flatten_ok();
+                return Ok(Box::new(it));
+            }
+            Some(partitions) => partitions,
+        };
+
+        debug!("hash join spilled to disk");
+        let mut left_partitions: Vec<_> = (0..HASH_JOIN_PARTITIONS)
+            .map(|_| SpillFile::new())
+            .try_collect()?;
+        for tuple in left_iter {
+            let tuple = tuple?;
+            let key = left_join_indices
+                .iter()
+                .map(|i| tuple[*i].clone())
+                .collect_vec();
+            left_partitions[hash_partition(&key)].push(&tuple)?;
+        }
+        let it = left_partitions
+            .into_iter()
+            .zip(right_partitions)
+            .map(move |(left, right)| -> Result<Vec<Tuple>> {
+                let mut table: FxHashMap<Tuple, Vec<Tuple>> = FxHashMap::default();
+                for tuple in right.into_tuples()? {
+                    let tuple = tuple?;
+                    let key = right_join_indices
+                        .iter()
+                        .map(|i| tuple[*i].clone())
+                        .collect_vec();
+                    table.entry(key).or_default().push(tuple);
+                }
+                let mut ret = vec![];
+                for tuple in left.into_tuples()? {
+                    ret.extend(probe_hash_table(
+                        &table,
+                        tuple?,
+                        &left_join_indices,
+                        &eliminate_indices,
+                    ));
+                }
+                Ok(ret)
+            })
+            .flatten_ok();
+        Ok(Box::new(it))
+    }
+}
+
+/// number of partitions both sides of a hash join are split into when spilling
+const HASH_JOIN_PARTITIONS: usize = 16;
+
+fn hash_partition(key: &[DataValue]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % HASH_JOIN_PARTITIONS as u64) as usize
+}
+
+fn probe_hash_table(
+    table: &FxHashMap<Tuple, Vec<Tuple>>,
+    tuple: Tuple,
+    left_join_indices: &[usize],
+    eliminate_indices: &BTreeSet<usize>,
+) -> Vec<Tuple> {
+    let key = left_join_indices
+        .iter()
+        .map(|i| tuple[*i].clone())
+        .collect_vec();
+    match table.get(&key) {
+        None => vec![],
+        Some(found) => found
+            .iter()
+            .map(|right| {
+                let mut ret = tuple.clone();
+                ret.extend_from_slice(right);
+                eliminate_from_tuple(ret, eliminate_indices)
+            })
+            .collect_vec(),
+    }
 }
 
 struct CachedMaterializedIterator<'a> {
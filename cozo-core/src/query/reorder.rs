@@ -96,6 +96,14 @@ impl NormalFormInlineRule {
                         pending.push(NormalFormAtom::LshSearch(s));
                     }
                 }
+                NormalFormAtom::RTreeSearch(s) => {
+                    if seen_variables.contains(&s.query) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::RTreeSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::RTreeSearch(s));
+                    }
+                }
             }
         }
 
@@ -136,6 +144,10 @@ impl NormalFormInlineRule {
                     seen_variables.extend(s.all_bindings().cloned());
                     collected.push(NormalFormAtom::LshSearch(s));
                 }
+                NormalFormAtom::RTreeSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::RTreeSearch(s));
+                }
             }
             for atom in last_pending.iter() {
                 match atom {
@@ -178,6 +190,14 @@ impl NormalFormInlineRule {
                             pending.push(NormalFormAtom::LshSearch(s.clone()));
                         }
                     }
+                    NormalFormAtom::RTreeSearch(s) => {
+                        if seen_variables.contains(&s.query) {
+                            seen_variables.extend(s.all_bindings().cloned());
+                            collected.push(NormalFormAtom::RTreeSearch(s.clone()));
+                        } else {
+                            pending.push(NormalFormAtom::RTreeSearch(s.clone()));
+                        }
+                    }
                     NormalFormAtom::Predicate(p) => {
                         if p.bindings()?.is_subset(&seen_variables) {
                             collected.push(NormalFormAtom::Predicate(p.clone()));
@@ -229,6 +249,9 @@ impl NormalFormInlineRule {
                     NormalFormAtom::LshSearch(s) => {
                         bail!(UnboundVariable(s.span))
                     }
+                    NormalFormAtom::RTreeSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
                 }
             }
         }
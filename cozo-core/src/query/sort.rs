@@ -6,8 +6,8 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet};
 
 use itertools::Itertools;
 use miette::Result;
@@ -15,23 +15,34 @@ use miette::Result;
 use crate::data::program::SortDir;
 use crate::data::symb::Symbol;
 use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
 impl<'a> SessionTx<'a> {
+    /// Sorts the tuples of the store. With `limit`, only the first `limit` tuples in order
+    /// are kept while going through the store, instead of sorting all of them.
     pub(crate) fn sort_and_collect(
         &mut self,
         original: EpochStore,
         sorters: &[(Symbol, SortDir)],
         head: &[Symbol],
+        limit: Option<usize>,
     ) -> Result<Vec<Tuple>> {
-        let head_indices: BTreeMap<_, _> = head.iter().enumerate().map(|(i, k)| (k, i)).collect();
-        let idx_sorters = sorters
-            .iter()
-            .map(|(k, dir)| (head_indices[k], *dir))
-            .collect_vec();
+        let idx_sorters = sorter_indices(sorters, head);
+
+        if let Some(k) = limit {
+            let mut top_k = TopK::new(idx_sorters, k);
+            for tuple in original.all_iter() {
+                top_k.offer(&tuple?.into_tuple());
+            }
+            return Ok(top_k.into_sorted());
+        }
 
-        let mut all_data: Vec<_> = original.all_iter().map(|v| v.into_tuple()).collect_vec();
+        let mut all_data: Vec<_> = original
+            .all_iter()
+            .map_ok(|v| v.into_tuple())
+            .try_collect()?;
         all_data.sort_by(|a, b| {
             for (idx, dir) in &idx_sorters {
                 match a[*idx].cmp(&b[*idx]) {
@@ -50,3 +61,73 @@ impl<'a> SessionTx<'a> {
         Ok(all_data)
     }
 }
+
+pub(crate) fn sorter_indices(
+    sorters: &[(Symbol, SortDir)],
+    head: &[Symbol],
+) -> Vec<(usize, SortDir)> {
+    let head_indices: BTreeMap<_, _> = head.iter().enumerate().map(|(i, k)| (k, i)).collect();
+    sorters
+        .iter()
+        .map(|(k, dir)| (head_indices[k], *dir))
+        .collect_vec()
+}
+
+/// A value of a sorted column, ordered in the direction of the sorter
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortKeyPart {
+    Asc(DataValue),
+    Dsc(Reverse<DataValue>),
+}
+
+/// The first `k` distinct tuples offered, in the order given by the sorters.
+/// Ties are broken by the tuples themselves, as the stores are iterated in
+/// tuple order and the full sort is stable.
+pub(crate) struct TopK {
+    sorters: Vec<(usize, SortDir)>,
+    k: usize,
+    kept: BTreeSet<(Vec<SortKeyPart>, Tuple)>,
+}
+
+impl TopK {
+    pub(crate) fn new(sorters: Vec<(usize, SortDir)>, k: usize) -> Self {
+        Self {
+            sorters,
+            k,
+            kept: Default::default(),
+        }
+    }
+
+    /// Returns false if the tuple is certainly not among the first `k`
+    pub(crate) fn offer(&mut self, tuple: &[DataValue]) -> bool {
+        if self.k == 0 {
+            return false;
+        }
+        let key = self
+            .sorters
+            .iter()
+            .map(|(idx, dir)| match dir {
+                SortDir::Asc => SortKeyPart::Asc(tuple[*idx].clone()),
+                SortDir::Dsc => SortKeyPart::Dsc(Reverse(tuple[*idx].clone())),
+            })
+            .collect_vec();
+        let entry = (key, tuple.to_vec());
+        if self.kept.len() == self.k {
+            let worst = self.kept.last().unwrap();
+            if entry > *worst {
+                return false;
+            }
+            if entry == *worst {
+                return true;
+            }
+        }
+        if self.kept.insert(entry) && self.kept.len() > self.k {
+            self.kept.pop_last();
+        }
+        true
+    }
+
+    pub(crate) fn into_sorted(self) -> Vec<Tuple> {
+        self.kept.into_iter().map(|(_, tuple)| tuple).collect_vec()
+    }
+}
@@ -25,13 +25,16 @@ use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::FixedRuleHandle;
 use crate::fts::tokenizer::TextAnalyzer;
 use crate::parse::expr::build_expr;
-use crate::parse::{parse_script, CozoScriptParser, Rule};
+use crate::parse::sys::SysOp;
+use crate::parse::{parse_script, CozoScript, CozoScriptParser, Rule};
 use crate::runtime::callback::{CallbackCollector, CallbackOp};
 use crate::runtime::minhash_lsh::HashPermutations;
 use crate::runtime::relation::{
-    extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
+    extend_tuple_from_v, AccessLevel, CompiledCheck, InputRelationHandle, InsufficientAccessLevel,
+    RelationConstraints, RelationHandle,
 };
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::{RowChanges, WriteToView};
 use crate::storage::Storage;
 use crate::{Db, NamedRows, SourceSpan, StoreTx};
 
@@ -40,11 +43,117 @@ use crate::{Db, NamedRows, SourceSpan, StoreTx};
 #[diagnostic(code(eval::relation_arity_mismatch))]
 struct RelationArityMismatch(String, usize, usize);
 
+/// Compiled expressions and filter of each expression index
+type ExprIndexExtractors =
+    BTreeMap<SmartString<LazyCompact>, (Vec<Vec<Bytecode>>, Option<Vec<Bytecode>>)>;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("unique constraint on column {1} of relation {0} violated by value {2}")]
+#[diagnostic(code(eval::unique_violation))]
+struct UniqueConstraintViolation(String, String, DataValue, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "foreign key constraint on column {1} of relation {0} violated: {3} is not a key of relation {2}"
+)]
+#[diagnostic(code(eval::foreign_key_violation))]
+struct ForeignKeyViolation(String, String, String, DataValue, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("cannot remove key {1} from relation {0}: still referenced by column {3} of relation {2}")]
+#[diagnostic(code(eval::foreign_key_still_referenced))]
+struct ForeignKeyStillReferenced(String, DataValue, String, String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("check constraint `{1}` of relation {0} violated by row {2}")]
+#[diagnostic(code(eval::check_violation))]
+pub(crate) struct CheckConstraintViolation(
+    pub(crate) String,
+    pub(crate) String,
+    pub(crate) DataValue,
+    #[label] pub(crate) SourceSpan,
+);
+
+/// What is needed for checking the constraints of a relation during mutations
+#[derive(Default)]
+struct ConstraintCheckers {
+    /// column name and position, together with the index backing the constraint
+    unique: Vec<(SmartString<LazyCompact>, usize, RelationHandle, Vec<usize>)>,
+    /// column name and position, together with the referenced relation,
+    /// which is `None` if the relation references itself, and the index backing the constraint
+    references: Vec<(
+        SmartString<LazyCompact>,
+        usize,
+        Option<RelationHandle>,
+        RelationHandle,
+    )>,
+    /// referencing relation and column, together with the index backing the constraint
+    referenced_by: Vec<(
+        SmartString<LazyCompact>,
+        SmartString<LazyCompact>,
+        RelationHandle,
+        Vec<usize>,
+    )>,
+    checks: Vec<CompiledCheck>,
+}
+
 impl<'a> SessionTx<'a> {
+    /// Runs a trigger, which is either a query or a call of a stored procedure,
+    /// with `inputs` bound to constant rules.
+    fn run_trigger<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        trigger: &str,
+        inputs: Vec<(&str, Vec<Symbol>, Vec<DataValue>)>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let script = parse_script(
+            trigger,
+            &Default::default(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )?;
+        let res = match script {
+            CozoScript::Sys(SysOp::CallProcedure(name, args)) => db.call_procedure_in_tx(
+                self,
+                &name,
+                args,
+                &inputs,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+            ),
+            script => {
+                let mut program = script.get_single_program()?;
+                for (name, bindings, data) in inputs {
+                    make_const_rule(&mut program, name, bindings, data);
+                }
+                db.run_query(
+                    self,
+                    program,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    false,
+                )
+                .map(|(_, cleanups)| cleanups)
+            }
+        };
+        res.map_err(|err| {
+            if err.source_code().is_some() {
+                err
+            } else {
+                err.with_source_code(trigger.to_string())
+            }
+        })
+    }
+
     pub(crate) fn execute_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         op: RelationOp,
         meta: &InputRelationHandle,
         headers: &[Symbol],
@@ -55,6 +164,7 @@ impl<'a> SessionTx<'a> {
     ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let mut to_clear = vec![];
         let mut replaced_old_triggers = None;
+        self.written_relations.insert(meta.name.name.clone());
         if op == RelationOp::Replace {
             if !propagate_triggers {
                 #[derive(Debug, Error, Diagnostic)]
@@ -64,7 +174,10 @@ impl<'a> SessionTx<'a> {
                 bail!(ReplaceInTrigger(meta.name.to_string()))
             }
             if let Ok(old_handle) = self.get_relation(&meta.name, true) {
-                if !old_handle.indices.is_empty() {
+                if old_handle.view.is_some() {
+                    bail!(WriteToView(old_handle.name.to_string()))
+                }
+                if !old_handle.has_no_index_besides_constraints() {
                     #[derive(Debug, Error, Diagnostic)]
                     #[error("cannot replace relation {0} since it has indices")]
                     #[diagnostic(code(eval::replace_rel_with_indices))]
@@ -82,30 +195,14 @@ impl<'a> SessionTx<'a> {
                     replaced_old_triggers = Some((old_handle.put_triggers, old_handle.rm_triggers))
                 }
                 for trigger in &old_handle.replace_triggers {
-                    let program = parse_script(
+                    let cleanups = self.run_trigger(
+                        db,
                         trigger,
-                        &Default::default(),
-                        &db.fixed_rules.read().unwrap(),
+                        vec![],
                         cur_vld,
-                    )?
-                    .get_single_program()?;
-
-                    let (_, cleanups) = db
-                        .run_query(
-                            self,
-                            program,
-                            cur_vld,
-                            callback_targets,
-                            callback_collector,
-                            false,
-                        )
-                        .map_err(|err| {
-                            if err.source_code().is_some() {
-                                err
-                            } else {
-                                err.with_source_code(trigger.to_string())
-                            }
-                        })?;
+                        callback_targets,
+                        callback_collector,
+                    )?;
                     to_clear.extend(cleanups);
                 }
                 let destroy_res = self.destroy_relation(&meta.name)?;
@@ -119,6 +216,9 @@ impl<'a> SessionTx<'a> {
         } else {
             self.get_relation(&meta.name, false)?
         };
+        if relation_store.view.is_some() {
+            bail!(WriteToView(relation_store.name.to_string()))
+        }
         if let Some((old_put, old_retract)) = replaced_old_triggers {
             relation_store.put_triggers = old_put;
             relation_store.rm_triggers = old_retract;
@@ -203,7 +303,7 @@ impl<'a> SessionTx<'a> {
     fn put_into_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -236,12 +336,17 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
                 || (propagate_triggers && !relation_store.put_triggers.is_empty()));
+        let track_changes = !relation_store.dependent_views.is_empty();
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
+        let has_expr_indices = !relation_store.expr_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
+        let mut changes = RowChanges::default();
+        let n_keys = relation_store.metadata.keys.len();
 
         let val_extractors = make_extractors(
             &relation_store.metadata.non_keys,
@@ -254,8 +359,12 @@ impl<'a> SessionTx<'a> {
         let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
+        let expr_index_extractors = Self::make_expr_index_extractors(relation_store)?;
+        let constraint_checkers = self.make_constraint_checkers(relation_store)?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -263,34 +372,59 @@ impl<'a> SessionTx<'a> {
 
             let key = relation_store.encode_key_for_store(&extracted, span)?;
             let val = relation_store.encode_val_for_store(&extracted, span)?;
+            self.check_put_constraints(
+                relation_store,
+                &constraint_checkers,
+                &extracted,
+                &mut stack,
+                span,
+            )?;
 
             if need_to_collect
+                || track_changes
                 || has_indices
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_rtree_indices
+                || has_expr_indices
             {
                 if let Some(existing) = self.store_tx.get(&key, false)? {
                     let mut tup = extracted[0..relation_store.metadata.keys.len()].to_vec();
                     extend_tuple_from_v(&mut tup, &existing);
+                    if track_changes {
+                        changes.record(n_keys, Some(&tup), Some(&extracted));
+                    }
                     if has_indices && extracted != tup {
                         self.update_in_index(relation_store, &extracted, &tup)?;
                         self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &tup)?;
                         self.del_in_lsh(relation_store, &tup)?;
                     }
+                    self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &tup)?;
+                    self.del_in_expr_index(
+                        relation_store,
+                        &mut stack,
+                        &expr_index_extractors,
+                        &tup,
+                    )?;
 
                     if need_to_collect {
                         old_tuples.push(DataValue::List(tup));
                     }
-                } else if has_indices {
-                    for (idx_rel, extractor) in relation_store.indices.values() {
-                        let idx_tup_new = extractor
-                            .iter()
-                            .map(|i| extracted[*i].clone())
-                            .collect_vec();
-                        let encoded_new =
-                            idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
-                        self.store_tx.put(&encoded_new, &[])?;
+                } else {
+                    if track_changes {
+                        changes.record(n_keys, None, Some(&extracted));
+                    }
+                    if has_indices {
+                        for (idx_rel, extractor) in relation_store.indices.values() {
+                            let idx_tup_new = extractor
+                                .iter()
+                                .map(|i| extracted[*i].clone())
+                                .collect_vec();
+                            let encoded_new =
+                                idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
+                            self.store_tx.put(&encoded_new, &[])?;
+                        }
                     }
                 }
 
@@ -303,6 +437,13 @@ impl<'a> SessionTx<'a> {
                     &extracted,
                     &lsh_perms,
                 )?;
+                self.put_in_rtree(relation_store, &mut stack, &rtree_extractors, &extracted)?;
+                self.put_in_expr_index(
+                    relation_store,
+                    &mut stack,
+                    &expr_index_extractors,
+                    &extracted,
+                )?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(extracted));
@@ -316,6 +457,16 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        self.refresh_dependent_views(
+            db,
+            relation_store,
+            changes,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            to_clear,
+        )?;
+
         if need_to_collect && !new_tuples.is_empty() {
             self.collect_mutations(
                 db,
@@ -393,6 +544,66 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    fn put_in_rtree(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>,
+        new_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, manifest)) in rel_handle.rtree_indices.iter() {
+            let extractor = extractors.get(k).unwrap();
+            self.put_rtree_index_item(new_kv, extractor, stack, rel_handle, idx_handle, manifest)?;
+        }
+        Ok(())
+    }
+
+    fn del_in_rtree(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>,
+        old_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, _)) in rel_handle.rtree_indices.iter() {
+            let extractor = extractors.get(k).unwrap();
+            self.del_rtree_index_item(old_kv, extractor, stack, rel_handle, idx_handle)?;
+        }
+        Ok(())
+    }
+
+    fn put_in_expr_index(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &ExprIndexExtractors,
+        new_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, manifest)) in rel_handle.expr_indices.iter() {
+            let (elements, filter) = extractors.get(k).unwrap();
+            self.put_expr_index_item(
+                new_kv, elements, filter, stack, rel_handle, idx_handle, manifest,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn del_in_expr_index(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &ExprIndexExtractors,
+        old_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, manifest)) in rel_handle.expr_indices.iter() {
+            let (elements, filter) = extractors.get(k).unwrap();
+            self.del_expr_index_item(
+                old_kv, elements, filter, stack, rel_handle, idx_handle, manifest,
+            )?;
+        }
+        Ok(())
+    }
+
     fn update_in_hnsw(
         &mut self,
         relation_store: &RelationHandle,
@@ -467,6 +678,185 @@ impl<'a> SessionTx<'a> {
         Ok(processors)
     }
 
+    fn make_rtree_extractors(
+        relation_store: &RelationHandle,
+    ) -> Result<BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>> {
+        let mut extractors = BTreeMap::new();
+        for (name, (_, manifest)) in relation_store.rtree_indices.iter() {
+            extractors.insert(name.clone(), manifest.compile_extractor(relation_store)?);
+        }
+        Ok(extractors)
+    }
+
+    fn make_expr_index_extractors(relation_store: &RelationHandle) -> Result<ExprIndexExtractors> {
+        let mut extractors = BTreeMap::new();
+        for (name, (_, manifest)) in relation_store.expr_indices.iter() {
+            extractors.insert(name.clone(), manifest.compile(relation_store)?);
+        }
+        Ok(extractors)
+    }
+
+    fn make_constraint_checkers(
+        &self,
+        relation_store: &RelationHandle,
+    ) -> Result<ConstraintCheckers> {
+        let mut checkers = ConstraintCheckers::default();
+        let col_pos = |col: &str| {
+            relation_store
+                .metadata
+                .keys
+                .iter()
+                .chain(relation_store.metadata.non_keys.iter())
+                .position(|c| c.name == col)
+                .unwrap()
+        };
+        for col in &relation_store.constraints.unique {
+            let idx_name = RelationConstraints::unique_index_name(col);
+            let (idx_handle, extractor) = relation_store.indices.get(&idx_name).unwrap();
+            checkers.unique.push((
+                col.clone(),
+                col_pos(col),
+                idx_handle.clone(),
+                extractor.clone(),
+            ));
+        }
+        for (col, target) in &relation_store.constraints.references {
+            let target_handle = if *target == relation_store.name {
+                None
+            } else {
+                Some(self.get_relation(target, false)?)
+            };
+            let idx_name = RelationConstraints::references_index_name(col);
+            let (idx_handle, _) = relation_store.indices.get(&idx_name).unwrap();
+            checkers.references.push((
+                col.clone(),
+                col_pos(col),
+                target_handle,
+                idx_handle.clone(),
+            ));
+        }
+        for (referencing, col) in &relation_store.referenced_by {
+            let referencing_handle = if *referencing == relation_store.name {
+                relation_store.clone()
+            } else {
+                self.get_relation(referencing, false)?
+            };
+            let idx_name = RelationConstraints::references_index_name(col);
+            let (idx_handle, extractor) = referencing_handle.indices.get(&idx_name).unwrap();
+            checkers.referenced_by.push((
+                referencing.clone(),
+                col.clone(),
+                idx_handle.clone(),
+                extractor.clone(),
+            ));
+        }
+        checkers.checks = relation_store.constraints.compile_checks(relation_store)?;
+        Ok(checkers)
+    }
+
+    /// Checks the constraints for a row about to be written.
+    /// Must be called before the indices are updated for the row.
+    ///
+    /// The indices are scanned without being tracked for conflicts, so each check
+    /// also locks the prefix it scans under: concurrent writers checking the same
+    /// value then conflict instead of both passing.
+    fn check_put_constraints(
+        &mut self,
+        relation_store: &RelationHandle,
+        checkers: &ConstraintCheckers,
+        tuple: &[DataValue],
+        stack: &mut Vec<DataValue>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        for check in &checkers.checks {
+            check.check(&relation_store.name, tuple, stack, span)?;
+        }
+        for (col, pos, idx_handle, extractor) in &checkers.unique {
+            let val = &tuple[*pos];
+            if *val == DataValue::Null {
+                continue;
+            }
+            idx_handle.lock_prefix(self, std::slice::from_ref(val))?;
+            for found in idx_handle.scan_prefix(self, &vec![val.clone()]) {
+                let found = found?;
+                // the entry for the row itself, if it exists, is not a violation
+                let same_row = extractor
+                    .iter()
+                    .zip(found.iter())
+                    .all(|(i, v)| tuple[*i] == *v);
+                if !same_row {
+                    bail!(UniqueConstraintViolation(
+                        relation_store.name.to_string(),
+                        col.to_string(),
+                        val.clone(),
+                        span
+                    ))
+                }
+            }
+        }
+        for (col, pos, target, idx_handle) in &checkers.references {
+            let val = &tuple[*pos];
+            if *val == DataValue::Null {
+                continue;
+            }
+            let key = vec![val.clone()];
+            // the same lock is taken when removing the referenced key
+            idx_handle.lock_prefix(self, &key)?;
+            let found = match target {
+                Some(target) => target.exists_for_update(self, &key)?,
+                None => tuple[0] == *val || relation_store.exists_for_update(self, &key)?,
+            };
+            if !found {
+                bail!(ForeignKeyViolation(
+                    relation_store.name.to_string(),
+                    col.to_string(),
+                    target
+                        .as_ref()
+                        .map(|t| t.name.to_string())
+                        .unwrap_or_else(|| relation_store.name.to_string()),
+                    val.clone(),
+                    span
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that no row of any relation still references the key about to be removed
+    fn check_rm_constraints(
+        &mut self,
+        relation_store: &RelationHandle,
+        checkers: &ConstraintCheckers,
+        key: &[DataValue],
+        span: SourceSpan,
+    ) -> Result<()> {
+        for (referencing, col, idx_handle, extractor) in &checkers.referenced_by {
+            idx_handle.lock_prefix(self, &key[..1])?;
+            for found in idx_handle.scan_prefix(self, &vec![key[0].clone()]) {
+                let found = found?;
+                // a row referencing itself does not prevent its own removal
+                if *referencing == relation_store.name {
+                    let referencing_key = extractor
+                        .iter()
+                        .zip(found.iter())
+                        .find(|(i, _)| **i == 0)
+                        .map(|(_, v)| v);
+                    if referencing_key == Some(&key[0]) {
+                        continue;
+                    }
+                }
+                bail!(ForeignKeyStillReferenced(
+                    relation_store.name.to_string(),
+                    key[0].clone(),
+                    referencing.to_string(),
+                    col.to_string(),
+                    span
+                ))
+            }
+        }
+        Ok(())
+    }
+
     fn make_hnsw_filters(
         relation_store: &RelationHandle,
     ) -> Result<BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>> {
@@ -489,7 +879,7 @@ impl<'a> SessionTx<'a> {
     fn update_in_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -522,12 +912,17 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
                 || (propagate_triggers && !relation_store.put_triggers.is_empty()));
+        let track_changes = !relation_store.dependent_views.is_empty();
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
+        let has_expr_indices = !relation_store.expr_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
+        let mut changes = RowChanges::default();
+        let n_keys = relation_store.metadata.keys.len();
 
         let val_extractors = make_update_extractors(
             &relation_store.metadata.non_keys,
@@ -540,8 +935,12 @@ impl<'a> SessionTx<'a> {
         let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
+        let expr_index_extractors = Self::make_expr_index_extractors(relation_store)?;
+        let constraint_checkers = self.make_constraint_checkers(relation_store)?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let mut new_kv: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -579,15 +978,34 @@ impl<'a> SessionTx<'a> {
                 }
             }
             let new_val = relation_store.encode_val_for_store(&new_kv, span)?;
+            self.check_put_constraints(
+                relation_store,
+                &constraint_checkers,
+                &new_kv,
+                &mut stack,
+                span,
+            )?;
+            if track_changes {
+                changes.record(n_keys, Some(&old_kv), Some(&new_kv));
+            }
 
             if need_to_collect
                 || has_indices
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_rtree_indices
+                || has_expr_indices
             {
                 self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &old_kv)?;
                 self.del_in_lsh(relation_store, &old_kv)?;
+                self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &old_kv)?;
+                self.del_in_expr_index(
+                    relation_store,
+                    &mut stack,
+                    &expr_index_extractors,
+                    &old_kv,
+                )?;
                 self.update_in_index(relation_store, &new_kv, &old_kv)?;
 
                 if need_to_collect {
@@ -603,6 +1021,13 @@ impl<'a> SessionTx<'a> {
                     &new_kv,
                     &lsh_perms,
                 )?;
+                self.put_in_rtree(relation_store, &mut stack, &rtree_extractors, &new_kv)?;
+                self.put_in_expr_index(
+                    relation_store,
+                    &mut stack,
+                    &expr_index_extractors,
+                    &new_kv,
+                )?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(new_kv));
@@ -616,6 +1041,16 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        self.refresh_dependent_views(
+            db,
+            relation_store,
+            changes,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            to_clear,
+        )?;
+
         if need_to_collect && !new_tuples.is_empty() {
             self.collect_mutations(
                 db,
@@ -662,43 +1097,17 @@ impl<'a> SessionTx<'a> {
         let kv_bindings = bindings;
         if propagate_triggers {
             for trigger in &relation_store.put_triggers {
-                let mut program = parse_script(
+                let cleanups = self.run_trigger(
+                    db,
                     trigger,
-                    &Default::default(),
-                    &db.fixed_rules.read().unwrap(),
+                    vec![
+                        ("_new", kv_bindings.clone(), new_tuples.to_vec()),
+                        ("_old", kv_bindings.clone(), old_tuples.to_vec()),
+                    ],
                     cur_vld,
-                )?
-                .get_single_program()?;
-
-                make_const_rule(
-                    &mut program,
-                    "_new",
-                    kv_bindings.clone(),
-                    new_tuples.to_vec(),
-                );
-                make_const_rule(
-                    &mut program,
-                    "_old",
-                    kv_bindings.clone(),
-                    old_tuples.to_vec(),
-                );
-
-                let (_, cleanups) = db
-                    .run_query(
-                        self,
-                        program,
-                        cur_vld,
-                        callback_targets,
-                        callback_collector,
-                        false,
-                    )
-                    .map_err(|err| {
-                        if err.source_code().is_some() {
-                            err
-                        } else {
-                            err.with_source_code(trigger.to_string())
-                        }
-                    })?;
+                    callback_targets,
+                    callback_collector,
+                )?;
                 to_clear.extend(cleanups);
             }
         }
@@ -764,7 +1173,7 @@ impl<'a> SessionTx<'a> {
 
     fn ensure_not_in_relation(
         &mut self,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         relation_store: &mut RelationHandle,
@@ -788,6 +1197,7 @@ impl<'a> SessionTx<'a> {
         )?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -811,7 +1221,7 @@ impl<'a> SessionTx<'a> {
 
     fn ensure_in_relation(
         &mut self,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         relation_store: &mut RelationHandle,
@@ -844,6 +1254,7 @@ impl<'a> SessionTx<'a> {
         key_extractors.extend(val_extractors);
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -882,7 +1293,7 @@ impl<'a> SessionTx<'a> {
     fn remove_from_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -913,26 +1324,53 @@ impl<'a> SessionTx<'a> {
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
                 || (propagate_triggers && !relation_store.rm_triggers.is_empty()));
+        let track_changes = !relation_store.dependent_views.is_empty();
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
+        let has_expr_indices = !relation_store.expr_indices.is_empty();
         let fts_processors = self.make_fts_lsh_processors(relation_store)?;
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
+        let expr_index_extractors = Self::make_expr_index_extractors(relation_store)?;
+        let constraint_checkers = self.make_constraint_checkers(relation_store)?;
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
+        let mut changes = RowChanges::default();
+        let n_keys = relation_store.metadata.keys.len();
         let mut stack = vec![];
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
                 .try_collect()?;
             let key = relation_store.encode_key_for_store(&extracted, span)?;
-            if need_to_collect || has_indices || has_hnsw_indices || has_fts_indices {
+            self.check_rm_constraints(relation_store, &constraint_checkers, &extracted, span)?;
+            if need_to_collect
+                || track_changes
+                || has_indices
+                || has_hnsw_indices
+                || has_fts_indices
+                || has_rtree_indices
+                || has_expr_indices
+            {
                 if let Some(existing) = self.store_tx.get(&key, false)? {
                     let mut tup = extracted.clone();
                     extend_tuple_from_v(&mut tup, &existing);
+                    if track_changes {
+                        changes.record(n_keys, Some(&tup), None);
+                    }
                     self.del_in_fts(relation_store, &mut stack, &fts_processors, &tup)?;
                     self.del_in_lsh(relation_store, &tup)?;
+                    self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &tup)?;
+                    self.del_in_expr_index(
+                        relation_store,
+                        &mut stack,
+                        &expr_index_extractors,
+                        &tup,
+                    )?;
                     if has_indices {
                         for (idx_rel, extractor) in relation_store.indices.values() {
                             let idx_tup = extractor.iter().map(|i| tup[*i].clone()).collect_vec();
@@ -961,6 +1399,16 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        self.refresh_dependent_views(
+            db,
+            relation_store,
+            changes,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            to_clear,
+        )?;
+
         // triggers and callbacks
         if need_to_collect && !new_tuples.is_empty() {
             let k_bindings = relation_store
@@ -981,39 +1429,17 @@ impl<'a> SessionTx<'a> {
 
             if propagate_triggers {
                 for trigger in &relation_store.rm_triggers {
-                    let mut program = parse_script(
+                    let cleanups = self.run_trigger(
+                        db,
                         trigger,
-                        &Default::default(),
-                        &db.fixed_rules.read().unwrap(),
+                        vec![
+                            ("_new", k_bindings.clone(), new_tuples.clone()),
+                            ("_old", kv_bindings.clone(), old_tuples.clone()),
+                        ],
                         cur_vld,
-                    )?
-                    .get_single_program()?;
-
-                    make_const_rule(&mut program, "_new", k_bindings.clone(), new_tuples.clone());
-
-                    make_const_rule(
-                        &mut program,
-                        "_old",
-                        kv_bindings.clone(),
-                        old_tuples.clone(),
-                    );
-
-                    let (_, cleanups) = db
-                        .run_query(
-                            self,
-                            program,
-                            cur_vld,
-                            callback_targets,
-                            callback_collector,
-                            false,
-                        )
-                        .map_err(|err| {
-                            if err.source_code().is_some() {
-                                err
-                            } else {
-                                err.with_source_code(trigger.to_string())
-                            }
-                        })?;
+                        callback_targets,
+                        callback_collector,
+                    )?;
                     to_clear.extend(cleanups);
                 }
             }
@@ -1136,7 +1562,7 @@ fn make_extractor(
     }
 }
 
-fn make_const_rule(
+pub(crate) fn make_const_rule(
     program: &mut InputProgram,
     rule_name: &str,
     bindings: Vec<Symbol>,
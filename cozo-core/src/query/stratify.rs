@@ -8,6 +8,7 @@
 
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
 
 use itertools::Itertools;
 use miette::{ensure, Diagnostic, Result};
@@ -32,7 +33,8 @@ impl NormalFormAtom {
             | NormalFormAtom::Unification(_)
             | NormalFormAtom::HnswSearch(_)
             | NormalFormAtom::FtsSearch(_)
-            | NormalFormAtom::LshSearch(_) => Default::default(),
+            | NormalFormAtom::LshSearch(_)
+            | NormalFormAtom::RTreeSearch(_) => Default::default(),
             NormalFormAtom::Rule(r) => BTreeMap::from([(&r.name, false)]),
             NormalFormAtom::NegatedRule(r) => BTreeMap::from([(&r.name, true)]),
         }
@@ -167,7 +169,8 @@ fn verify_no_cycle(g: &StratifiedGraph<&'_ Symbol>, sccs: &[BTreeSet<&Symbol>])
                     #[diagnostic(help(
                         "The rule '{0}' is in the strongly connected component {1:?},\n\
                     and is involved in at least one forbidden dependency \n\
-                    (negation, non-meet aggregation, or algorithm-application)."
+                    (negation, non-meet aggregation, or algorithm-application).\n\
+                    Recursion through negation is allowed under the `:well_founded` option."
                     ))]
                     struct UnStratifiableProgram(String, Vec<String>);
 
@@ -224,6 +227,13 @@ impl NormalFormProgram {
     /// returns the stratified program and the store lifetimes of the intermediate relations
     pub(crate) fn into_stratified_program(
         self,
+    ) -> Result<(StratifiedNormalFormProgram, BTreeMap<MagicSymbol, usize>)> {
+        self.into_stratified_program_with_roots(&BTreeSet::new())
+    }
+    /// like `into_stratified_program`, but also keeps the rules reachable from the given rules
+    pub(crate) fn into_stratified_program_with_roots(
+        self,
+        roots: &BTreeSet<Symbol>,
     ) -> Result<(StratifiedNormalFormProgram, BTreeMap<MagicSymbol, usize>)> {
         // prerequisite: the program is already in disjunctive normal form
         // 0. build a graph of the program
@@ -232,8 +242,12 @@ impl NormalFormProgram {
         let graph = reduce_to_graph(&stratified_graph);
 
         // 1. find reachable clauses starting from the query
-        let reachable: BTreeSet<_> = reachable_components(&graph, &prog_entry)
-            .into_iter()
+        let starts = iter::once(prog_entry)
+            .chain(roots.iter().filter(|root| graph.contains_key(root)))
+            .collect_vec();
+        let reachable: BTreeSet<_> = starts
+            .iter()
+            .flat_map(|start| reachable_components(&graph, start))
             .map(|k| (*k).clone())
             .collect();
         // 2. prune the graph of unreachable clauses
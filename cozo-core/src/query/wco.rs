@@ -0,0 +1,502 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Worst-case optimal join of stored relations, by leapfrog triejoin.
+// Binary joins over cyclic patterns such as triangles can produce
+// intermediate results much larger than the final result. Instead, the
+// variables of the joined atoms are bound one at a time in a fixed order,
+// and the candidate values for each variable are found by intersecting the
+// sorted key columns of all atoms containing it. Every atom is read as a trie
+// through its key order, so each atom must have a relation or index whose
+// leading key columns hold its variables in an order compatible with the
+// variable order. Variables already bound by the atoms before the join come
+// first in the order, so that each tuple joined from the left seeds the
+// leapfrog with their values instead of the whole join being materialized.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Debug, Formatter};
+use std::iter;
+
+use itertools::Itertools;
+use miette::Result;
+
+use crate::data::program::MagicAtom;
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleIter};
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::query::ra::{eliminate_from_tuple, flatten_err};
+use crate::runtime::relation::{AccessLevel, RelationHandle};
+use crate::runtime::transact::SessionTx;
+
+/// upper bound on the combinations of relations and indices tried when planning
+const MAX_PLAN_CANDIDATES: usize = 1024;
+
+/// A stored relation atom taking part in a worst-case optimal join
+pub(crate) struct WcoAtom {
+    /// the relation or index read
+    pub(crate) storage: RelationHandle,
+    /// for each leading key column, the position of its variable in the bindings
+    pub(crate) levels: Vec<usize>,
+}
+
+pub(crate) struct WcoJoinRA {
+    /// the variables of all atoms, in the order they are bound
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) atoms: Vec<WcoAtom>,
+    /// for each variable, the atoms containing it and the key column holding it
+    participants: Vec<Vec<(usize, usize)>>,
+    pub(crate) span: SourceSpan,
+}
+
+impl Debug for WcoJoinRA {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WcoJoinRA")
+            .field("bindings", &self.bindings)
+            .field(
+                "atoms",
+                &self.atoms.iter().map(|a| &a.storage.name).collect_vec(),
+            )
+            .finish()
+    }
+}
+
+impl WcoJoinRA {
+    fn new(bindings: Vec<Symbol>, atoms: Vec<WcoAtom>, span: SourceSpan) -> Self {
+        let mut participants = vec![vec![]; bindings.len()];
+        for (atom_idx, atom) in atoms.iter().enumerate() {
+            for (col, var_idx) in atom.levels.iter().enumerate() {
+                participants[*var_idx].push((atom_idx, col));
+            }
+        }
+        Self {
+            bindings,
+            atoms,
+            participants,
+            span,
+        }
+    }
+
+    pub(crate) fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
+        self.iter_from(tx, vec![])
+    }
+
+    /// Joins with the left tuples binding the leading variables
+    pub(crate) fn prefix_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        left_iter: TupleIter<'a>,
+        (left_join_indices, right_join_indices): (Vec<usize>, Vec<usize>),
+        eliminate_indices: BTreeSet<usize>,
+    ) -> Result<TupleIter<'a>> {
+        let mut right_invert_indices = right_join_indices.iter().enumerate().collect_vec();
+        right_invert_indices.sort_by_key(|(_, b)| **b);
+        let left_to_prefix_indices = right_invert_indices
+            .into_iter()
+            .map(|(a, _)| left_join_indices[a])
+            .collect_vec();
+
+        let it = left_iter
+            .map_ok(move |tuple| -> Result<TupleIter<'a>> {
+                let prefix = left_to_prefix_indices
+                    .iter()
+                    .map(|i| tuple[*i].clone())
+                    .collect_vec();
+                if !self.seed_matches(tx, &prefix)? {
+                    return Ok(Box::new(iter::empty()));
+                }
+                Ok(Box::new(self.iter_from(tx, prefix)?.map_ok(move |found| {
+                    let mut ret = tuple.clone();
+                    ret.extend(found);
+                    ret
+                })))
+            })
+            .map(flatten_err)
+            .flatten_ok()
+            .map(flatten_err);
+        Ok(if eliminate_indices.is_empty() {
+            Box::new(it)
+        } else {
+            Box::new(it.map_ok(move |t| eliminate_from_tuple(t, &eliminate_indices)))
+        })
+    }
+
+    /// Whether the atoms whose variables are all bound by the seed contain it,
+    /// the other atoms are checked when their remaining variables are bound
+    fn seed_matches(&self, tx: &SessionTx<'_>, seed: &[DataValue]) -> Result<bool> {
+        for atom in &self.atoms {
+            if atom.levels.iter().all(|i| *i < seed.len()) {
+                let key = atom.levels.iter().map(|i| seed[*i].clone()).collect_vec();
+                if atom.storage.seek_in_prefix(tx, &key, &[])?.is_none() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// All tuples extending the values already bound to the leading variables
+    fn iter_from<'a>(&'a self, tx: &'a SessionTx<'_>, bound: Tuple) -> Result<TupleIter<'a>> {
+        if bound.len() == self.bindings.len() {
+            return Ok(Box::new(iter::once(Ok(bound))));
+        }
+        let values = Leapfrog::new(self, tx, &bound)?;
+        Ok(Box::new(
+            values
+                .map_ok(move |val| {
+                    let mut nxt = bound.clone();
+                    nxt.push(val);
+                    self.iter_from(tx, nxt)
+                })
+                .map(flatten_err)
+                .flatten_ok()
+                .map(flatten_err),
+        ))
+    }
+}
+
+/// Position within one level of the trie of an atom, that is,
+/// within the distinct values of a key column for a fixed prefix
+struct TrieCursor<'a> {
+    storage: &'a RelationHandle,
+    prefix: Tuple,
+    current: DataValue,
+}
+
+impl<'a> TrieCursor<'a> {
+    /// Moves to the first value not less than `val`, returns false if there is none
+    fn seek(&mut self, tx: &SessionTx<'_>, val: &DataValue) -> Result<bool> {
+        self.seek_from(tx, std::slice::from_ref(val))
+    }
+    /// Moves past the current value, returns false if there is no further value
+    fn next(&mut self, tx: &SessionTx<'_>) -> Result<bool> {
+        let past = [self.current.clone(), DataValue::Bot];
+        self.seek_from(tx, &past)
+    }
+    fn seek_from(&mut self, tx: &SessionTx<'_>, lower: &[DataValue]) -> Result<bool> {
+        match self.storage.seek_in_prefix(tx, &self.prefix, lower)? {
+            None => Ok(false),
+            Some(mut found) => {
+                self.current = found.swap_remove(self.prefix.len());
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// The values of a variable present in all atoms containing it,
+/// in ascending order
+struct Leapfrog<'a, 'b> {
+    tx: &'a SessionTx<'b>,
+    cursors: Vec<TrieCursor<'a>>,
+    at_match: bool,
+    exhausted: bool,
+}
+
+impl<'a, 'b> Leapfrog<'a, 'b> {
+    fn new(ra: &'a WcoJoinRA, tx: &'a SessionTx<'b>, bound: &[DataValue]) -> Result<Self> {
+        let mut cursors = vec![];
+        let mut exhausted = false;
+        for (atom_idx, col) in &ra.participants[bound.len()] {
+            let atom = &ra.atoms[*atom_idx];
+            let mut cursor = TrieCursor {
+                storage: &atom.storage,
+                prefix: atom.levels[..*col]
+                    .iter()
+                    .map(|i| bound[*i].clone())
+                    .collect_vec(),
+                current: DataValue::Null,
+            };
+            if !cursor.seek_from(tx, &[])? {
+                exhausted = true;
+                break;
+            }
+            cursors.push(cursor);
+        }
+        Ok(Self {
+            tx,
+            cursors,
+            at_match: false,
+            exhausted,
+        })
+    }
+
+    fn advance(&mut self) -> Result<Option<DataValue>> {
+        if self.at_match && !self.cursors[0].next(self.tx)? {
+            return Ok(None);
+        }
+        loop {
+            let hi = self
+                .cursors
+                .iter()
+                .map(|c| &c.current)
+                .max()
+                .unwrap()
+                .clone();
+            let mut aligned = true;
+            for cursor in self.cursors.iter_mut() {
+                if cursor.current < hi {
+                    if !cursor.seek(self.tx, &hi)? {
+                        return Ok(None);
+                    }
+                    if cursor.current != hi {
+                        aligned = false;
+                    }
+                }
+            }
+            if aligned {
+                self.at_match = true;
+                return Ok(Some(hi));
+            }
+        }
+    }
+}
+
+impl Iterator for Leapfrog<'_, '_> {
+    type Item = Result<DataValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.exhausted {
+            return None;
+        }
+        match self.advance() {
+            Ok(Some(val)) => Some(Ok(val)),
+            Ok(None) => {
+                self.exhausted = true;
+                None
+            }
+            Err(err) => {
+                self.exhausted = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+struct WcoCandidate {
+    body_pos: usize,
+    store: RelationHandle,
+    args: Vec<Symbol>,
+    vars: BTreeSet<Symbol>,
+    span: SourceSpan,
+}
+
+/// Reduces the atoms by repeatedly removing variables occurring in a single atom
+/// and atoms whose variables all occur in another atom. The atoms remaining
+/// form the cyclic part of the body, none remain if the body is acyclic.
+fn cyclic_core(candidates: &[WcoCandidate]) -> Vec<usize> {
+    let mut edges = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i, c.vars.clone()))
+        .collect_vec();
+    loop {
+        let mut changed = false;
+        let mut counts: BTreeMap<Symbol, usize> = BTreeMap::new();
+        for (_, vars) in &edges {
+            for v in vars {
+                *counts.entry(v.clone()).or_default() += 1;
+            }
+        }
+        for (_, vars) in edges.iter_mut() {
+            let before = vars.len();
+            vars.retain(|v| counts[v] > 1);
+            changed |= vars.len() != before;
+        }
+        let contained = (0..edges.len())
+            .find(|i| (0..edges.len()).any(|j| *i != j && edges[*i].1.is_subset(&edges[j].1)));
+        if let Some(i) = contained {
+            edges.remove(i);
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+    edges
+        .into_iter()
+        .filter(|(_, vars)| !vars.is_empty())
+        .map(|(i, _)| i)
+        .collect_vec()
+}
+
+/// The relation and indices through which the atom can be read as a trie,
+/// each with the variables of its leading key columns
+fn trie_orders(candidate: &WcoCandidate) -> Vec<(RelationHandle, Vec<Symbol>)> {
+    let bound_positions: BTreeSet<usize> = candidate
+        .args
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.is_generated_ignored_symbol())
+        .map(|(i, _)| i)
+        .collect();
+    let n_bound = bound_positions.len();
+    let key_order = (0..candidate.store.metadata.keys.len()).collect_vec();
+    iter::once((candidate.store.clone(), key_order))
+        .chain(candidate.store.indices.values().cloned())
+        .filter(|(_, cols)| {
+            cols.len() >= n_bound && cols[..n_bound].iter().all(|c| bound_positions.contains(c))
+        })
+        .map(|(handle, cols)| {
+            let vars = cols[..n_bound]
+                .iter()
+                .map(|c| candidate.args[*c].clone())
+                .collect_vec();
+            (handle, vars)
+        })
+        .collect_vec()
+}
+
+/// The variables bound by the atoms of a rule body
+fn bound_by(atoms: &[MagicAtom]) -> BTreeSet<Symbol> {
+    let mut ret = BTreeSet::new();
+    for atom in atoms {
+        match atom {
+            MagicAtom::Rule(rule_app) => ret.extend(rule_app.args.iter().cloned()),
+            MagicAtom::Relation(rel_app) => ret.extend(rel_app.args.iter().cloned()),
+            MagicAtom::Unification(u) => {
+                ret.insert(u.binding.clone());
+            }
+            MagicAtom::HnswSearch(s) => ret.extend(s.all_bindings().cloned()),
+            MagicAtom::FtsSearch(s) => ret.extend(s.all_bindings().cloned()),
+            MagicAtom::LshSearch(s) => ret.extend(s.all_bindings().cloned()),
+            MagicAtom::RTreeSearch(s) => ret.extend(s.all_bindings().cloned()),
+            MagicAtom::Predicate(_) | MagicAtom::NegatedRule(_) | MagicAtom::NegatedRelation(_) => {
+            }
+        }
+    }
+    ret
+}
+
+/// A variable order in which the variables of every sequence appear in the same order,
+/// preferring variables occurring earlier in the rule body
+fn consistent_order(seqs: &[&Vec<Symbol>], rank: &BTreeMap<Symbol, usize>) -> Option<Vec<Symbol>> {
+    let mut preds: BTreeMap<&Symbol, BTreeSet<&Symbol>> = BTreeMap::new();
+    for seq in seqs {
+        for v in seq.iter() {
+            preds.entry(v).or_default();
+        }
+        for (a, b) in seq.iter().tuple_windows() {
+            preds.get_mut(b).unwrap().insert(a);
+        }
+    }
+    let mut order: Vec<Symbol> = vec![];
+    while !preds.is_empty() {
+        let next = *preds
+            .iter()
+            .filter(|(_, p)| p.is_empty())
+            .map(|(v, _)| v)
+            .min_by_key(|v| rank[**v])?;
+        preds.remove(next);
+        for p in preds.values_mut() {
+            p.remove(next);
+        }
+        order.push(next.clone());
+    }
+    Some(order)
+}
+
+impl<'a> SessionTx<'a> {
+    /// Finds the stored relation atoms of the rule body sharing variables cyclically
+    /// and a variable order in which all of them can be read as tries, with the variables
+    /// bound before the join first. Returns the positions of these atoms in the body,
+    /// with the join replacing them.
+    pub(crate) fn plan_wco_join(
+        &self,
+        body: &[MagicAtom],
+    ) -> Result<Option<(BTreeSet<usize>, WcoJoinRA)>> {
+        let mut candidates = vec![];
+        for (body_pos, atom) in body.iter().enumerate() {
+            let rel_app = match atom {
+                MagicAtom::Relation(rel_app) if rel_app.valid_at.is_none() => rel_app,
+                _ => continue,
+            };
+            let store = self.get_relation(&rel_app.name, false)?;
+            if store.access_level < AccessLevel::ReadOnly
+                || store.arity() != rel_app.args.len()
+                || !store.expr_indices.is_empty()
+            {
+                continue;
+            }
+            let vars = rel_app
+                .args
+                .iter()
+                .filter(|v| !v.is_generated_ignored_symbol())
+                .cloned()
+                .collect_vec();
+            if !vars.iter().all_unique() {
+                continue;
+            }
+            candidates.push(WcoCandidate {
+                body_pos,
+                store,
+                args: rel_app.args.clone(),
+                vars: vars.into_iter().collect(),
+                span: rel_app.span,
+            });
+        }
+        if candidates.len() < 3 {
+            return Ok(None);
+        }
+
+        let core = cyclic_core(&candidates);
+        if core.is_empty() {
+            return Ok(None);
+        }
+        let first_pos = core.iter().map(|i| candidates[*i].body_pos).min().unwrap();
+        let bound = bound_by(&body[..first_pos]);
+        let mut rank = BTreeMap::new();
+        for i in core.iter() {
+            for v in candidates[*i].args.iter() {
+                if bound.contains(v) {
+                    let next_rank = rank.len();
+                    rank.entry(v.clone()).or_insert(next_rank);
+                }
+            }
+        }
+        for i in core.iter() {
+            for v in candidates[*i].args.iter() {
+                let next_rank = rank.len();
+                rank.entry(v.clone()).or_insert(next_rank);
+            }
+        }
+        let options = core
+            .iter()
+            .map(|i| trie_orders(&candidates[*i]))
+            .collect_vec();
+        for choice in options
+            .iter()
+            .map(|o| o.iter())
+            .multi_cartesian_product()
+            .take(MAX_PLAN_CANDIDATES)
+        {
+            let seqs = choice.iter().map(|(_, vars)| vars).collect_vec();
+            if let Some(order) = consistent_order(&seqs, &rank) {
+                // the bound variables must form a prefix of the order to seed the join,
+                // otherwise it would have to be evaluated in full
+                let n_bound = order.iter().filter(|v| bound.contains(*v)).count();
+                if order[..n_bound].iter().any(|v| !bound.contains(v)) {
+                    continue;
+                }
+                let positions: BTreeMap<_, _> =
+                    order.iter().enumerate().map(|(i, v)| (v, i)).collect();
+                let atoms = choice
+                    .iter()
+                    .map(|(storage, vars)| WcoAtom {
+                        storage: storage.clone(),
+                        levels: vars.iter().map(|v| positions[v]).collect_vec(),
+                    })
+                    .collect_vec();
+                let body_positions = core.iter().map(|i| candidates[*i].body_pos).collect();
+                let span = candidates[core[0]].span;
+                return Ok(Some((body_positions, WcoJoinRA::new(order, atoms, span))));
+            }
+        }
+        Ok(None)
+    }
+}
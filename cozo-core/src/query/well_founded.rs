@@ -0,0 +1,179 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Evaluation under the well-founded semantics, by the alternating fixpoint.
+// Programs with recursion through negation have no stratification. Instead,
+// the negated rule applications are evaluated against a fixed interpretation,
+// given to the program as constant rules, which makes the program stratifiable.
+// Evaluating against an underestimate of the true tuples gives an overestimate,
+// and vice versa. Starting from the empty underestimate, the two alternate until
+// the underestimate no longer grows. The tuples of the final underestimate are
+// true, and those only in the overestimate are undefined.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use itertools::Itertools;
+use miette::Result;
+use smartstring::SmartString;
+
+use crate::data::expr::Expr;
+use crate::data::program::{
+    FixedRuleApply, InputProgram, MagicSymbol, NormalFormAtom, NormalFormProgram,
+    NormalFormRulesOrFixed,
+};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::fixed_rule::utilities::Constant;
+use crate::fixed_rule::FixedRuleHandle;
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::transact::SessionTx;
+
+/// The tuples of some rules, all other tuples being false
+type Interpretation = BTreeMap<Symbol, BTreeSet<Tuple>>;
+
+impl NormalFormProgram {
+    /// The rules applied under negation, with their arities
+    fn negated_rules(&self) -> BTreeMap<Symbol, usize> {
+        let mut ret = BTreeMap::new();
+        for ruleset in self.prog.values() {
+            if let NormalFormRulesOrFixed::Rules { rules } = ruleset {
+                for atom in rules.iter().flat_map(|rule| rule.body.iter()) {
+                    if let NormalFormAtom::NegatedRule(r) = atom {
+                        ret.insert(r.name.clone(), r.args.len());
+                    }
+                }
+            }
+        }
+        ret
+    }
+    /// Makes the negated rule applications refer to constant rules holding the interpretation
+    fn negate_against(&mut self, negated: &BTreeMap<Symbol, usize>, interp: &Interpretation) {
+        let shadow_name = |name: &Symbol| Symbol::new(format!("{}*wf", name.name), name.span);
+        for ruleset in self.prog.values_mut() {
+            if let NormalFormRulesOrFixed::Rules { rules } = ruleset {
+                for atom in rules.iter_mut().flat_map(|rule| rule.body.iter_mut()) {
+                    if let NormalFormAtom::NegatedRule(r) = atom {
+                        r.name = shadow_name(&r.name);
+                    }
+                }
+            }
+        }
+        for (name, arity) in negated {
+            let data = interp
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|tuple| DataValue::List(tuple.clone()))
+                .collect_vec();
+            let options = BTreeMap::from([(
+                SmartString::from("data"),
+                Expr::Const {
+                    val: DataValue::List(data),
+                    span: name.span,
+                },
+            )]);
+            self.prog.insert(
+                shadow_name(name),
+                NormalFormRulesOrFixed::Fixed {
+                    fixed: FixedRuleApply {
+                        fixed_handle: FixedRuleHandle {
+                            name: Symbol::new("Constant", name.span),
+                        },
+                        rule_args: vec![],
+                        options: Arc::new(options),
+                        head: (0..*arity)
+                            .map(|i| Symbol::new(format!("_{i}"), name.span))
+                            .collect(),
+                        arity: *arity,
+                        span: name.span,
+                        fixed_impl: Arc::new(Box::new(Constant)),
+                    },
+                },
+            );
+        }
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Evaluates the program under the well-founded semantics,
+    /// returning the true and the undefined tuples of the entry rule.
+    pub(crate) fn well_founded_evaluate(
+        &mut self,
+        prog: InputProgram,
+        poison: Poison,
+    ) -> Result<(Vec<Tuple>, Vec<Tuple>)> {
+        let entry = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+        let (normalized, _) = prog.clone().into_normalized_program(self)?;
+        let negated = normalized.negated_rules();
+
+        let mut under = Interpretation::new();
+        loop {
+            let over = self.evaluate_negating_against(&prog, &negated, &under, &poison)?;
+            let next_under = self.evaluate_negating_against(&prog, &negated, &over, &poison)?;
+            if next_under == under {
+                let true_tuples = under.remove(&entry).unwrap_or_default();
+                let undefined_tuples = over
+                    .get(&entry)
+                    .into_iter()
+                    .flatten()
+                    .filter(|tuple| !true_tuples.contains(*tuple))
+                    .cloned()
+                    .collect_vec();
+                return Ok((true_tuples.into_iter().collect_vec(), undefined_tuples));
+            }
+            under = next_under;
+        }
+    }
+    /// Returns the tuples of the entry and the negated rules
+    fn evaluate_negating_against(
+        &mut self,
+        prog: &InputProgram,
+        negated: &BTreeMap<Symbol, usize>,
+        interp: &Interpretation,
+        poison: &Poison,
+    ) -> Result<Interpretation> {
+        poison.check()?;
+        let (mut normalized, _) = prog.clone().into_normalized_program(self)?;
+        normalized.negate_against(negated, interp);
+        // rules only applied under negation must still be computed
+        let roots = negated.keys().cloned().collect();
+        let (stratified, _) = normalized.into_stratified_program_with_roots(&roots)?;
+        let compiled = self.stratified_magic_compile(stratified.plain_rewrite(self)?)?;
+        let store_lifetimes = compiled
+            .iter()
+            .flat_map(|stratum| stratum.keys())
+            .map(|name| (name.clone(), usize::MAX))
+            .collect();
+        let (stores, _) = self.evaluate_strata(
+            &compiled,
+            store_lifetimes,
+            None,
+            None,
+            None,
+            poison.clone(),
+            None,
+        )?;
+
+        let entry = Symbol::new(PROG_ENTRY, SourceSpan(0, 0));
+        let mut ret = Interpretation::new();
+        for name in negated.keys().chain([&entry]) {
+            let store = stores.get(&MagicSymbol::Muggle {
+                inner: name.clone(),
+            });
+            let tuples = store
+                .into_iter()
+                .flat_map(|store| store.all_iter().map_ok(|t| t.into_tuple()))
+                .try_collect()?;
+            ret.insert(name.clone(), tuples);
+        }
+        Ok(ret)
+    }
+}
@@ -61,13 +61,16 @@ impl<'s, S: Storage<'s>> Db<S> {
     pub(crate) fn current_callback_targets(&self) -> BTreeSet<SmartString<LazyCompact>> {
         #[cfg(not(target_arch = "wasm32"))]
         {
-            self.event_callbacks
+            let mut targets: BTreeSet<_> = self
+                .event_callbacks
                 .read()
                 .unwrap()
                 .1
                 .keys()
                 .cloned()
-                .collect()
+                .collect();
+            targets.extend(self.subscription_targets());
+            targets
         }
 
         #[cfg(target_arch = "wasm32")]
@@ -78,6 +81,7 @@ impl<'s, S: Storage<'s>> Db<S> {
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) fn send_callbacks(&'s self, collector: CallbackCollector) {
         let mut to_remove = vec![];
+        let changed = collector.keys().cloned().collect();
 
         for (table, vals) in collector {
             for (op, new, old) in vals {
@@ -113,5 +117,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 }
             }
         }
+
+        self.queue_subscription_updates(&changed);
     }
 }
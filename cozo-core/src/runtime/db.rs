@@ -13,7 +13,7 @@ use std::fmt::{Debug, Formatter};
 use std::iter;
 use std::path::Path;
 #[allow(unused_imports)]
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
 use std::thread;
@@ -28,13 +28,17 @@ use itertools::Itertools;
 use miette::Report;
 #[allow(unused_imports)]
 use miette::{bail, ensure, miette, Diagnostic, IntoDiagnostic, Result, WrapErr};
+#[cfg(not(target_arch = "wasm32"))]
+use rand::Rng;
 use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::functions::current_validity;
 use crate::data::json::JsonValue;
-use crate::data::program::{InputProgram, QueryAssertion, RelationOp};
+use crate::data::program::{
+    InputInlineRulesOrFixed, InputProgram, QueryAssertion, QueryOutOptions, RelationOp,
+};
 use crate::data::relation::ColumnDef;
 use crate::data::tuple::{Tuple, TupleT};
 use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
@@ -44,9 +48,10 @@ use crate::parse::sys::SysOp;
 use crate::parse::{parse_script, CozoScript, SourceSpan};
 use crate::query::compile::{CompiledProgram, CompiledRule, CompiledRuleSet};
 use crate::query::ra::{
-    FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LshSearchRA, NegJoin, RelAlgebra, ReorderRA,
-    StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
+    FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LshSearchRA, NegJoin, RTreeSearchRA,
+    RelAlgebra, ReorderRA, StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
 };
+use crate::query::sort::{sorter_indices, TopK};
 #[allow(unused_imports)]
 use crate::runtime::callback::{
     CallbackCollector, CallbackDeclaration, CallbackOp, EventCallbackRegistry,
@@ -54,9 +59,17 @@ use crate::runtime::callback::{
 use crate::runtime::relation::{
     extend_tuple_from_v, AccessLevel, InsufficientAccessLevel, RelationHandle, RelationId,
 };
+use crate::runtime::result_cache::{
+    is_cacheable_fixed_rule, mentions_volatile, result_cache_key, ResultCache, ResultCacheKey,
+};
+use crate::runtime::session::Session;
+use crate::runtime::stored_rule::StoredRule;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::runtime::subscription::{SubscriptionQueue, SubscriptionRegistry};
+use crate::runtime::temp_store::approx_tuple_size;
 use crate::runtime::transact::SessionTx;
 use crate::storage::temp::TempStorage;
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{is_conflict, Storage, StoreTx};
 use crate::{decode_tuple_from_kv, FixedRule};
 
 pub(crate) struct RunningQueryHandle {
@@ -93,10 +106,23 @@ pub struct Db<S> {
     pub(crate) running_queries: Arc<Mutex<BTreeMap<u64, RunningQueryHandle>>>,
     pub(crate) fixed_rules: Arc<ShardedLock<BTreeMap<String, Arc<Box<dyn FixedRule>>>>>,
     pub(crate) tokenizers: Arc<TokenizerCache>,
+    /// default memory limit of queries in bytes, zero for no limit
+    max_memory: Arc<AtomicUsize>,
+    /// default limit on the iterations of recursive rules, zero for no limit
+    max_iterations: Arc<AtomicUsize>,
+    /// number of tuples, zero for never spilling
+    temp_spill_threshold: Arc<AtomicUsize>,
+    /// default number of retries of transactions failing on conflicts
+    conflict_retries: Arc<AtomicUsize>,
+    pub(crate) result_cache: Arc<Mutex<ResultCache>>,
     #[cfg(not(target_arch = "wasm32"))]
-    callback_count: Arc<AtomicU32>,
+    pub(crate) callback_count: Arc<AtomicU32>,
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) event_callbacks: Arc<ShardedLock<EventCallbackRegistry>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) subscriptions: Arc<ShardedLock<SubscriptionRegistry>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) subscription_queue: Arc<SubscriptionQueue>,
     relation_locks: Arc<ShardedLock<BTreeMap<SmartString<LazyCompact>, Arc<ShardedLock<()>>>>>,
 }
 
@@ -227,6 +253,12 @@ pub enum TransactionPayload {
     Abort,
     /// Run a query inside the transaction
     Query((String, BTreeMap<String, DataValue>)),
+    /// Mark a savepoint of the given name
+    Savepoint(String),
+    /// Discard the changes made since the savepoint of the given name, keeping the savepoint
+    RollbackTo(String),
+    /// Remove the savepoint of the given name and those after it, keeping the changes
+    Release(String),
 }
 
 impl<'s, S: Storage<'s>> Db<S> {
@@ -242,11 +274,20 @@ impl<'s, S: Storage<'s>> Db<S> {
             running_queries: Default::default(),
             fixed_rules: Arc::new(ShardedLock::new(DEFAULT_FIXED_RULES.clone())),
             tokenizers: Arc::new(Default::default()),
+            max_memory: Default::default(),
+            max_iterations: Default::default(),
+            temp_spill_threshold: Default::default(),
+            conflict_retries: Default::default(),
+            result_cache: Default::default(),
             #[cfg(not(target_arch = "wasm32"))]
             callback_count: Default::default(),
             // callback_receiver: Arc::new(receiver),
             #[cfg(not(target_arch = "wasm32"))]
             event_callbacks: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            subscriptions: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            subscription_queue: Default::default(),
             relation_locks: Default::default(),
         };
         Ok(ret)
@@ -289,6 +330,8 @@ impl<'s, S: Storage<'s>> Db<S> {
         let callback_targets = self.current_callback_targets();
         let mut callback_collector = BTreeMap::new();
         let mut write_locks = BTreeMap::new();
+        // for each savepoint, the cleanups and callbacks due at that point
+        let mut savepoint_states: Vec<(usize, CallbackCollector)> = vec![];
 
         for payload in payloads {
             match payload {
@@ -311,6 +354,36 @@ impl<'s, S: Storage<'s>> Db<S> {
                     let _ = results.send(Ok(NamedRows::default()));
                     break;
                 }
+                TransactionPayload::Savepoint(name) => {
+                    let res = tx.savepoint(&name).map(|_| {
+                        savepoint_states.push((cleanups.len(), callback_collector.clone()));
+                        NamedRows::default()
+                    });
+                    if results.send(res).is_err() {
+                        break;
+                    }
+                }
+                TransactionPayload::RollbackTo(name) => {
+                    let res = tx.rollback_to(&name).map(|idx| {
+                        savepoint_states.truncate(idx + 1);
+                        let (n_cleanups, callbacks) = &savepoint_states[idx];
+                        cleanups.truncate(*n_cleanups);
+                        callback_collector = callbacks.clone();
+                        NamedRows::default()
+                    });
+                    if results.send(res).is_err() {
+                        break;
+                    }
+                }
+                TransactionPayload::Release(name) => {
+                    let res = tx.release(&name).map(|idx| {
+                        savepoint_states.truncate(idx);
+                        NamedRows::default()
+                    });
+                    if results.send(res).is_err() {
+                        break;
+                    }
+                }
                 TransactionPayload::Query((script, params)) => {
                     let p =
                         match parse_script(&script, &params, &self.fixed_rules.read().unwrap(), ts)
@@ -355,6 +428,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         ts,
                         &callback_targets,
                         &mut callback_collector,
+                        true,
                     );
                     if results.send(res).is_err() {
                         break;
@@ -364,6 +438,49 @@ impl<'s, S: Storage<'s>> Db<S> {
         }
     }
 
+    /// Set the default limit, in bytes, on the approximate memory used by the temp data
+    /// and results of each query. Queries can override it with the `:max_memory` option.
+    /// `None` removes the limit.
+    pub fn set_max_memory(&self, bytes: Option<usize>) {
+        self.max_memory.store(bytes.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Set the default limit on the number of iterations in which the recursive rules of
+    /// each query may derive new tuples. Queries can override it with the `:max_iterations`
+    /// option. `None` removes the limit.
+    pub fn set_max_iterations(&self, iterations: Option<usize>) {
+        self.max_iterations.store(iterations.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Set the number of tuples a temp store of a query may hold in memory before writing
+    /// them out as a sorted run to a file in the temp directory of the system.
+    /// Hash joins whose build side exceeds the same number of tuples are partitioned into files.
+    /// Stores holding meet aggregations are always kept in memory.
+    /// `None`, the default, keeps all temp stores in memory.
+    pub fn set_temp_spill_threshold(&self, tuples: Option<usize>) {
+        self.temp_spill_threshold.store(tuples.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Set the number of times a script is retried when its transaction fails because of
+    /// a conflict with concurrent transactions, waiting a random and growing time before
+    /// each retry. Queries can override it with the `:retry` option.
+    /// `None`, the default, never retries and reports the conflict.
+    pub fn set_conflict_retries(&self, retries: Option<usize>) {
+        self.conflict_retries.store(retries.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Set the number of query results kept in the result cache. Results of read-only
+    /// queries are cached by the script text and parameters, and dropped as soon as a
+    /// write to any of the stored relations read by the query commits.
+    /// Queries using random numbers, the current time or custom fixed rules are not cached.
+    /// `None`, the default, disables the cache.
+    pub fn set_result_cache_capacity(&self, results: Option<usize>) {
+        self.result_cache
+            .lock()
+            .unwrap()
+            .set_capacity(results.unwrap_or_default());
+    }
+
     /// Run the CozoScript passed in. The `params` argument is a map of parameters.
     pub fn run_script(
         &'s self,
@@ -371,7 +488,19 @@ impl<'s, S: Storage<'s>> Db<S> {
         params: BTreeMap<String, DataValue>,
     ) -> Result<NamedRows> {
         let cur_vld = current_validity();
-        self.do_run_script(payload, &params, cur_vld)
+        self.do_run_script(payload, &params, cur_vld, None)
+    }
+    /// Run the CozoScript passed in within the session. Temp relations, those with names
+    /// starting with an underscore, created or written by a successful script are kept in the
+    /// session, in memory, and seen by the later scripts run in it, but not by any other.
+    pub fn run_script_in_session(
+        &'s self,
+        session: &Session,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        let cur_vld = current_validity();
+        self.do_run_script(payload, &params, cur_vld, Some(session))
     }
     /// Export relations to JSON data.
     ///
@@ -461,7 +590,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                 bail!(ImportIntoIndex(relation.to_string()))
             }
             let handle = tx.get_relation(relation, false)?;
-            let has_indices = !handle.indices.is_empty();
+            let has_indices = !handle.indices.is_empty() || !handle.expr_indices.is_empty();
+            let expr_index_extractors: Vec<_> = handle
+                .expr_indices
+                .values()
+                .map(|(idx_rel, manifest)| -> Result<_> {
+                    let (elements, filter) = manifest.compile(&handle)?;
+                    Ok((idx_rel, manifest, elements, filter))
+                })
+                .try_collect()?;
+            let mut stack = vec![];
 
             if handle.access_level < AccessLevel::Protected {
                 bail!(InsufficientAccessLevel(
@@ -470,6 +608,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                     handle.access_level
                 ));
             }
+            tx.written_relations.insert(handle.name.clone());
 
             let header2idx: BTreeMap<_, _> = in_data
                 .headers
@@ -537,6 +676,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                                     idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                                 tx.store_tx.del(&encoded)?;
                             }
+                            for (idx_rel, manifest, elements, filter) in &expr_index_extractors {
+                                tx.del_expr_index_item(
+                                    &old, elements, filter, &mut stack, &handle, idx_rel, manifest,
+                                )?;
+                            }
                         }
                     }
                 }
@@ -563,6 +707,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                                 idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                             tx.store_tx.put(&encoded, &[])?;
                         }
+                        for (idx_rel, manifest, elements, filter) in &expr_index_extractors {
+                            tx.put_expr_index_item(
+                                &kv, elements, filter, &mut stack, &handle, idx_rel, manifest,
+                            )?;
+                        }
                     }
                 }
             }
@@ -610,6 +759,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             let iter = s_tx.store_tx.total_scan();
             self.db.batch_put(iter)?;
             s_tx.commit_tx()?;
+            self.result_cache.lock().unwrap().clear();
             Ok(())
         }
         #[cfg(not(feature = "storage-sqlite"))]
@@ -665,6 +815,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         dst_handle.access_level
                     ));
                 }
+                dst_tx.written_relations.insert(dst_handle.name.clone());
 
                 let src_lower = Tuple::default().encode_as_key(src_handle.id);
                 let src_upper = Tuple::default().encode_as_key(src_handle.id.next());
@@ -807,6 +958,10 @@ impl<'s, S: Storage<'s>> Db<S> {
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
             tokenizers: self.tokenizers.clone(),
+            temp_spill_threshold: self.temp_spill_threshold.load(Ordering::Relaxed),
+            written_relations: Default::default(),
+            savepoints: vec![],
+            result_cache: None,
         };
         Ok(ret)
     }
@@ -817,23 +972,34 @@ impl<'s, S: Storage<'s>> Db<S> {
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
             tokenizers: self.tokenizers.clone(),
+            temp_spill_threshold: self.temp_spill_threshold.load(Ordering::Relaxed),
+            written_relations: Default::default(),
+            savepoints: vec![],
+            result_cache: Some(self.result_cache.clone()),
         };
         Ok(ret)
     }
 
     pub(crate) fn execute_single_program(
-        &'s self,
+        &self,
         p: InputProgram,
         tx: &mut SessionTx<'_>,
         cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
+        top_level: bool,
     ) -> Result<NamedRows> {
         #[allow(unused_variables)]
         let sleep_opt = p.out_opts.sleep;
-        let (q_res, q_cleanups) =
-            self.run_query(tx, p, cur_vld, callback_targets, callback_collector, true)?;
+        let (q_res, q_cleanups) = self.run_query(
+            tx,
+            p,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            top_level,
+        )?;
         cleanups.extend(q_cleanups);
         #[cfg(not(target_arch = "wasm32"))]
         if let Some(secs) = sleep_opt {
@@ -847,20 +1013,151 @@ impl<'s, S: Storage<'s>> Db<S> {
         payload: &str,
         param_pool: &BTreeMap<String, DataValue>,
         cur_vld: ValidityTs,
+        session: Option<&Session>,
     ) -> Result<NamedRows> {
+        let cache_key = if session.is_none() && self.result_cache.lock().unwrap().is_enabled() {
+            let key = result_cache_key(payload, param_pool);
+            if let Some(rows) = self.result_cache.lock().unwrap().get(&key) {
+                return Ok(rows);
+            }
+            Some(key)
+        } else {
+            None
+        };
         match parse_script(
             payload,
             param_pool,
             &self.fixed_rules.read().unwrap(),
             cur_vld,
         )? {
-            CozoScript::Single(p) => self.execute_single(cur_vld, p),
-            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps),
-            CozoScript::Sys(op) => self.run_sys_op(op),
+            CozoScript::Single(p) => match cache_key {
+                Some(key) => self.execute_single_cached(cur_vld, p, key),
+                None => self.execute_single(cur_vld, p, session),
+            },
+            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps, session),
+            CozoScript::Sys(op) => self.run_sys_op(op, session),
         }
     }
 
-    fn execute_single(&'s self, cur_vld: ValidityTs, p: InputProgram) -> Result<NamedRows, Report> {
+    fn execute_single_cached(
+        &'s self,
+        cur_vld: ValidityTs,
+        p: InputProgram,
+        key: ResultCacheKey,
+    ) -> Result<NamedRows> {
+        let since = self.result_cache.lock().unwrap().clock();
+        match self.cacheable_reads(&key.0, &p, cur_vld)? {
+            None => self.execute_single(cur_vld, p, None),
+            Some(reads) => {
+                let res = self.execute_single(cur_vld, p, None)?;
+                self.result_cache
+                    .lock()
+                    .unwrap()
+                    .insert(key, res.clone(), reads, since);
+                Ok(res)
+            }
+        }
+    }
+
+    /// The stored relations read by the query if its result may be cached
+    fn cacheable_reads(
+        &'s self,
+        script: &str,
+        p: &InputProgram,
+        cur_vld: ValidityTs,
+    ) -> Result<Option<BTreeSet<SmartString<LazyCompact>>>> {
+        if p.out_opts.store_relation.is_some()
+            || p.out_opts.sleep.is_some()
+            || mentions_volatile(script)
+        {
+            return Ok(None);
+        }
+        for rules_or_fixed in p.prog.values() {
+            if let InputInlineRulesOrFixed::Fixed { fixed } = rules_or_fixed {
+                if !is_cacheable_fixed_rule(&fixed.fixed_handle.name.name) {
+                    return Ok(None);
+                }
+            }
+        }
+        let tx = self.transact()?;
+        let mut inlined = p.clone();
+        inlined.inline_stored_rules(&tx, cur_vld)?;
+        for name in inlined.prog.keys() {
+            if p.prog.contains_key(name) {
+                continue;
+            }
+            if let Some(stored) = tx.get_stored_rule(&name.name)? {
+                if mentions_volatile(&stored.src) {
+                    return Ok(None);
+                }
+            }
+        }
+        let mut reads = BTreeSet::new();
+        for name in inlined.stored_relations_read() {
+            // reading an index is reading the relation it indexes
+            let base = match name.split_once(':') {
+                None => name,
+                Some((base, _)) => SmartString::from(base),
+            };
+            if base.starts_with('_') {
+                return Ok(None);
+            }
+            reads.insert(base);
+        }
+        Ok(Some(reads))
+    }
+
+    fn execute_single(
+        &'s self,
+        cur_vld: ValidityTs,
+        p: InputProgram,
+        session: Option<&Session>,
+    ) -> Result<NamedRows, Report> {
+        let retries = p
+            .out_opts
+            .retry
+            .unwrap_or_else(|| self.default_conflict_retries());
+        if retries == 0 {
+            return self.execute_single_once(cur_vld, p, session);
+        }
+        self.retry_on_conflict(retries, || {
+            self.execute_single_once(cur_vld, p.clone(), session)
+        })
+    }
+    pub(crate) fn default_conflict_retries(&self) -> usize {
+        self.conflict_retries.load(Ordering::Relaxed)
+    }
+    /// Runs `f` again while it fails on a transaction conflict, at most `retries` more times
+    pub(crate) fn retry_on_conflict<T>(
+        &self,
+        retries: usize,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if attempt < retries && is_conflict(&err) => {
+                    attempt += 1;
+                    #[cfg(not(target_arch = "wasm32"))]
+                    {
+                        // randomised exponential backoff, so that the conflicting
+                        // transactions are unlikely to collide again
+                        let max_wait = 1u64 << attempt.min(10);
+                        let wait = rand::thread_rng().gen_range(0..=max_wait);
+                        thread::sleep(Duration::from_millis(wait));
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+    fn execute_single_once(
+        &'s self,
+        cur_vld: ValidityTs,
+        p: InputProgram,
+        session: Option<&Session>,
+    ) -> Result<NamedRows, Report> {
+        let mut session_temps = session.map(|s| s.lock());
         let mut callback_collector = BTreeMap::new();
         let write_lock_names = p.needs_write_lock();
         let is_write = write_lock_names.is_some();
@@ -883,6 +1180,9 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 self.transact()?
             };
+            if let Some(temps) = &session_temps {
+                temps.enter(&mut tx);
+            }
 
             res = self.execute_single_program(
                 p,
@@ -891,6 +1191,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 cur_vld,
                 &callback_targets,
                 &mut callback_collector,
+                true,
             )?;
 
             for (lower, upper) in cleanups {
@@ -898,6 +1199,9 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
 
             tx.commit_tx()?;
+            if let Some(temps) = &mut session_temps {
+                temps.leave(&mut tx);
+            }
         }
         #[cfg(not(target_arch = "wasm32"))]
         if !callback_collector.is_empty() {
@@ -1013,6 +1317,22 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         rel_stack.push(right);
                                         (t, json!(null), json!(joiner.as_map()), json!(null))
                                     }
+                                    RelAlgebra::HashJoin(inner) => {
+                                        let InnerJoin {
+                                            left,
+                                            right,
+                                            joiner,
+                                            ..
+                                        } = inner.as_ref();
+                                        rel_stack.push(left);
+                                        rel_stack.push(right);
+                                        (
+                                            "hash_join",
+                                            json!(null),
+                                            json!(joiner.as_map()),
+                                            json!(null),
+                                        )
+                                    }
                                     RelAlgebra::NegJoin(inner) => {
                                         let t = inner.join_type();
                                         let NegJoin {
@@ -1089,6 +1409,28 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             .map(|f| f.to_string())
                                             .collect_vec()),
                                     ),
+                                    RelAlgebra::WcoJoin(w) => (
+                                        "wco_join",
+                                        json!(w
+                                            .atoms
+                                            .iter()
+                                            .map(|a| format!(":{}", a.storage.name))
+                                            .collect_vec()),
+                                        json!(null),
+                                        json!(null),
+                                    ),
+                                    RelAlgebra::RTreeSearch(RTreeSearchRA {
+                                        rtree_search, ..
+                                    }) => (
+                                        "rtree_index",
+                                        json!(format!(":{}", rtree_search.query.name)),
+                                        json!(rtree_search.query.name),
+                                        json!(rtree_search
+                                            .filter
+                                            .iter()
+                                            .map(|f| f.to_string())
+                                            .collect_vec()),
+                                    ),
                                 };
                                 ret_for_relation.push(json!({
                                     STRATUM: stratum,
@@ -1130,10 +1472,11 @@ impl<'s, S: Storage<'s>> Db<S> {
 
         Ok(NamedRows::new(headers, rows))
     }
-    fn run_sys_op(&'s self, op: SysOp) -> Result<NamedRows> {
+    fn run_sys_op(&'s self, op: SysOp, session: Option<&Session>) -> Result<NamedRows> {
         match op {
-            SysOp::Explain(prog) => {
+            SysOp::Explain(mut prog) => {
                 let mut tx = self.transact()?;
+                prog.inline_stored_rules(&tx, current_validity())?;
                 let (normalized_program, _) = prog.into_normalized_program(&tx)?;
                 let (stratified_program, _) = normalized_program.into_stratified_program()?;
                 let program = stratified_program.magic_sets_rewrite(&tx)?;
@@ -1141,6 +1484,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                 tx.commit_tx()?;
                 self.explain_compiled(&compiled)
             }
+            SysOp::Why(mut prog, tuple) => {
+                let mut tx = self.transact()?;
+                prog.inline_stored_rules(&tx, current_validity())?;
+                let poison = self.query_poison(&prog.out_opts)?;
+                let res = tx.why_derived(*prog, tuple, poison)?;
+                tx.commit_tx()?;
+                Ok(res)
+            }
             SysOp::Compact => {
                 self.compact_relation()?;
                 Ok(NamedRows::new(
@@ -1164,7 +1515,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                 let locks = self.obtain_relation_locks(rel_name_strs);
                 let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
                 let mut bounds = vec![];
+                let mut session_temps = session.map(|s| s.lock());
                 let mut tx = self.transact_write()?;
+                if let Some(temps) = &session_temps {
+                    temps.enter(&mut tx);
+                }
                 for rs in rel_names {
                     let bound = tx.destroy_relation(&rs)?;
                     if !rs.is_temp_store_name() {
@@ -1175,14 +1530,25 @@ impl<'s, S: Storage<'s>> Db<S> {
                     tx.store_tx.del_range_from_persisted(&lower, &upper)?;
                 }
                 tx.commit_tx()?;
+                if let Some(temps) = &mut session_temps {
+                    temps.leave(&mut tx);
+                }
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
             SysOp::DescribeRelation(rel_name, description) => {
+                let mut session_temps = session.map(|s| s.lock());
                 let mut tx = self.transact_write()?;
+                if let Some(temps) = &session_temps {
+                    temps.enter(&mut tx);
+                }
                 tx.describe_relation(&rel_name, description)?;
+                tx.commit_tx()?;
+                if let Some(temps) = &mut session_temps {
+                    temps.leave(&mut tx);
+                }
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
                     vec![vec![DataValue::from(OK_STR)]],
@@ -1202,6 +1568,183 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CreateView(name, query) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let cleanups = tx.create_view(self, &name, &query, current_validity())?;
+                for (lower, upper) in cleanups {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::DropView(name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                if tx.get_relation(&name, false)?.view.is_none() {
+                    bail!("Relation `{}` is not a materialized view", name.name)
+                }
+                for (lower, upper) in tx.destroy_relation(&name)? {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::DefineRule(name, arity, src) => {
+                let mut tx = self.transact_write()?;
+                let description = match tx.get_stored_rule(&name.name)? {
+                    None => Default::default(),
+                    Some(existing) => existing.description,
+                };
+                tx.put_stored_rule(&StoredRule {
+                    name: name.name,
+                    arity,
+                    src,
+                    description,
+                })?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::DropRule(name) => {
+                let mut tx = self.transact_write()?;
+                tx.remove_stored_rule(&name.name)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::DescribeRule(name, description) => {
+                let mut tx = self.transact_write()?;
+                tx.describe_stored_rule(&name.name, description)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListRules => {
+                let tx = self.transact()?;
+                let rows = tx
+                    .list_stored_rules()?
+                    .into_iter()
+                    .map(|rule| {
+                        vec![
+                            DataValue::from(&rule.name as &str),
+                            DataValue::from(rule.arity as i64),
+                            DataValue::from(&rule.description as &str),
+                            DataValue::from(rule.src),
+                        ]
+                    })
+                    .collect_vec();
+                Ok(NamedRows::new(
+                    vec![
+                        "name".to_string(),
+                        "arity".to_string(),
+                        "description".to_string(),
+                        "definition".to_string(),
+                    ],
+                    rows,
+                ))
+            }
+            SysOp::CreateProcedure(name, params, src) => {
+                let mut tx = self.transact_write()?;
+                let version = tx.put_procedure(&name.name, params, src)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string(), "version".to_string()],
+                    vec![vec![
+                        DataValue::from(OK_STR),
+                        DataValue::from(version as i64),
+                    ]],
+                ))
+            }
+            SysOp::DropProcedure(name) => {
+                let mut tx = self.transact_write()?;
+                tx.remove_procedure(&name.name)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListProcedures => {
+                let tx = self.transact()?;
+                let rows = tx
+                    .list_procedures()?
+                    .into_iter()
+                    .map(|p| {
+                        vec![
+                            DataValue::from(&p.name as &str),
+                            DataValue::List(
+                                p.params
+                                    .iter()
+                                    .map(|s| DataValue::from(format!("${s}")))
+                                    .collect_vec(),
+                            ),
+                            DataValue::from(p.version as i64),
+                            DataValue::from(p.src),
+                        ]
+                    })
+                    .collect_vec();
+                Ok(NamedRows::new(
+                    vec![
+                        "name".to_string(),
+                        "params".to_string(),
+                        "version".to_string(),
+                        "definition".to_string(),
+                    ],
+                    rows,
+                ))
+            }
+            SysOp::CallProcedure(name, args) => {
+                self.call_procedure_in_session(&name.name, args, session)
+            }
+            SysOp::AlterRelation(rel_name, op) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.alter_relation(&rel_name, op)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateExprIndex(config) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&config.base_relation.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.create_expr_index(config)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::CreateVectorIndex(config) => {
                 let lock = self
                     .obtain_relation_locks(iter::once(&config.base_relation))
@@ -1244,6 +1787,20 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CreateRTreeIndex(config) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&config.base_relation))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.create_rtree_index(config)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::RemoveIndex(rel_name, idx_name) => {
                 let lock = self
                     .obtain_relation_locks(iter::once(&rel_name.name))
@@ -1261,8 +1818,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
-            SysOp::ListColumns(rs) => self.list_columns(&rs),
-            SysOp::ListIndices(rs) => self.list_indices(&rs),
+            SysOp::ListColumns(rs) => self.list_columns(&rs, session),
+            SysOp::ListIndices(rs) => self.list_indices(&rs, session),
             SysOp::RenameRelation(rename_pairs) => {
                 let rel_names = rename_pairs.iter().flat_map(|(f, t)| [&f.name, &t.name]);
                 let locks = self.obtain_relation_locks(rel_names);
@@ -1343,7 +1900,7 @@ impl<'s, S: Storage<'s>> Db<S> {
     pub(crate) fn run_query(
         &self,
         tx: &mut SessionTx<'_>,
-        input_program: InputProgram,
+        mut input_program: InputProgram,
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
@@ -1352,6 +1909,12 @@ impl<'s, S: Storage<'s>> Db<S> {
         // cleanups contain stored relations that should be deleted at the end of query
         let mut clean_ups = vec![];
 
+        input_program.inline_stored_rules(tx, cur_vld)?;
+
+        if input_program.out_opts.well_founded {
+            return Ok((self.run_well_founded_query(tx, input_program)?, vec![]));
+        }
+
         // Some checks in case the query specifies mutation
         if let Some((meta, op)) = &input_program.out_opts.store_relation {
             if *op == RelationOp::Create {
@@ -1390,27 +1953,8 @@ impl<'s, S: Storage<'s>> Db<S> {
         let compiled = tx.stratified_magic_compile(program)?;
 
         // poison is used to terminate queries early
-        let poison = Poison::default();
-        if let Some(secs) = out_opts.timeout {
-            poison.set_timeout(secs)?;
-        }
-        // give the query an ID and store it so that it can be queried and cancelled
-        let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
-
-        // time the query
-        let since_the_epoch = seconds_since_the_epoch()?;
-
-        let handle = RunningQueryHandle {
-            started_at: since_the_epoch,
-            poison: poison.clone(),
-        };
-        self.running_queries.lock().unwrap().insert(id, handle);
-
-        // RAII cleanups of running query handle
-        let _guard = RunningQueryCleanup {
-            id,
-            running_queries: self.running_queries.clone(),
-        };
+        let poison = self.query_poison(&out_opts)?;
+        let _guard = self.register_running(&poison)?;
 
         let total_num_to_take = if out_opts.sorters.is_empty() {
             out_opts.num_to_take()
@@ -1424,13 +1968,27 @@ impl<'s, S: Storage<'s>> Db<S> {
             None
         };
 
+        // a sorted query with a limit only needs to keep its best rows
+        let top_k_to_take = if out_opts.sorters.is_empty() {
+            None
+        } else {
+            out_opts.num_to_take()
+        };
+        let top_k = top_k_to_take.map(|k| {
+            TopK::new(
+                sorter_indices(&out_opts.sorters, &entry_head_or_default),
+                k,
+            )
+        });
+
         // the real evaluation
         let (result_store, early_return) = tx.stratified_magic_evaluate(
             &compiled,
             store_lifetimes,
             total_num_to_take,
             num_to_skip,
-            poison,
+            top_k,
+            poison.clone(),
         )?;
 
         // deal with assertions
@@ -1438,6 +1996,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             match assertion {
                 QueryAssertion::AssertNone(span) => {
                     if let Some(tuple) = result_store.all_iter().next() {
+                        let tuple = tuple?;
                         #[derive(Debug, Error, Diagnostic)]
                         #[error(
                             "The query is asserted to return no result, but a tuple {0:?} is found"
@@ -1461,8 +2020,12 @@ impl<'s, S: Storage<'s>> Db<S> {
 
         if !out_opts.sorters.is_empty() {
             // sort outputs if required
-            let sorted_result =
-                tx.sort_and_collect(result_store, &out_opts.sorters, &entry_head_or_default)?;
+            let sorted_result = tx.sort_and_collect(
+                result_store,
+                &out_opts.sorters,
+                &entry_head_or_default,
+                top_k_to_take,
+            )?;
             let sorted_iter = if let Some(offset) = out_opts.offset {
                 Left(sorted_result.into_iter().skip(offset))
             } else {
@@ -1477,7 +2040,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 let to_clear = tx
                     .execute_relation(
                         self,
-                        sorted_iter,
+                        sorted_iter.map(Ok),
                         *relation_op,
                         meta,
                         &entry_head_or_default,
@@ -1498,6 +2061,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 // not sorting outputs
                 let rows: Vec<Tuple> = sorted_iter.collect_vec();
+                poison.charge_memory(|| rows.iter().map(|t| approx_tuple_size(t)).sum())?;
                 Ok((
                     NamedRows::new(
                         entry_head_or_default
@@ -1512,7 +2076,9 @@ impl<'s, S: Storage<'s>> Db<S> {
         } else {
             let scan = if early_return {
                 Right(Left(
-                    result_store.early_returned_iter().map(|t| t.into_tuple()),
+                    result_store
+                        .early_returned_iter()
+                        .map_ok(|t| t.into_tuple()),
                 ))
             } else if out_opts.limit.is_some() || out_opts.offset.is_some() {
                 let limit = out_opts.limit.unwrap_or(usize::MAX);
@@ -1522,10 +2088,10 @@ impl<'s, S: Storage<'s>> Db<S> {
                         .all_iter()
                         .skip(offset)
                         .take(limit)
-                        .map(|t| t.into_tuple()),
+                        .map_ok(|t| t.into_tuple()),
                 ))
             } else {
-                Left(result_store.all_iter().map(|t| t.into_tuple()))
+                Left(result_store.all_iter().map_ok(|t| t.into_tuple()))
             };
 
             if let Some((meta, relation_op)) = &out_opts.store_relation {
@@ -1551,7 +2117,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                     clean_ups,
                 ))
             } else {
-                let rows: Vec<Tuple> = scan.collect_vec();
+                let rows: Vec<Tuple> = scan.try_collect()?;
+                poison.charge_memory(|| rows.iter().map(|t| approx_tuple_size(t)).sum())?;
 
                 Ok((
                     NamedRows::new(
@@ -1566,6 +2133,82 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
         }
     }
+    /// Creates the poison of a query, with the limits given by its options
+    /// or else the defaults of the database
+    pub(crate) fn query_poison(&self, out_opts: &QueryOutOptions) -> Result<Poison> {
+        let poison = Poison::default();
+        if let Some(secs) = out_opts.timeout {
+            poison.set_timeout(secs)?;
+        }
+        poison.set_memory_limit(
+            out_opts
+                .max_memory
+                .unwrap_or_else(|| self.max_memory.load(Ordering::Relaxed)),
+        );
+        poison.set_max_iterations(
+            out_opts
+                .max_iterations
+                .unwrap_or_else(|| self.max_iterations.load(Ordering::Relaxed)),
+        );
+        Ok(poison)
+    }
+    /// Gives the query an ID and stores it so that it can be listed and cancelled,
+    /// until the returned guard is dropped
+    fn register_running(&self, poison: &Poison) -> Result<RunningQueryCleanup> {
+        let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
+
+        // time the query
+        let since_the_epoch = seconds_since_the_epoch()?;
+
+        let handle = RunningQueryHandle {
+            started_at: since_the_epoch,
+            poison: poison.clone(),
+        };
+        self.running_queries.lock().unwrap().insert(id, handle);
+
+        // RAII cleanups of running query handle
+        Ok(RunningQueryCleanup {
+            id,
+            running_queries: self.running_queries.clone(),
+        })
+    }
+    /// Runs a query under the well-founded semantics. The true rows are returned first,
+    /// followed by the undefined rows.
+    fn run_well_founded_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        input_program: InputProgram,
+    ) -> Result<NamedRows> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("The option :well_founded cannot be used together with {0}")]
+        #[diagnostic(code(eval::well_founded_incompatible_option))]
+        struct WellFoundedIncompatibleOption(&'static str);
+
+        let out_opts = &input_program.out_opts;
+        ensure!(
+            out_opts.store_relation.is_none(),
+            WellFoundedIncompatibleOption("relation operations")
+        );
+        ensure!(
+            out_opts.sorters.is_empty() && out_opts.limit.is_none() && out_opts.offset.is_none(),
+            WellFoundedIncompatibleOption(":order, :limit or :offset")
+        );
+        ensure!(
+            out_opts.assertion.is_none(),
+            WellFoundedIncompatibleOption(":assert")
+        );
+        let headers = input_program
+            .get_entry_out_head_or_default()?
+            .into_iter()
+            .map(|s| s.name.to_string())
+            .collect_vec();
+        let poison = self.query_poison(out_opts)?;
+        let _guard = self.register_running(&poison)?;
+        let (true_rows, undefined_rows) = tx.well_founded_evaluate(input_program, poison)?;
+        let mut ret = NamedRows::new(headers.clone(), true_rows);
+        ret.next = Some(Box::new(NamedRows::new(headers, undefined_rows)));
+        Ok(ret)
+    }
     pub(crate) fn list_running(&self) -> Result<NamedRows> {
         let rows = self
             .running_queries
@@ -1584,8 +2227,16 @@ impl<'s, S: Storage<'s>> Db<S> {
             rows,
         ))
     }
-    fn list_indices(&'s self, name: &str) -> Result<NamedRows> {
+    /// A read transaction that also sees the temp relations of the session
+    fn transact_in_session(&'s self, session: Option<&Session>) -> Result<SessionTx<'s>> {
         let mut tx = self.transact()?;
+        if let Some(session) = session {
+            session.lock().enter(&mut tx);
+        }
+        Ok(tx)
+    }
+    fn list_indices(&'s self, name: &str, session: Option<&Session>) -> Result<NamedRows> {
+        let mut tx = self.transact_in_session(session)?;
         let handle = tx.get_relation(name, false)?;
         let mut rows = vec![];
         for (name, (rel, cols)) in &handle.indices {
@@ -1596,6 +2247,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!({ "indices": cols }),
             ]);
         }
+        for (name, (rel, manifest)) in &handle.expr_indices {
+            rows.push(vec![
+                json!(name),
+                json!("expr"),
+                json!([rel.name]),
+                json!({
+                    "expressions": manifest.elements,
+                    "filter": manifest.filter,
+                }),
+            ]);
+        }
         for (name, (rel, manifest)) in &handle.hnsw_indices {
             rows.push(vec![
                 json!(name),
@@ -1645,6 +2307,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                 }),
             ]);
         }
+        for (name, (rel, manifest)) in &handle.rtree_indices {
+            rows.push(vec![
+                json!(name),
+                json!("rtree"),
+                json!([rel.name]),
+                json!({
+                    "extractor": manifest.extractor,
+                    "max_entries": manifest.max_entries,
+                }),
+            ]);
+        }
         tx.commit_tx()?;
         let rows = rows
             .into_iter()
@@ -1660,9 +2333,24 @@ impl<'s, S: Storage<'s>> Db<S> {
             rows,
         ))
     }
-    fn list_columns(&'s self, name: &str) -> Result<NamedRows> {
-        let mut tx = self.transact()?;
+    fn list_columns(&'s self, name: &str, session: Option<&Session>) -> Result<NamedRows> {
+        let mut tx = self.transact_in_session(session)?;
         let handle = tx.get_relation(name, false)?;
+        let column_constraints = |col: &str| {
+            let mut constraints = vec![];
+            if handle.constraints.unique.iter().any(|c| c == col) {
+                constraints.push("unique".to_string());
+            }
+            for (_, target) in handle
+                .constraints
+                .references
+                .iter()
+                .filter(|(c, _)| c == col)
+            {
+                constraints.push(format!("references {target}"));
+            }
+            constraints
+        };
         let mut rows = vec![];
         let mut idx = 0;
         for col in &handle.metadata.keys {
@@ -1672,6 +2360,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(idx),
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
+                json!(column_constraints(&col.name)),
             ]);
             idx += 1;
         }
@@ -1682,6 +2371,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(idx),
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
+                json!(column_constraints(&col.name)),
             ]);
             idx += 1;
         }
@@ -1697,6 +2387,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 "index".to_string(),
                 "type".to_string(),
                 "has_default".to_string(),
+                "constraints".to_string(),
             ],
             rows,
         ))
@@ -1757,7 +2448,17 @@ impl<'s, S: Storage<'s>> Db<S> {
 
 /// Used for user-initiated termination of running queries
 #[derive(Clone, Default)]
-pub struct Poison(pub(crate) Arc<AtomicBool>);
+pub struct Poison(pub(crate) Arc<AtomicBool>, pub(crate) Arc<QueryBudget>);
+
+/// Limits on the resources of a query, and the approximate memory used by its temp data
+#[derive(Default)]
+pub(crate) struct QueryBudget {
+    /// in bytes, zero for no limit
+    memory_limit: AtomicUsize,
+    memory_used: AtomicUsize,
+    /// of recursive rules, zero for no limit
+    max_iterations: AtomicUsize,
+}
 
 impl Poison {
     /// Will return `Err` if user has initiated termination.
@@ -1774,6 +2475,38 @@ impl Poison {
         }
         Ok(())
     }
+    pub(crate) fn set_memory_limit(&self, bytes: usize) {
+        self.1.memory_limit.store(bytes, Ordering::Relaxed);
+    }
+    pub(crate) fn set_max_iterations(&self, iterations: usize) {
+        self.1.max_iterations.store(iterations, Ordering::Relaxed);
+    }
+    /// Zero for no limit
+    pub(crate) fn max_iterations(&self) -> usize {
+        self.1.max_iterations.load(Ordering::Relaxed)
+    }
+    /// Records temp data of the given size, returning `Err` if the memory limit is exceeded.
+    /// The size is only computed when there is a limit.
+    pub(crate) fn charge_memory(&self, size: impl FnOnce() -> usize) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Running query exceeded its memory limit of {0} bytes")]
+        #[diagnostic(code(eval::memory_limit_exceeded))]
+        #[diagnostic(help(
+            "Raise the limit with the `:max_memory` option, or check for unbounded recursion"
+        ))]
+        struct MemoryLimitExceeded(usize);
+
+        let limit = self.1.memory_limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return Ok(());
+        }
+        let bytes = size();
+        let used = self.1.memory_used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > limit {
+            bail!(MemoryLimitExceeded(limit))
+        }
+        Ok(())
+    }
     #[cfg(target_arch = "wasm32")]
     pub(crate) fn set_timeout(&self, _secs: f64) -> Result<()> {
         bail!("Cannot set timeout when threading is disallowed");
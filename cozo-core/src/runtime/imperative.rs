@@ -16,9 +16,13 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::symb::Symbol;
-use crate::parse::{ImperativeCondition, ImperativeProgram, ImperativeStmt, SourceSpan};
+use crate::parse::{
+    parse_script, CozoScript, DeferredBlock, ImperativeCondition, ImperativeProgram,
+    ImperativeStmt, SourceSpan,
+};
 use crate::runtime::callback::CallbackCollector;
 use crate::runtime::db::{seconds_since_the_epoch, RunningQueryCleanup, RunningQueryHandle};
+use crate::runtime::session::Session;
 use crate::runtime::transact::SessionTx;
 use crate::{DataValue, Db, NamedRows, Poison, Storage, ValidityTs};
 
@@ -28,15 +32,45 @@ enum ControlCode {
     Continue(Option<SmartString<LazyCompact>>, SourceSpan),
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("the query bound to ${0} must return a single column and at most one row")]
+#[diagnostic(code(eval::let_not_scalar))]
+struct LetNotScalar(String, #[label] SourceSpan);
+
 impl<'s, S: Storage<'s>> Db<S> {
+    fn parse_deferred_block(
+        &self,
+        block: &DeferredBlock,
+        bindings: impl IntoIterator<Item = (String, DataValue)>,
+        cur_vld: ValidityTs,
+    ) -> Result<ImperativeProgram> {
+        if block.src.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let mut param_pool = block.param_pool.clone();
+        param_pool.extend(bindings);
+        match parse_script(
+            &block.src,
+            &param_pool,
+            &self.fixed_rules.read().unwrap(),
+            cur_vld,
+        )
+        .map_err(|err| err.with_source_code(block.src.clone()))?
+        {
+            CozoScript::Imperative(ps) => Ok(ps),
+            CozoScript::Single(prog) => Ok(vec![ImperativeStmt::Program { prog }]),
+            CozoScript::Sys(_) => unreachable!(),
+        }
+    }
     fn execute_imperative_condition(
-        &'s self,
+        &self,
         p: &ImperativeCondition,
         tx: &mut SessionTx<'_>,
         cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
+        top_level: bool,
     ) -> Result<bool> {
         let res = match p {
             Left(rel) => {
@@ -50,13 +84,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                 cur_vld,
                 callback_targets,
                 callback_collector,
+                top_level,
             )?,
         };
         Ok(!res.rows.is_empty())
     }
 
     fn execute_imperative_stmts(
-        &'s self,
+        &self,
         ps: &ImperativeProgram,
         tx: &mut SessionTx<'_>,
         cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
@@ -64,6 +99,7 @@ impl<'s, S: Storage<'s>> Db<S> {
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
         poison: &Poison,
+        top_level: bool,
     ) -> Result<Either<NamedRows, ControlCode>> {
         let mut ret = NamedRows::default();
         for p in ps {
@@ -89,13 +125,20 @@ impl<'s, S: Storage<'s>> Db<S> {
                                 cur_vld,
                                 callback_targets,
                                 callback_collector,
+                                top_level,
                             )?,
                             Right(rel) => {
                                 let relation = tx.get_relation(rel, false)?;
                                 relation.as_named_rows(tx)?
                             }
                         };
-                        nr.next = current;
+                        // results may already be chained, e.g. the undefined rows of
+                        // well-founded queries
+                        let mut last = &mut nr;
+                        while last.next.is_some() {
+                            last = last.next.as_mut().unwrap();
+                        }
+                        last.next = current;
                         current = Some(Box::new(nr))
                     }
                     return Ok(Right(ControlCode::Termination(*current.unwrap())));
@@ -113,6 +156,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         cur_vld,
                         callback_targets,
                         callback_collector,
+                        top_level,
                     )?;
                 }
                 ImperativeStmt::IgnoreErrorProgram { prog, .. } => {
@@ -123,6 +167,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         cur_vld,
                         callback_targets,
                         callback_collector,
+                        top_level,
                     ) {
                         Ok(res) => ret = res,
                         Err(_) => {
@@ -147,6 +192,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         cur_vld,
                         callback_targets,
                         callback_collector,
+                        top_level,
                     )?;
                     let cond_val = if *negated { !cond_val } else { cond_val };
                     let to_execute = if cond_val { then_branch } else { else_branch };
@@ -158,6 +204,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                         callback_targets,
                         callback_collector,
                         poison,
+                        top_level,
                     )? {
                         Left(rows) => {
                             ret = rows;
@@ -178,6 +225,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                             callback_targets,
                             callback_collector,
                             poison,
+                            top_level,
                         )? {
                             Left(_) => {}
                             Right(ctrl) => match ctrl {
@@ -202,6 +250,82 @@ impl<'s, S: Storage<'s>> Db<S> {
                         }
                     }
                 }
+                ImperativeStmt::For {
+                    row,
+                    relation,
+                    body,
+                    ..
+                } => {
+                    ret = Default::default();
+                    // the rows are read upfront, so the body may write to the relation
+                    let rows = tx.get_relation(relation, false)?.as_named_rows(tx)?;
+                    for tuple in rows.rows {
+                        poison.check()?;
+
+                        let bindings = rows
+                            .headers
+                            .iter()
+                            .cloned()
+                            .zip(tuple.iter().cloned())
+                            .chain([(row.to_string(), DataValue::List(tuple.clone()))]);
+                        let stmts = self.parse_deferred_block(body, bindings, cur_vld)?;
+                        match self.execute_imperative_stmts(
+                            &stmts,
+                            tx,
+                            cleanups,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                            poison,
+                            top_level,
+                        )? {
+                            Left(_) => {}
+                            Right(ctrl) => match ctrl {
+                                ControlCode::Termination(ret) => {
+                                    return Ok(Right(ControlCode::Termination(ret)))
+                                }
+                                ControlCode::Break(None, _) => break,
+                                ControlCode::Continue(None, _) => continue,
+                                ctrl => return Ok(Right(ctrl)),
+                            },
+                        }
+                    }
+                }
+                ImperativeStmt::Let {
+                    name,
+                    prog,
+                    rest,
+                    span,
+                } => {
+                    let res = self.execute_single_program(
+                        prog.clone(),
+                        tx,
+                        cleanups,
+                        cur_vld,
+                        callback_targets,
+                        callback_collector,
+                        top_level,
+                    )?;
+                    if res.headers.len() != 1 || res.rows.len() > 1 {
+                        bail!(LetNotScalar(name.clone(), *span))
+                    }
+                    let val = match res.rows.into_iter().next() {
+                        None => DataValue::Null,
+                        Some(mut row) => row.pop().unwrap(),
+                    };
+                    let stmts = self.parse_deferred_block(rest, [(name.clone(), val)], cur_vld)?;
+                    // the binding is always the last statement of its block
+                    return self.execute_imperative_stmts(
+                        &stmts,
+                        tx,
+                        cleanups,
+                        cur_vld,
+                        callback_targets,
+                        callback_collector,
+                        poison,
+                        top_level,
+                    );
+                }
                 ImperativeStmt::TempSwap { left, right, .. } => {
                     tx.rename_temp_relation(
                         Symbol::new(left.clone(), Default::default()),
@@ -222,11 +346,88 @@ impl<'s, S: Storage<'s>> Db<S> {
         }
         Ok(Left(ret))
     }
+    fn execute_imperative_with_poison(
+        &self,
+        ps: &ImperativeProgram,
+        tx: &mut SessionTx<'_>,
+        cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        poison: &Poison,
+        top_level: bool,
+    ) -> Result<NamedRows> {
+        match self.execute_imperative_stmts(
+            ps,
+            tx,
+            cleanups,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            poison,
+            top_level,
+        )? {
+            Left(res) => Ok(res),
+            Right(ctrl) => match ctrl {
+                ControlCode::Termination(res) => Ok(res),
+                ControlCode::Break(_, span) | ControlCode::Continue(_, span) => {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("control flow has nowhere to go")]
+                    #[diagnostic(code(eval::dangling_ctrl_flow))]
+                    struct DanglingControlFlow(#[label] SourceSpan);
+
+                    bail!(DanglingControlFlow(span))
+                }
+            },
+        }
+    }
+    /// Runs an imperative program within the transaction of a trigger. As with
+    /// queries run by triggers, the writes do not fire further triggers.
+    pub(crate) fn execute_imperative_in_tx(
+        &self,
+        ps: &ImperativeProgram,
+        tx: &mut SessionTx<'_>,
+        cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<NamedRows> {
+        self.execute_imperative_with_poison(
+            ps,
+            tx,
+            cleanups,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            &Poison::default(),
+            false,
+        )
+    }
     pub(crate) fn execute_imperative(
         &'s self,
         cur_vld: ValidityTs,
         ps: &ImperativeProgram,
+        session: Option<&Session>,
     ) -> Result<NamedRows, Report> {
+        let retries = ps
+            .iter()
+            .filter_map(|stmt| stmt.max_retry())
+            .max()
+            .unwrap_or_else(|| self.default_conflict_retries());
+        if retries == 0 {
+            return self.execute_imperative_once(cur_vld, ps, session);
+        }
+        self.retry_on_conflict(retries, || {
+            self.execute_imperative_once(cur_vld, ps, session)
+        })
+    }
+    fn execute_imperative_once(
+        &'s self,
+        cur_vld: ValidityTs,
+        ps: &ImperativeProgram,
+        session: Option<&Session>,
+    ) -> Result<NamedRows, Report> {
+        let mut session_temps = session.map(|s| s.lock());
         let mut callback_collector = BTreeMap::new();
         let mut write_lock_names = BTreeSet::new();
         for p in ps {
@@ -249,6 +450,9 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 self.transact()?
             };
+            if let Some(temps) = &session_temps {
+                temps.enter(&mut tx);
+            }
 
             let poison = Poison::default();
             let qid = self.queries_count.fetch_add(1, Ordering::AcqRel);
@@ -264,7 +468,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 running_queries: self.running_queries.clone(),
             };
 
-            match self.execute_imperative_stmts(
+            ret = self.execute_imperative_with_poison(
                 ps,
                 &mut tx,
                 &mut cleanups,
@@ -272,28 +476,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                 &callback_targets,
                 &mut callback_collector,
                 &poison,
-            )? {
-                Left(res) => ret = res,
-                Right(ctrl) => match ctrl {
-                    ControlCode::Termination(res) => {
-                        ret = res;
-                    }
-                    ControlCode::Break(_, span) | ControlCode::Continue(_, span) => {
-                        #[derive(Debug, Error, Diagnostic)]
-                        #[error("control flow has nowhere to go")]
-                        #[diagnostic(code(eval::dangling_ctrl_flow))]
-                        struct DanglingControlFlow(#[label] SourceSpan);
-
-                        bail!(DanglingControlFlow(span))
-                    }
-                },
-            }
+                true,
+            )?;
 
             for (lower, upper) in cleanups {
                 tx.store_tx.del_range_from_persisted(&lower, &upper)?;
             }
 
             tx.commit_tx()?;
+            if let Some(temps) = &mut session_temps {
+                temps.leave(&mut tx);
+            }
         }
         #[cfg(not(target_arch = "wasm32"))]
         if !callback_collector.is_empty() {
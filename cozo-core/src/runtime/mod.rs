@@ -14,5 +14,14 @@ pub(crate) mod temp_store;
 pub(crate) mod transact;
 pub(crate) mod hnsw;
 pub(crate) mod minhash_lsh;
+pub(crate) mod rtree;
+pub(crate) mod view;
+pub(crate) mod stored_rule;
+pub(crate) mod procedure;
+pub(crate) mod spill;
+pub(crate) mod result_cache;
+pub(crate) mod session;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod subscription;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,210 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Stored procedures are imperative scripts saved with `::procedure create`,
+// kept as source in the system key space. Every definition under the same
+// name gets a new version number. `::call` binds the arguments to the
+// declared parameters and runs the script, either in its own transaction or,
+// when called from a trigger, within the transaction of the triggering write.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use miette::{bail, ensure, Diagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::functions::current_validity;
+use crate::data::symb::Symbol;
+use crate::data::tuple::TupleT;
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::{parse_script, CozoScript, ImperativeProgram, ImperativeStmt};
+use crate::query::stored::make_const_rule;
+use crate::runtime::callback::CallbackCollector;
+use crate::runtime::relation::RelationId;
+use crate::runtime::session::Session;
+use crate::runtime::transact::SessionTx;
+use crate::{Db, NamedRows, Storage};
+
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct StoredProcedure {
+    pub(crate) name: SmartString<LazyCompact>,
+    /// names of the parameters, without the `$`
+    pub(crate) params: Vec<String>,
+    /// source of the imperative script
+    pub(crate) src: String,
+    pub(crate) version: u64,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Stored procedure {0} not found")]
+#[diagnostic(code(eval::procedure_not_found))]
+pub(crate) struct ProcedureNotFound(pub(crate) String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot deserialize stored procedure")]
+#[diagnostic(code(deser::procedure))]
+struct ProcedureDeserError;
+
+const PROCEDURES_PREFIX: &str = "PROCEDURES";
+
+fn procedure_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from(PROCEDURES_PREFIX),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn get_procedure(&self, name: &str) -> Result<Option<StoredProcedure>> {
+        match self.store_tx.get(&procedure_key(name), false)? {
+            None => Ok(None),
+            Some(data) => Ok(Some(
+                rmp_serde::from_slice(&data).map_err(|_| ProcedureDeserError)?,
+            )),
+        }
+    }
+
+    /// Saves the procedure as the next version of any existing one, returning the version
+    pub(crate) fn put_procedure(
+        &mut self,
+        name: &str,
+        params: Vec<String>,
+        src: String,
+    ) -> Result<u64> {
+        let version = match self.get_procedure(name)? {
+            None => 1,
+            Some(existing) => existing.version + 1,
+        };
+        let procedure = StoredProcedure {
+            name: SmartString::from(name),
+            params,
+            src,
+            version,
+        };
+        let mut val = vec![];
+        procedure.serialize(&mut Serializer::new(&mut val)).unwrap();
+        self.store_tx.put(&procedure_key(name), &val)?;
+        Ok(version)
+    }
+
+    pub(crate) fn remove_procedure(&mut self, name: &str) -> Result<()> {
+        let key = procedure_key(name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(ProcedureNotFound(name.to_string()))
+        }
+        self.store_tx.del(&key)
+    }
+
+    pub(crate) fn list_procedures(&self) -> Result<Vec<StoredProcedure>> {
+        let lower = procedure_key("");
+        let upper = vec![
+            DataValue::Null,
+            DataValue::from(PROCEDURES_PREFIX),
+            DataValue::Bot,
+        ]
+        .encode_as_key(RelationId::SYSTEM);
+        let mut ret = vec![];
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (_, v) = kv?;
+            ret.push(rmp_serde::from_slice(&v).map_err(|_| ProcedureDeserError)?);
+        }
+        Ok(ret)
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Call a stored procedure with the given arguments, in a transaction of its own.
+    pub fn call_procedure(&'s self, name: &str, args: Vec<DataValue>) -> Result<NamedRows> {
+        self.call_procedure_in_session(name, args, None)
+    }
+
+    pub(crate) fn call_procedure_in_session(
+        &'s self,
+        name: &str,
+        args: Vec<DataValue>,
+        session: Option<&Session>,
+    ) -> Result<NamedRows> {
+        let cur_vld = current_validity();
+        let ps = {
+            let tx = self.transact()?;
+            self.procedure_program(&tx, name, args, cur_vld)?
+        };
+        self.execute_imperative(cur_vld, &ps, session)
+    }
+
+    /// Call a stored procedure within the transaction of a trigger. The rows passed in
+    /// `inputs` are made available to every query of the procedure as constant rules.
+    pub(crate) fn call_procedure_in_tx(
+        &self,
+        tx: &mut SessionTx<'_>,
+        name: &Symbol,
+        args: Vec<DataValue>,
+        inputs: &[(&str, Vec<Symbol>, Vec<DataValue>)],
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut ps = self.procedure_program(tx, &name.name, args, cur_vld)?;
+        for stmt in ps.iter_mut() {
+            stmt.for_each_program_mut(&mut |prog| {
+                for (rule_name, bindings, data) in inputs {
+                    make_const_rule(prog, rule_name, bindings.clone(), data.clone());
+                }
+            });
+        }
+        let mut cleanups = vec![];
+        self.execute_imperative_in_tx(
+            &ps,
+            tx,
+            &mut cleanups,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+        )?;
+        Ok(cleanups)
+    }
+
+    fn procedure_program(
+        &self,
+        tx: &SessionTx<'_>,
+        name: &str,
+        args: Vec<DataValue>,
+        cur_vld: ValidityTs,
+    ) -> Result<ImperativeProgram> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Stored procedure {0} expects {1} arguments, got {2}")]
+        #[diagnostic(code(eval::procedure_arity_mismatch))]
+        struct ProcedureArityMismatch(String, usize, usize);
+
+        let procedure = match tx.get_procedure(name)? {
+            None => bail!(ProcedureNotFound(name.to_string())),
+            Some(p) => p,
+        };
+        ensure!(
+            procedure.params.len() == args.len(),
+            ProcedureArityMismatch(name.to_string(), procedure.params.len(), args.len())
+        );
+        let param_pool: BTreeMap<_, _> = procedure.params.iter().cloned().zip(args).collect();
+        match parse_script(
+            &procedure.src,
+            &param_pool,
+            &self.fixed_rules.read().unwrap(),
+            cur_vld,
+        )
+        .map_err(|err| err.with_source_code(procedure.src.clone()))?
+        {
+            CozoScript::Imperative(ps) => Ok(ps),
+            CozoScript::Single(prog) => Ok(vec![ImperativeStmt::Program { prog }]),
+            CozoScript::Sys(_) => bail!("Stored procedure {} is not an imperative script", name),
+        }
+    }
+}
@@ -12,13 +12,15 @@ use std::sync::atomic::Ordering;
 
 use itertools::Itertools;
 use log::error;
-use miette::{bail, ensure, Diagnostic, IntoDiagnostic, Result};
+use miette::{bail, ensure, Diagnostic, IntoDiagnostic, Result, WrapErr};
 use pest::Parser;
 use rmp_serde::Serializer;
 use serde::Serialize;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::{eval_bytecode, eval_bytecode_pred, Bytecode, Expr};
+use crate::data::functions::current_validity;
 use crate::data::memcmp::MemCmpEncoder;
 use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
 use crate::data::symb::Symbol;
@@ -26,12 +28,18 @@ use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT, ENCODED_KEY_MIN_L
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fts::FtsIndexManifest;
 use crate::parse::expr::build_expr;
-use crate::parse::sys::{FtsIndexConfig, HnswIndexConfig, MinHashLshConfig};
+use crate::parse::sys::{
+    AlterRelationOp, ExprIndexConfig, FtsIndexConfig, HnswIndexConfig, MinHashLshConfig,
+    RTreeIndexConfig,
+};
 use crate::parse::{CozoScriptParser, Rule, SourceSpan};
 use crate::query::compile::IndexPositionUse;
+use crate::query::stored::CheckConstraintViolation;
 use crate::runtime::hnsw::HnswIndexManifest;
 use crate::runtime::minhash_lsh::{HashPermutations, LshParams, MinHashLshIndexManifest, Weights};
+use crate::runtime::rtree::RTreeIndexManifest;
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::{RelationUsedByView, ViewManifest, WriteToView};
 use crate::{NamedRows, StoreTx};
 use crate::utils::TempCollector;
 
@@ -90,6 +98,184 @@ pub(crate) struct RelationHandle {
         (RelationHandle, RelationHandle, MinHashLshIndexManifest),
     >,
     pub(crate) description: SmartString<LazyCompact>,
+    #[serde(default)]
+    pub(crate) rtree_indices:
+        BTreeMap<SmartString<LazyCompact>, (RelationHandle, RTreeIndexManifest)>,
+    #[serde(default)]
+    pub(crate) expr_indices:
+        BTreeMap<SmartString<LazyCompact>, (RelationHandle, ExprIndexManifest)>,
+    #[serde(default)]
+    pub(crate) constraints: RelationConstraints,
+    /// relations having a column that references this relation, with the referencing column
+    #[serde(default)]
+    pub(crate) referenced_by: Vec<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+    /// set if the relation is a materialized view
+    #[serde(default)]
+    pub(crate) view: Option<ViewManifest>,
+    /// materialized views to be refreshed when this relation changes
+    #[serde(default)]
+    pub(crate) dependent_views: Vec<SmartString<LazyCompact>>,
+}
+
+/// An index or constraint depending on a column, preventing some schema changes
+enum ColumnUser {
+    PlainIndex(SmartString<LazyCompact>),
+    Index(SmartString<LazyCompact>),
+    Constraint(String),
+    Check(String),
+}
+
+impl Display for ColumnUser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColumnUser::PlainIndex(name) | ColumnUser::Index(name) => write!(f, "index {name}"),
+            ColumnUser::Constraint(desc) => write!(f, "{desc} constraint"),
+            ColumnUser::Check(src) => write!(f, "check constraint `{src}`"),
+        }
+    }
+}
+
+fn source_uses_column(src: &str, col: &str) -> Result<bool> {
+    let parsed = CozoScriptParser::parse(Rule::expr, src)
+        .into_diagnostic()?
+        .next()
+        .unwrap();
+    let expr = build_expr(parsed, &Default::default())?;
+    Ok(expr.bindings()?.iter().any(|b| b.name == col))
+}
+
+/// Constraints declared on the columns in the schema of a relation.
+/// Every constraint is backed by an ordinary index on the constrained column.
+#[derive(
+    Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub(crate) struct RelationConstraints {
+    /// columns whose non-null values must be distinct across rows
+    pub(crate) unique: Vec<SmartString<LazyCompact>>,
+    /// columns whose non-null values must be keys of the referenced relation
+    pub(crate) references: Vec<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+    /// predicates every row must satisfy, as source text
+    #[serde(default)]
+    pub(crate) checks: Vec<String>,
+}
+
+impl RelationConstraints {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.unique.is_empty() && self.references.is_empty() && self.checks.is_empty()
+    }
+    /// Compiles the check constraints against the columns of the relation
+    pub(crate) fn compile_checks(&self, rel_handle: &RelationHandle) -> Result<Vec<CompiledCheck>> {
+        let binding_map = rel_handle.raw_binding_map();
+        self.checks
+            .iter()
+            .map(|src| -> Result<CompiledCheck> {
+                let parsed = CozoScriptParser::parse(Rule::expr, src)
+                    .into_diagnostic()?
+                    .next()
+                    .unwrap();
+                let mut expr = build_expr(parsed, &Default::default())?;
+                expr.fill_binding_indices(&binding_map)?;
+                let cols = expr
+                    .bindings()?
+                    .iter()
+                    .filter_map(|b| binding_map.get(b).copied())
+                    .collect();
+                Ok(CompiledCheck {
+                    src: src.clone(),
+                    code: expr.compile()?,
+                    cols,
+                })
+            })
+            .try_collect()
+    }
+    pub(crate) fn unique_index_name(col: &str) -> SmartString<LazyCompact> {
+        SmartString::from(format!("unique_{col}"))
+    }
+    pub(crate) fn references_index_name(col: &str) -> SmartString<LazyCompact> {
+        SmartString::from(format!("fk_{col}"))
+    }
+    /// Whether the index is created and maintained for a constraint
+    pub(crate) fn backs_index(&self, index_name: &str) -> bool {
+        self.unique
+            .iter()
+            .any(|col| Self::unique_index_name(col) == index_name)
+            || self
+                .references
+                .iter()
+                .any(|(col, _)| Self::references_index_name(col) == index_name)
+    }
+}
+
+/// A check constraint compiled against the columns of its relation
+pub(crate) struct CompiledCheck {
+    pub(crate) src: String,
+    code: Vec<Bytecode>,
+    /// positions of the columns the check refers to
+    cols: Vec<usize>,
+}
+
+impl CompiledCheck {
+    /// As in SQL, a check is not enforced on rows where a column it refers to is null
+    pub(crate) fn check(
+        &self,
+        rel_name: &str,
+        tuple: &[DataValue],
+        stack: &mut Vec<DataValue>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        if self.cols.iter().any(|i| tuple[*i] == DataValue::Null) {
+            return Ok(());
+        }
+        if !eval_bytecode_pred(&self.code, tuple, stack, span)? {
+            bail!(CheckConstraintViolation(
+                rel_name.to_string(),
+                self.src.clone(),
+                DataValue::List(tuple.to_vec()),
+                span
+            ))
+        }
+        Ok(())
+    }
+}
+
+/// Index on computed expressions of the columns, optionally restricted to rows satisfying a filter.
+/// Rows of the index relation consist of the values of the expressions,
+/// followed by the keys of the base relation not already present.
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct ExprIndexManifest {
+    pub(crate) elements: Vec<String>,
+    pub(crate) filter: Option<String>,
+    /// for each key of the base relation, its position in the index relation
+    pub(crate) key_positions: Vec<usize>,
+}
+
+impl ExprIndexManifest {
+    pub(crate) fn parse_exprs(&self) -> Result<(Vec<Expr>, Option<Expr>)> {
+        let parse = |src: &str| -> Result<Expr> {
+            let parsed = CozoScriptParser::parse(Rule::expr, src)
+                .into_diagnostic()?
+                .next()
+                .unwrap();
+            build_expr(parsed, &Default::default())
+        };
+        let elements = self.elements.iter().map(|s| parse(s)).try_collect()?;
+        let filter = self.filter.as_ref().map(|s| parse(s)).transpose()?;
+        Ok((elements, filter))
+    }
+    pub(crate) fn compile(
+        &self,
+        rel_handle: &RelationHandle,
+    ) -> Result<(Vec<Vec<Bytecode>>, Option<Vec<Bytecode>>)> {
+        let (elements, filter) = self.parse_exprs()?;
+        let binding_map = rel_handle.raw_binding_map();
+        let mut compile = |mut expr: Expr| -> Result<Vec<Bytecode>> {
+            expr.fill_binding_indices(&binding_map)?;
+            expr.compile()
+        };
+        let elements = elements.into_iter().map(&mut compile).try_collect()?;
+        let filter = filter.map(compile).transpose()?;
+        Ok((elements, filter))
+    }
 }
 
 impl RelationHandle {
@@ -98,12 +284,95 @@ impl RelationHandle {
             || self.hnsw_indices.contains_key(index_name)
             || self.fts_indices.contains_key(index_name)
             || self.lsh_indices.contains_key(index_name)
+            || self.rtree_indices.contains_key(index_name)
+            || self.expr_indices.contains_key(index_name)
     }
-    pub(crate) fn has_no_index(&self) -> bool {
-        self.indices.is_empty()
+    /// Finds the indices and constraints depending on the given column
+    fn column_users(&self, col: &str) -> Result<Vec<ColumnUser>> {
+        let pos = match self
+            .metadata
+            .keys
+            .iter()
+            .chain(self.metadata.non_keys.iter())
+            .position(|c| c.name == col)
+        {
+            None => return Ok(vec![]),
+            Some(pos) => pos,
+        };
+        let uses_col = |src: &Option<String>| -> Result<bool> {
+            match src {
+                None => Ok(false),
+                Some(src) => source_uses_column(src, col),
+            }
+        };
+        let mut ret = vec![];
+        for (name, (_, extractor)) in &self.indices {
+            if !self.constraints.backs_index(name) && extractor.contains(&pos) {
+                ret.push(ColumnUser::PlainIndex(name.clone()));
+            }
+        }
+        for (name, (_, manifest)) in &self.hnsw_indices {
+            if manifest.vec_fields.contains(&pos) || uses_col(&manifest.index_filter)? {
+                ret.push(ColumnUser::Index(name.clone()));
+            }
+        }
+        for (name, (_, manifest)) in &self.fts_indices {
+            if source_uses_column(&manifest.extractor, col)? {
+                ret.push(ColumnUser::Index(name.clone()));
+            }
+        }
+        for (name, (_, _, manifest)) in &self.lsh_indices {
+            if source_uses_column(&manifest.extractor, col)? {
+                ret.push(ColumnUser::Index(name.clone()));
+            }
+        }
+        for (name, (_, manifest)) in &self.rtree_indices {
+            if source_uses_column(&manifest.extractor, col)? {
+                ret.push(ColumnUser::Index(name.clone()));
+            }
+        }
+        for (name, (_, manifest)) in &self.expr_indices {
+            let mut used = uses_col(&manifest.filter)?;
+            for element in &manifest.elements {
+                used = used || source_uses_column(element, col)?;
+            }
+            if used {
+                ret.push(ColumnUser::Index(name.clone()));
+            }
+        }
+        if self.constraints.unique.iter().any(|c| c == col) {
+            ret.push(ColumnUser::Constraint("unique".to_string()));
+        }
+        for (c, target) in &self.constraints.references {
+            if c == col {
+                ret.push(ColumnUser::Constraint(format!("foreign key to {target}")));
+            }
+        }
+        // foreign keys reference the key of the relation
+        if pos < self.metadata.keys.len() {
+            for (rel, c) in &self.referenced_by {
+                ret.push(ColumnUser::Constraint(format!(
+                    "foreign key from {rel}.{c}"
+                )));
+            }
+        }
+        for check in &self.constraints.checks {
+            if source_uses_column(check, col)? {
+                ret.push(ColumnUser::Check(check.clone()));
+            }
+        }
+        Ok(ret)
+    }
+    /// Indices backing constraints are not counted, as they are managed together with the relation
+    pub(crate) fn has_no_index_besides_constraints(&self) -> bool {
+        self.indices
+            .keys()
+            .all(|k| self.constraints.backs_index(k))
             && self.hnsw_indices.is_empty()
             && self.fts_indices.is_empty()
             && self.lsh_indices.is_empty()
+            && self.rtree_indices.is_empty()
+            && self.expr_indices.is_empty()
     }
 }
 
@@ -327,6 +596,8 @@ pub(crate) struct InputRelationHandle {
     pub(crate) key_bindings: Vec<Symbol>,
     pub(crate) dep_bindings: Vec<Symbol>,
     pub(crate) span: SourceSpan,
+    #[serde(default)]
+    pub(crate) constraints: RelationConstraints,
 }
 
 impl Debug for RelationHandle {
@@ -428,6 +699,32 @@ impl RelationHandle {
         }
     }
 
+    /// Like `exists`, but the transaction fails to commit if another one
+    /// writes the key in the meantime
+    pub(crate) fn exists_for_update(&self, tx: &SessionTx<'_>, key: &[DataValue]) -> Result<bool> {
+        let key_data = key.encode_as_key(self.id);
+        if self.is_temp {
+            tx.temp_store_tx.exists(&key_data, true)
+        } else {
+            tx.store_tx.exists(&key_data, true)
+        }
+    }
+
+    /// Makes concurrent transactions locking the same prefix conflict with each other,
+    /// which scanning the rows under the prefix does not do.
+    /// The prefix must be shorter than the keys of the relation, so that its own key is never
+    /// a row: that key is read for update, then deleted, which is a write for the purpose of
+    /// conflict detection but leaves nothing behind.
+    pub(crate) fn lock_prefix(&self, tx: &mut SessionTx<'_>, prefix: &[DataValue]) -> Result<()> {
+        // temp relations are private to the transaction
+        if self.is_temp {
+            return Ok(());
+        }
+        let key_data = prefix.encode_as_key(self.id);
+        tx.store_tx.exists(&key_data, true)?;
+        tx.store_tx.del(&key_data)
+    }
+
     pub(crate) fn scan_prefix<'a>(
         &self,
         tx: &'a SessionTx<'_>,
@@ -513,10 +810,38 @@ impl RelationHandle {
                 .range_skip_scan_tuple(&lower_encoded, &upper_encoded, valid_at)
         }
     }
+    /// The first key starting with `prefix` that is not less than `prefix` followed by `lower`
+    pub(crate) fn seek_in_prefix(
+        &self,
+        tx: &SessionTx<'_>,
+        prefix: &[DataValue],
+        lower: &[DataValue],
+    ) -> Result<Option<Tuple>> {
+        let mut lower_t = prefix.to_vec();
+        lower_t.extend_from_slice(lower);
+        let mut upper_t = prefix.to_vec();
+        upper_t.push(DataValue::Bot);
+        let lower_encoded = lower_t.encode_as_key(self.id);
+        let upper_encoded = upper_t.encode_as_key(self.id);
+        if self.is_temp {
+            tx.temp_store_tx
+                .range_scan_tuple(&lower_encoded, &upper_encoded)
+                .next()
+                .transpose()
+        } else {
+            tx.store_tx
+                .range_scan_tuple(&lower_encoded, &upper_encoded)
+                .next()
+                .transpose()
+        }
+    }
 }
 
 const DEFAULT_SIZE_HINT: usize = 16;
 
+/// Number of rows read at a time when rewriting the rows of an altered relation
+const ALTER_BATCH_SIZE: usize = 1024;
+
 /// Decode tuple from key-value pairs. Used for customizing storage
 /// in trait [`StoreTx`](crate::StoreTx).
 #[inline]
@@ -609,8 +934,8 @@ impl<'a> SessionTx<'a> {
         } else {
             self.relation_store_id.fetch_add(1, Ordering::SeqCst)
         };
-        let meta = RelationHandle {
-            name: input_meta.name.name,
+        let mut meta = RelationHandle {
+            name: input_meta.name.name.clone(),
             id: RelationId::new(last_id + 1),
             metadata,
             put_triggers: vec![],
@@ -623,7 +948,16 @@ impl<'a> SessionTx<'a> {
             fts_indices: Default::default(),
             lsh_indices: Default::default(),
             description: Default::default(),
+            rtree_indices: Default::default(),
+            expr_indices: Default::default(),
+            constraints: input_meta.constraints.clone(),
+            referenced_by: vec![],
+            view: None,
+            dependent_views: vec![],
         };
+        if !meta.constraints.is_empty() {
+            self.register_constraints(&mut meta, input_meta.span)?;
+        }
 
         let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
         let mut meta_val = vec![];
@@ -642,7 +976,79 @@ impl<'a> SessionTx<'a> {
             self.store_tx.put(&t_encoded, &meta.id.raw_encode())?;
         }
 
-        Ok(meta)
+        if meta.constraints.is_empty() {
+            Ok(meta)
+        } else {
+            let rel_name = Symbol::new(meta.name.clone(), input_meta.span);
+            for col in meta.constraints.unique.clone() {
+                let idx_name = RelationConstraints::unique_index_name(&col);
+                self.create_index(
+                    &rel_name,
+                    &Symbol::new(idx_name, input_meta.span),
+                    vec![Symbol::new(col, input_meta.span)],
+                )?;
+            }
+            for (col, _) in meta.constraints.references.clone() {
+                let idx_name = RelationConstraints::references_index_name(&col);
+                self.create_index(
+                    &rel_name,
+                    &Symbol::new(idx_name, input_meta.span),
+                    vec![Symbol::new(col, input_meta.span)],
+                )?;
+            }
+            self.get_relation(&meta.name, false)
+        }
+    }
+    /// Checks the declared constraints of a relation being created,
+    /// and records foreign keys on the referenced relations.
+    fn register_constraints(&mut self, meta: &mut RelationHandle, span: SourceSpan) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("unique and foreign key constraints are not supported for temp relation {0}")]
+        #[diagnostic(code(tx::constraint_on_temp_rel))]
+        struct ConstraintOnTempRelation(String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("relation {1} referenced by column {0} must have exactly one key column")]
+        #[diagnostic(code(tx::bad_referenced_rel))]
+        struct BadReferencedRelation(String, String, #[label] SourceSpan);
+
+        ensure!(
+            !meta.is_temp
+                || (meta.constraints.unique.is_empty() && meta.constraints.references.is_empty()),
+            ConstraintOnTempRelation(meta.name.to_string(), span)
+        );
+        // fails early if some check refers to columns not in the relation
+        meta.constraints.compile_checks(meta)?;
+        for (col, target) in meta.constraints.references.clone() {
+            if target == meta.name {
+                ensure!(
+                    meta.metadata.keys.len() == 1,
+                    BadReferencedRelation(col.to_string(), target.to_string(), span)
+                );
+                meta.referenced_by.push((meta.name.clone(), col));
+            } else {
+                let mut target_handle = self.get_relation(&target, true)?;
+                ensure!(
+                    target_handle.metadata.keys.len() == 1 && !target_handle.is_temp,
+                    BadReferencedRelation(col.to_string(), target.to_string(), span)
+                );
+                target_handle.referenced_by.push((meta.name.clone(), col));
+                self.put_relation_meta(&target_handle)?;
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn put_relation_meta(&mut self, meta: &RelationHandle) -> Result<()> {
+        let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        meta.serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
+            .unwrap();
+        if meta.is_temp {
+            self.temp_store_tx.put(&name_key, &meta_val)?;
+        } else {
+            self.store_tx.put(&name_key, &meta_val)?;
+        }
+        Ok(())
     }
     pub(crate) fn get_relation(&self, name: &str, lock: bool) -> Result<RelationHandle> {
         #[derive(Error, Diagnostic, Debug)]
@@ -693,12 +1099,31 @@ impl<'a> SessionTx<'a> {
         //     bail!("Cannot destroy temp relation");
         // }
         let store = self.get_relation(name, true)?;
-        if !store.has_no_index() {
+        if !store.has_no_index_besides_constraints() {
             bail!(
                 "Cannot remove stored relation `{}` with indices attached.",
                 name
             );
         }
+        if let Some(view) = store.dependent_views.first() {
+            bail!(RelationUsedByView(name.to_string(), view.to_string()))
+        }
+        if let Some((referencing, col)) = store
+            .referenced_by
+            .iter()
+            .find(|(referencing, _)| *referencing != store.name)
+        {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("cannot remove relation {0} since column {2} of relation {1} references it")]
+            #[diagnostic(code(tx::remove_referenced_rel))]
+            struct RemoveReferencedRelation(String, String, String);
+
+            bail!(RemoveReferencedRelation(
+                name.to_string(),
+                referencing.to_string(),
+                col.to_string()
+            ))
+        }
         if store.access_level < AccessLevel::Normal {
             bail!(InsufficientAccessLevel(
                 store.name.to_string(),
@@ -717,6 +1142,28 @@ impl<'a> SessionTx<'a> {
             to_clean.extend(more_to_clean);
         }
 
+        if let Some(manifest) = &store.view {
+            for base in &manifest.bases {
+                let mut base_handle = self.get_relation(base, true)?;
+                base_handle.dependent_views.retain(|v| *v != store.name);
+                self.put_relation_meta(&base_handle)?;
+            }
+            for kept in manifest.maintenance.kept_relations() {
+                let more_to_clean = self.destroy_relation(&kept)?;
+                to_clean.extend(more_to_clean);
+            }
+        }
+
+        for (col, target) in &store.constraints.references {
+            if *target != store.name {
+                let mut target_handle = self.get_relation(target, true)?;
+                target_handle
+                    .referenced_by
+                    .retain(|(referencing, c)| !(*referencing == store.name && c == col));
+                self.put_relation_meta(&target_handle)?;
+            }
+        }
+
         let key = DataValue::from(name);
         let encoded = vec![key].encode_as_key(RelationId::SYSTEM);
         if is_temp {
@@ -874,6 +1321,102 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    pub(crate) fn create_rtree_index(&mut self, config: RTreeIndexConfig) -> Result<()> {
+        // Get relation handle
+        let mut rel_handle = self.get_relation(&config.base_relation, true)?;
+
+        // Check if index already exists
+        if rel_handle.has_index(&config.index_name) {
+            bail!(IndexAlreadyExists(
+                config.index_name.to_string(),
+                config.index_name.to_string()
+            ));
+        }
+
+        // each row of the index is a node of the tree
+        let idx_keys = vec![ColumnDef {
+            name: SmartString::from("node"),
+            typing: NullableColType {
+                coltype: ColType::Int,
+                nullable: false,
+            },
+            default_gen: None,
+        }];
+        let idx_vals = vec![
+            ColumnDef {
+                name: SmartString::from("level"),
+                typing: NullableColType {
+                    coltype: ColType::Int,
+                    nullable: false,
+                },
+                default_gen: None,
+            },
+            ColumnDef {
+                name: SmartString::from("entries"),
+                typing: NullableColType {
+                    coltype: ColType::List {
+                        eltype: Box::new(NullableColType {
+                            coltype: ColType::Any,
+                            nullable: false,
+                        }),
+                        len: None,
+                    },
+                    nullable: false,
+                },
+                default_gen: None,
+            },
+        ];
+
+        let idx_handle = self.write_idx_relation(
+            &config.base_relation,
+            &config.index_name,
+            idx_keys,
+            idx_vals,
+        )?;
+
+        // add index to relation
+        let manifest = RTreeIndexManifest {
+            base_relation: config.base_relation,
+            index_name: config.index_name,
+            extractor: config.extractor,
+            max_entries: config.max_entries,
+        };
+
+        // populate index
+        let extractor = manifest.compile_extractor(&rel_handle)?;
+        let mut stack = vec![];
+
+        let mut existing = TempCollector::default();
+        for tuple in rel_handle.scan_all(self) {
+            existing.push(tuple?);
+        }
+        for tuple in existing.into_iter() {
+            self.put_rtree_index_item(
+                &tuple,
+                &extractor,
+                &mut stack,
+                &rel_handle,
+                &idx_handle,
+                &manifest,
+            )?;
+        }
+
+        rel_handle
+            .rtree_indices
+            .insert(manifest.index_name.clone(), (idx_handle, manifest));
+
+        // update relation metadata
+        let new_encoded =
+            vec![DataValue::from(&rel_handle.name as &str)].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        rel_handle
+            .serialize(&mut Serializer::new(&mut meta_val))
+            .unwrap();
+        self.store_tx.put(&new_encoded, &meta_val)?;
+
+        Ok(())
+    }
+
     pub(crate) fn create_fts_index(&mut self, config: FtsIndexConfig) -> Result<()> {
         // Get relation handle
         let mut rel_handle = self.get_relation(&config.base_relation, true)?;
@@ -1232,6 +1775,7 @@ impl<'a> SessionTx<'a> {
             key_bindings,
             dep_bindings,
             span: Default::default(),
+            constraints: Default::default(),
         };
         let idx_handle = self.create_relation(idx_handle)?;
         Ok(idx_handle)
@@ -1309,6 +1853,7 @@ impl<'a> SessionTx<'a> {
             key_bindings,
             dep_bindings: vec![],
             span: Default::default(),
+            constraints: Default::default(),
         };
 
         let idx_handle = self.create_relation(idx_handle)?;
@@ -1375,16 +1920,214 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    pub(crate) fn create_expr_index(&mut self, config: ExprIndexConfig) -> Result<()> {
+        let rel_name = &config.base_relation;
+        let idx_name = &config.index_name;
+        // Get relation handle
+        let mut rel_handle = self.get_relation(rel_name, true)?;
+
+        // Check if index already exists
+        if rel_handle.has_index(&idx_name.name) {
+            bail!(IndexAlreadyExists(
+                idx_name.name.to_string(),
+                rel_name.name.to_string()
+            ));
+        }
+
+        let mut manifest = ExprIndexManifest {
+            elements: config.elements,
+            filter: config.filter,
+            key_positions: vec![],
+        };
+        let (elements, _) = manifest.parse_exprs()?;
+
+        // Build column definitions: plain columns keep their definitions,
+        // computed values can be anything
+        let mut col_defs = vec![];
+        for (i, expr) in elements.iter().enumerate() {
+            let orig_col = expr.get_binding().and_then(|var| {
+                rel_handle
+                    .metadata
+                    .keys
+                    .iter()
+                    .chain(rel_handle.metadata.non_keys.iter())
+                    .find(|col| col.name == var.name)
+            });
+            col_defs.push(match orig_col {
+                Some(col) => col.clone(),
+                None => ColumnDef {
+                    name: format!("expr_{i}").into(),
+                    typing: NullableColType {
+                        coltype: ColType::Any,
+                        nullable: true,
+                    },
+                    default_gen: None,
+                },
+            });
+        }
+        for key in rel_handle.metadata.keys.iter() {
+            let pos = match elements.iter().position(|expr| {
+                expr.get_binding()
+                    .map(|var| var.name == key.name)
+                    .unwrap_or(false)
+            }) {
+                Some(pos) => pos,
+                None => {
+                    col_defs.push(key.clone());
+                    col_defs.len() - 1
+                }
+            };
+            manifest.key_positions.push(pos);
+        }
+
+        let key_bindings = col_defs
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let idx_meta = StoredRelationMetadata {
+            keys: col_defs,
+            non_keys: vec![],
+        };
+
+        // create index relation
+        let idx_handle = InputRelationHandle {
+            name: Symbol::new(
+                format!("{}:{}", rel_name.name, idx_name.name),
+                Default::default(),
+            ),
+            metadata: idx_meta,
+            key_bindings,
+            dep_bindings: vec![],
+            span: Default::default(),
+            constraints: Default::default(),
+        };
+
+        let idx_handle = self.create_relation(idx_handle)?;
+
+        // populate index, this also checks that the expressions only refer to existing columns
+        let (extractors, filter) = manifest.compile(&rel_handle)?;
+        let mut stack = vec![];
+        let mut existing = TempCollector::default();
+        for tuple in rel_handle.scan_all(self) {
+            existing.push(tuple?);
+        }
+        for tuple in existing.into_iter() {
+            self.put_expr_index_item(
+                &tuple,
+                &extractors,
+                &filter,
+                &mut stack,
+                &rel_handle,
+                &idx_handle,
+                &manifest,
+            )?;
+        }
+
+        // add index to relation
+        rel_handle
+            .expr_indices
+            .insert(idx_name.name.clone(), (idx_handle, manifest));
+
+        // update relation metadata
+        let new_encoded =
+            vec![DataValue::from(&rel_name.name as &str)].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        rel_handle
+            .serialize(&mut Serializer::new(&mut meta_val))
+            .unwrap();
+        self.store_tx.put(&new_encoded, &meta_val)?;
+
+        Ok(())
+    }
+
+    fn expr_index_tuple(
+        &self,
+        tuple: &[DataValue],
+        extractors: &[Vec<Bytecode>],
+        filter: &Option<Vec<Bytecode>>,
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        manifest: &ExprIndexManifest,
+    ) -> Result<Option<Vec<DataValue>>> {
+        if let Some(code) = filter {
+            if !eval_bytecode_pred(code, tuple, stack, Default::default())? {
+                return Ok(None);
+            }
+        }
+        let mut ret: Vec<_> = extractors
+            .iter()
+            .map(|code| eval_bytecode(code, tuple, stack))
+            .try_collect()?;
+        for (i, pos) in manifest.key_positions.iter().enumerate() {
+            if *pos >= extractors.len() {
+                ret.push(tuple[i].clone());
+            }
+        }
+        debug_assert!(ret.len() >= rel_handle.metadata.keys.len());
+        Ok(Some(ret))
+    }
+
+    pub(crate) fn put_expr_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractors: &[Vec<Bytecode>],
+        filter: &Option<Vec<Bytecode>>,
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        manifest: &ExprIndexManifest,
+    ) -> Result<()> {
+        if let Some(idx_tuple) =
+            self.expr_index_tuple(tuple, extractors, filter, stack, rel_handle, manifest)?
+        {
+            let key = idx_handle.encode_key_for_store(&idx_tuple, Default::default())?;
+            self.store_tx.put(&key, &[])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn del_expr_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractors: &[Vec<Bytecode>],
+        filter: &Option<Vec<Bytecode>>,
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        manifest: &ExprIndexManifest,
+    ) -> Result<()> {
+        if let Some(idx_tuple) =
+            self.expr_index_tuple(tuple, extractors, filter, stack, rel_handle, manifest)?
+        {
+            let key = idx_handle.encode_key_for_store(&idx_tuple, Default::default())?;
+            self.store_tx.del(&key)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn remove_index(
         &mut self,
         rel_name: &Symbol,
         idx_name: &Symbol,
     ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let mut rel = self.get_relation(rel_name, true)?;
+        if rel.constraints.backs_index(&idx_name.name) {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("index {0} for relation {1} backs a constraint and cannot be removed")]
+            #[diagnostic(code(tx::remove_constraint_idx))]
+            pub(crate) struct RemoveConstraintIndex(String, String);
+
+            bail!(RemoveConstraintIndex(
+                idx_name.to_string(),
+                rel_name.to_string()
+            ));
+        }
         let is_lsh = rel.lsh_indices.contains_key(&idx_name.name);
         if rel.indices.remove(&idx_name.name).is_none()
             && rel.hnsw_indices.remove(&idx_name.name).is_none()
             && rel.lsh_indices.remove(&idx_name.name).is_none()
+            && rel.rtree_indices.remove(&idx_name.name).is_none()
+            && rel.expr_indices.remove(&idx_name.name).is_none()
         {
             #[derive(Debug, Error, Diagnostic)]
             #[error("index {0} for relation {1} not found")]
@@ -1411,6 +2154,252 @@ impl<'a> SessionTx<'a> {
         Ok(to_clean)
     }
 
+    /// Changes the columns of a relation. The relation keeps its id, indices,
+    /// triggers and access level.
+    ///
+    /// Except for renaming, this is a blocking full rewrite: every row is rewritten
+    /// within the transaction, which holds all the rewritten rows until it commits,
+    /// so it takes time and memory proportional to the size of the relation.
+    pub(crate) fn alter_relation(&mut self, rel_name: &Symbol, op: AlterRelationOp) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} not found in relation {1}")]
+        #[diagnostic(code(tx::alter_col_not_found))]
+        struct AlterColumnNotFound(String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} already exists in relation {1}")]
+        #[diagnostic(code(tx::alter_col_exists))]
+        struct AlterColumnExists(String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("cannot drop or change the type of key column {0} of relation {1}")]
+        #[diagnostic(code(tx::alter_key_col))]
+        struct AlterKeyColumn(String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} of relation {1} is used by {2}")]
+        #[diagnostic(code(tx::alter_col_in_use))]
+        #[diagnostic(help("Remove the index or constraint first"))]
+        struct AlterColumnInUse(String, String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("new column {0} must either be nullable or have a default")]
+        #[diagnostic(code(tx::alter_add_no_default))]
+        struct AddColumnWithoutDefault(String, #[label] SourceSpan);
+
+        let mut handle = self.get_relation(rel_name, true)?;
+        if handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "schema alteration".to_string(),
+                handle.access_level
+            ));
+        }
+        if handle.view.is_some() {
+            bail!(WriteToView(handle.name.to_string()))
+        }
+        if let Some(view) = handle.dependent_views.first() {
+            bail!(RelationUsedByView(handle.name.to_string(), view.to_string()))
+        }
+        let n_keys = handle.metadata.keys.len();
+        let find_col = |handle: &RelationHandle, col: &Symbol| {
+            handle
+                .metadata
+                .keys
+                .iter()
+                .chain(handle.metadata.non_keys.iter())
+                .position(|c| c.name == col.name)
+        };
+        let ensure_not_key = |pos: usize, col: &Symbol| -> Result<()> {
+            ensure!(
+                pos >= n_keys,
+                AlterKeyColumn(col.name.to_string(), rel_name.name.to_string(), col.span)
+            );
+            Ok(())
+        };
+        let cur_vld = current_validity();
+
+        /// How each existing row is changed
+        enum RowChange {
+            Add(ColumnDef),
+            Drop(usize),
+            Convert(usize, NullableColType),
+        }
+
+        let row_change = match op {
+            AlterRelationOp::AddColumn(col, checks) => {
+                let col_name = Symbol::new(col.name.clone(), rel_name.span);
+                if find_col(&handle, &col_name).is_some() {
+                    bail!(AlterColumnExists(
+                        col.name.to_string(),
+                        rel_name.name.to_string(),
+                        rel_name.span
+                    ))
+                }
+                ensure!(
+                    col.default_gen.is_some() || col.typing.nullable,
+                    AddColumnWithoutDefault(col.name.to_string(), rel_name.span)
+                );
+                handle.metadata.non_keys.push(col.clone());
+                handle.constraints.checks.extend(checks);
+                Some(RowChange::Add(col))
+            }
+            AlterRelationOp::DropColumn(col) => {
+                let pos = find_col(&handle, &col).ok_or_else(|| {
+                    AlterColumnNotFound(col.name.to_string(), rel_name.name.to_string(), col.span)
+                })?;
+                ensure_not_key(pos, &col)?;
+                if let Some(user) = handle.column_users(&col.name)?.first() {
+                    bail!(AlterColumnInUse(
+                        col.name.to_string(),
+                        rel_name.name.to_string(),
+                        user.to_string(),
+                        col.span
+                    ))
+                }
+                handle.metadata.non_keys.remove(pos - n_keys);
+                // positions of the columns after the dropped one are shifted
+                for (_, extractor) in handle.indices.values_mut() {
+                    for i in extractor.iter_mut() {
+                        if *i > pos {
+                            *i -= 1;
+                        }
+                    }
+                }
+                for (_, manifest) in handle.hnsw_indices.values_mut() {
+                    for i in manifest.vec_fields.iter_mut() {
+                        if *i > pos {
+                            *i -= 1;
+                        }
+                    }
+                }
+                Some(RowChange::Drop(pos))
+            }
+            AlterRelationOp::RenameColumn(old, new) => {
+                let pos = find_col(&handle, &old).ok_or_else(|| {
+                    AlterColumnNotFound(old.name.to_string(), rel_name.name.to_string(), old.span)
+                })?;
+                if find_col(&handle, &new).is_some() {
+                    bail!(AlterColumnExists(
+                        new.name.to_string(),
+                        rel_name.name.to_string(),
+                        new.span
+                    ))
+                }
+                for user in handle.column_users(&old.name)? {
+                    if !matches!(user, ColumnUser::PlainIndex(_)) {
+                        bail!(AlterColumnInUse(
+                            old.name.to_string(),
+                            rel_name.name.to_string(),
+                            user.to_string(),
+                            old.span
+                        ))
+                    }
+                }
+                if pos < n_keys {
+                    handle.metadata.keys[pos].name = new.name.clone();
+                } else {
+                    handle.metadata.non_keys[pos - n_keys].name = new.name.clone();
+                }
+                // plain indices carry the names of the columns they contain
+                for (idx_handle, _) in handle.indices.values_mut() {
+                    for c in idx_handle
+                        .metadata
+                        .keys
+                        .iter_mut()
+                        .chain(idx_handle.metadata.non_keys.iter_mut())
+                    {
+                        if c.name == old.name {
+                            c.name = new.name.clone();
+                        }
+                    }
+                    self.put_relation_meta(idx_handle)?;
+                }
+                None
+            }
+            AlterRelationOp::ChangeType(col, typing) => {
+                let pos = find_col(&handle, &col).ok_or_else(|| {
+                    AlterColumnNotFound(col.name.to_string(), rel_name.name.to_string(), col.span)
+                })?;
+                ensure_not_key(pos, &col)?;
+                for user in handle.column_users(&col.name)? {
+                    if !matches!(user, ColumnUser::Check(_)) {
+                        bail!(AlterColumnInUse(
+                            col.name.to_string(),
+                            rel_name.name.to_string(),
+                            user.to_string(),
+                            col.span
+                        ))
+                    }
+                }
+                handle.metadata.non_keys[pos - n_keys].typing = typing.clone();
+                Some(RowChange::Convert(pos, typing))
+            }
+        };
+
+        if let Some(row_change) = row_change {
+            let checks = handle.constraints.compile_checks(&handle)?;
+            let mut stack = vec![];
+            // the store cannot be written while it is being scanned, so the rows are read a batch
+            // at a time, each starting after the last row of the previous one
+            let mut lower = Tuple::default().encode_as_key(handle.id);
+            let upper = Tuple::default().encode_as_key(handle.id.next());
+            loop {
+                let batch: Vec<(Vec<u8>, Vec<u8>)> = if handle.is_temp {
+                    self.temp_store_tx
+                        .range_scan(&lower, &upper)
+                        .take(ALTER_BATCH_SIZE)
+                        .try_collect()?
+                } else {
+                    self.store_tx
+                        .range_scan(&lower, &upper)
+                        .take(ALTER_BATCH_SIZE)
+                        .try_collect()?
+                };
+                match batch.last() {
+                    None => break,
+                    Some((last_key, _)) => {
+                        // the smallest key after the last one
+                        lower = last_key.clone();
+                        lower.push(0);
+                    }
+                }
+                for (key, val) in batch {
+                    let mut row = decode_tuple_from_kv(&key, &val, None);
+                    match &row_change {
+                        RowChange::Add(col) => {
+                            let val = match &col.default_gen {
+                                Some(expr) => expr.clone().eval_to_const()?,
+                                None => DataValue::Null,
+                            };
+                            row.push(col.typing.coerce(val, cur_vld)?);
+                        }
+                        RowChange::Drop(pos) => {
+                            row.remove(*pos);
+                        }
+                        RowChange::Convert(pos, typing) => {
+                            let val = std::mem::replace(&mut row[*pos], DataValue::Null);
+                            row[*pos] = typing
+                                .coerce(val, cur_vld)
+                                .wrap_err_with(|| format!("when converting tuple {row:?}"))?;
+                        }
+                    }
+                    for check in &checks {
+                        check.check(&handle.name, &row, &mut stack, rel_name.span)?;
+                    }
+                    let key = handle.encode_key_for_store(&row, rel_name.span)?;
+                    let val = handle.encode_val_for_store(&row, rel_name.span)?;
+                    if handle.is_temp {
+                        self.temp_store_tx.put(&key, &val)?;
+                    } else {
+                        self.store_tx.put(&key, &val)?;
+                    }
+                }
+            }
+        }
+        self.put_relation_meta(&handle)
+    }
+
     pub(crate) fn rename_relation(&mut self, old: Symbol, new: Symbol) -> Result<()> {
         if old.name.starts_with('_') || new.name.starts_with('_') {
             bail!("Bad name given");
@@ -1433,6 +2422,18 @@ impl<'a> SessionTx<'a> {
                 rel.access_level
             ));
         }
+        if !rel.constraints.references.is_empty() || !rel.referenced_by.is_empty() {
+            bail!(
+                "Cannot rename relation `{}` taking part in foreign key constraints",
+                old.name
+            );
+        }
+        if rel.view.is_some() || !rel.dependent_views.is_empty() {
+            bail!(
+                "Cannot rename relation `{}` taking part in materialized views",
+                old.name
+            );
+        }
         rel.name = new.name;
 
         let mut meta_val = vec![];
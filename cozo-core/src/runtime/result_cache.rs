@@ -0,0 +1,184 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// The result cache keeps the results of read-only queries, keyed by the script
+// and its parameters. Every cached result records the stored relations its
+// query read, and committing a write to any of them drops the result. Writes
+// not attributed to particular relations, such as schema changes, drop all.
+//
+// A query may read its data before a write commits and finish after the write
+// has invalidated the cache. The cache therefore counts invalidations, and a
+// result is only kept if none of the relations it read was written since the
+// query started.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::value::DataValue;
+use crate::fixed_rule::DEFAULT_FIXED_RULES;
+use crate::NamedRows;
+
+/// Functions, aggregations and literals whose value is not determined by the stored data
+const VOLATILE_NAMES: &[&str] = &[
+    "now",
+    "current_timestamp",
+    "rand_float",
+    "rand_bernoulli",
+    "rand_int",
+    "rand_choose",
+    "rand_vec",
+    "rand_uuid_v1",
+    "rand_uuid_v4",
+    "choice_rand",
+    "NOW",
+];
+
+/// Built-in fixed rules whose results are not determined by their inputs
+const VOLATILE_FIXED_RULES: &[&str] =
+    &["RandomWalk", "LabelPropagation", "CsvReader", "JsonReader"];
+
+/// Whether the source mentions anything making results vary between runs.
+/// Any identifier with the name of a volatile function counts, even inside
+/// string literals, so this errs on the side of not caching.
+pub(crate) fn mentions_volatile(src: &str) -> bool {
+    src.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|token| VOLATILE_NAMES.contains(&token))
+}
+
+/// Whether results of the fixed rule of the given name may be cached
+pub(crate) fn is_cacheable_fixed_rule(name: &str) -> bool {
+    DEFAULT_FIXED_RULES.contains_key(name) && !VOLATILE_FIXED_RULES.contains(&name)
+}
+
+/// The script, with surrounding whitespace trimmed, and the parameters
+pub(crate) type ResultCacheKey = (String, BTreeMap<String, DataValue>);
+
+pub(crate) fn result_cache_key(
+    script: &str,
+    params: &BTreeMap<String, DataValue>,
+) -> ResultCacheKey {
+    (script.trim().to_string(), params.clone())
+}
+
+struct CachedResult {
+    rows: NamedRows,
+    reads: BTreeSet<SmartString<LazyCompact>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct ResultCache {
+    /// maximal number of results kept, zero for disabled
+    capacity: usize,
+    entries: BTreeMap<ResultCacheKey, CachedResult>,
+    /// keys of the entries by the time they were last used
+    recency: BTreeMap<u64, ResultCacheKey>,
+    uses: u64,
+    /// number of invalidations so far
+    clock: u64,
+    /// clock at the last write to each relation
+    last_written: BTreeMap<SmartString<LazyCompact>, u64>,
+    /// clock at the last time all entries were dropped
+    last_cleared: u64,
+}
+
+impl ResultCache {
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.evict_oldest();
+        }
+    }
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity != 0
+    }
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+    /// To be taken before a query starts reading, and passed to [Self::insert]
+    pub(crate) fn clock(&self) -> u64 {
+        self.clock
+    }
+    pub(crate) fn get(&mut self, key: &ResultCacheKey) -> Option<NamedRows> {
+        let entry = self.entries.get_mut(key)?;
+        self.uses += 1;
+        let key = self.recency.remove(&entry.last_used).unwrap();
+        self.recency.insert(self.uses, key);
+        entry.last_used = self.uses;
+        Some(entry.rows.clone())
+    }
+    /// Keeps the result unless any of the relations read was written after `since`
+    pub(crate) fn insert(
+        &mut self,
+        key: ResultCacheKey,
+        rows: NamedRows,
+        reads: BTreeSet<SmartString<LazyCompact>>,
+        since: u64,
+    ) {
+        if !self.is_enabled() || self.last_cleared > since {
+            return;
+        }
+        if reads
+            .iter()
+            .any(|rel| matches!(self.last_written.get(rel), Some(t) if *t > since))
+        {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&key) {
+            self.recency.remove(&old.last_used);
+        }
+        while self.entries.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        self.uses += 1;
+        self.recency.insert(self.uses, key.clone());
+        self.entries.insert(
+            key,
+            CachedResult {
+                rows,
+                reads,
+                last_used: self.uses,
+            },
+        );
+    }
+    /// Drops the results that read any of the written relations,
+    /// or all results if no relation is given
+    pub(crate) fn invalidate(&mut self, written: &BTreeSet<SmartString<LazyCompact>>) {
+        if written.is_empty() {
+            self.clear();
+            return;
+        }
+        self.clock += 1;
+        for rel in written {
+            self.last_written.insert(rel.clone(), self.clock);
+        }
+        let stale = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.reads.is_disjoint(written))
+            .map(|(key, entry)| (key.clone(), entry.last_used))
+            .collect::<Vec<_>>();
+        for (key, last_used) in stale {
+            self.entries.remove(&key);
+            self.recency.remove(&last_used);
+        }
+    }
+    pub(crate) fn clear(&mut self) {
+        self.clock += 1;
+        self.last_cleared = self.clock;
+        self.entries.clear();
+        self.recency.clear();
+    }
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+}
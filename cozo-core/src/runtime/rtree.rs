@@ -0,0 +1,580 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// The R-tree follows Guttman's original paper with the quadratic split.
+// Every node is stored as one row of the index relation, keyed by its ID:
+// the root always has ID 0, other nodes get random IDs. Each entry of a node
+// is a list `[min_x, min_y, max_x, max_y, payload]`, where the payload is
+// the ID of the child node for internal nodes, and the list of keys of the
+// indexed row for leaves.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result};
+use ordered_float::OrderedFloat;
+use pest::Parser;
+use rand::Rng;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::{eval_bytecode, eval_bytecode_pred, Bytecode};
+use crate::data::functions::op_geometry;
+use crate::data::geo::{BBox, Geometry};
+use crate::data::tuple::Tuple;
+use crate::parse::expr::build_expr;
+use crate::parse::{CozoScriptParser, Rule};
+use crate::runtime::relation::RelationHandle;
+use crate::runtime::transact::SessionTx;
+use crate::{DataValue, Expr, SourceSpan, Symbol};
+
+const ROOT_NODE: i64 = 0;
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct RTreeIndexManifest {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    pub(crate) extractor: String,
+    pub(crate) max_entries: usize,
+}
+
+impl RTreeIndexManifest {
+    pub(crate) fn compile_extractor(&self, rel_handle: &RelationHandle) -> Result<Vec<Bytecode>> {
+        let parsed = CozoScriptParser::parse(Rule::expr, &self.extractor)
+            .into_diagnostic()?
+            .next()
+            .unwrap();
+        let mut code_expr = build_expr(parsed, &Default::default())?;
+        let binding_map = rel_handle.raw_binding_map();
+        code_expr.fill_binding_indices(&binding_map)?;
+        code_expr.compile()
+    }
+    fn min_entries(&self) -> usize {
+        (self.max_entries * 2 / 5).max(1)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum RTreeSearchKind {
+    /// rows whose geometry intersects the query
+    Intersects,
+    /// rows ordered by their distance to the query
+    Nearest,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct RTreeSearch {
+    pub(crate) base_handle: RelationHandle,
+    pub(crate) idx_handle: RelationHandle,
+    pub(crate) manifest: RTreeIndexManifest,
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) kind: RTreeSearchKind,
+    pub(crate) query: Symbol,
+    pub(crate) within_distance: Option<f64>,
+    /// distances are on the sphere in meters, with coordinates read as longitudes and latitudes
+    pub(crate) geodesic: bool,
+    pub(crate) k: Option<usize>,
+    pub(crate) bind_distance: Option<Symbol>,
+    pub(crate) filter: Option<Expr>,
+    pub(crate) span: SourceSpan,
+}
+
+impl RTreeSearch {
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_distance.iter())
+    }
+}
+
+struct RTreeNode {
+    level: i64,
+    entries: Vec<(BBox, DataValue)>,
+}
+
+impl RTreeNode {
+    fn bbox(&self) -> BBox {
+        let mut it = self.entries.iter();
+        let first = it.next().unwrap().0;
+        it.fold(first, |acc, (b, _)| acc.union(b))
+    }
+}
+
+/// Converts the result of an extractor to a geometry. Nulls are not indexed.
+pub(crate) fn to_geometry(v: DataValue) -> Result<Option<Geometry>> {
+    match v {
+        DataValue::Null => Ok(None),
+        DataValue::Geometry(g) => Ok(Some(g)),
+        v @ (DataValue::Str(_) | DataValue::Json(_)) => match op_geometry(&[v])? {
+            DataValue::Geometry(g) => Ok(Some(g)),
+            _ => unreachable!(),
+        },
+        v => bail!("Cannot put value {:?} into an R-tree index", v),
+    }
+}
+
+fn encode_entry(bbox: &BBox, payload: DataValue) -> DataValue {
+    DataValue::List(vec![
+        DataValue::from(bbox.min_x),
+        DataValue::from(bbox.min_y),
+        DataValue::from(bbox.max_x),
+        DataValue::from(bbox.max_y),
+        payload,
+    ])
+}
+
+fn decode_entry(entry: DataValue) -> (BBox, DataValue) {
+    match entry {
+        DataValue::List(mut l) => {
+            let payload = l.pop().unwrap();
+            let coords = l.iter().map(|v| v.get_float().unwrap()).collect_vec();
+            (
+                BBox {
+                    min_x: coords[0],
+                    min_y: coords[1],
+                    max_x: coords[2],
+                    max_y: coords[3],
+                },
+                payload,
+            )
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Guttman's quadratic split
+fn split_entries(
+    mut entries: Vec<(BBox, DataValue)>,
+    min_entries: usize,
+) -> (Vec<(BBox, DataValue)>, Vec<(BBox, DataValue)>) {
+    let waste = |a: &BBox, b: &BBox| {
+        let u = a.union(b);
+        (
+            OrderedFloat(u.area() - a.area() - b.area()),
+            OrderedFloat(u.margin() - a.margin() - b.margin()),
+        )
+    };
+    let (seed_a, seed_b) = (0..entries.len())
+        .tuple_combinations()
+        .max_by_key(|(i, j)| waste(&entries[*i].0, &entries[*j].0))
+        .unwrap();
+    // remove the larger index first so that the smaller one stays valid
+    let entry_b = entries.swap_remove(seed_b);
+    let entry_a = entries.swap_remove(seed_a);
+    let mut box_a = entry_a.0;
+    let mut box_b = entry_b.0;
+    let mut group_a = vec![entry_a];
+    let mut group_b = vec![entry_b];
+
+    while !entries.is_empty() {
+        if group_a.len() + entries.len() <= min_entries {
+            group_a.append(&mut entries);
+            break;
+        }
+        if group_b.len() + entries.len() <= min_entries {
+            group_b.append(&mut entries);
+            break;
+        }
+        let (idx, _) = entries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (b, _))| {
+                OrderedFloat((box_a.enlargement(b) - box_b.enlargement(b)).abs())
+            })
+            .unwrap();
+        let entry = entries.swap_remove(idx);
+        let key_a = (
+            OrderedFloat(box_a.enlargement(&entry.0)),
+            OrderedFloat(box_a.area()),
+            group_a.len(),
+        );
+        let key_b = (
+            OrderedFloat(box_b.enlargement(&entry.0)),
+            OrderedFloat(box_b.area()),
+            group_b.len(),
+        );
+        if key_a <= key_b {
+            box_a = box_a.union(&entry.0);
+            group_a.push(entry);
+        } else {
+            box_b = box_b.union(&entry.0);
+            group_b.push(entry);
+        }
+    }
+    (group_a, group_b)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum NearestCandidate {
+    Node(i64),
+    Row(Tuple),
+}
+
+impl<'a> SessionTx<'a> {
+    fn rtree_load_node(&self, idx_handle: &RelationHandle, id: i64) -> Result<Option<RTreeNode>> {
+        Ok(idx_handle
+            .get_val_only(self, &[DataValue::from(id)])?
+            .map(|mut vals| {
+                let entries = match vals.pop() {
+                    Some(DataValue::List(l)) => l.into_iter().map(decode_entry).collect_vec(),
+                    _ => unreachable!(),
+                };
+                let level = vals[0].get_int().unwrap();
+                RTreeNode { level, entries }
+            }))
+    }
+    fn rtree_save_node(
+        &mut self,
+        idx_handle: &RelationHandle,
+        id: i64,
+        node: &RTreeNode,
+    ) -> Result<()> {
+        let tuple = vec![
+            DataValue::from(id),
+            DataValue::from(node.level),
+            DataValue::List(
+                node.entries
+                    .iter()
+                    .map(|(b, payload)| encode_entry(b, payload.clone()))
+                    .collect_vec(),
+            ),
+        ];
+        let key = idx_handle.encode_key_for_store(&tuple, Default::default())?;
+        let val = idx_handle.encode_val_for_store(&tuple, Default::default())?;
+        self.store_tx.put(&key, &val)?;
+        Ok(())
+    }
+    fn rtree_del_node(&mut self, idx_handle: &RelationHandle, id: i64) -> Result<()> {
+        let key = idx_handle.encode_key_for_store(&[DataValue::from(id)], Default::default())?;
+        self.store_tx.del(&key)?;
+        Ok(())
+    }
+    fn rtree_new_node_id(&self, idx_handle: &RelationHandle) -> Result<i64> {
+        let mut rng = rand::thread_rng();
+        loop {
+            let id = rng.gen_range(1..i64::MAX);
+            if !idx_handle.exists(self, &[DataValue::from(id)])? {
+                return Ok(id);
+            }
+        }
+    }
+    pub(crate) fn put_rtree_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        manifest: &RTreeIndexManifest,
+    ) -> Result<()> {
+        let geom = match to_geometry(eval_bytecode(extractor, tuple, stack)?)? {
+            None => return Ok(()),
+            Some(g) => g,
+        };
+        let bbox = geom.bbox();
+        let key = DataValue::List(tuple[..rel_handle.metadata.keys.len()].to_vec());
+
+        // descend to the leaf, remembering the path and the chosen entries
+        let mut path: Vec<(i64, RTreeNode, usize)> = vec![];
+        let mut cur_id = ROOT_NODE;
+        let mut cur = self
+            .rtree_load_node(idx_handle, ROOT_NODE)?
+            .unwrap_or(RTreeNode {
+                level: 0,
+                entries: vec![],
+            });
+        while cur.level > 0 {
+            let (chosen, _) = cur
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (b, _))| {
+                    (OrderedFloat(b.enlargement(&bbox)), OrderedFloat(b.area()))
+                })
+                .unwrap();
+            let child_id = cur.entries[chosen].1.get_int().unwrap();
+            let child = self
+                .rtree_load_node(idx_handle, child_id)?
+                .ok_or_else(|| miette!("R-tree node {} not found", child_id))?;
+            path.push((cur_id, cur, chosen));
+            cur_id = child_id;
+            cur = child;
+        }
+        cur.entries.push((bbox, key));
+
+        // go back up, splitting overflowing nodes and adjusting bounding boxes
+        loop {
+            let mut new_sibling = None;
+            if cur.entries.len() > manifest.max_entries {
+                let entries = std::mem::take(&mut cur.entries);
+                let (a, b) = split_entries(entries, manifest.min_entries());
+                if cur_id == ROOT_NODE {
+                    // the root keeps its ID, so both halves move to new nodes
+                    let a_node = RTreeNode {
+                        level: cur.level,
+                        entries: a,
+                    };
+                    let b_node = RTreeNode {
+                        level: cur.level,
+                        entries: b,
+                    };
+                    let a_id = self.rtree_new_node_id(idx_handle)?;
+                    self.rtree_save_node(idx_handle, a_id, &a_node)?;
+                    let b_id = self.rtree_new_node_id(idx_handle)?;
+                    self.rtree_save_node(idx_handle, b_id, &b_node)?;
+                    cur = RTreeNode {
+                        level: cur.level + 1,
+                        entries: vec![
+                            (a_node.bbox(), DataValue::from(a_id)),
+                            (b_node.bbox(), DataValue::from(b_id)),
+                        ],
+                    };
+                } else {
+                    cur.entries = a;
+                    let b_node = RTreeNode {
+                        level: cur.level,
+                        entries: b,
+                    };
+                    let b_id = self.rtree_new_node_id(idx_handle)?;
+                    self.rtree_save_node(idx_handle, b_id, &b_node)?;
+                    new_sibling = Some((b_node.bbox(), DataValue::from(b_id)));
+                }
+            }
+            self.rtree_save_node(idx_handle, cur_id, &cur)?;
+            match path.pop() {
+                None => break,
+                Some((parent_id, mut parent, idx)) => {
+                    parent.entries[idx].0 = cur.bbox();
+                    if let Some(sibling) = new_sibling {
+                        parent.entries.push(sibling);
+                    }
+                    cur_id = parent_id;
+                    cur = parent;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn del_rtree_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+    ) -> Result<()> {
+        let geom = match to_geometry(eval_bytecode(extractor, tuple, stack)?)? {
+            None => return Ok(()),
+            Some(g) => g,
+        };
+        let bbox = geom.bbox();
+        let key = DataValue::List(tuple[..rel_handle.metadata.keys.len()].to_vec());
+
+        let root = match self.rtree_load_node(idx_handle, ROOT_NODE)? {
+            None => return Ok(()),
+            Some(r) => r,
+        };
+        // depth-first search for the leaf holding the entry
+        let mut path: Vec<(i64, RTreeNode, usize)> = vec![];
+        let mut cur_id = ROOT_NODE;
+        let mut cur = root;
+        let mut next_idx = 0;
+        let found_idx = loop {
+            if cur.level == 0 {
+                if let Some(i) = cur
+                    .entries
+                    .iter()
+                    .position(|(b, k)| *k == key && b.contains(&bbox))
+                {
+                    break Some(i);
+                }
+            } else if let Some(i) = cur.entries[next_idx..]
+                .iter()
+                .position(|(b, _)| b.contains(&bbox))
+            {
+                let i = i + next_idx;
+                let child_id = cur.entries[i].1.get_int().unwrap();
+                let child = self
+                    .rtree_load_node(idx_handle, child_id)?
+                    .ok_or_else(|| miette!("R-tree node {} not found", child_id))?;
+                path.push((cur_id, cur, i));
+                cur_id = child_id;
+                cur = child;
+                next_idx = 0;
+                continue;
+            }
+            // backtrack
+            match path.pop() {
+                None => break None,
+                Some((parent_id, parent, i)) => {
+                    cur_id = parent_id;
+                    cur = parent;
+                    next_idx = i + 1;
+                }
+            }
+        };
+        let found_idx = match found_idx {
+            None => return Ok(()),
+            Some(i) => i,
+        };
+
+        cur.entries.swap_remove(found_idx);
+        // underfull nodes are tolerated, only empty ones are removed
+        loop {
+            match path.pop() {
+                None => {
+                    if cur.entries.is_empty() {
+                        cur.level = 0;
+                    }
+                    self.rtree_save_node(idx_handle, cur_id, &cur)?;
+                    break;
+                }
+                Some((parent_id, mut parent, idx)) => {
+                    if cur.entries.is_empty() {
+                        self.rtree_del_node(idx_handle, cur_id)?;
+                        parent.entries.swap_remove(idx);
+                    } else {
+                        self.rtree_save_node(idx_handle, cur_id, &cur)?;
+                        parent.entries[idx].0 = cur.bbox();
+                    }
+                    cur_id = parent_id;
+                    cur = parent;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn rtree_get_row(&self, config: &RTreeSearch, key: &DataValue) -> Result<Tuple> {
+        let key = match key {
+            DataValue::List(l) => l,
+            _ => unreachable!(),
+        };
+        config
+            .base_handle
+            .get(self, key)?
+            .ok_or_else(|| miette!("Tuple not found in base R-tree relation"))
+    }
+    pub(crate) fn rtree_search(
+        &self,
+        q: &Geometry,
+        config: &RTreeSearch,
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        filter_code: &Option<(Vec<Bytecode>, SourceSpan)>,
+    ) -> Result<Vec<Tuple>> {
+        let q_box = q.bbox();
+        let mut ret = vec![];
+        let passes_filter = |tuple: &Tuple, stack: &mut Vec<DataValue>| -> Result<bool> {
+            Ok(match filter_code {
+                None => true,
+                Some((code, span)) => eval_bytecode_pred(code, tuple, stack, *span)?,
+            })
+        };
+        match config.kind {
+            RTreeSearchKind::Intersects => {
+                let mut to_visit = vec![ROOT_NODE];
+                while let Some(id) = to_visit.pop() {
+                    let node = match self.rtree_load_node(&config.idx_handle, id)? {
+                        None => continue,
+                        Some(n) => n,
+                    };
+                    for (b, payload) in node.entries {
+                        if !b.intersects(&q_box) {
+                            continue;
+                        }
+                        if node.level > 0 {
+                            to_visit.push(payload.get_int().unwrap());
+                            continue;
+                        }
+                        let mut tuple = self.rtree_get_row(config, &payload)?;
+                        let geom = to_geometry(eval_bytecode(extractor, &tuple, stack)?)?;
+                        if !geom.map(|g| g.intersects(q)).unwrap_or(false)
+                            || !passes_filter(&tuple, stack)?
+                        {
+                            continue;
+                        }
+                        if config.bind_distance.is_some() {
+                            tuple.push(DataValue::from(0.));
+                        }
+                        ret.push(tuple);
+                        if Some(ret.len()) == config.k {
+                            return Ok(ret);
+                        }
+                    }
+                }
+            }
+            RTreeSearchKind::Nearest => {
+                // best-first search: nodes are keyed by the distance to their
+                // bounding boxes, which never exceeds the distance to any row inside
+                let max_distance = config.within_distance.unwrap_or(f64::INFINITY);
+                let sphere_point = match q {
+                    Geometry::Point(p) if config.geodesic => Some(p),
+                    _ if config.geodesic => {
+                        bail!("Geodesic R-tree search requires a point as the query")
+                    }
+                    _ => None,
+                };
+                let mut heap = BinaryHeap::new();
+                heap.push(Reverse((
+                    OrderedFloat(0.),
+                    NearestCandidate::Node(ROOT_NODE),
+                )));
+                while let Some(Reverse((OrderedFloat(dist), candidate))) = heap.pop() {
+                    if dist > max_distance {
+                        break;
+                    }
+                    match candidate {
+                        NearestCandidate::Row(mut tuple) => {
+                            if config.bind_distance.is_some() {
+                                tuple.push(DataValue::from(dist));
+                            }
+                            ret.push(tuple);
+                            if Some(ret.len()) == config.k {
+                                break;
+                            }
+                        }
+                        NearestCandidate::Node(id) => {
+                            let node = match self.rtree_load_node(&config.idx_handle, id)? {
+                                None => continue,
+                                Some(n) => n,
+                            };
+                            for (b, payload) in node.entries {
+                                let d = match sphere_point {
+                                    None => b.distance(&q_box),
+                                    Some(p) => b.sphere_distance(p),
+                                };
+                                if d > max_distance {
+                                    continue;
+                                }
+                                if node.level > 0 {
+                                    heap.push(Reverse((
+                                        OrderedFloat(d),
+                                        NearestCandidate::Node(payload.get_int().unwrap()),
+                                    )));
+                                    continue;
+                                }
+                                let tuple = self.rtree_get_row(config, &payload)?;
+                                let d = match to_geometry(eval_bytecode(extractor, &tuple, stack)?)?
+                                {
+                                    None => continue,
+                                    Some(g) => match sphere_point {
+                                        None => g.distance(q),
+                                        Some(p) => g.sphere_distance(p),
+                                    },
+                                };
+                                if d > max_distance || !passes_filter(&tuple, stack)? {
+                                    continue;
+                                }
+                                heap.push(Reverse((OrderedFloat(d), NearestCandidate::Row(tuple))));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Temp relations, those with names starting with an underscore, are kept in
+// an in-memory store belonging to the transaction, and so vanish with it.
+// A session keeps that store between scripts instead: every script run in the
+// session starts from a copy of it, and a script that succeeds puts its copy
+// back. The copy shares the data with the session until the script writes to
+// it, so that only scripts changing temp relations pay for copying them. Scripts of a session therefore see the temp relations left by the
+// previous ones, while a failed script leaves them untouched, and no other
+// session or script sees them at all.
+
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::runtime::transact::SessionTx;
+use crate::storage::temp::TempTx;
+
+/// A session holding temp relations across scripts.
+/// Create one with [Session::default] and run scripts in it with
+/// [crate::Db::run_script_in_session]. Cloning gives a handle to the same session.
+/// Scripts of the same session run one after the other.
+#[derive(Clone, Default)]
+pub struct Session {
+    temps: Arc<Mutex<SessionTemps>>,
+}
+
+#[derive(Default)]
+pub(crate) struct SessionTemps {
+    store: TempTx,
+    last_id: u32,
+}
+
+impl Session {
+    /// Held for the whole script
+    pub(crate) fn lock(&self) -> MutexGuard<'_, SessionTemps> {
+        self.temps.lock().unwrap()
+    }
+}
+
+impl SessionTemps {
+    /// Gives the transaction a copy of the temp relations of the session
+    pub(crate) fn enter(&self, tx: &mut SessionTx<'_>) {
+        tx.temp_store_tx = self.store.clone();
+        tx.temp_store_id.store(self.last_id, Ordering::Relaxed);
+    }
+    /// Keeps the temp relations of the transaction, to be called after it commits
+    pub(crate) fn leave(&mut self, tx: &mut SessionTx<'_>) {
+        self.store = std::mem::take(&mut tx.temp_store_tx);
+        self.last_id = tx.temp_store_id.load(Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,291 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Sorted runs of tuples written out by temp stores that grow past their spill
+// threshold. A run is a temporary file made of blocks of consecutive tuples
+// serialized with MessagePack. Only the first and last tuples and the position
+// of each block are kept in memory, indexed by the first tuple, so lookups and
+// range scans read just the blocks they need, and lookups falling between two
+// blocks read none. The file is removed when the run is dropped.
+// Hash joins too large for memory write their partitions to unsorted spill
+// files instead, which are only ever read back from the start.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{env, fs, io, iter, mem, process};
+
+use either::{Left, Right};
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result};
+use serde::Deserialize;
+
+use crate::data::tuple::Tuple;
+
+/// number of tuples in a block
+const BLOCK_LEN: usize = 64;
+
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+struct BlockHandle {
+    last: Tuple,
+    offset: u64,
+    len: usize,
+    count: usize,
+}
+
+pub(crate) struct SpilledRun {
+    path: PathBuf,
+    file: File,
+    /// blocks indexed by their first tuple
+    blocks: BTreeMap<Tuple, BlockHandle>,
+}
+
+impl Debug for SpilledRun {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SpilledRun({})", self.path.display())
+    }
+}
+
+impl Drop for SpilledRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn create_spill_file() -> Result<(PathBuf, File)> {
+    let path = env::temp_dir().join(format!(
+        "cozo-spill-{}-{}",
+        process::id(),
+        RUN_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .into_diagnostic()?;
+    Ok((path, file))
+}
+
+impl SpilledRun {
+    /// Writes the tuples, which must be sorted and distinct, to a new run,
+    /// together with their skip flags.
+    pub(crate) fn write<T: Borrow<Tuple>>(
+        tuples: impl Iterator<Item = Result<(T, bool)>>,
+    ) -> Result<Self> {
+        let (path, file) = create_spill_file()?;
+        // from here on, dropping the run removes the file
+        let mut ret = Self {
+            path,
+            file,
+            blocks: Default::default(),
+        };
+
+        let mut blocks = BTreeMap::new();
+        let mut writer = BufWriter::new(&ret.file);
+        let mut offset = 0;
+        let mut buf = vec![];
+        for chunk in &tuples.chunks(BLOCK_LEN) {
+            buf.clear();
+            let mut first = None;
+            let mut last = None;
+            let mut count = 0;
+            for item in chunk {
+                let (tuple, skip) = item?;
+                let tuple = tuple.borrow();
+                if first.is_none() {
+                    first = Some(tuple.clone());
+                }
+                rmp_serde::encode::write(&mut buf, &(tuple, skip)).into_diagnostic()?;
+                last = Some(tuple.clone());
+                count += 1;
+            }
+            writer.write_all(&buf).into_diagnostic()?;
+            blocks.insert(
+                first.unwrap(),
+                BlockHandle {
+                    last: last.unwrap(),
+                    offset,
+                    len: buf.len(),
+                    count,
+                },
+            );
+            offset += buf.len() as u64;
+        }
+        writer.flush().into_diagnostic()?;
+        drop(writer);
+        ret.blocks = blocks;
+        Ok(ret)
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Vec<(Tuple, bool)>> {
+        let mut buf = vec![0; handle.len];
+        read_exact_at(&self.file, &mut buf, handle.offset).map_err(|err| {
+            miette!(
+                "cannot read spilled temp store {}: {err}",
+                self.path.display()
+            )
+        })?;
+        let mut de = rmp_serde::Deserializer::from_read_ref(&buf);
+        (0..handle.count)
+            .map(|_| {
+                <(Tuple, bool)>::deserialize(&mut de).map_err(|err| {
+                    miette!(
+                        "corrupted spilled temp store {}: {err}",
+                        self.path.display()
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn block_iter(&self, handle: &BlockHandle) -> impl Iterator<Item = Result<(Tuple, bool)>> {
+        match self.read_block(handle) {
+            Ok(tuples) => Left(tuples.into_iter().map(Ok)),
+            Err(err) => Right(iter::once(Err(err))),
+        }
+    }
+
+    /// The skip flag of the tuple, if the run contains it
+    pub(crate) fn get(&self, tuple: &Tuple) -> Result<Option<bool>> {
+        let handle = match self.blocks.range::<Tuple, _>(..=tuple).next_back() {
+            None => return Ok(None),
+            Some((_, handle)) => handle,
+        };
+        if handle.last < *tuple {
+            return Ok(None);
+        }
+        for (found, skip) in self.read_block(handle)? {
+            match found.cmp(tuple) {
+                CmpOrdering::Less => {}
+                CmpOrdering::Equal => return Ok(Some(skip)),
+                CmpOrdering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tuples not less than `lower` and up to `upper`, in order
+    pub(crate) fn range_iter(
+        &self,
+        lower: Tuple,
+        upper: Tuple,
+        upper_inclusive: bool,
+    ) -> impl Iterator<Item = Result<(Tuple, bool)>> + '_ {
+        let blocks = match self.blocks.range::<Tuple, _>(..=&lower).next_back() {
+            None => self.blocks.range::<Tuple, _>(..),
+            Some((first, _)) => self.blocks.range::<Tuple, _>(first..),
+        };
+        blocks
+            .flat_map(|(_, handle)| self.block_iter(handle))
+            .skip_while(move |res| matches!(res, Ok((t, _)) if *t < lower))
+            .take_while(move |res| match res {
+                Ok((t, _)) if upper_inclusive => *t <= upper,
+                Ok((t, _)) => *t < upper,
+                Err(_) => true,
+            })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Result<(Tuple, bool)>> + '_ {
+        self.blocks
+            .values()
+            .flat_map(|handle| self.block_iter(handle))
+    }
+
+    /// Consumes the run, the file is removed once the iterator is dropped
+    pub(crate) fn into_tuples(mut self) -> impl Iterator<Item = Result<(Tuple, bool)>> {
+        let blocks = mem::take(&mut self.blocks);
+        blocks
+            .into_values()
+            .flat_map(move |handle| self.block_iter(&handle))
+    }
+}
+
+/// Tuples in the order they were written, in a file removed on drop
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl Debug for SpillFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SpillFile({})", self.path.display())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl SpillFile {
+    pub(crate) fn new() -> Result<Self> {
+        let (path, file) = create_spill_file()?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            count: 0,
+        })
+    }
+
+    pub(crate) fn push(&mut self, tuple: &Tuple) -> Result<()> {
+        rmp_serde::encode::write(&mut self.writer, tuple).into_diagnostic()?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Reads back all tuples written, the file is removed once the iterator is dropped
+    pub(crate) fn into_tuples(mut self) -> Result<impl Iterator<Item = Result<Tuple>>> {
+        self.writer.flush().into_diagnostic()?;
+        let mut file = self.writer.get_ref().try_clone().into_diagnostic()?;
+        io::Seek::rewind(&mut file).into_diagnostic()?;
+        let mut de = rmp_serde::Deserializer::new(BufReader::new(file));
+        let count = self.count;
+        let guard = self;
+        Ok((0..count).map(move |_| {
+            Tuple::deserialize(&mut de)
+                .map_err(|err| miette!("corrupted spill file {}: {err}", guard.path.display()))
+        }))
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
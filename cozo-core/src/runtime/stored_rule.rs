@@ -0,0 +1,108 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Stored rules are rule definitions saved with `::rules define`. They live
+// in the system key space next to the relation metadata and are kept as
+// source. Queries applying a rule they do not define themselves get the
+// stored definition inlined before compilation, so stored rules take part in
+// stratification like any other rule of the query.
+
+use miette::{bail, Diagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::tuple::TupleT;
+use crate::data::value::DataValue;
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct StoredRule {
+    pub(crate) name: SmartString<LazyCompact>,
+    pub(crate) arity: usize,
+    /// source of the rule clauses
+    pub(crate) src: String,
+    pub(crate) description: SmartString<LazyCompact>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Stored rule {0} not found")]
+#[diagnostic(code(eval::stored_rule_not_found))]
+pub(crate) struct StoredRuleNotFound(pub(crate) String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot deserialize stored rule")]
+#[diagnostic(code(deser::stored_rule))]
+struct StoredRuleDeserError;
+
+const STORED_RULES_PREFIX: &str = "STORED_RULES";
+
+fn stored_rule_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from(STORED_RULES_PREFIX),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn get_stored_rule(&self, name: &str) -> Result<Option<StoredRule>> {
+        match self.store_tx.get(&stored_rule_key(name), false)? {
+            None => Ok(None),
+            Some(data) => Ok(Some(
+                rmp_serde::from_slice(&data).map_err(|_| StoredRuleDeserError)?,
+            )),
+        }
+    }
+
+    pub(crate) fn put_stored_rule(&mut self, rule: &StoredRule) -> Result<()> {
+        let mut val = vec![];
+        rule.serialize(&mut Serializer::new(&mut val)).unwrap();
+        self.store_tx.put(&stored_rule_key(&rule.name), &val)
+    }
+
+    pub(crate) fn remove_stored_rule(&mut self, name: &str) -> Result<()> {
+        let key = stored_rule_key(name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(StoredRuleNotFound(name.to_string()))
+        }
+        self.store_tx.del(&key)
+    }
+
+    pub(crate) fn describe_stored_rule(
+        &mut self,
+        name: &str,
+        description: SmartString<LazyCompact>,
+    ) -> Result<()> {
+        let mut rule = match self.get_stored_rule(name)? {
+            None => bail!(StoredRuleNotFound(name.to_string())),
+            Some(rule) => rule,
+        };
+        rule.description = description;
+        self.put_stored_rule(&rule)
+    }
+
+    pub(crate) fn list_stored_rules(&self) -> Result<Vec<StoredRule>> {
+        let lower = stored_rule_key("");
+        let upper = vec![
+            DataValue::Null,
+            DataValue::from(STORED_RULES_PREFIX),
+            DataValue::Bot,
+        ]
+        .encode_as_key(RelationId::SYSTEM);
+        let mut ret = vec![];
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (_, v) = kv?;
+            ret.push(rmp_serde::from_slice(&v).map_err(|_| StoredRuleDeserError)?);
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// A subscription is a read-only query registered together with a channel.
+// The stored relations read by the query are made callback targets, so that
+// every committed write touching one of them is reported after the commit.
+// The writer only adds the changed relations to a queue and returns. A notifier
+// thread, running while there are subscriptions, takes all the relations queued
+// since it last looked, evaluates the affected queries again and sends the
+// differences from their previous results down the channels, so that writes
+// committed in quick succession are coalesced into a single evaluation.
+// Evaluation and sending happen under the lock of the subscription, so the
+// deltas of a subscription form a consistent sequence starting from its initial
+// result. Sending never waits: a subscriber whose bounded channel is full has
+// fallen behind, and is dropped before its query is evaluated.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use itertools::{EitherOrBoth, Itertools};
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::functions::current_validity;
+use crate::data::tuple::Tuple;
+use crate::parse::{parse_script, CozoScript};
+use crate::{DataValue, Db, NamedRows, Storage};
+
+/// The change in the result of a subscribed query caused by a committed write.
+#[derive(Debug, Clone)]
+pub struct QueryDelta {
+    /// rows that entered the result
+    pub added: NamedRows,
+    /// rows that left the result
+    pub removed: NamedRows,
+}
+
+pub(crate) struct Subscription {
+    query: String,
+    params: BTreeMap<String, DataValue>,
+    pub(crate) bases: BTreeSet<SmartString<LazyCompact>>,
+    last: Mutex<Vec<Tuple>>,
+    sender: Sender<QueryDelta>,
+}
+
+pub(crate) type SubscriptionRegistry = BTreeMap<u32, Arc<Subscription>>;
+
+/// Relations changed by committed writes, waiting for the notifier thread
+#[derive(Default)]
+pub(crate) struct SubscriptionQueue {
+    state: Mutex<QueueState>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    changed: BTreeSet<SmartString<LazyCompact>>,
+    notifier_running: bool,
+}
+
+/// How often an idle notifier checks whether it is still needed
+const NOTIFIER_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("only a single read-only query can be subscribed to")]
+#[diagnostic(code(eval::bad_subscription))]
+#[diagnostic(help("Remove any mutation or imperative constructs from the query"))]
+struct BadSubscription;
+
+fn sorted_rows(mut rows: Vec<Tuple>) -> Vec<Tuple> {
+    rows.sort();
+    rows.dedup();
+    rows
+}
+
+impl<S> Db<S>
+where
+    S: for<'s> Storage<'s> + Clone + Send + Sync + 'static,
+{
+    /// Register a read-only query as a subscription, returning its ID, the current result
+    /// of the query and a channel. Whenever a committed write changes the result of the query,
+    /// the rows added to and removed from the result are sent to the channel.
+    /// The ID can be used to unsubscribe.
+    ///
+    /// The query is evaluated again on a background thread after the write returns, and the
+    /// changes of several writes committed in quick succession may arrive as a single delta.
+    /// As with callbacks, writes that bypass queries, such as imports, are not observed.
+    /// The subscription is dropped when the channel is closed or the query stops evaluating.
+    /// If `capacity` is given, the subscription is also dropped when a delta is due while
+    /// the channel is full, and the channel is closed once the pending deltas are received.
+    pub fn subscribe(
+        &self,
+        query: &str,
+        params: BTreeMap<String, DataValue>,
+        capacity: Option<usize>,
+    ) -> Result<(u32, NamedRows, Receiver<QueryDelta>)> {
+        let mut program = match parse_script(
+            query,
+            &params,
+            &self.fixed_rules.read().unwrap(),
+            current_validity(),
+        )? {
+            CozoScript::Single(p) if p.out_opts.store_relation.is_none() => p,
+            _ => bail!(BadSubscription),
+        };
+        program.inline_stored_rules(&self.transact()?, current_validity())?;
+        let bases = program.stored_relations_read();
+        if bases.iter().any(|b| b.starts_with('_')) {
+            bail!(BadSubscription)
+        }
+
+        let (sender, receiver) = if let Some(c) = capacity {
+            bounded(c)
+        } else {
+            unbounded()
+        };
+        let subscription = Arc::new(Subscription {
+            query: query.to_string(),
+            params,
+            bases,
+            last: Default::default(),
+            sender,
+        });
+        let id = self.callback_count.fetch_add(1, atomic::Ordering::SeqCst);
+        // registering before the initial evaluation, and holding the lock during it,
+        // ensures that no write committed in between goes unnoticed
+        let mut last = subscription.last.lock().unwrap();
+        self.subscriptions
+            .write()
+            .unwrap()
+            .insert(id, subscription.clone());
+        let res = match self.run_script(&subscription.query, subscription.params.clone()) {
+            Ok(res) => res,
+            Err(err) => {
+                drop(last);
+                self.unsubscribe(id);
+                return Err(err);
+            }
+        };
+        let rows = sorted_rows(res.rows);
+        *last = rows.clone();
+        drop(last);
+        self.start_subscription_notifier();
+        Ok((id, NamedRows::new(res.headers, rows), receiver))
+    }
+
+    fn start_subscription_notifier(&self) {
+        let mut state = self.subscription_queue.state.lock().unwrap();
+        if !state.notifier_running {
+            state.notifier_running = true;
+            let db = self.clone();
+            thread::spawn(move || db.run_subscription_notifier());
+        }
+    }
+
+    /// Evaluates the subscriptions affected by the queued changes until there are no more
+    /// subscriptions, or no other handle to the database
+    fn run_subscription_notifier(&self) {
+        let queue = &self.subscription_queue;
+        loop {
+            let changed = {
+                let mut state = queue.state.lock().unwrap();
+                loop {
+                    if !state.changed.is_empty() {
+                        break std::mem::take(&mut state.changed);
+                    }
+                    if self.subscriptions.read().unwrap().is_empty()
+                        || Arc::strong_count(&self.subscriptions) == 1
+                    {
+                        state.notifier_running = false;
+                        return;
+                    }
+                    state = queue
+                        .wake
+                        .wait_timeout(state, NOTIFIER_IDLE_CHECK)
+                        .unwrap()
+                        .0;
+                }
+            };
+            self.notify_subscriptions(&changed);
+        }
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Unregister a subscription.
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        self.subscriptions.write().unwrap().remove(&id).is_some()
+    }
+
+    pub(crate) fn subscription_targets(&self) -> BTreeSet<SmartString<LazyCompact>> {
+        self.subscriptions
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|s| s.bases.iter().cloned())
+            .collect()
+    }
+
+    /// Queues the changed relations for the notifier, called after the changes are committed.
+    pub(crate) fn queue_subscription_updates(&self, changed: &BTreeSet<SmartString<LazyCompact>>) {
+        let affects_any = self
+            .subscriptions
+            .read()
+            .unwrap()
+            .values()
+            .any(|s| !s.bases.is_disjoint(changed));
+        if !affects_any {
+            return;
+        }
+        let mut state = self.subscription_queue.state.lock().unwrap();
+        state.changed.extend(changed.iter().cloned());
+        self.subscription_queue.wake.notify_one();
+    }
+
+    /// Re-evaluate the subscriptions reading any of the changed relations
+    fn notify_subscriptions(&'s self, changed: &BTreeSet<SmartString<LazyCompact>>) {
+        let affected = self
+            .subscriptions
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| !s.bases.is_disjoint(changed))
+            .map(|(id, s)| (*id, s.clone()))
+            .collect_vec();
+
+        let mut to_remove = vec![];
+        for (id, subscription) in affected {
+            // the subscriber has fallen behind
+            if subscription.sender.is_full() {
+                to_remove.push(id);
+                continue;
+            }
+            let mut last = subscription.last.lock().unwrap();
+            let res = match self.run_script(&subscription.query, subscription.params.clone()) {
+                Ok(res) => res,
+                Err(_) => {
+                    to_remove.push(id);
+                    continue;
+                }
+            };
+            let rows = sorted_rows(res.rows);
+            let mut added = vec![];
+            let mut removed = vec![];
+            for pair in last
+                .iter()
+                .merge_join_by(rows.iter(), |old, new| old.cmp(new))
+            {
+                match pair {
+                    EitherOrBoth::Left(old) => removed.push(old.clone()),
+                    EitherOrBoth::Right(new) => added.push(new.clone()),
+                    EitherOrBoth::Both(_, _) => {}
+                }
+            }
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            *last = rows;
+            let delta = QueryDelta {
+                added: NamedRows::new(res.headers.clone(), added),
+                removed: NamedRows::new(res.headers, removed),
+            };
+            if subscription.sender.try_send(delta).is_err() {
+                to_remove.push(id);
+            }
+        }
+
+        for id in to_remove {
+            self.unsubscribe(id);
+        }
+    }
+}
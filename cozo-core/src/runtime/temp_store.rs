@@ -6,37 +6,74 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::collections::Bound::Included;
 use std::mem;
+use std::mem::size_of;
 use std::ops::Bound::Excluded;
 
 use either::{Left, Right};
 use itertools::Itertools;
+use log::warn;
 use miette::Result;
 
 use crate::data::aggr::Aggregation;
 use crate::data::tuple::Tuple;
-use crate::data::value::DataValue;
+use crate::data::value::{DataValue, Vector};
+use crate::runtime::spill::SpilledRun;
 
 /// A store holding temp data during evaluation of queries.
 /// The public interface is used in custom implementations of algorithms/utilities.
+///
+/// Once the tuples held in memory reach the spill threshold, they are written
+/// out as a sorted run to a temporary file. Runs may overlap with each other
+/// and with the tuples in memory, duplicates are removed when iterating.
 #[derive(Default, Debug)]
 pub struct RegularTempStore {
     inner: BTreeMap<Tuple, bool>,
+    spilled: Vec<SpilledRun>,
+    /// number of tuples, zero for never spilling
+    spill_threshold: usize,
 }
 
-const EMPTY_TUPLE_REF: &Tuple = &vec![];
+const EMPTY_TUPLE_REF: &[DataValue] = &[];
+
+/// Spilled runs are merged into one when there are more of them than this,
+/// to keep lookups cheap
+const MAX_SPILLED_RUNS: usize = 8;
 
 impl RegularTempStore {
+    pub(crate) fn with_spill_threshold(spill_threshold: usize) -> Self {
+        Self {
+            spill_threshold,
+            ..Default::default()
+        }
+    }
     pub(crate) fn wrap(self) -> TempStore {
         TempStore::Normal(self)
     }
     /// Tests if a key already exists in the store.
-    pub fn exists(&self, key: &Tuple) -> bool {
-        self.inner.contains_key(key)
+    pub fn exists(&self, key: &Tuple) -> Result<bool> {
+        Ok(self.inner.contains_key(key) || Self::spilled_skip(&self.spilled, key)?.is_some())
+    }
+    /// The skip flag of a spilled tuple, taken from the latest run containing it
+    fn spilled_skip(spilled: &[SpilledRun], key: &Tuple) -> Result<Option<bool>> {
+        for run in spilled.iter().rev() {
+            if let Some(skip) = run.get(key)? {
+                return Ok(Some(skip));
+            }
+        }
+        Ok(None)
+    }
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty() && self.spilled.is_empty()
+    }
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.spilled.clear();
     }
 
     fn range_iter(
@@ -44,50 +81,142 @@ impl RegularTempStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let lower_bound = Included(lower.to_vec());
         let upper_bound = if upper_inclusive {
             Included(upper.to_vec())
         } else {
             Excluded(upper.to_vec())
         };
-        self.inner
+        let in_memory = self
+            .inner
             .range((lower_bound, upper_bound))
-            .map(|(t, skip)| TupleInIter(t, EMPTY_TUPLE_REF, *skip))
+            .map(|(t, skip)| Ok(TupleInIter(Cow::Borrowed(t), EMPTY_TUPLE_REF, *skip)));
+        if self.spilled.is_empty() {
+            return Left(in_memory);
+        }
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<TupleInIter<'_>>>>> =
+            vec![Box::new(in_memory)];
+        for run in self.spilled.iter().rev() {
+            sources.push(Box::new(
+                run.range_iter(lower.to_vec(), upper.to_vec(), upper_inclusive)
+                    .map_ok(|(t, skip)| TupleInIter(Cow::Owned(t), EMPTY_TUPLE_REF, skip)),
+            ));
+        }
+        Right(merge_sources(sources, |a, b| a.cmp(b)))
     }
     /// Add a tuple to the store
     pub fn put(&mut self, tuple: Tuple) {
         self.inner.insert(tuple, false);
+        self.maybe_spill();
     }
     pub(crate) fn put_with_skip(&mut self, tuple: Tuple) {
         self.inner.insert(tuple, true);
+        self.maybe_spill();
+    }
+    fn maybe_spill(&mut self) {
+        if self.spill_threshold == 0 || self.inner.len() < self.spill_threshold {
+            return;
+        }
+        match SpilledRun::write(self.inner.iter().map(|(t, skip)| Ok((t, *skip)))) {
+            Ok(run) => {
+                self.inner.clear();
+                self.spilled.push(run);
+            }
+            Err(err) => {
+                warn!("cannot spill temp store, keeping it in memory: {err}");
+                self.spill_threshold = 0;
+                return;
+            }
+        }
+        if self.spilled.len() > MAX_SPILLED_RUNS {
+            let runs = self
+                .spilled
+                .iter()
+                .rev()
+                .map(|run| Box::new(run.iter()) as Box<dyn Iterator<Item = Result<(Tuple, bool)>>>)
+                .collect_vec();
+            match SpilledRun::write(merge_sources(runs, |a, b| a.0.cmp(&b.0))) {
+                Ok(run) => self.spilled = vec![run],
+                Err(err) => warn!("cannot merge spilled runs of temp store: {err}"),
+            }
+        }
+    }
+    /// Consumes the store, returning its tuples in order
+    fn into_sorted_iter(self) -> impl Iterator<Item = Result<(Tuple, bool)>> {
+        if self.spilled.is_empty() {
+            return Left(self.inner.into_iter().map(Ok));
+        }
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<(Tuple, bool)>>>> =
+            vec![Box::new(self.inner.into_iter().map(Ok))];
+        for run in self.spilled.into_iter().rev() {
+            sources.push(Box::new(run.into_tuples()));
+        }
+        Right(merge_sources(sources, |a, b| a.0.cmp(&b.0)))
     }
     // returns true if prev is guaranteed to be the same as self after this function call,
     // false if we are not sure.
-    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> bool {
-        prev.inner.clear();
-        if new.inner.is_empty() {
-            return false;
+    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> Result<bool> {
+        prev.clear();
+        if new.is_empty() {
+            return Ok(false);
         }
-        if self.inner.is_empty() {
+        if self.is_empty() {
             mem::swap(&mut new, self);
-            return true;
+            return Ok(true);
         }
-        for (k, v) in new.inner {
+        for item in new.into_sorted_iter() {
+            let (k, v) = item?;
             match self.inner.entry(k) {
                 Entry::Vacant(ent) => {
-                    prev.inner.insert(ent.key().clone(), v);
-                    ent.insert(v);
+                    match Self::spilled_skip(&self.spilled, ent.key())? {
+                        // only the skip flag can change, the latest one is kept in memory
+                        Some(skip) => {
+                            if skip != v {
+                                ent.insert(v);
+                                self.maybe_spill();
+                            }
+                        }
+                        None => {
+                            prev.inner.insert(ent.key().clone(), v);
+                            ent.insert(v);
+                            prev.maybe_spill();
+                            self.maybe_spill();
+                        }
+                    }
                 }
                 Entry::Occupied(mut ent) => {
                     ent.insert(v);
                 }
             }
         }
-        false
+        Ok(false)
     }
 }
 
+/// Merges sources sorted by `cmp`, keeping only the version from the earliest source
+/// of a tuple found in several
+fn merge_sources<'a, T: 'a>(
+    sources: Vec<Box<dyn Iterator<Item = Result<T>> + 'a>>,
+    cmp: impl Fn(&T, &T) -> Ordering + Copy + 'a,
+) -> impl Iterator<Item = Result<T>> + 'a {
+    sources
+        .into_iter()
+        .enumerate()
+        .map(|(rank, source)| source.map(move |item| (rank, item)))
+        .kmerge_by(move |(rank_a, a), (rank_b, b)| match (a, b) {
+            // errors come out first
+            (Err(_), _) => true,
+            (Ok(_), Err(_)) => false,
+            (Ok(a), Ok(b)) => cmp(a, b).then(rank_a.cmp(rank_b)) == Ordering::Less,
+        })
+        .map(|(_, item)| item)
+        .dedup_by(move |prev, cur| match (prev, cur) {
+            (Ok(prev), Ok(cur)) => cmp(prev, cur) == Ordering::Equal,
+            _ => false,
+        })
+}
+
 #[derive(Debug)]
 pub(crate) struct MeetAggrStore {
     inner: BTreeMap<Tuple, Tuple>,
@@ -106,6 +235,12 @@ impl MeetAggrStore {
     pub(crate) fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
+    fn in_memory_size(&self) -> usize {
+        self.inner
+            .iter()
+            .map(|(k, v)| approx_tuple_size(k) + approx_tuple_size(v))
+            .sum()
+    }
     pub(crate) fn new(aggrs: Vec<Option<(Aggregation, Vec<DataValue>)>>) -> Result<Self> {
         let total_key_len = aggrs.len();
         let mut aggregations = aggrs.into_iter().flatten().collect_vec();
@@ -159,7 +294,7 @@ impl MeetAggrStore {
         self.inner
             .range(lower_key..=upper_key)
             .filter_map(move |(k, v)| {
-                let ret = TupleInIter(k, v, false);
+                let ret = TupleInIter(Cow::Borrowed(k), v, false);
                 if ret.partial_cmp(&lower as &[DataValue]) == Some(Ordering::Less) {
                     None
                 } else {
@@ -178,19 +313,21 @@ impl MeetAggrStore {
             })
     }
     /// returns true if prev is guaranteed to be the same as self after this function call,
-    /// false if we are not sure.
-    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> Result<bool> {
+    /// false if we are not sure, together with the approximate number of bytes self grew by.
+    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> Result<(bool, usize)> {
         prev.inner.clear();
         if new.inner.is_empty() {
-            return Ok(false);
+            return Ok((false, 0));
         }
         if self.inner.is_empty() {
             mem::swap(self, &mut new);
-            return Ok(true);
+            return Ok((true, self.in_memory_size()));
         }
+        let mut grown = 0;
         for (k, v) in new.inner {
             match self.inner.entry(k) {
                 Entry::Vacant(ent) => {
+                    grown += approx_tuple_size(ent.key()) + approx_tuple_size(&v);
                     prev.inner.insert(ent.key().clone(), v.clone());
                     ent.insert(v);
                 }
@@ -198,10 +335,13 @@ impl MeetAggrStore {
                     let mut changed = false;
                     {
                         let target = ent.get_mut();
+                        let old_size = approx_tuple_size(target);
                         for (i, (aggr_op, _)) in self.aggregations.iter().enumerate() {
                             let op = aggr_op.meet_op.as_ref().unwrap();
                             changed |= op.update(&mut target[i], &v[i])?;
                         }
+                        // the aggregate replaces the old one in place
+                        grown += approx_tuple_size(target).saturating_sub(old_size);
                     }
                     if changed {
                         prev.inner.insert(ent.key().clone(), ent.get().clone());
@@ -209,7 +349,7 @@ impl MeetAggrStore {
                 }
             }
         }
-        Ok(false)
+        Ok((false, grown))
     }
 }
 
@@ -220,10 +360,10 @@ pub(crate) enum TempStore {
 }
 
 impl TempStore {
-    fn exists(&self, key: &Tuple) -> bool {
+    fn exists(&self, key: &Tuple) -> Result<bool> {
         match self {
             TempStore::Normal(n) => n.exists(key),
-            TempStore::MeetAggr(m) => m.exists(key),
+            TempStore::MeetAggr(m) => Ok(m.exists(key)),
         }
     }
     fn range_iter(
@@ -231,15 +371,21 @@ impl TempStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         match self {
             TempStore::Normal(n) => Left(n.range_iter(lower, upper, upper_inclusive)),
-            TempStore::MeetAggr(m) => Right(m.range_iter(lower, upper, upper_inclusive)),
+            TempStore::MeetAggr(m) => Right(m.range_iter(lower, upper, upper_inclusive).map(Ok)),
+        }
+    }
+    fn in_memory_size(&self) -> usize {
+        match self {
+            TempStore::Normal(n) => n.inner.keys().map(|t| approx_tuple_size(t)).sum(),
+            TempStore::MeetAggr(m) => m.in_memory_size(),
         }
     }
     fn is_empty(&self) -> bool {
         match self {
-            TempStore::Normal(n) => n.inner.is_empty(),
+            TempStore::Normal(n) => n.is_empty(),
             TempStore::MeetAggr(m) => m.inner.is_empty(),
         }
     }
@@ -250,18 +396,21 @@ pub(crate) struct EpochStore {
     total: TempStore,
     delta: TempStore,
     use_total_for_delta: bool,
+    /// approximate number of bytes a meet aggregation grew by in the last merge
+    meet_grown: usize,
     pub(crate) arity: usize,
 }
 
 impl EpochStore {
-    pub(crate) fn exists(&self, key: &Tuple) -> bool {
+    pub(crate) fn exists(&self, key: &Tuple) -> Result<bool> {
         self.total.exists(key)
     }
-    pub(crate) fn new_normal(arity: usize) -> Self {
+    pub(crate) fn new_normal(arity: usize, spill_threshold: usize) -> Self {
         Self {
-            total: TempStore::Normal(RegularTempStore::default()),
-            delta: TempStore::Normal(RegularTempStore::default()),
+            total: TempStore::Normal(RegularTempStore::with_spill_threshold(spill_threshold)),
+            delta: TempStore::Normal(RegularTempStore::with_spill_threshold(spill_threshold)),
             use_total_for_delta: true,
+            meet_grown: 0,
             arity,
         }
     }
@@ -270,21 +419,34 @@ impl EpochStore {
             total: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec())?),
             delta: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec())?),
             use_total_for_delta: true,
+            meet_grown: 0,
             arity: aggrs.len(),
         })
     }
     pub(crate) fn merge_in(&mut self, new: TempStore) -> Result<()> {
         match (&mut self.total, &mut self.delta, new) {
             (TempStore::Normal(total), TempStore::Normal(prev), TempStore::Normal(new)) => {
-                self.use_total_for_delta = total.merge_in(prev, new);
+                self.use_total_for_delta = total.merge_in(prev, new)?;
             }
             (TempStore::MeetAggr(total), TempStore::MeetAggr(prev), TempStore::MeetAggr(new)) => {
-                self.use_total_for_delta = total.merge_in(prev, new)?;
+                (self.use_total_for_delta, self.meet_grown) = total.merge_in(prev, new)?;
             }
             _ => unreachable!(),
         }
         Ok(())
     }
+    /// Approximate size of the tuples added by the last merge that are kept in memory
+    pub(crate) fn delta_size(&self) -> usize {
+        // the delta of a meet aggregation holds updated groups that were already counted
+        if let TempStore::MeetAggr(_) = self.total {
+            return self.meet_grown;
+        }
+        if self.use_total_for_delta {
+            self.total.in_memory_size()
+        } else {
+            self.delta.in_memory_size()
+        }
+    }
     pub(crate) fn has_delta(&self) -> bool {
         if self.use_total_for_delta {
             !self.total.is_empty()
@@ -297,7 +459,7 @@ impl EpochStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.total.range_iter(lower, upper, upper_inclusive)
     }
     pub(crate) fn delta_range_iter(
@@ -305,14 +467,17 @@ impl EpochStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         if self.use_total_for_delta {
             self.total.range_iter(lower, upper, upper_inclusive)
         } else {
             self.delta.range_iter(lower, upper, upper_inclusive)
         }
     }
-    pub(crate) fn prefix_iter(&self, prefix: &Tuple) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn prefix_iter(
+        &self,
+        prefix: &Tuple,
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let mut upper = prefix.to_vec();
         upper.push(DataValue::Bot);
         self.range_iter(prefix, &upper, true)
@@ -320,27 +485,47 @@ impl EpochStore {
     pub(crate) fn delta_prefix_iter(
         &self,
         prefix: &Tuple,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let mut upper = prefix.to_vec();
         upper.push(DataValue::Bot);
         self.delta_range_iter(prefix, &upper, true)
     }
-    pub(crate) fn all_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn all_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.prefix_iter(&vec![])
     }
-    pub(crate) fn delta_all_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn delta_all_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.delta_prefix_iter(&vec![])
     }
-    pub(crate) fn early_returned_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
-        self.all_iter().filter(|t| !t.should_skip())
+    pub(crate) fn early_returned_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
+        self.all_iter()
+            .filter(|t| !matches!(t, Ok(t) if t.should_skip()))
     }
 }
 
-#[derive(Copy, Clone)]
-pub(crate) struct TupleInIter<'a>(&'a Tuple, &'a Tuple, bool);
+/// Approximate number of bytes taken by a tuple, used for enforcing memory limits.
+pub(crate) fn approx_tuple_size(tuple: &[DataValue]) -> usize {
+    size_of::<Tuple>() + tuple.iter().map(approx_value_size).sum::<usize>()
+}
+
+fn approx_value_size(val: &DataValue) -> usize {
+    size_of::<DataValue>()
+        + match val {
+            DataValue::Str(s) => s.len(),
+            DataValue::Bytes(b) => b.len(),
+            DataValue::List(l) => l.iter().map(approx_value_size).sum(),
+            DataValue::Set(s) => s.iter().map(approx_value_size).sum(),
+            DataValue::Vec(Vector::F32(v)) => v.len() * size_of::<f32>(),
+            DataValue::Vec(Vector::F64(v)) => v.len() * size_of::<f64>(),
+            _ => 0,
+        }
+}
+
+/// A tuple found in a temp store, either borrowed from memory or read back from a spilled run.
+/// For meet aggregations the tuple is split into the grouping keys and the aggregated values.
+pub(crate) struct TupleInIter<'a>(Cow<'a, [DataValue]>, &'a [DataValue], bool);
 
 impl<'a> TupleInIter<'a> {
-    pub(crate) fn get(self, idx: usize) -> &'a DataValue {
+    pub(crate) fn get(&self, idx: usize) -> &DataValue {
         self.0
             .get(idx)
             .unwrap_or_else(|| self.1.get(idx - self.0.len()).unwrap())
@@ -348,31 +533,21 @@ impl<'a> TupleInIter<'a> {
     fn should_skip(&self) -> bool {
         self.2
     }
-    pub(crate) fn into_tuple(self) -> Tuple {
-        self.into_iter().cloned().collect_vec()
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &DataValue> {
+        self.0.iter().chain(self.1.iter())
     }
-}
-
-impl<'a> IntoIterator for TupleInIter<'a> {
-    type Item = &'a DataValue;
-    type IntoIter = TupleInIterIterator<'a>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        TupleInIterIterator {
-            inner: self,
-            idx: 0,
+    pub(crate) fn into_tuple(self) -> Tuple {
+        if self.1.is_empty() {
+            self.0.into_owned()
+        } else {
+            self.iter().cloned().collect_vec()
         }
     }
 }
 
-pub(crate) struct TupleInIterIterator<'a> {
-    inner: TupleInIter<'a>,
-    idx: usize,
-}
-
 impl PartialEq for TupleInIter<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.into_iter().eq(other.into_iter())
+        self.iter().eq(other.iter())
     }
 }
 
@@ -380,7 +555,7 @@ impl Eq for TupleInIter<'_> {}
 
 impl Ord for TupleInIter<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.into_iter().cmp(other.into_iter())
+        self.iter().cmp(other.iter())
     }
 }
 
@@ -392,28 +567,12 @@ impl PartialOrd for TupleInIter<'_> {
 
 impl PartialEq<[DataValue]> for TupleInIter<'_> {
     fn eq(&self, other: &'_ [DataValue]) -> bool {
-        self.into_iter().eq(other.iter())
+        self.iter().eq(other.iter())
     }
 }
 
 impl PartialOrd<[DataValue]> for TupleInIter<'_> {
     fn partial_cmp(&self, other: &'_ [DataValue]) -> Option<Ordering> {
-        self.into_iter().partial_cmp(other.iter())
-    }
-}
-
-impl<'a> Iterator for TupleInIterIterator<'a> {
-    type Item = &'a DataValue;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = match self.inner.0.get(self.idx) {
-            Some(d) => d,
-            None => match self.inner.1.get(self.idx - self.inner.0.len()) {
-                None => return None,
-                Some(d) => d,
-            },
-        };
-        self.idx += 1;
-        Some(ret)
+        self.iter().partial_cmp(other.iter())
     }
 }
@@ -8,6 +8,8 @@
  */
 
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use itertools::Itertools;
@@ -23,7 +25,11 @@ use crate::fts::{TokenizerCache, TokenizerConfig};
 use crate::parse::SourceSpan;
 use crate::runtime::callback::CallbackOp;
 use crate::runtime::db::Poison;
-use crate::{new_cozo_mem, DbInstance, FixedRule, RegularTempStore};
+use crate::runtime::temp_store::EpochStore;
+use crate::storage::TransactionConflict;
+use crate::{
+    new_cozo_mem, DbInstance, FixedRule, NamedRows, RegularTempStore, Session, SimpleFixedRule,
+};
 
 #[test]
 fn test_limit_offset() {
@@ -1154,3 +1160,2016 @@ fn multi_index_vec() {
         println!("{}", row);
     }
 }
+
+#[test]
+fn temporal_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[at, day, took] <- [['2023-01-02T03:04:05Z', '2023-01-02', '1h'],
+                             ['2022-12-31T23:00:00-02:00', '2023-01-01', 90]]
+        :create events {at: Timestamp, day: Date => took: Duration}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"
+            ?[at, day, took, dow] := *events{at, day, took}, dow = day_of_week(day)
+            :order at
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([
+            ["2023-01-01T01:00:00Z", "2023-01-01", "1m30s", 7],
+            ["2023-01-02T03:04:05Z", "2023-01-02", "1h", 1]
+        ])
+    );
+    let res = db
+        .run_script(
+            r"
+            ?[n] := *events{at}, at > timestamp('2023-01-01T12:00:00Z'), n = 1
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1]]));
+    assert!(db
+        .run_script(
+            "?[at, day, took] <- [['yesterday', '2023-01-01', 1]] :put events {at, day => took}",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn decimal_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[k, price] <- [['a', '0.1'], ['b', 0.2], ['c', '-3.25'], ['d', 10]]
+        :create prices {k => price: Decimal}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"
+            ?[k, price] := *prices{k, price}
+            :order price
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([["c", "-3.25"], ["a", "0.1"], ["b", "0.2"], ["d", "10"]])
+    );
+    let res = db
+        .run_script(
+            r"
+            ?[sum(price), max(price)] := *prices{price}, price > 0
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["10.3", "10"]]));
+    db.run_script(
+        r"
+        ?[price, k] <- [[decimal('1.10'), 'x'], [decimal('-0.5'), 'y'], [decimal('1.1000001'), 'z']]
+        :create by_price {price: Decimal => k}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[price, k] := *by_price{price, k}, price >= decimal('1.1')",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["1.1", "x"], ["1.1000001", "z"]]));
+    let res = db
+        .run_script(
+            r"
+            r[x] <- [[decimal('1.5')], [3], [0.5], [decimal('2.25')]]
+            ?[x] := r[x], x > 1, x < 2.5
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["1.5"], ["2.25"]]));
+    assert!(db
+        .run_script(
+            "?[k, price] <- [['e', 'cheap']] :put prices {k => price}",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn rtree_index() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[k, loc] := x in int_range(10), y in int_range(10), k = x * 10 + y, loc = st_point(x, y)
+        :create places {k: Int => loc: Geometry?}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"::rtree create places:rt {extractor: loc, max_entries: 4}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ?[k, loc] <- [[100, 'LINESTRING (20 20, 30 30)'], [101, null],
+                      [102, 'POLYGON ((2.5 2.5, 3.5 2.5, 3.5 3.5, 2.5 3.5))']]
+        :put places {k => loc}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    let check_intersects = |q: &str| {
+        let by_index = db
+            .run_script(
+                &format!("?[k] := ~places:rt{{k | intersects: geometry('{q}')}}"),
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        let by_scan = db
+            .run_script(
+                &format!(
+                    "?[k] := *places{{k, loc}}, !is_null(loc), st_intersects(loc, geometry('{q}'))"
+                ),
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        assert_eq!(by_index["rows"], by_scan["rows"]);
+        by_index["rows"].as_array().unwrap().len()
+    };
+    assert_eq!(
+        check_intersects("POLYGON ((1.5 1.5, 3 1.5, 3 3, 1.5 3))"),
+        5
+    );
+    assert_eq!(check_intersects("LINESTRING (0 9.5, 25 9.5)"), 0);
+    assert_eq!(check_intersects("POINT (25 25)"), 1);
+
+    let res = db
+        .run_script(
+            r"
+            ?[k, d] := ~places:rt{k | query: st_point(3.1, 3.1), k: 3, bind_distance: d}
+            :order d, k
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0], json!([102, 0.0]));
+    assert_eq!(res["rows"].as_array().unwrap().len(), 3);
+    assert_eq!(res["rows"][1][0], json!(33));
+    let res = db
+        .run_script(
+            r"
+            ?[k] := ~places:rt{k | query: st_point(0, 0), within_distance: 1.5,
+                                   filter: k != 0}
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1], [10], [11]]));
+    // in meters on the sphere: one degree is about 111 km
+    let res = db
+        .run_script(
+            r"
+            ?[k] := ~places:rt{k | query: st_point(0, 0), within_distance: 200000,
+                                   geodesic: true, filter: k != 0}
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1], [10], [11]]));
+    let by_index = db
+        .run_script(
+            r"
+            ?[k, d] := ~places:rt{k | query: st_point(3.1, 3.1), k: 5, geodesic: true,
+                                      bind_distance: d}
+            :order d, k
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    let by_scan = db
+        .run_script(
+            r"
+            ?[k, d] := *places{k, loc}, loc != null,
+                       d = st_distance_sphere(st_point(3.1, 3.1), loc)
+            :order d, k
+            :limit 5
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(by_index["rows"], by_scan["rows"]);
+
+    // the index follows updates and removals
+    db.run_script(
+        r"
+        ?[k] := k in int_range(0, 100, 2)
+        :rm places {k}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ?[k, loc] <- [[1, 'POINT (25 25)'], [102, null], [3, 'POINT (-5 -5)']]
+        :update places {k => loc}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[k, loc] <- [[5, 'POINT (26 26)']] :put places {k => loc}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        check_intersects("POLYGON ((1.5 1.5, 3 1.5, 3 3, 1.5 3))"),
+        2
+    );
+    assert_eq!(
+        check_intersects("POLYGON ((20 20, 30 20, 30 30, 20 30))"),
+        3
+    );
+    assert_eq!(
+        check_intersects("POLYGON ((-10 -10, 10 -10, 10 10, -10 10))"),
+        48
+    );
+
+    let res = db
+        .run_script("::indices places", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][1], json!("rtree"));
+    db.run_script("::rtree drop places:rt", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script(
+            "?[k] := ~places:rt{k | intersects: st_point(1, 1)}",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn expr_index() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, email, status, data] <- [[1, 'Alice@Example.com', 'active', {'customer_id': 10}],
+                                        [2, 'bob@example.com', 'inactive', {'customer_id': 10}],
+                                        [3, 'CAROL@example.com', 'active', {'customer_id': 20}],
+                                        [4, 'dave@example.com', 'active', {}]]
+        :create users {id: Int => email: String, status: String, data: Json}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"::index create users:by_email {lowercase(email)}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"::index create users:active_customer {get(data, 'customer_id', null) | status == 'active'}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let by_email = |email: &str| {
+        let res = db
+            .run_script(
+                &format!("?[id] := *users{{id, email}}, lowercase(email) == '{email}'"),
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        res["rows"].clone()
+    };
+    let by_customer = |cid: i64| {
+        let res = db
+            .run_script(
+                &format!(
+                    "?[id] := *users{{id, status, data}}, get(data, 'customer_id', null) == {cid}, status == 'active'"
+                ),
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        res["rows"].clone()
+    };
+    assert_eq!(by_email("alice@example.com"), json!([[1]]));
+    assert_eq!(by_email("carol@example.com"), json!([[3]]));
+    assert_eq!(by_customer(10), json!([[1]]));
+    assert_eq!(by_customer(20), json!([[3]]));
+
+    let explain = db
+        .run_script(
+            "::explain { ?[id] := *users{id, email}, lowercase(email) == 'alice@example.com' }",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert!(explain.to_string().contains("users:by_email"));
+    let explain = db
+        .run_script(
+            "::explain { ?[id] := *users{id, data}, get(data, 'customer_id', null) == 10 }",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert!(!explain.to_string().contains("users:active_customer"));
+    // expressions are matched by structure, not by spelling
+    let explain = db
+        .run_script(
+            "::explain { ?[id] := *users{id, status, data}, 'active' == status, \
+                          20 == get(data, 'customer_id', null) }",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert!(explain.to_string().contains("users:active_customer"));
+    // the primary key is preferred when fully bound
+    let explain = db
+        .run_script(
+            "::explain { ?[email] := id = 1, *users{id, email}, \
+                          lowercase(email) == 'alice@example.com' }",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert!(!explain.to_string().contains("users:by_email"));
+
+    // the indices follow updates and removals
+    db.run_script(
+        r"
+        ?[id, status] <- [[1, 'inactive'], [2, 'active']]
+        :update users {id => status}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ?[id, email, status, data] <- [[5, 'ALICE@example.com', 'active', {'customer_id': 20}]]
+        :put users {id => email, status, data}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(r"?[id] <- [[3]] :rm users {id}", Default::default())
+        .unwrap();
+    assert_eq!(by_email("alice@example.com"), json!([[1], [5]]));
+    assert_eq!(by_email("carol@example.com"), json!([]));
+    assert_eq!(by_customer(10), json!([[2]]));
+    assert_eq!(by_customer(20), json!([[5]]));
+
+    let res = db
+        .run_script("::indices users", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][1], json!("expr"));
+    assert_eq!(res["rows"].as_array().unwrap().len(), 2);
+    db.run_script("::index drop users:by_email", Default::default())
+        .unwrap();
+    assert_eq!(by_email("alice@example.com"), json!([[1], [5]]));
+}
+
+#[test]
+fn unique_and_foreign_key_constraints() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, email] <- [[1, 'a@x.com'], [2, 'b@x.com']]
+        :create users {id: Int => email: String unique}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ?[id, owner, parent] <- [[10, 1, null], [11, 2, 10]]
+        :create items {id: Int => owner: Int references users, parent: Int? references items}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    let err = db
+        .run_script(
+            r"?[id, email] <- [[3, 'a@x.com']] :put users {id => email}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::unique_violation"));
+    // updating a row with its own value is fine
+    db.run_script(
+        r"?[id, email] <- [[1, 'a@x.com'], [3, 'c@x.com']] :put users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            r"?[id, email] <- [[3, 'b@x.com']] :update users {id => email}",
+            Default::default(),
+        )
+        .is_err());
+    // uniqueness is also checked within a single write
+    assert!(db
+        .run_script(
+            r"?[id, email] <- [[4, 'd@x.com'], [5, 'd@x.com']] :put users {id => email}",
+            Default::default(),
+        )
+        .is_err());
+
+    let err = db
+        .run_script(
+            r"?[id, owner, parent] <- [[12, 4, null]] :put items {id => owner, parent}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::foreign_key_violation"));
+    assert!(db
+        .run_script(
+            r"?[id, owner, parent] <- [[12, 3, 99]] :put items {id => owner, parent}",
+            Default::default(),
+        )
+        .is_err());
+    db.run_script(
+        r"?[id, owner, parent] <- [[12, 3, 11], [13, 3, 13]] :put items {id => owner, parent}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let err = db
+        .run_script(r"?[id] <- [[1]] :rm users {id}", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::foreign_key_still_referenced"));
+    assert!(db
+        .run_script(r"?[id] <- [[11]] :rm items {id}", Default::default())
+        .is_err());
+    db.run_script(r"?[id] <- [[12], [13]] :rm items {id}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"?[id, owner] <- [[11, 3]] :update items {id => owner}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(r"?[id] <- [[2]] :rm users {id}", Default::default())
+        .unwrap();
+
+    let res = db
+        .run_script("::columns items", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][1][5], json!(["references users"]));
+    assert!(db
+        .run_script("::index drop users:unique_email", Default::default())
+        .is_err());
+    assert!(db.run_script("::remove users", Default::default()).is_err());
+    db.run_script("::remove items", Default::default()).unwrap();
+    db.run_script("::remove users", Default::default()).unwrap();
+}
+
+#[test]
+fn check_constraints() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, age, status, lo, hi] <- [[1, 30, 'a', 0, 1]]
+        :create people {
+            id: Int
+            =>
+            age: Int check age >= 0,
+            status: String check is_in(status, ['a', 'b']),
+            lo: Int,
+            hi: Int,
+            check lo <= hi
+        }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let err = db
+        .run_script(
+            r"?[id, age, status, lo, hi] <- [[2, -1, 'a', 0, 1]] :put people {id => age, status, lo, hi}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::check_violation"));
+    let err = db
+        .run_script(
+            r"?[id, age, status, lo, hi] <- [[2, 1, 'c', 0, 1]] :put people {id => age, status, lo, hi}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::check_violation"));
+    let err = db
+        .run_script(
+            r"?[id, lo] <- [[1, 5]] :update people {id => lo}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::check_violation"));
+    db.run_script(
+        r"?[id, lo, hi] <- [[1, 5, 6]] :update people {id => lo, hi}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[lo, hi] := *people{lo, hi}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[5, 6]]));
+
+    // checks are not enforced on null columns
+    db.run_script(
+        r"
+        ?[id, age] <- [[1, null], [2, 3]]
+        :create members {id: Int => age: Int? check age >= 0}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let err = db
+        .run_script(
+            r"?[id, age] <- [[3, -3]] :put members {id => age}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::check_violation"));
+    let res = db
+        .run_script("?[id, age] := *members{id, age}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, null], [2, 3]]));
+
+    // checks are validated against the columns at creation
+    assert!(db
+        .run_script(
+            r":create bad {a: Int => b: Int check c > 0}",
+            Default::default()
+        )
+        .is_err());
+    // a column merely named like the keyword is still a column
+    db.run_script(
+        r":create checked {checker: Int => check: Bool}",
+        Default::default(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn alter_relation() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, name, score] <- [[1, 'a', 10], [2, 'b', 20]]
+        :create players {id: Int => name: String, score: Int}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::index create players:by_score {score}",
+        Default::default(),
+    )
+    .unwrap();
+
+    db.run_script(
+        "::alter players add level: Int default 1",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::alter players add note: String?", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script("::alter players add bad: String", Default::default())
+        .is_err());
+    let res = db
+        .run_script(
+            "?[id, level, note] := *players{id, level, note}",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, 1, null], [2, 1, null]]));
+
+    // the index survives and keeps working after a preceding column is dropped
+    db.run_script("::alter players drop name", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script("::alter players drop score", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("::alter players drop id", Default::default())
+        .is_err());
+    db.run_script(
+        "?[id, score, level, note] <- [[3, 15, 2, 'x']] :put players {id => score, level, note}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[id] := *players:by_score{score, id}, score > 12",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2], [3]]));
+
+    db.run_script("::alter players rename score to points", Default::default())
+        .unwrap();
+    let res = db
+        .run_script(
+            "?[id, points] := *players{id, points}, points > 12",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2, 20], [3, 15]]));
+    let res = db
+        .run_script("::columns players:by_score", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][0][0], json!("points"));
+
+    db.run_script("::alter players change level: Float", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("?[level] := *players{id: 3, level}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2.0]]));
+    assert!(db
+        .run_script("::alter players change note: Int?", Default::default())
+        .is_err());
+
+    // existing rows must satisfy checks added with new columns
+    let err = db
+        .run_script(
+            "::alter players add bonus: Int default 0 check bonus > 0",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::check_violation"));
+    db.run_script(
+        "::alter players add bonus: Int default 1 check bonus > 0",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            "?[id, bonus] <- [[1, -1]] :update players {id => bonus}",
+            Default::default()
+        )
+        .is_err());
+
+    // key columns referenced by foreign keys keep their names
+    db.run_script(":create teams {name: String}", Default::default())
+        .unwrap();
+    db.run_script(
+        ":create members {id: Int => team: String references teams}",
+        Default::default(),
+    )
+    .unwrap();
+    let err = db
+        .run_script("::alter teams rename name to title", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("tx::alter_col_in_use"));
+
+    // all rows are rewritten, however many batches they are read in
+    db.run_script(
+        r"
+        ?[id] := id in int_range(3000)
+        :create many {id: Int}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::alter many add n: Int default 7", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("?[count(id), sum(n)] := *many{id, n}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[3000, 21000.0]]));
+}
+
+#[test]
+fn materialized_view() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] <- [[1, 2], [2, 3]]
+        :create edge {a, b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ::view create reach {
+            r[a, b] := *edge{a, b}
+            r[a, c] := r[a, b], *edge{a: b, b: c}
+            ?[a, b] := r[a, b]
+        }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::view create from_one { ?[b] := *reach{a: 1, b} }",
+        Default::default(),
+    )
+    .unwrap();
+    let reach = || {
+        db.run_script("?[a, b] := *reach{a, b}", Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(reach(), json!([[1, 2], [1, 3], [2, 3]]));
+
+    db.run_script("?[a, b] <- [[3, 4]] :put edge {a, b}", Default::default())
+        .unwrap();
+    assert_eq!(
+        reach(),
+        json!([[1, 2], [1, 3], [1, 4], [2, 3], [2, 4], [3, 4]])
+    );
+    let from_one = db
+        .run_script("?[b] := *from_one{b}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(from_one["rows"], json!([[2], [3], [4]]));
+
+    db.run_script("?[a, b] <- [[2, 3]] :rm edge {a, b}", Default::default())
+        .unwrap();
+    assert_eq!(reach(), json!([[1, 2], [3, 4]]));
+
+    let err = db
+        .run_script("?[a, b] <- [[5, 6]] :put reach {a, b}", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::write_to_view"));
+    let err = db
+        .run_script("::remove edge", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::relation_used_by_view"));
+
+    db.run_script("::view drop from_one", Default::default())
+        .unwrap();
+    db.run_script("::view drop reach", Default::default())
+        .unwrap();
+    db.run_script("::remove edge", Default::default()).unwrap();
+}
+
+#[test]
+fn materialized_view_incremental() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create edge {a, b}", Default::default())
+        .unwrap();
+    let two_hop = "?[a, c] := *edge{a, b}, *edge{a: b, b: c}";
+    let reach = r"
+        r[a, b] := *edge{a, b}
+        r[a, c] := r[a, b], *edge{a: b, b: c}
+        ?[a, b] := r[a, b]
+    ";
+    db.run_script(
+        &format!("::view create two_hop {{ {two_hop} }}"),
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        &format!("::view create reach {{ {reach} }}"),
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::view create from_one { ?[b] := *reach{a: 1, b}, not *two_hop{a: 1, c: b} }",
+        Default::default(),
+    )
+    .unwrap();
+    let rows = |query: &str| {
+        db.run_script(query, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    let check = || {
+        assert_eq!(rows("?[a, c] := *two_hop{a, c}"), rows(two_hop));
+        assert_eq!(rows("?[a, b] := *reach{a, b}"), rows(reach));
+        assert_eq!(
+            rows("?[b] := *from_one{b}"),
+            rows("?[b] := *reach{a: 1, b}, not *two_hop{a: 1, c: b}")
+        );
+    };
+    for (op, edges) in [
+        // 1 reaches 3 through both 2 and 4
+        ("put", "[[1, 2], [2, 3], [1, 4], [4, 3], [5, 5]]"),
+        ("rm", "[[1, 2]]"),
+        // a cycle through 1
+        ("put", "[[3, 1], [3, 6], [1, 2]]"),
+        ("rm", "[[4, 3], [5, 5]]"),
+        ("rm", "[[3, 1], [7, 8]]"),
+        ("put", "[[6, 1], [2, 3]]"),
+        ("rm", "[[1, 2], [1, 4], [2, 3], [3, 6], [6, 1]]"),
+    ] {
+        db.run_script(
+            &format!("?[a, b] <- {edges} :{op} edge {{a, b}}"),
+            Default::default(),
+        )
+        .unwrap();
+        check();
+    }
+}
+
+#[test]
+fn materialized_view_touches_affected_rows() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] := a in int_range(5000), b = a % 10
+        :create big {a => b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::view create matched { ?[a, b] := *big{a, b}, b > 2 }",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ::view create reached {
+            r[a, b] := *big{a, b}
+            r[a, c] := r[a, b], *big{a: b, b: c}
+            ?[a, b] := r[a, b]
+        }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    // evaluating either view in full takes far more than the limit
+    db.set_max_memory(Some(20000));
+    let err = db
+        .run_script("?[a, b] := *big{a, b}, b > 2", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::memory_limit_exceeded"));
+    db.run_script(
+        "?[a, b] <- [[6000, 7], [13, 2]] :put big {a => b}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[a] <- [[23]] :rm big {a}", Default::default())
+        .unwrap();
+    db.set_max_memory(None);
+
+    let count = |view: &str| {
+        db.run_script(
+            &format!("?[count(a)] := *{view}{{a, b}}, a in [13, 23, 6000]"),
+            Default::default(),
+        )
+        .unwrap()
+        .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(count("matched"), json!([[1]]));
+    assert_eq!(count("reached"), json!([[2]]));
+    assert_eq!(
+        db.run_script("?[count(a)] := *matched{a}", Default::default())
+            .unwrap()
+            .into_json()["rows"],
+        json!([[3499]])
+    );
+}
+
+#[test]
+fn query_subscription() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] <- [[1, 5], [2, 0]]
+        :create nums {a => b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(":create other {x}", Default::default())
+        .unwrap();
+
+    assert!(db
+        .subscribe("?[a] <- [[1]] :put nums {a}", Default::default(), None)
+        .is_err());
+    let (id, initial, receiver) = db
+        .subscribe("?[a] := *nums{a, b}, b > 1", Default::default(), None)
+        .unwrap();
+    assert_eq!(initial.into_json()["rows"], json!([[1]]));
+
+    let timeout = Duration::from_secs(10);
+    db.run_script(
+        "?[a, b] <- [[3, 7], [4, 0]] :put nums {a => b}",
+        Default::default(),
+    )
+    .unwrap();
+    let delta = receiver.recv_timeout(timeout).unwrap();
+    assert_eq!(delta.added.into_json()["rows"], json!([[3]]));
+    assert_eq!(delta.removed.into_json()["rows"], json!([]));
+
+    // writes that leave the result unchanged produce no delta,
+    // so the next delta is that of the write changing the result
+    db.run_script("?[a, b] <- [[4, 1]] :put nums {a => b}", Default::default())
+        .unwrap();
+    db.run_script("?[x] <- [[1]] :put other {x}", Default::default())
+        .unwrap();
+    db.run_script(
+        "?[a, b] <- [[1, 0], [2, 9]] :put nums {a => b}",
+        Default::default(),
+    )
+    .unwrap();
+    let delta = receiver.recv_timeout(timeout).unwrap();
+    assert_eq!(delta.added.into_json()["rows"], json!([[2]]));
+    assert_eq!(delta.removed.into_json()["rows"], json!([[1]]));
+
+    // the channel is closed without further deltas after unsubscribing
+    assert!(db.unsubscribe(id));
+    db.run_script("?[a] <- [[3]] :rm nums {a}", Default::default())
+        .unwrap();
+    assert!(receiver.recv_timeout(timeout).is_err());
+
+    // a subscriber with a full channel is dropped
+    let (id, _, receiver) = db
+        .subscribe("?[a] := *nums{a, b}, b > 1", Default::default(), Some(1))
+        .unwrap();
+    db.run_script("?[a, b] <- [[5, 5]] :put nums {a => b}", Default::default())
+        .unwrap();
+    let started = std::time::Instant::now();
+    while receiver.is_empty() {
+        assert!(started.elapsed() < timeout);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    db.run_script("?[a, b] <- [[6, 6]] :put nums {a => b}", Default::default())
+        .unwrap();
+    // receiving before the notifier tries to send would make room for the delta
+    let started = std::time::Instant::now();
+    while db.subscriptions.read().unwrap().contains_key(&id) {
+        assert!(started.elapsed() < timeout);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let delta = receiver.recv_timeout(timeout).unwrap();
+    assert_eq!(delta.added.into_json()["rows"], json!([[5]]));
+    assert!(receiver.recv_timeout(timeout).is_err());
+    assert!(!db.unsubscribe(id));
+}
+
+#[test]
+fn stored_rules() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] <- [[1, 2], [2, 3], [3, 4]]
+        :create edge {a, b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ::rules define
+            reach[a, b] := *edge{a, b}
+            reach[a, c] := reach[a, b], *edge{a: b, b: c}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::rules define from_one[b] := reach[1, b]",
+        Default::default(),
+    )
+    .unwrap();
+
+    let res = db
+        .run_script("?[b] := reach[2, b]", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[3], [4]]));
+    // stored rules can use other stored rules, and are stratified with the query
+    let res = db
+        .run_script("?[a] := *edge{a}, not from_one[a]", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1]]));
+    // rules defined by the query take precedence
+    let res = db
+        .run_script(
+            "reach[a, b] <- [[2, 100]] ?[b] := reach[2, b]",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[100]]));
+    // but not within stored rules, which always use the stored rules they depend on
+    let res = db
+        .run_script(
+            "reach[a, b] <- [[1, 100]] ?[b] := from_one[b]",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2], [3], [4]]));
+    let res = db
+        .run_script(
+            "reach[a, b] <- [[1, 100]] ?[b] := from_one[b] or reach[1, b]",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2], [3], [4], [100]]));
+
+    let err = db
+        .run_script(
+            "::rules define a[x] := x = 1 b[x] := x = 2",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("parser::stored_rule_name_mismatch"));
+
+    db.run_script(
+        "::rules describe reach 'transitive closure of edges'",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("::rules list", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][1][0], json!("reach"));
+    assert_eq!(res["rows"][1][1], json!(2));
+    assert_eq!(res["rows"][1][2], json!("transitive closure of edges"));
+
+    db.run_script("::rules drop from_one", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script("?[b] := from_one[b]", Default::default())
+        .is_err());
+    let err = db
+        .run_script("::rules drop from_one", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::stored_rule_not_found"));
+}
+
+#[test]
+fn stored_procedures() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create users {id: Int => name: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(":create audit {id: Int, name: String}", Default::default())
+        .unwrap();
+    db.run_script(":create log {id: Int, name: String}", Default::default())
+        .unwrap();
+
+    let res = db
+        .run_script(
+            r"
+            ::procedure create upsert($id, $name) {
+                {?[id, name] <- [[$id, $name]] :put users {id => name}}
+                {?[id, name] <- [[$id, $name]] :put audit {id, name}}
+            }
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["OK", 1]]));
+    let err = db
+        .run_script(
+            "::procedure create bad($id) { ?[x] <- [[$other]] }",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("parser::param_not_found"));
+
+    db.run_script("::call upsert(1, 'alice')", Default::default())
+        .unwrap();
+    db.call_procedure("upsert", vec![DataValue::from(1), DataValue::from("bob")])
+        .unwrap();
+    let res = db
+        .run_script("?[id, name] := *users{id, name}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, "bob"]]));
+    let res = db
+        .run_script("?[id, name] := *audit{id, name}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1, "alice"], [1, "bob"]]));
+    let err = db
+        .run_script("::call upsert(1)", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::procedure_arity_mismatch"));
+
+    // procedures called from triggers see the triggering rows as `_new` and `_old`
+    db.run_script(
+        "::procedure create log_put() { ?[id, name] := _new[id, name] :put log {id, name} }",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::set_triggers users on put { ::call log_put() }",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::call upsert(2, 'carol')", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("?[id, name] := *log{id, name}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[2, "carol"]]));
+
+    let res = db
+        .run_script(
+            "::procedure create upsert($id, $name) { {?[id, name] <- [[$id, $name]] :put users {id => name}} }",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["OK", 2]]));
+    let res = db
+        .run_script("::procedure list", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"][1][0], json!("upsert"));
+    assert_eq!(res["rows"][1][1], json!(["$id", "$name"]));
+    assert_eq!(res["rows"][1][2], json!(2));
+
+    db.run_script("::procedure drop upsert", Default::default())
+        .unwrap();
+    let err = db
+        .run_script("::call upsert(3, 'dave')", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::procedure_not_found"));
+}
+
+#[test]
+fn imperative_for_and_let() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create totals {k: String => v: Int}", Default::default())
+        .unwrap();
+
+    let res = db
+        .run_script(
+            r"
+            {?[name, n] <- [['a', 1], ['b', 2], ['c', 3]] :replace _items {name, n}}
+            {:create _seen {name: String}}
+            %for item in _items {
+                {?[k, v] <- [[$name, $n * 10]] :put totals {k => v}}
+                {?[name] <- [[get($item, 0)]] :put _seen {name}}
+            }
+            %let $total = { ?[sum(v)] := *totals{v} }
+            %let $count = { ?[count(name)] := *_seen{name} }
+            {?[total, count] <- [[$total, $count]]}
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[60.0, 3]]));
+    let res = db
+        .run_script("?[k, v] := *totals{k, v}", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["a", 10], ["b", 20], ["c", 30]]));
+
+    let res = db
+        .run_script(
+            r"
+            {?[n] <- [[1], [2], [3], [4]] :replace _nums {n}}
+            {:create _small {n: Int}}
+            %for row in _nums {
+                %if { ?[x] := x = $n, x > 2 } %then %break %end
+                {?[n] <- [[$n]] :put _small {n}}
+            }
+            %return _small
+            ",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[1], [2]]));
+
+    let err = db
+        .run_script(
+            r"
+            %let $x = { ?[k] := *totals{k} }
+            {?[x] <- [[$x]]}
+            ",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::let_not_scalar"));
+}
+
+#[test]
+fn max_memory() {
+    let db = new_cozo_mem().unwrap();
+    let runaway = r"
+        nat[n] := n = 0
+        nat[n] := nat[m], n = m + 1, n < 10000
+        ?[n] := nat[n]
+    ";
+
+    let err = db
+        .run_script(&format!("{runaway} :max_memory 100000"), Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::memory_limit_exceeded"));
+    let res = db
+        .run_script(
+            &format!("{runaway} :max_memory 1000000000"),
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 10000);
+
+    // the database-wide default applies unless overridden
+    db.set_max_memory(Some(100000));
+    let err = db.run_script(runaway, Default::default()).unwrap_err();
+    assert!(format!("{err:?}").contains("eval::memory_limit_exceeded"));
+    db.run_script("?[n] := n = 1", Default::default()).unwrap();
+    db.run_script(&format!("{runaway} :max_memory 0"), Default::default())
+        .unwrap();
+    db.set_max_memory(None);
+    db.run_script(runaway, Default::default()).unwrap();
+
+    // improving the aggregates of existing groups takes no more memory
+    let res = db
+        .run_script(
+            r"
+            countdown[n, min(d)] := n in int_range(10), d = 1000
+            countdown[n, min(d)] := countdown[n, e], e > 0, d = e - 1
+            ?[n, d] := countdown[n, d]
+            :max_memory 100000
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 10);
+    assert!(res.rows.iter().all(|row| row[1] == DataValue::from(0)));
+}
+
+#[test]
+fn temp_store_spilling() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] := a in int_range(50), b = a + 1
+        :create edge {a, b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let query = r"
+        reach[a, b] := *edge{a, b}
+        reach[a, c] := reach[a, b], *edge{a: b, b: c}
+        unreached[a, b] := a in int_range(51), b in int_range(51), not reach[a, b]
+        long[a, b] := reach[a, b], b - a >= 25, a > 10
+        ?[kind, count(a)] := reach[a, _], kind = 'reach'
+        ?[kind, count(a)] := unreached[a, _], kind = 'unreached'
+        ?[kind, count(a)] := long[a, _], kind = 'long'
+    ";
+    let expected = db.run_script(query, Default::default()).unwrap().rows;
+    assert_eq!(
+        expected,
+        vec![
+            vec![DataValue::from("long"), DataValue::from(120)],
+            vec![DataValue::from("reach"), DataValue::from(1275)],
+            vec![DataValue::from("unreached"), DataValue::from(1326)],
+        ]
+    );
+
+    db.set_temp_spill_threshold(Some(50));
+    let res = db.run_script(query, Default::default()).unwrap().rows;
+    assert_eq!(res, expected);
+}
+
+#[test]
+fn temp_store_spilled_lookups() {
+    let mut store = EpochStore::new_normal(1, 100);
+    let mut skipped = RegularTempStore::with_spill_threshold(100);
+    for i in 0..300 {
+        skipped.put_with_skip(vec![DataValue::from(i * 2)]);
+    }
+    store.merge_in(skipped.wrap()).unwrap();
+    for i in -1..601 {
+        let found = store.exists(&vec![DataValue::from(i)]).unwrap();
+        assert_eq!(found, (0..600).contains(&i) && i % 2 == 0, "{i}");
+    }
+    assert_eq!(store.early_returned_iter().count(), 0);
+
+    let mut rederived = RegularTempStore::with_spill_threshold(100);
+    rederived.put(vec![DataValue::from(10)]);
+    rederived.put(vec![DataValue::from(300)]);
+    store.merge_in(rederived.wrap()).unwrap();
+    let kept: Vec<_> = store
+        .early_returned_iter()
+        .map_ok(|t| t.into_tuple())
+        .try_collect()
+        .unwrap();
+    assert_eq!(
+        kept,
+        vec![vec![DataValue::from(10)], vec![DataValue::from(300)]]
+    );
+}
+
+#[test]
+fn wco_join_cyclic_bodies() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] := a in int_range(40), b in int_range(40), a != b, (a * 7 + b * 13) % 5 == 0
+        :create edge {a, b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ?[a, tag] := a in int_range(40), tag = a % 3
+        :create tag {a => tag}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    let bodies = [
+        "*edge[a, b], *edge[b, c], *edge[a, c]",
+        "*edge[a, b], *edge[b, c], *edge[c, a]",
+        "*edge[a, b], *edge[b, c], *edge[c, d], *edge[d, a], a < c",
+        "a = 3, *edge[a, b], *edge[b, c], *edge[a, c], *tag[c, 1]",
+        "c = 3, *edge[a, b], *edge[b, c], *edge[a, c]",
+    ];
+    let explain = |body: &str| {
+        db.run_script(
+            &format!("::explain {{ ?[a, b, c] := {body} }}"),
+            Default::default(),
+        )
+        .unwrap()
+        .into_json()
+        .to_string()
+    };
+    let plan_has_wco = |body: &str| explain(body).contains("wco_join");
+    // a variable bound before the join seeds it instead of the join being evaluated in full
+    let plan_is_seeded = |body: &str| explain(body).contains("wco_prefix_join");
+    let results = |body: &str| {
+        let binary = body.replace("*edge", "e");
+        let wco = db
+            .run_script(&format!("?[a, b, c] := {body}"), Default::default())
+            .unwrap()
+            .rows;
+        let expected = db
+            .run_script(
+                &format!("e[a, b] := *edge[a, b] ?[a, b, c] := {binary}"),
+                Default::default(),
+            )
+            .unwrap()
+            .rows;
+        (wco, expected)
+    };
+
+    assert!(plan_has_wco(bodies[0]));
+    // without an index in the reverse order, the triangle cannot be read as tries
+    assert!(!plan_has_wco(bodies[1]));
+    assert!(!plan_has_wco("*edge[a, b], *edge[b, c], *tag[c, _]"));
+    assert!(plan_is_seeded(bodies[3]));
+    // `c` cannot come first in any variable order readable as tries
+    assert!(!plan_has_wco(bodies[4]));
+    for body in bodies {
+        let (wco, expected) = results(body);
+        assert!(!expected.is_empty());
+        assert_eq!(wco, expected);
+    }
+
+    db.run_script("::index create edge:rev {b, a}", Default::default())
+        .unwrap();
+    for body in bodies {
+        assert!(plan_has_wco(body));
+        let (wco, expected) = results(body);
+        assert_eq!(wco, expected);
+    }
+    assert!(plan_is_seeded(bodies[3]));
+    assert!(plan_is_seeded(bodies[4]));
+    // an atom with all its variables bound is checked against the seed
+    let missing_edge = "a = 3, b = 3, *edge[a, b], *edge[b, c], *edge[a, c]";
+    assert!(plan_is_seeded(missing_edge));
+    let (wco, expected) = results(missing_edge);
+    assert!(wco.is_empty());
+    assert_eq!(wco, expected);
+}
+
+#[test]
+fn hash_join_spilling() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, group] := id in int_range(300), group = id % 7
+        :create item {id => group}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    // `group` is not a key prefix of `item`, so the join cannot use prefix lookups
+    let query = r"
+        g[group, id] := *item{id, group}
+        ?[count(a)] := g[group, a], *item{id: b, group}, a < b
+    ";
+    let explain = db
+        .run_script(&format!("::explain {{ {query} }}"), Default::default())
+        .unwrap()
+        .into_json();
+    assert!(explain.to_string().contains("hash_join"));
+
+    let expected = db.run_script(query, Default::default()).unwrap().rows;
+    assert_eq!(expected, vec![vec![DataValue::from(6279)]]);
+
+    db.set_temp_spill_threshold(Some(20));
+    let res = db.run_script(query, Default::default()).unwrap().rows;
+    assert_eq!(res, expected);
+}
+
+#[test]
+fn sorted_limit_top_k() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[x, score, group] := x in int_range(1000), score = (x * 37) % 101, group = x % 10
+        :create data {x => score, group}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    for (order, limit, offset) in [
+        ("-score, x", 10, 0),
+        ("-score, x", 10, 5),
+        ("group", 7, 3),
+        ("-group", 25, 0),
+        ("score", 2000, 990),
+        ("score", 0, 0),
+    ] {
+        let query = format!("?[x, score, group] := *data{{x, score, group}} :order {order}");
+        let all = db.run_script(&query, Default::default()).unwrap().rows;
+        let limited = db
+            .run_script(
+                &format!("{query} :limit {limit} :offset {offset}"),
+                Default::default(),
+            )
+            .unwrap()
+            .rows;
+        let expected = all.into_iter().skip(offset).take(limit).collect_vec();
+        assert_eq!(limited, expected);
+    }
+
+    // the entry rule is evaluated over several epochs of a recursive rule
+    let query = r"
+        r[x] := x = 0
+        r[y] := r[x], y = x + 1, y < 300
+        ?[x, score] := r[x], score = (x * 37) % 101
+        :order -score, x
+    ";
+    let all = db.run_script(query, Default::default()).unwrap().rows;
+    let limited = db
+        .run_script(&format!("{query} :limit 5"), Default::default())
+        .unwrap()
+        .rows;
+    assert_eq!(limited, all.into_iter().take(5).collect_vec());
+}
+
+#[test]
+fn why_provenance() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[a, b] <- [['a', 'b'], ['b', 'c'], ['c', 'd'], ['d', 'a'], ['b', 'd']]
+        :create edge {a, b}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let query = r"
+        path[a, b] := *edge[a, b]
+        path[a, c] := path[a, b], *edge[b, c]
+        ?[a, b] := path[a, b]
+    ";
+    let res = db
+        .run_script(
+            &format!("::why {{ {query} }} for ['a', 'd']"),
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.headers,
+        vec!["id", "parent", "kind", "name", "clause", "tuple"]
+    );
+    let root = &res.rows[0];
+    assert_eq!(root[1], DataValue::Null);
+    assert_eq!(root[3], DataValue::from("?"));
+    assert_eq!(root[5], DataValue::List(vec!["a".into(), "d".into()]));
+
+    // the edges used form a path from 'a' to 'd'
+    let edges = res
+        .rows
+        .iter()
+        .filter(|row| row[2] == DataValue::from("relation"))
+        .map(|row| {
+            assert_eq!(row[3], DataValue::from("edge"));
+            row[5].get_slice().unwrap().to_vec()
+        })
+        .collect_vec();
+    assert!(!edges.is_empty());
+    assert_eq!(edges[0][0], DataValue::from("a"));
+    assert_eq!(edges[edges.len() - 1][1], DataValue::from("d"));
+    for pair in edges.windows(2) {
+        assert_eq!(pair[0][1], pair[1][0]);
+    }
+    // every row except the root has an earlier parent
+    for (i, row) in res.rows.iter().enumerate().skip(1) {
+        assert!(row[1].get_int().unwrap() < i as i64);
+    }
+
+    assert!(db
+        .run_script(
+            &format!("::why {{ {query} }} for ['a', 'x']"),
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn max_iterations() {
+    let db = new_cozo_mem().unwrap();
+    let diverging = r"
+        seed[n] := n = 0
+        nat[n] := seed[n]
+        nat[n] := nat[m], n = m + 1
+        ?[n] := nat[n]
+    ";
+    let err = db
+        .run_script(
+            &format!("{diverging} :max_iterations 50"),
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::iteration_limit_exceeded"));
+    assert!(err.to_string().contains("nat"));
+    assert!(!err.to_string().contains("seed"));
+
+    let bounded = r"
+        nat[n] := n = 0
+        nat[n] := nat[m], n = m + 1, n < 100
+        ?[n] := nat[n]
+    ";
+    let res = db
+        .run_script(
+            &format!("{bounded} :max_iterations 200"),
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 100);
+    assert!(db
+        .run_script(&format!("{bounded} :max_iterations 50"), Default::default())
+        .is_err());
+
+    // the database-wide default applies unless overridden
+    db.set_max_iterations(Some(10));
+    let err = db.run_script(diverging, Default::default()).unwrap_err();
+    assert!(format!("{err:?}").contains("eval::iteration_limit_exceeded"));
+    db.run_script(&format!("{bounded} :max_iterations 0"), Default::default())
+        .unwrap();
+    db.set_max_iterations(None);
+    db.run_script(bounded, Default::default()).unwrap();
+}
+
+#[test]
+fn well_founded_negation() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[fr, to] <- [['a', 'b'], ['b', 'a'], ['b', 'c'], ['c', 'd']]
+        :create move {fr, to}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let game = r"
+        win[x] := *move[x, y], not win[y]
+        ?[x] := win[x]
+    ";
+    let err = db.run_script(game, Default::default()).unwrap_err();
+    assert!(format!("{err:?}").contains("eval::unstratifiable"));
+
+    // 'd' has no moves, so 'c' wins, while 'a' and 'b' can only draw by moving back and forth
+    let res = db
+        .run_script(&format!("{game} :well_founded"), Default::default())
+        .unwrap();
+    assert_eq!(res.headers, vec!["x"]);
+    assert_eq!(res.rows, vec![vec![DataValue::from("c")]]);
+    let undefined = res.next.unwrap();
+    assert_eq!(
+        undefined.rows,
+        vec![vec![DataValue::from("a")], vec![DataValue::from("b")]]
+    );
+
+    // stratifiable programs have no undefined tuples
+    let res = db
+        .run_script(
+            r"
+            pos[x] := *move[x, _]
+            pos[x] := *move[_, x]
+            stuck[x] := pos[x], not *move[x, _]
+            ?[x] := pos[x], not stuck[x]
+            :well_founded
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 3);
+    assert!(res.next.unwrap().rows.is_empty());
+
+    assert!(db
+        .run_script(
+            &format!("{game} :well_founded :limit 1"),
+            Default::default()
+        )
+        .is_err());
+
+    // well-founded queries are listed as running and can be killed
+    let runner = db.clone();
+    let endless = std::thread::spawn(move || {
+        runner.run_script(
+            r"
+            nat[x] := x = 0
+            nat[y] := nat[x], y = x + 1
+            ?[x] := nat[x], x < 0
+            :well_founded
+            ",
+            Default::default(),
+        )
+    });
+    let mut running = vec![];
+    for _ in 0..500 {
+        running = db.run_script("::running", Default::default()).unwrap().rows;
+        if !running.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let id = running[0][0].get_int().unwrap();
+    let killed = db
+        .run_script(&format!("::kill {id}"), Default::default())
+        .unwrap();
+    assert_eq!(killed.rows, vec![vec![DataValue::from("KILLING")]]);
+    let err = endless.join().unwrap().unwrap_err();
+    assert!(format!("{err:?}").contains("eval::killed"));
+}
+
+#[test]
+fn result_cache() {
+    let db = new_cozo_mem().unwrap();
+    db.set_result_cache_capacity(Some(2));
+    db.run_script(":create a {x}", Default::default()).unwrap();
+    db.run_script(":create b {x}", Default::default()).unwrap();
+    db.run_script("?[x] <- [[1]] :put a {x}", Default::default())
+        .unwrap();
+    let cached_len = || db.result_cache.lock().unwrap().len();
+    let query = "?[x] := *a{x}";
+
+    assert_eq!(
+        db.run_script(query, Default::default()).unwrap().rows.len(),
+        1
+    );
+    assert_eq!(cached_len(), 1);
+
+    // writes to other relations keep the result
+    db.run_script("?[x] <- [[1]] :put b {x}", Default::default())
+        .unwrap();
+    assert_eq!(cached_len(), 1);
+
+    // writes to the relation read drop it
+    db.run_script("?[x] <- [[2]] :put a {x}", Default::default())
+        .unwrap();
+    assert_eq!(cached_len(), 0);
+    assert_eq!(
+        db.run_script(query, Default::default()).unwrap().rows.len(),
+        2
+    );
+    assert_eq!(cached_len(), 1);
+
+    // parameters are part of the key
+    let param_query = "?[x] := *a{x}, x > $min";
+    let params = BTreeMap::from([("min".to_string(), DataValue::from(1))]);
+    assert_eq!(db.run_script(param_query, params).unwrap().rows.len(), 1);
+    let params = BTreeMap::from([("min".to_string(), DataValue::from(0))]);
+    assert_eq!(db.run_script(param_query, params).unwrap().rows.len(), 2);
+    assert_eq!(cached_len(), 2);
+
+    // schema changes drop all results
+    db.run_script("::remove b", Default::default()).unwrap();
+    assert_eq!(cached_len(), 0);
+
+    // non-deterministic queries are not cached
+    db.run_script("?[x] := x = rand_float()", Default::default())
+        .unwrap();
+    assert_eq!(cached_len(), 0);
+
+    db.set_result_cache_capacity(None);
+    db.run_script(query, Default::default()).unwrap();
+    assert_eq!(cached_len(), 0);
+}
+
+#[test]
+fn session_temp_relations() {
+    let db = new_cozo_mem().unwrap();
+    let session = Session::default();
+    db.run_script_in_session(&session, ":create _tmp {x}", Default::default())
+        .unwrap();
+    db.run_script_in_session(&session, "?[x] <- [[1]] :put _tmp {x}", Default::default())
+        .unwrap();
+    let read = "?[x] := *_tmp{x}";
+    let res = db
+        .run_script_in_session(&session, read, Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(1)]]);
+
+    // invisible outside the session
+    assert!(db.run_script(read, Default::default()).is_err());
+    assert!(db
+        .run_script_in_session(&Session::default(), read, Default::default())
+        .is_err());
+
+    // a failed script leaves the temp relations untouched
+    assert!(db
+        .run_script_in_session(
+            &session,
+            "{?[x] <- [[2]] :put _tmp {x}} {?[x] := *nonexistent{x}}",
+            Default::default()
+        )
+        .is_err());
+    let res = db
+        .run_script_in_session(&session, read, Default::default())
+        .unwrap();
+    assert_eq!(res.rows.len(), 1);
+
+    // system ops see the temp relations of the session too
+    let res = db
+        .run_script_in_session(&session, "::columns _tmp", Default::default())
+        .unwrap();
+    assert_eq!(res.rows.len(), 1);
+    db.run_script_in_session(&session, "::indices _tmp", Default::default())
+        .unwrap();
+    db.run_script_in_session(&session, "::describe _tmp 'scratch'", Default::default())
+        .unwrap();
+    db.run_script(
+        "::procedure create read_tmp() { ?[x] := *_tmp{x} }",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script_in_session(&session, "::call read_tmp()", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(1)]]);
+    assert!(db.run_script("::columns _tmp", Default::default()).is_err());
+
+    db.run_script_in_session(&session, "::remove _tmp", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script_in_session(&session, read, Default::default())
+        .is_err());
+
+    // a multi-transaction keeps temp relations across its queries
+    let db = DbInstance::new("mem", "", "").unwrap();
+    let tx = db.multi_transaction(true);
+    tx.run_script(":create _m {x}", Default::default()).unwrap();
+    tx.run_script("?[x] <- [[1]] :put _m {x}", Default::default())
+        .unwrap();
+    let res = tx.run_script("?[x] := *_m{x}", Default::default()).unwrap();
+    assert_eq!(res.rows.len(), 1);
+    tx.commit().unwrap();
+}
+
+#[test]
+fn savepoints() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(":create a {x}", Default::default()).unwrap();
+    let tx = db.multi_transaction(true);
+    let put = |x: i64| {
+        tx.run_script(&format!("?[x] <- [[{x}]] :put a {{x}}"), Default::default())
+            .unwrap();
+    };
+    let read = || {
+        tx.run_script("?[x] := *a{x}", Default::default())
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| row[0].get_int().unwrap())
+            .collect_vec()
+    };
+    put(1);
+    tx.savepoint("s1").unwrap();
+    put(2);
+    tx.run_script(":create _t {x}", Default::default()).unwrap();
+    tx.savepoint("s2").unwrap();
+    put(3);
+    assert_eq!(read(), vec![1, 2, 3]);
+
+    tx.rollback_to("s1").unwrap();
+    assert_eq!(read(), vec![1]);
+    assert!(tx.run_script("?[x] := *_t{x}", Default::default()).is_err());
+    // the savepoint is kept, but not those after it
+    assert!(tx.rollback_to("s2").is_err());
+    put(4);
+    tx.rollback_to("s1").unwrap();
+    put(5);
+    tx.release("s1").unwrap();
+    let err = tx.rollback_to("s1").unwrap_err();
+    assert!(format!("{err:?}").contains("tx::no_such_savepoint"));
+    tx.commit().unwrap();
+
+    let res = db.run_script("?[x] := *a{x}", Default::default()).unwrap();
+    assert_eq!(
+        res.rows,
+        vec![vec![DataValue::from(1)], vec![DataValue::from(5)]]
+    );
+}
+
+#[test]
+fn retry_on_conflict() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(":create a {x}", Default::default()).unwrap();
+    // fails with a conflict until called the given number of times
+    let calls = Arc::new(AtomicUsize::new(0));
+    let fail_until = Arc::new(AtomicUsize::new(0));
+    let (c, f) = (calls.clone(), fail_until.clone());
+    db.register_fixed_rule(
+        "Flaky".to_string(),
+        SimpleFixedRule::new(1, move |_, _| {
+            let n = c.fetch_add(1, Ordering::SeqCst) + 1;
+            if n <= f.load(Ordering::SeqCst) {
+                return Err(TransactionConflict("simulated".to_string()).into());
+            }
+            Ok(NamedRows::new(
+                vec![],
+                vec![vec![DataValue::from(n as i64)]],
+            ))
+        }),
+    )
+    .unwrap();
+    let run = |script: &str, failures: usize| {
+        calls.store(0, Ordering::SeqCst);
+        fail_until.store(failures, Ordering::SeqCst);
+        db.run_script(script, Default::default())
+    };
+
+    let err = run("?[x] <~ Flaky() :put a {x}", 1).unwrap_err();
+    assert!(format!("{err:?}").contains("tx::conflict"));
+    run("?[x] <~ Flaky() :put a {x} :retry 2", 2).unwrap();
+    assert!(run("?[x] <~ Flaky() :put a {x} :retry 1", 2).is_err());
+
+    db.set_conflict_retries(Some(3));
+    run("?[x] <~ Flaky() :put a {x}", 3).unwrap();
+    run("{?[x] <~ Flaky() :put a {x}}", 2).unwrap();
+    assert!(run("?[x] <~ Flaky() :put a {x} :retry 0", 1).is_err());
+
+    let res = db.run_script("?[x] := *a{x}", Default::default()).unwrap();
+    assert_eq!(
+        res.rows,
+        vec![vec![DataValue::from(3)], vec![DataValue::from(4)]]
+    );
+
+    // imperative scripts retry as many times as the largest `:retry` of their queries,
+    // including those nested in blocks
+    db.set_conflict_retries(None);
+    assert!(run("{?[x] <- [[1]]} {?[x] <~ Flaky() :put a {x}}", 1).is_err());
+    run(
+        r"
+        {?[x] <- [[1]] :retry 1}
+        %if {?[x] <- [[1]]} %then {?[x] <~ Flaky() :put a {x} :retry 3} %end
+        ",
+        3,
+    )
+    .unwrap();
+    let looped = r"
+        {?[x] <- [[1]] :replace _rows {x}}
+        %for row in _rows { ?[x] <~ Flaky() :put a {x} :retry 2 }
+    ";
+    run(looped, 2).unwrap();
+    assert!(run(looped, 3).is_err());
+}
+
+#[test]
+fn sqlite_busy_is_conflict() {
+    let path = std::env::temp_dir().join(format!("cozo-busy-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let db = DbInstance::new("sqlite", path, "").unwrap();
+    db.run_script(":create a {x}", Default::default()).unwrap();
+    let other = DbInstance::new("sqlite", path, "").unwrap();
+
+    let tx = db.multi_transaction(true);
+    tx.run_script("?[x] <- [[1]] :put a {x}", Default::default())
+        .unwrap();
+    let err = other
+        .run_script("?[x] <- [[2]] :put a {x}", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("tx::conflict"));
+    tx.commit().unwrap();
+    other
+        .run_script("?[x] <- [[2]] :put a {x}", Default::default())
+        .unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(feature = "storage-rocksdb")]
+#[test]
+fn concurrent_constraint_checks_conflict() {
+    let path = std::env::temp_dir().join(format!("cozo-constraints-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let db = DbInstance::new("rocksdb", path.to_str().unwrap(), "").unwrap();
+    db.run_script(
+        r"
+        {:create users {id: Int => email: String unique}}
+        {:create items {id: Int => owner: Int references users}}
+        {?[id, email] <- [[1, 'a']] :put users {id => email}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    // the second writer of the same unique value conflicts instead of passing the check
+    let tx = db.multi_transaction(true);
+    tx.run_script(
+        "?[id, email] <- [[2, 'b']] :put users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    let err = db
+        .run_script(
+            "?[id, email] <- [[3, 'b']] :put users {id => email}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("tx::conflict"));
+    tx.commit().unwrap();
+
+    // a key cannot be removed while a row referencing it is being written
+    let tx = db.multi_transaction(true);
+    tx.run_script(
+        "?[id, owner] <- [[1, 1]] :put items {id => owner}",
+        Default::default(),
+    )
+    .unwrap();
+    let err = db
+        .run_script("?[id] <- [[1]] :rm users {id}", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("tx::conflict"));
+    tx.commit().unwrap();
+    let err = db
+        .run_script("?[id] <- [[1]] :rm users {id}", Default::default())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("eval::foreign_key_still_referenced"));
+    let _ = std::fs::remove_dir_all(&path);
+}
@@ -6,15 +6,19 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::sync::atomic::{AtomicU32, AtomicU64};
-use std::sync::Arc;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-use miette::{bail, Result};
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
 
 use crate::data::tuple::TupleT;
 use crate::data::value::DataValue;
 use crate::fts::TokenizerCache;
 use crate::runtime::relation::RelationId;
+use crate::runtime::result_cache::ResultCache;
 use crate::storage::temp::TempTx;
 use crate::storage::StoreTx;
 
@@ -24,8 +28,28 @@ pub struct SessionTx<'a> {
     pub(crate) relation_store_id: Arc<AtomicU64>,
     pub(crate) temp_store_id: AtomicU32,
     pub(crate) tokenizers: Arc<TokenizerCache>,
+    /// number of tuples a temp store holds in memory before spilling, zero for never
+    pub(crate) temp_spill_threshold: usize,
+    /// stored relations written, for invalidating cached results on commit
+    pub(crate) written_relations: BTreeSet<SmartString<LazyCompact>>,
+    /// only present for write transactions
+    pub(crate) result_cache: Option<Arc<Mutex<ResultCache>>>,
+    /// savepoints, innermost last
+    pub(crate) savepoints: Vec<Savepoint>,
 }
 
+/// A savepoint, with the state of the transaction not kept in the storage
+pub(crate) struct Savepoint {
+    name: SmartString<LazyCompact>,
+    temp_store_tx: TempTx,
+    temp_store_id: u32,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("No savepoint named '{0}' in the transaction")]
+#[diagnostic(code(tx::no_such_savepoint))]
+struct NoSuchSavepoint(String);
+
 pub const CURRENT_STORAGE_VERSION: [u8; 1] = [0x00];
 
 fn storage_version_key() -> Vec<u8> {
@@ -69,8 +93,57 @@ impl<'a> SessionTx<'a> {
         Ok(ret)
     }
 
+    /// Marks a savepoint. A savepoint with the same name as an earlier one shadows it.
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.store_tx.set_savepoint()?;
+        self.savepoints.push(Savepoint {
+            name: SmartString::from(name),
+            temp_store_tx: self.temp_store_tx.clone(),
+            temp_store_id: self.temp_store_id.load(Ordering::Relaxed),
+        });
+        Ok(())
+    }
+
+    /// Discards all changes made since the savepoint, and removes the savepoints after it.
+    /// The savepoint itself is kept. Returns its position among the savepoints.
+    pub fn rollback_to(&mut self, name: &str) -> Result<usize> {
+        let idx = self.find_savepoint(name)?;
+        while self.savepoints.len() > idx + 1 {
+            self.store_tx.pop_savepoint()?;
+            self.savepoints.pop();
+        }
+        self.store_tx.rollback_to_savepoint()?;
+        self.store_tx.set_savepoint()?;
+        let savepoint = &self.savepoints[idx];
+        self.temp_store_tx = savepoint.temp_store_tx.clone();
+        self.temp_store_id
+            .store(savepoint.temp_store_id, Ordering::Relaxed);
+        Ok(idx)
+    }
+
+    /// Removes the savepoint and those after it, keeping all changes.
+    /// Returns its position among the savepoints.
+    pub fn release(&mut self, name: &str) -> Result<usize> {
+        let idx = self.find_savepoint(name)?;
+        while self.savepoints.len() > idx {
+            self.store_tx.pop_savepoint()?;
+            self.savepoints.pop();
+        }
+        Ok(idx)
+    }
+
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        match self.savepoints.iter().rposition(|sp| sp.name == name) {
+            None => bail!(NoSuchSavepoint(name.to_string())),
+            Some(idx) => Ok(idx),
+        }
+    }
+
     pub fn commit_tx(&mut self) -> Result<()> {
         self.store_tx.commit()?;
+        if let Some(cache) = &self.result_cache {
+            cache.lock().unwrap().invalidate(&self.written_relations);
+        }
         Ok(())
     }
 }
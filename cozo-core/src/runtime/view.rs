@@ -0,0 +1,740 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// A materialized view is a stored relation holding the result of a query,
+// with every output column being part of the key. The relations read by the
+// query record the view as dependent, and every write to them brings the view
+// up to date within the same transaction. Where the query allows it, only the
+// consequences of the written rows are computed, by the delta programs of
+// `query::delta`, using derivation counts or the tuples of recursive rules kept
+// in relations named after the view. Otherwise the query is evaluated again,
+// and only the difference from the stored rows is written. Either way callbacks
+// only see the actual changes, which are passed on in turn to the views
+// defined on top of the view.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::program::InputProgram;
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::parse_script;
+use crate::query::delta::{
+    counting_delta_program, counting_program, plan_view, rederive_delta_program, rederive_program,
+    rederive_rule_names, CountingOutputs, FlatRule, ViewPlan,
+};
+use crate::runtime::callback::{CallbackCollector, CallbackOp};
+use crate::runtime::db::Poison;
+use crate::runtime::relation::{InputRelationHandle, RelationHandle};
+use crate::runtime::result_cache::mentions_volatile;
+use crate::runtime::transact::SessionTx;
+use crate::storage::Storage;
+use crate::{Db, NamedRows, SourceSpan};
+
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct ViewManifest {
+    /// source of the defining query
+    pub(crate) query: String,
+    /// stored relations read by the query
+    pub(crate) bases: Vec<SmartString<LazyCompact>>,
+    /// how the view is brought up to date
+    #[serde(default)]
+    pub(crate) maintenance: ViewMaintenance,
+}
+
+#[derive(
+    Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub(crate) enum ViewMaintenance {
+    /// the query is evaluated again
+    #[default]
+    Recompute,
+    /// the relation keeps the number of derivations of each row
+    Counting(SmartString<LazyCompact>),
+    /// the relations keep the tuples of each recursive rule
+    Rederive(BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>>),
+}
+
+impl ViewMaintenance {
+    /// The relations kept for maintaining the view
+    pub(crate) fn kept_relations(&self) -> Vec<SmartString<LazyCompact>> {
+        match self {
+            ViewMaintenance::Recompute => vec![],
+            ViewMaintenance::Counting(counts) => vec![counts.clone()],
+            ViewMaintenance::Rederive(kept) => kept.values().cloned().collect(),
+        }
+    }
+}
+
+/// Rows of a relation changed by a write, for bringing the views on it up to date
+#[derive(Default)]
+pub(crate) struct RowChanges {
+    /// the row before the first and after the last write of each key
+    rows: BTreeMap<Tuple, (Option<Tuple>, Option<Tuple>)>,
+}
+
+impl RowChanges {
+    pub(crate) fn record(
+        &mut self,
+        n_keys: usize,
+        before: Option<&[DataValue]>,
+        after: Option<&[DataValue]>,
+    ) {
+        let key = match before.or(after) {
+            None => return,
+            Some(row) => row[..n_keys].to_vec(),
+        };
+        self.rows
+            .entry(key)
+            .or_insert_with(|| (before.map(|row| row.to_vec()), None))
+            .1 = after.map(|row| row.to_vec());
+    }
+    /// Returns the removed rows and the added rows
+    pub(crate) fn into_rows(self) -> (Vec<Tuple>, Vec<Tuple>) {
+        let mut removed = vec![];
+        let mut added = vec![];
+        for (before, after) in self.rows.into_values() {
+            if before != after {
+                removed.extend(before);
+                added.extend(after);
+            }
+        }
+        (removed, added)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("cannot write into materialized view {0}")]
+#[diagnostic(code(eval::write_to_view))]
+#[diagnostic(help("Views are maintained from the relations they are defined on"))]
+pub(crate) struct WriteToView(pub(crate) String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("relation {0} is used by materialized view {1}")]
+#[diagnostic(code(eval::relation_used_by_view))]
+pub(crate) struct RelationUsedByView(pub(crate) String, pub(crate) String);
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &Symbol,
+        query: &str,
+        cur_vld: ValidityTs,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("materialized view {0} cannot read from temp relation {1}")]
+        #[diagnostic(code(eval::view_on_temp_rel))]
+        struct ViewOnTempRelation(String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("columns of materialized view {0} must have distinct names, found {1} twice")]
+        #[diagnostic(code(eval::view_dup_col))]
+        #[diagnostic(help("Bind the output columns to distinct variables"))]
+        struct DuplicateViewColumn(String, String, #[label] SourceSpan);
+
+        if name.is_temp_store_name() {
+            bail!(
+                "Materialized view `{}` cannot be a temp relation",
+                name.name
+            )
+        }
+
+        let program = self.parse_view_query(db, query, cur_vld)?;
+        let bases = program.stored_relations_read();
+        if let Some(base) = bases.iter().find(|b| b.starts_with('_')) {
+            bail!(ViewOnTempRelation(
+                name.name.to_string(),
+                base.to_string(),
+                name.span
+            ))
+        }
+        let head = program.get_entry_out_head_or_default()?;
+        let mut seen = BTreeSet::new();
+        for symb in &head {
+            symb.ensure_valid_field()?;
+            if !seen.insert(&symb.name) {
+                bail!(DuplicateViewColumn(
+                    name.name.to_string(),
+                    symb.name.to_string(),
+                    name.span
+                ))
+            }
+        }
+
+        let plan = self.plan_view_query(query, &program)?;
+        let mut cleanups = vec![];
+        let (rows, maintenance) = self.materialize_view(
+            db,
+            &name.name,
+            program,
+            plan,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            &mut cleanups,
+        )?;
+
+        let metadata = StoredRelationMetadata {
+            keys: head
+                .iter()
+                .map(|s| ColumnDef {
+                    name: s.name.clone(),
+                    typing: NullableColType {
+                        coltype: ColType::Any,
+                        nullable: true,
+                    },
+                    default_gen: None,
+                })
+                .collect(),
+            non_keys: vec![],
+        };
+        let mut handle = self.create_relation(InputRelationHandle {
+            name: name.clone(),
+            metadata,
+            key_bindings: head,
+            dep_bindings: vec![],
+            span: name.span,
+            constraints: Default::default(),
+        })?;
+        for row in &rows {
+            let key = handle.encode_key_for_store(row, name.span)?;
+            let val = handle.encode_val_for_store(row, name.span)?;
+            self.store_tx.put(&key, &val)?;
+        }
+        for base in &bases {
+            let mut base_handle = self.get_relation(base, true)?;
+            base_handle.dependent_views.push(name.name.clone());
+            self.put_relation_meta(&base_handle)?;
+        }
+        handle.view = Some(ViewManifest {
+            query: query.to_string(),
+            bases: bases.into_iter().collect(),
+            maintenance,
+        });
+        self.put_relation_meta(&handle)?;
+        Ok(cleanups)
+    }
+
+    /// Parses the query of a view, with the stored rules it applies inlined
+    fn parse_view_query<'s, S: Storage<'s>>(
+        &self,
+        db: &Db<S>,
+        query: &str,
+        cur_vld: ValidityTs,
+    ) -> Result<InputProgram> {
+        let mut program = parse_script(
+            query,
+            &Default::default(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )?
+        .get_single_program()?;
+        program.inline_stored_rules(self, cur_vld)?;
+        Ok(program)
+    }
+
+    /// Returns how the view can be maintained incrementally, if it can
+    fn plan_view_query(&self, query: &str, program: &InputProgram) -> Result<Option<ViewPlan>> {
+        // volatile functions give other rows at each evaluation, so their derivations cannot be tracked
+        if mentions_volatile(query) {
+            return Ok(None);
+        }
+        for name in program.prog.keys() {
+            if let Some(stored) = self.get_stored_rule(&name.name)? {
+                if mentions_volatile(&stored.src) {
+                    return Ok(None);
+                }
+            }
+        }
+        plan_view(self, program.clone())
+    }
+
+    /// Evaluates the query of the view in full, creating the relations kept for maintaining
+    /// the view. Returns the rows of the view.
+    fn materialize_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        view: &str,
+        program: InputProgram,
+        plan: Option<ViewPlan>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(Vec<Tuple>, ViewMaintenance)> {
+        let poison = db.query_poison(&program.out_opts)?;
+        match plan {
+            None => {
+                let (res, cleanups) = db.run_query(
+                    self,
+                    program,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    false,
+                )?;
+                to_clear.extend(cleanups);
+                Ok((res.rows, ViewMaintenance::Recompute))
+            }
+            Some(ViewPlan::Counting(rules)) => {
+                let (prog, outputs) = counting_program(&rules)?;
+                let counts = self.count_derivations(prog, &outputs, poison)?;
+                let arity = program.get_entry_arity()?;
+                let handle = self.create_kept_relation(view, "counts", arity, true)?;
+                for (row, count) in &counts {
+                    let mut kv = row.clone();
+                    kv.push(DataValue::from(*count));
+                    let key = handle.encode_key_for_store(&kv, Default::default())?;
+                    let val = handle.encode_val_for_store(&kv, Default::default())?;
+                    self.store_tx.put(&key, &val)?;
+                }
+                Ok((
+                    counts.into_keys().collect(),
+                    ViewMaintenance::Counting(handle.name),
+                ))
+            }
+            Some(ViewPlan::Rederive { recursive, entry }) => {
+                let mut tuples =
+                    self.evaluate_view_program(rederive_program(&recursive, &entry), poison)?;
+                let mut kept = BTreeMap::new();
+                for (name, rules) in &recursive {
+                    let handle =
+                        self.create_kept_relation(view, &name.name, rules[0].head.len(), false)?;
+                    for tuple in tuples.remove(name).unwrap_or_default() {
+                        let key = handle.encode_key_for_store(&tuple, Default::default())?;
+                        self.store_tx.put(&key, &[])?;
+                    }
+                    kept.insert(name.name.clone(), handle.name);
+                }
+                let entry_name = Symbol::new(PROG_ENTRY, Default::default());
+                let rows = tuples.remove(&entry_name).unwrap_or_default();
+                Ok((rows, ViewMaintenance::Rederive(kept)))
+            }
+        }
+    }
+
+    /// Creates the relation `view:suffix`, with `arity` keys and possibly a count
+    fn create_kept_relation(
+        &mut self,
+        view: &str,
+        suffix: &str,
+        arity: usize,
+        counted: bool,
+    ) -> Result<RelationHandle> {
+        let column = |name: String, coltype: ColType| ColumnDef {
+            name: name.into(),
+            typing: NullableColType {
+                coltype,
+                nullable: true,
+            },
+            default_gen: None,
+        };
+        let keys = (0..arity)
+            .map(|i| column(format!("c{i}"), ColType::Any))
+            .collect_vec();
+        let non_keys = if counted {
+            vec![column("count".to_string(), ColType::Int)]
+        } else {
+            vec![]
+        };
+        let to_bindings = |cols: &[ColumnDef]| {
+            cols.iter()
+                .map(|c| Symbol::new(c.name.clone(), Default::default()))
+                .collect_vec()
+        };
+        self.create_relation(InputRelationHandle {
+            name: Symbol::new(format!("{view}:{suffix}"), Default::default()),
+            key_bindings: to_bindings(&keys),
+            dep_bindings: to_bindings(&non_keys),
+            metadata: StoredRelationMetadata { keys, non_keys },
+            span: Default::default(),
+            constraints: Default::default(),
+        })
+    }
+
+    /// Sums the signs of the derivations of each row
+    fn count_derivations(
+        &mut self,
+        prog: crate::data::program::NormalFormProgram,
+        outputs: &CountingOutputs,
+        poison: Poison,
+    ) -> Result<BTreeMap<Tuple, i64>> {
+        let mut tuples = self.evaluate_view_program(prog, poison)?;
+        let mut counts = BTreeMap::new();
+        for (name, sign, positions) in outputs {
+            for derivation in tuples.remove(name).unwrap_or_default() {
+                let row = positions
+                    .iter()
+                    .map(|i| derivation[*i].clone())
+                    .collect_vec();
+                *counts.entry(row).or_default() += sign;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Brings the views on the relation up to date with the changes of a write
+    pub(crate) fn refresh_dependent_views<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        relation: &RelationHandle,
+        changes: RowChanges,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let (removed, added) = changes.into_rows();
+        if removed.is_empty() && added.is_empty() {
+            return Ok(());
+        }
+        for view in &relation.dependent_views {
+            self.refresh_view(
+                db,
+                view,
+                &relation.name,
+                &removed,
+                &added,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                to_clear,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Brings the view up to date with a write to the relation `written`,
+    /// where `removed` and `added` are the rows that changed
+    pub(crate) fn refresh_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &str,
+        written: &str,
+        removed: &[Tuple],
+        added: &[Tuple],
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let handle = self.get_relation(name, false)?;
+        let manifest = match &handle.view {
+            None => return Ok(()),
+            Some(manifest) => manifest,
+        };
+        let (removed, added) = self
+            .apply_view_changes(
+                db,
+                &handle,
+                written,
+                removed,
+                added,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                to_clear,
+            )
+            .map_err(|err| {
+                if err.source_code().is_some() {
+                    err
+                } else {
+                    err.with_source_code(manifest.query.to_string())
+                }
+            })?;
+        if removed.is_empty() && added.is_empty() {
+            return Ok(());
+        }
+        self.written_relations.insert(handle.name.clone());
+
+        if callback_targets.contains(&handle.name) {
+            let headers = handle
+                .metadata
+                .keys
+                .iter()
+                .map(|c| c.name.to_string())
+                .collect_vec();
+            let target_collector = callback_collector.entry(handle.name.clone()).or_default();
+            if !removed.is_empty() {
+                target_collector.push((
+                    CallbackOp::Rm,
+                    NamedRows::new(headers.clone(), removed.clone()),
+                    NamedRows::new(headers.clone(), removed.clone()),
+                ));
+            }
+            if !added.is_empty() {
+                target_collector.push((
+                    CallbackOp::Put,
+                    NamedRows::new(headers.clone(), added.clone()),
+                    NamedRows::new(headers, vec![]),
+                ));
+            }
+        }
+
+        for view in &handle.dependent_views {
+            self.refresh_view(
+                db,
+                view,
+                &handle.name,
+                &removed,
+                &added,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                to_clear,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the changes of the view caused by a write, returning the removed and added rows
+    fn apply_view_changes<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        view: &RelationHandle,
+        written: &str,
+        removed: &[Tuple],
+        added: &[Tuple],
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(Vec<Tuple>, Vec<Tuple>)> {
+        let manifest = view.view.as_ref().unwrap();
+        let program = self.parse_view_query(db, &manifest.query, cur_vld)?;
+        let plan = self.plan_view_query(&manifest.query, &program)?;
+        let arity = self.get_relation(written, false)?.arity();
+        let poison = db.query_poison(&program.out_opts)?;
+        let changes = match (&manifest.maintenance, &plan) {
+            (ViewMaintenance::Counting(counts), Some(ViewPlan::Counting(rules))) => {
+                self.count_changes(counts, rules, written, arity, removed, added, poison)?
+            }
+            (ViewMaintenance::Rederive(kept), Some(ViewPlan::Rederive { recursive, entry }))
+                if kept.keys().eq(recursive.keys().map(|name| &name.name)) =>
+            {
+                Some(self.rederive_changes(
+                    view, kept, recursive, entry, written, arity, removed, added, poison,
+                )?)
+            }
+            _ => None,
+        };
+        let (removed, added) = match changes {
+            Some(changes) => changes,
+            // the stored rules the view applies have changed since the view was created
+            None => {
+                return self.rebuild_view(
+                    db,
+                    view,
+                    program,
+                    plan,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    to_clear,
+                )
+            }
+        };
+        for row in &removed {
+            self.store_tx
+                .del(&view.encode_key_for_store(row, Default::default())?)?;
+        }
+        for row in &added {
+            let key = view.encode_key_for_store(row, Default::default())?;
+            let val = view.encode_val_for_store(row, Default::default())?;
+            self.store_tx.put(&key, &val)?;
+        }
+        Ok((removed, added))
+    }
+
+    /// Updates the derivation counts, returning the rows that lost all their derivations
+    /// and the rows that gained their first ones, or `None` if the counts are inconsistent
+    fn count_changes(
+        &mut self,
+        counts: &str,
+        rules: &[FlatRule],
+        written: &str,
+        arity: usize,
+        removed: &[Tuple],
+        added: &[Tuple],
+        poison: Poison,
+    ) -> Result<Option<(Vec<Tuple>, Vec<Tuple>)>> {
+        let (prog, outputs) = counting_delta_program(rules, written, arity, removed, added)?;
+        let deltas = self.count_derivations(prog, &outputs, poison)?;
+        let counts = self.get_relation(counts, false)?;
+        let n_keys = counts.metadata.keys.len();
+        let mut updated = Vec::with_capacity(deltas.len());
+        for (row, delta) in deltas {
+            if delta == 0 {
+                continue;
+            }
+            let before = match counts.get(self, &row)? {
+                None => 0,
+                Some(kv) => kv[n_keys].get_int().unwrap_or_default(),
+            };
+            let after = before + delta;
+            if after < 0 {
+                return Ok(None);
+            }
+            updated.push((row, before, after));
+        }
+        let mut removed_rows = vec![];
+        let mut added_rows = vec![];
+        for (mut row, before, after) in updated {
+            let key = counts.encode_key_for_store(&row, Default::default())?;
+            if after == 0 {
+                self.store_tx.del(&key)?;
+                removed_rows.push(row);
+            } else {
+                row.push(DataValue::from(after));
+                let val = counts.encode_val_for_store(&row, Default::default())?;
+                self.store_tx.put(&key, &val)?;
+                row.pop();
+                if before == 0 {
+                    added_rows.push(row);
+                }
+            }
+        }
+        Ok(Some((removed_rows, added_rows)))
+    }
+
+    /// Deletes, rederives and inserts the tuples of the recursive rules, returning
+    /// the removed and the added rows of the view
+    fn rederive_changes(
+        &mut self,
+        view: &RelationHandle,
+        kept: &BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>>,
+        recursive: &BTreeMap<Symbol, Vec<FlatRule>>,
+        entry: &[FlatRule],
+        written: &str,
+        arity: usize,
+        removed: &[Tuple],
+        added: &[Tuple],
+        poison: Poison,
+    ) -> Result<(Vec<Tuple>, Vec<Tuple>)> {
+        let kept_by_rule = recursive
+            .keys()
+            .map(|name| (name.clone(), kept[&name.name].clone()))
+            .collect();
+        let (prog, affected) = rederive_delta_program(
+            recursive,
+            entry,
+            &kept_by_rule,
+            written,
+            arity,
+            removed,
+            added,
+        );
+        let mut tuples = self.evaluate_view_program(prog, poison)?;
+        let mut view_changes = (vec![], vec![]);
+        for name in affected {
+            let [deleted, rederived, inserted] =
+                rederive_rule_names(&name).map(|rule| -> BTreeSet<Tuple> {
+                    tuples
+                        .remove(&rule)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .collect()
+                });
+            let handle = if name.is_prog_entry() {
+                view.clone()
+            } else {
+                self.get_relation(&kept[&name.name], false)?
+            };
+            let removed_tuples = deleted
+                .iter()
+                .filter(|t| !rederived.contains(*t) && !inserted.contains(*t))
+                .cloned()
+                .collect_vec();
+            let mut added_tuples = vec![];
+            for tuple in inserted {
+                if !deleted.contains(&tuple) && !handle.exists(self, &tuple)? {
+                    added_tuples.push(tuple);
+                }
+            }
+            if name.is_prog_entry() {
+                view_changes = (removed_tuples, added_tuples);
+            } else {
+                for tuple in &removed_tuples {
+                    self.store_tx
+                        .del(&handle.encode_key_for_store(tuple, Default::default())?)?;
+                }
+                for tuple in &added_tuples {
+                    let key = handle.encode_key_for_store(tuple, Default::default())?;
+                    self.store_tx.put(&key, &[])?;
+                }
+            }
+        }
+        Ok(view_changes)
+    }
+
+    /// Evaluates the query of the view in full and writes the difference from the stored rows,
+    /// creating anew the relations kept for maintaining the view
+    fn rebuild_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        view: &RelationHandle,
+        program: InputProgram,
+        plan: Option<ViewPlan>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(Vec<Tuple>, Vec<Tuple>)> {
+        let mut view = view.clone();
+        let manifest = view.view.as_mut().unwrap();
+        for kept in manifest.maintenance.kept_relations() {
+            to_clear.extend(self.destroy_relation(&kept)?);
+        }
+        let (rows, maintenance) = self.materialize_view(
+            db,
+            &view.name,
+            program,
+            plan,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            to_clear,
+        )?;
+        manifest.maintenance = maintenance;
+
+        // rows are compared by their encoded keys, as all columns of a view are keys
+        let mut new_rows = BTreeMap::new();
+        for row in rows {
+            new_rows.insert(view.encode_key_for_store(&row, Default::default())?, row);
+        }
+        let mut old_rows = BTreeMap::new();
+        for row in view.scan_all(self) {
+            let row = row?;
+            old_rows.insert(view.encode_key_for_store(&row, Default::default())?, row);
+        }
+        let mut removed = vec![];
+        for (key, row) in &old_rows {
+            if !new_rows.contains_key(key) {
+                self.store_tx.del(key)?;
+                removed.push(row.clone());
+            }
+        }
+        let mut added = vec![];
+        for (key, row) in &new_rows {
+            if !old_rows.contains_key(key) {
+                let val = view.encode_val_for_store(row, Default::default())?;
+                self.store_tx.put(key, &val)?;
+                added.push(row.clone());
+            }
+        }
+        self.put_relation_meta(&view)?;
+        Ok((removed, added))
+    }
+}
@@ -51,7 +51,7 @@ impl<'s> Storage<'s> for MemStorage {
     fn transact(&'s self, write: bool) -> Result<Self::Tx> {
         Ok(if write {
             let wtr = self.store.write().unwrap();
-            MemTx::Writer(wtr, Default::default())
+            MemTx::Writer(wtr, Default::default(), vec![])
         } else {
             let rdr = self.store.read().unwrap();
             MemTx::Reader(rdr)
@@ -80,6 +80,8 @@ pub enum MemTx<'s> {
     Writer(
         ShardedLockWriteGuard<'s, BTreeMap<Vec<u8>, Vec<u8>>>,
         BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        // the changes at each savepoint
+        Vec<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
     ),
 }
 
@@ -87,7 +89,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
     fn get(&self, key: &[u8], _for_update: bool) -> Result<Option<Vec<u8>>> {
         Ok(match self {
             MemTx::Reader(rdr) => rdr.get(key).cloned(),
-            MemTx::Writer(wtr, cache) => match cache.get(key) {
+            MemTx::Writer(wtr, cache, _) => match cache.get(key) {
                 Some(r) => r.clone(),
                 None => wtr.get(key).cloned(),
             },
@@ -99,7 +101,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
             MemTx::Reader(_) => {
                 bail!("write in read transaction")
             }
-            MemTx::Writer(_, cache) => {
+            MemTx::Writer(_, cache, _) => {
                 cache.insert(key.to_vec(), Some(val.to_vec()));
                 Ok(())
             }
@@ -119,7 +121,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
             MemTx::Reader(_) => {
                 bail!("write in read transaction")
             }
-            MemTx::Writer(_, cache) => {
+            MemTx::Writer(_, cache, _) => {
                 cache.insert(key.to_vec(), None);
                 Ok(())
             }
@@ -131,7 +133,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
             MemTx::Reader(_) => {
                 bail!("write in read transaction")
             }
-            MemTx::Writer(ref mut wtr, _) => {
+            MemTx::Writer(ref mut wtr, _, _) => {
                 let keys = wtr
                     .range(lower.to_vec()..upper.to_vec())
                     .map(|kv| kv.0.clone())
@@ -148,7 +150,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
     fn exists(&self, key: &[u8], _for_update: bool) -> Result<bool> {
         Ok(match self {
             MemTx::Reader(rdr) => rdr.contains_key(key),
-            MemTx::Writer(wtr, cache) => match cache.get(key) {
+            MemTx::Writer(wtr, cache, _) => match cache.get(key) {
                 Some(r) => r.is_some(),
                 None => wtr.contains_key(key),
             },
@@ -158,7 +160,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
     fn commit(&mut self) -> Result<()> {
         match self {
             MemTx::Reader(_) => Ok(()),
-            MemTx::Writer(wtr, cached) => {
+            MemTx::Writer(wtr, cached, _) => {
                 let mut cache = BTreeMap::default();
                 mem::swap(&mut cache, cached);
                 for (k, mv) in cache {
@@ -176,6 +178,45 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
         }
     }
 
+    fn set_savepoint(&mut self) -> Result<()> {
+        match self {
+            MemTx::Reader(_) => {
+                bail!("savepoint in read transaction")
+            }
+            MemTx::Writer(_, cache, saved) => {
+                saved.push(cache.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<()> {
+        match self {
+            MemTx::Reader(_) => {
+                bail!("savepoint in read transaction")
+            }
+            MemTx::Writer(_, cache, saved) => match saved.pop() {
+                None => bail!("no savepoint to roll back to"),
+                Some(prev) => {
+                    *cache = prev;
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    fn pop_savepoint(&mut self) -> Result<()> {
+        match self {
+            MemTx::Reader(_) => {
+                bail!("savepoint in read transaction")
+            }
+            MemTx::Writer(_, _, saved) => match saved.pop() {
+                None => bail!("no savepoint to remove"),
+                Some(_) => Ok(()),
+            },
+        }
+    }
+
     fn range_scan_tuple<'a>(
         &'a self,
         lower: &[u8],
@@ -189,7 +230,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
                 rdr.range(lower.to_vec()..upper.to_vec())
                     .map(|(k, v)| Ok(decode_tuple_from_kv(k, v, None))),
             ),
-            MemTx::Writer(wtr, cache) => Box::new(CacheIter {
+            MemTx::Writer(wtr, cache, _) => Box::new(CacheIter {
                 change_iter: cache.range(lower.to_vec()..upper.to_vec()).fuse(),
                 db_iter: wtr.range(lower.to_vec()..upper.to_vec()).fuse(),
                 change_cache: None,
@@ -215,7 +256,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
                 }
                 .map(Ok),
             ),
-            MemTx::Writer(stored, delta) => Box::new(
+            MemTx::Writer(stored, delta, _) => Box::new(
                 SkipDualIterator {
                     stored,
                     delta,
@@ -241,7 +282,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
                 rdr.range(lower.to_vec()..upper.to_vec())
                     .map(|(k, v)| Ok((k.clone(), v.clone()))),
             ),
-            MemTx::Writer(wtr, cache) => Box::new(CacheIterRaw {
+            MemTx::Writer(wtr, cache, _) => Box::new(CacheIterRaw {
                 change_iter: cache.range(lower.to_vec()..upper.to_vec()).fuse(),
                 db_iter: wtr.range(lower.to_vec()..upper.to_vec()).fuse(),
                 change_cache: None,
@@ -256,7 +297,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
     {
         Ok(match self {
             MemTx::Reader(rdr) => rdr.range(lower.to_vec()..upper.to_vec()).count(),
-            MemTx::Writer(wtr, cache) => (CacheIterRaw {
+            MemTx::Writer(wtr, cache, _) => (CacheIterRaw {
                 change_iter: cache.range(lower.to_vec()..upper.to_vec()).fuse(),
                 db_iter: wtr.range(lower.to_vec()..upper.to_vec()).fuse(),
                 change_cache: None,
@@ -272,7 +313,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
     {
         match self {
             MemTx::Reader(rdr) => Box::new(rdr.iter().map(|(k, v)| Ok((k.clone(), v.clone())))),
-            MemTx::Writer(wtr, cache) => Box::new(CacheIterRaw {
+            MemTx::Writer(wtr, cache, _) => Box::new(CacheIterRaw {
                 change_iter: cache.iter().fuse(),
                 db_iter: wtr.iter().fuse(),
                 change_cache: None,
@@ -7,7 +7,8 @@
  */
 
 use itertools::Itertools;
-use miette::Result;
+use miette::{bail, Diagnostic, Report, Result};
+use thiserror::Error;
 
 use crate::data::tuple::Tuple;
 use crate::data::value::ValidityTs;
@@ -25,6 +26,19 @@ pub(crate) mod temp;
 pub(crate) mod tikv;
 // pub(crate) mod re;
 
+/// Raised by engines when a transaction cannot commit because of concurrent transactions.
+/// Retrying the transaction may succeed.
+#[derive(Debug, Error, Diagnostic)]
+#[error("Transaction conflicts with a concurrent one: {0}")]
+#[diagnostic(code(tx::conflict))]
+#[diagnostic(help("Retry the transaction, or use the `:retry` option to retry automatically"))]
+pub(crate) struct TransactionConflict(pub(crate) String);
+
+pub(crate) fn is_conflict(err: &Report) -> bool {
+    err.chain()
+        .any(|e| e.downcast_ref::<TransactionConflict>().is_some())
+}
+
 /// Swappable storage trait for Cozo's storage engine
 pub trait Storage<'s>: Send + Sync + Clone {
     /// The associated transaction type used by this engine
@@ -101,6 +115,22 @@ pub trait StoreTx<'s>: Sync {
     /// and discard all changes introduced by this transaction.
     fn commit(&mut self) -> Result<()>;
 
+    /// Mark a savepoint in a write transaction. Savepoints form a stack.
+    /// The default implementation returns an error, for engines without savepoints.
+    fn set_savepoint(&mut self) -> Result<()> {
+        bail!("savepoints are not supported by this storage engine")
+    }
+
+    /// Discard all changes made since the last savepoint, and remove the savepoint.
+    fn rollback_to_savepoint(&mut self) -> Result<()> {
+        bail!("savepoints are not supported by this storage engine")
+    }
+
+    /// Remove the last savepoint, keeping the changes made since.
+    fn pop_savepoint(&mut self) -> Result<()> {
+        bail!("savepoints are not supported by this storage engine")
+    }
+
     /// Scan on a range. `lower` is inclusive whereas `upper` is exclusive.
     /// The default implementation calls [`range_scan_owned`](Self::range_scan) and converts the results.
     ///
@@ -10,15 +10,15 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use log::info;
-use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use miette::{miette, IntoDiagnostic, Report, Result, WrapErr};
 
-use cozorocks::{DbBuilder, DbIter, RocksDb, Tx};
+use cozorocks::{DbBuilder, DbIter, RocksDb, RocksDbStatus, StatusCode, StatusSubCode, Tx};
 
 use crate::data::tuple::{check_key_for_validity, Tuple};
 use crate::data::value::ValidityTs;
 use crate::runtime::db::{BadDbInit, DbManifest};
 use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{Storage, StoreTx, TransactionConflict};
 use crate::utils::swap_option_result;
 use crate::Db;
 
@@ -150,6 +150,18 @@ impl Storage<'_> for RocksDbStorage {
     }
 }
 
+/// Reports lock timeouts and write conflicts as transaction conflicts
+fn tx_error(status: RocksDbStatus) -> Report {
+    let is_conflict = status.code == StatusCode::kBusy
+        || status.code == StatusCode::kTryAgain
+        || (status.code == StatusCode::kTimedOut && status.subcode == StatusSubCode::kLockTimeout);
+    if is_conflict {
+        Report::new(TransactionConflict(status.to_string()))
+    } else {
+        Report::new(status)
+    }
+}
+
 pub struct RocksDbTx {
     db_tx: Tx,
 }
@@ -159,12 +171,16 @@ unsafe impl Sync for RocksDbTx {}
 impl<'s> StoreTx<'s> for RocksDbTx {
     #[inline]
     fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>> {
-        Ok(self.db_tx.get(key, for_update)?.map(|v| v.to_vec()))
+        Ok(self
+            .db_tx
+            .get(key, for_update)
+            .map_err(tx_error)?
+            .map(|v| v.to_vec()))
     }
 
     #[inline]
     fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
-        Ok(self.db_tx.put(key, val)?)
+        self.db_tx.put(key, val).map_err(tx_error)
     }
 
     fn supports_par_put(&self) -> bool {
@@ -173,17 +189,17 @@ impl<'s> StoreTx<'s> for RocksDbTx {
 
     #[inline]
     fn par_put(&self, key: &[u8], val: &[u8]) -> Result<()> {
-        Ok(self.db_tx.put(key, val)?)
+        self.db_tx.put(key, val).map_err(tx_error)
     }
 
     #[inline]
     fn del(&mut self, key: &[u8]) -> Result<()> {
-        Ok(self.db_tx.del(key)?)
+        self.db_tx.del(key).map_err(tx_error)
     }
 
     #[inline]
     fn par_del(&self, key: &[u8]) -> Result<()> {
-        Ok(self.db_tx.del(key)?)
+        self.db_tx.del(key).map_err(tx_error)
     }
 
     fn del_range_from_persisted(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
@@ -201,11 +217,24 @@ impl<'s> StoreTx<'s> for RocksDbTx {
 
     #[inline]
     fn exists(&self, key: &[u8], for_update: bool) -> Result<bool> {
-        Ok(self.db_tx.exists(key, for_update)?)
+        self.db_tx.exists(key, for_update).map_err(tx_error)
     }
 
     fn commit(&mut self) -> Result<()> {
-        Ok(self.db_tx.commit()?)
+        self.db_tx.commit().map_err(tx_error)
+    }
+
+    fn set_savepoint(&mut self) -> Result<()> {
+        self.db_tx.save();
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<()> {
+        Ok(self.db_tx.rollback_to_save()?)
+    }
+
+    fn pop_savepoint(&mut self) -> Result<()> {
+        Ok(self.db_tx.pop_save()?)
     }
 
     fn range_scan_tuple<'a>(
@@ -12,15 +12,31 @@ use std::sync::{Arc, Mutex};
 use ::sqlite::Connection;
 use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
 use either::{Either, Left, Right};
-use miette::{bail, miette, IntoDiagnostic, Result};
+use miette::{bail, miette, IntoDiagnostic, Report, Result};
 use sqlite::{ConnectionWithFullMutex, State, Statement};
 
 use crate::data::tuple::{check_key_for_validity, Tuple};
 use crate::data::value::ValidityTs;
 use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{Storage, StoreTx, TransactionConflict};
 use crate::utils::swap_option_result;
 
+/// Primary result codes of a database locked by another connection
+/// and of a table locked within the same connection
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+
+/// Reports a busy or locked database as a transaction conflict
+fn tx_error(err: sqlite::Error) -> Report {
+    // extended result codes keep the primary code in the lowest byte
+    match err.code.map(|code| code & 0xff) {
+        Some(SQLITE_BUSY) | Some(SQLITE_LOCKED) => {
+            Report::new(TransactionConflict(err.to_string()))
+        }
+        _ => Report::msg(err),
+    }
+}
+
 /// The Sqlite storage engine
 #[derive(Clone)]
 pub struct SqliteStorage {
@@ -75,8 +91,8 @@ impl<'s> Storage<'s> for SqliteStorage {
             Left(self.lock.read().unwrap())
         };
         if write {
-            let mut stmt = conn.prepare("begin;").into_diagnostic()?;
-            while stmt.next().into_diagnostic()? != State::Done {}
+            let mut stmt = conn.prepare("begin;").map_err(tx_error)?;
+            while stmt.next().map_err(tx_error)? != State::Done {}
         }
         Ok(SqliteTx {
             lock,
@@ -89,6 +105,7 @@ impl<'s> Storage<'s> for SqliteStorage {
                 Mutex::new(None),
             ],
             committed: false,
+            savepoints: 0,
         })
     }
 
@@ -122,6 +139,7 @@ pub struct SqliteTx<'a> {
     conn: Option<ConnectionWithFullMutex>,
     stmts: [Mutex<Option<Statement<'a>>>; N_CACHED_QUERIES],
     committed: bool,
+    savepoints: usize,
 }
 
 unsafe impl Sync for SqliteTx<'_> {}
@@ -161,6 +179,12 @@ impl Drop for SqliteTx<'_> {
 }
 
 impl<'s> SqliteTx<'s> {
+    fn execute_in_write(&self, query: &str) -> Result<()> {
+        if let Left(ShardedLockReadGuard { .. }) = self.lock {
+            bail!("savepoint in read transaction")
+        }
+        self.conn.as_ref().unwrap().execute(query).map_err(tx_error)
+    }
     fn ensure_stmt(&self, idx: usize) {
         let mut stmt = self.stmts[idx].lock().unwrap();
         if stmt.is_none() {
@@ -185,9 +209,9 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        Ok(match statement.next().into_diagnostic()? {
+        Ok(match statement.next().map_err(tx_error)? {
             State::Row => {
-                let res = statement.read::<Vec<u8>, _>(0).into_diagnostic()?;
+                let res = statement.read::<Vec<u8>, _>(0).map_err(tx_error)?;
                 Some(res)
             }
             State::Done => None,
@@ -210,7 +234,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
 
         statement.bind((1, key)).unwrap();
         statement.bind((2, val)).unwrap();
-        while statement.next().into_diagnostic()? != State::Done {}
+        while statement.next().map_err(tx_error)? != State::Done {}
         Ok(())
     }
 
@@ -225,7 +249,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        while statement.next().into_diagnostic()? != State::Done {}
+        while statement.next().map_err(tx_error)? != State::Done {}
 
         Ok(())
     }
@@ -249,7 +273,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        Ok(match statement.next().into_diagnostic()? {
+        Ok(match statement.next().map_err(tx_error)? {
             State::Row => true,
             State::Done => false,
         })
@@ -260,7 +284,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
             if !self.committed {
                 let query = r#"commit;"#;
                 let mut statement = self.conn.as_ref().unwrap().prepare(query).unwrap();
-                while statement.next().into_diagnostic()? != State::Done {}
+                while statement.next().map_err(tx_error)? != State::Done {}
                 self.committed = true;
             } else {
                 bail!("multiple commits")
@@ -269,6 +293,31 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         Ok(())
     }
 
+    fn set_savepoint(&mut self) -> Result<()> {
+        self.execute_in_write(&format!("savepoint cozo_sp{};", self.savepoints))?;
+        self.savepoints += 1;
+        Ok(())
+    }
+
+    fn rollback_to_savepoint(&mut self) -> Result<()> {
+        if self.savepoints == 0 {
+            bail!("no savepoint to roll back to")
+        }
+        let name = format!("cozo_sp{}", self.savepoints - 1);
+        self.execute_in_write(&format!("rollback to {name}; release {name};"))?;
+        self.savepoints -= 1;
+        Ok(())
+    }
+
+    fn pop_savepoint(&mut self) -> Result<()> {
+        if self.savepoints == 0 {
+            bail!("no savepoint to remove")
+        }
+        self.execute_in_write(&format!("release cozo_sp{};", self.savepoints - 1))?;
+        self.savepoints -= 1;
+        Ok(())
+    }
+
     fn range_scan_tuple<'a>(
         &'a self,
         lower: &[u8],
@@ -396,11 +445,11 @@ struct SkipIter<'l> {
 impl<'l> SkipIter<'l> {
     fn next_inner(&mut self) -> Result<Option<Tuple>> {
         loop {
-            self.stmt.reset().into_diagnostic()?;
+            self.stmt.reset().map_err(tx_error)?;
             self.stmt.bind((1, &self.next_bound as &[u8])).unwrap();
             self.stmt.bind((2, &self.upper_bound as &[u8])).unwrap();
 
-            match self.stmt.next().into_diagnostic()? {
+            match self.stmt.next().map_err(tx_error)? {
                 State::Done => return Ok(None),
                 State::Row => {
                     let k = self.stmt.read::<Vec<u8>, _>(0).unwrap();
@@ -8,6 +8,7 @@
 
 use std::collections::BTreeMap;
 use std::default::Default;
+use std::sync::Arc;
 
 use miette::Result;
 
@@ -45,8 +46,10 @@ impl<'s> Storage<'s> for TempStorage {
     }
 }
 
+/// Clones share the data until one of them writes to it
+#[derive(Default, Clone)]
 pub(crate) struct TempTx {
-    store: BTreeMap<Vec<u8>, Vec<u8>>,
+    store: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
 }
 
 impl<'s> StoreTx<'s> for TempTx {
@@ -55,7 +58,7 @@ impl<'s> StoreTx<'s> for TempTx {
     }
 
     fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
-        self.store.insert(key.to_vec(), val.to_vec());
+        Arc::make_mut(&mut self.store).insert(key.to_vec(), val.to_vec());
         Ok(())
     }
 
@@ -64,7 +67,9 @@ impl<'s> StoreTx<'s> for TempTx {
     }
 
     fn del(&mut self, key: &[u8]) -> Result<()> {
-        self.store.remove(key);
+        if self.store.contains_key(key) {
+            Arc::make_mut(&mut self.store).remove(key);
+        }
         Ok(())
     }
 
@@ -36,6 +36,18 @@ class CozoTx {
     commit() {
         return native.commit_tx(this.tx_id)
     }
+
+    savepoint(name) {
+        return native.savepoint_tx(this.tx_id, name)
+    }
+
+    rollbackTo(name) {
+        return native.rollback_to_tx(this.tx_id, name)
+    }
+
+    release(name) {
+        return native.release_tx(this.tx_id, name)
+    }
 }
 
 class CozoDb {
@@ -179,6 +179,11 @@ fn value2js<'a>(cx: &mut impl Context<'a>, val: &DataValue) -> JsResult<'a, JsVa
             target_l.as_value(cx)
         }
         DataValue::Json(JsonData(j)) => json2js(cx, j)?,
+        v @ (DataValue::Timestamp(_)
+        | DataValue::Date(_)
+        | DataValue::Duration(_)
+        | DataValue::Decimal(_)
+        | DataValue::Geometry(_)) => json2js(cx, &serde_json::Value::from(v.clone()))?,
     })
 }
 
@@ -386,6 +391,42 @@ fn commit_tx(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     }
 }
 
+fn savepoint_tx(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let tx = get_tx!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    match tx.savepoint(&name) {
+        Ok(_) => Ok(cx.undefined()),
+        Err(err) => {
+            let msg = cx.string(err.to_string());
+            cx.throw(msg)
+        }
+    }
+}
+
+fn rollback_to_tx(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let tx = get_tx!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    match tx.rollback_to(&name) {
+        Ok(_) => Ok(cx.undefined()),
+        Err(err) => {
+            let msg = cx.string(err.to_string());
+            cx.throw(msg)
+        }
+    }
+}
+
+fn release_tx(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let tx = get_tx!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    match tx.release(&name) {
+        Ok(_) => Ok(cx.undefined()),
+        Err(err) => {
+            let msg = cx.string(err.to_string());
+            cx.throw(msg)
+        }
+    }
+}
+
 fn query_db(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let db = get_db!(cx);
     let query = cx.argument::<JsString>(1)?.value(&mut cx);
@@ -776,6 +817,9 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("unregister_named_rule", unregister_named_rule)?;
     cx.export_function("abort_tx", abort_tx)?;
     cx.export_function("commit_tx", commit_tx)?;
+    cx.export_function("savepoint_tx", savepoint_tx)?;
+    cx.export_function("rollback_to_tx", rollback_to_tx)?;
+    cx.export_function("release_tx", release_tx)?;
     cx.export_function("multi_transact", multi_transact)?;
     cx.export_function("query_tx", query_tx)?;
     Ok(())
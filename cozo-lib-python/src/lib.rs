@@ -158,6 +158,11 @@ fn value_to_py(val: DataValue, py: Python<'_>) -> PyObject {
             }
         },
         DataValue::Json(JsonData(j)) => json_to_py(j, py),
+        v @ (DataValue::Timestamp(_)
+        | DataValue::Date(_)
+        | DataValue::Duration(_)
+        | DataValue::Decimal(_)
+        | DataValue::Geometry(_)) => json_to_py(serde_json::Value::from(v), py),
     }
 }
 
@@ -370,6 +375,21 @@ impl CozoDbMulTx {
             .commit()
             .map_err(|err| PyException::new_err(err.to_string()))
     }
+    pub fn savepoint(&self, name: &str) -> PyResult<()> {
+        self.tx
+            .savepoint(name)
+            .map_err(|err| PyException::new_err(err.to_string()))
+    }
+    pub fn rollback_to(&self, name: &str) -> PyResult<()> {
+        self.tx
+            .rollback_to(name)
+            .map_err(|err| PyException::new_err(err.to_string()))
+    }
+    pub fn release(&self, name: &str) -> PyResult<()> {
+        self.tx
+            .release(name)
+            .map_err(|err| PyException::new_err(err.to_string()))
+    }
     pub fn run_script(&self, py: Python<'_>, query: &str, params: &PyDict) -> PyResult<PyObject> {
         let params = convert_params(params)?;
         match py.allow_threads(|| self.tx.run_script(query, params)) {